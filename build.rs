@@ -11,6 +11,10 @@ fn main() -> Result<(), Box<dyn Error>> {
         .print_metadata(MetadataPrintout::Full)
         .capability(Capability::InputAttachment)
         .build()?;
+    SpirvBuilder::new("res/shader/multiview", "spirv-unknown-vulkan1.1")
+        .print_metadata(MetadataPrintout::Full)
+        .capability(Capability::MultiView)
+        .build()?;
     SpirvBuilder::new("res/shader/gui", "spirv-unknown-vulkan1.1")
         .print_metadata(MetadataPrintout::Full)
         .build()?;
@@ -13,13 +13,22 @@
 #[cfg(not(target_arch = "spirv"))]
 use spirv_std::macros::spirv;
 
+#[cfg(target_arch = "spirv")]
+use spirv_std::num_traits::Float;
+
 use spirv_std::{
     glam::{vec4, Mat4, Vec2, Vec4},
     image::{Image2d, SampledImage},
 };
 
-pub struct Mat {
-    matrix: Mat4,
+pub struct Constants {
+    transform: Mat4,
+    /// Whether the target is sRGB, in which case colors need to be linearized
+    srgb: u32,
+}
+
+fn srgb_to_linear(color: Vec4) -> Vec4 {
+    vec4(color.x.powf(2.2), color.y.powf(2.2), color.z.powf(2.2), color.w)
 }
 
 #[allow(unused_attributes)]
@@ -37,7 +46,7 @@ pub fn gui_fs(
 #[allow(unused_attributes)]
 #[spirv(vertex)]
 pub fn gui_vs(
-    #[spirv(push_constant)] transform: &Mat,
+    #[spirv(push_constant)] constants: &Constants,
     in_pos: Vec2,
     in_uv: Vec2,
     in_color: Vec4,
@@ -45,7 +54,12 @@ pub fn gui_vs(
     color: &mut Vec4,
     #[spirv(position, invariant)] out_pos: &mut Vec4,
 ) {
-    *out_pos = transform.matrix * vec4(in_pos.x, in_pos.y, 0.0, 1.0);
+    *out_pos = constants.transform * vec4(in_pos.x, in_pos.y, 0.0, 1.0);
     *uv = in_uv;
-    *color = in_color;
+    // Imgui colors are sRGB-authored, while an sRGB target expects linear values
+    *color = if constants.srgb != 0 {
+        srgb_to_linear(in_color)
+    } else {
+        in_color
+    };
 }
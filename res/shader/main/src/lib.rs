@@ -14,8 +14,8 @@
 use spirv_std::macros::spirv;
 
 use spirv_std::{
-    glam::{vec4, IVec2, Mat4, Vec2, Vec3, Vec4},
-    image::{Image, Image2d, SampledImage},
+    glam::{vec4, IVec2, Mat3, Mat4, UVec3, Vec2, Vec3, Vec4},
+    image::{Image, Image2d, Image2dArray, SampledImage},
 };
 
 #[allow(unused_attributes)]
@@ -28,6 +28,39 @@ pub struct Mat {
     matrix: Mat4,
 }
 
+/// Inverse-transpose of the model-view 3x3, with columns padded as std140 requires
+pub struct NormalMat {
+    x_axis: Vec4,
+    y_axis: Vec4,
+    z_axis: Vec4,
+}
+
+impl NormalMat {
+    fn get_matrix(&self) -> Mat3 {
+        Mat3::from_cols(
+            Vec3::new(self.x_axis.x, self.x_axis.y, self.x_axis.z),
+            Vec3::new(self.y_axis.x, self.y_axis.y, self.y_axis.z),
+            Vec3::new(self.z_axis.x, self.z_axis.y, self.z_axis.z),
+        )
+    }
+}
+
+/// Weights of up to four morph targets
+pub struct Weights {
+    weights: Vec4,
+}
+
+/// Parameters to fade particles near opaque geometry, see `SoftParticleConstants`
+pub struct SoftParticle {
+    fade_distance: f32,
+    near: f32,
+    far: f32,
+    width: f32,
+    height: f32,
+    /// Whether depth values follow the reverse-Z convention
+    reverse_z: u32,
+}
+
 pub struct Color {
     r: f32,
     g: f32,
@@ -35,11 +68,46 @@ pub struct Color {
     a: f32,
 }
 
+pub struct Material {
+    color: Color,
+    /// Texture coordinate set of the albedo texture
+    albedo_uv: u32,
+    /// Layer of the albedo array texture
+    albedo_layer: u32,
+}
+
+/// Transform to the clip space of the light rendering the shadow map
+pub struct ShadowUniform {
+    light_space: Mat4,
+    /// Added to the depth of a fragment before comparing it, to avoid shadow acne
+    bias: f32,
+    /// How much shadowed fragments are darkened, zero when no shadow map has been rendered
+    strength: f32,
+}
+
+/// Returns the factor of a fragment at `shadow_pos` in the clip space of the light,
+/// which is one when lit and decreases by `strength` when occluded. Depth is reversed
+/// by the projection, hence occluders have greater depth than the fragments they shadow.
+fn get_shadow(shadow: &ShadowUniform, shadow_map: &SampledImage<Image2d>, shadow_pos: Vec4) -> f32 {
+    let ndc = Vec3::new(shadow_pos.x, shadow_pos.y, shadow_pos.z) / shadow_pos.w;
+    let uv = Vec2::new(ndc.x * 0.5 + 0.5, ndc.y * 0.5 + 0.5);
+    if uv.x < 0.0 || uv.x > 1.0 || uv.y < 0.0 || uv.y > 1.0 {
+        return 1.0;
+    }
+    let depth = ndc.z;
+    let occluder: Vec4 = unsafe { shadow_map.sample(uv) };
+    if depth + shadow.bias >= occluder.x {
+        1.0
+    } else {
+        1.0 - shadow.strength
+    }
+}
+
 #[allow(unused_attributes)]
 #[spirv(vertex)]
 pub fn line_vs(
     #[spirv(uniform, descriptor_set = 0, binding = 0)] model: &Mat,
-    #[spirv(uniform, descriptor_set = 0, binding = 1)] _model_view: &Mat,
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] _model_view: &NormalMat,
     #[spirv(uniform, descriptor_set = 1, binding = 0)] view: &Mat,
     #[spirv(uniform, descriptor_set = 1, binding = 1)] proj: &Mat,
     in_pos: Vec3,
@@ -55,19 +123,27 @@ pub fn line_vs(
 #[allow(unused_attributes)]
 #[spirv(fragment)]
 pub fn main_fs(
-    #[spirv(uniform, descriptor_set = 2, binding = 0)] material_color: &Color,
-    #[spirv(descriptor_set = 2, binding = 1)] material_albedo: &SampledImage<Image2d>,
+    #[spirv(uniform, descriptor_set = 2, binding = 0)] material: &Material,
+    #[spirv(descriptor_set = 2, binding = 1)] material_albedo: &SampledImage<Image2dArray>,
+    #[spirv(uniform, descriptor_set = 3, binding = 0)] shadow: &ShadowUniform,
+    #[spirv(descriptor_set = 3, binding = 1)] shadow_map: &SampledImage<Image2d>,
     color: Vec4,
     normal: Vec3,
     uv: Vec2,
+    uv1: Vec2,
+    shadow_pos: Vec4,
     out_color: &mut Vec4,
     out_normal: &mut Vec4,
 ) {
-    let frag: Vec4 = unsafe { material_albedo.sample(uv) };
+    let albedo_uv = if material.albedo_uv == 1 { uv1 } else { uv };
+    let albedo_coord = Vec3::new(albedo_uv.x, albedo_uv.y, material.albedo_layer as f32);
+    let frag: Vec4 = unsafe { material_albedo.sample(albedo_coord) };
+    let material_color = &material.color;
+    let lit = get_shadow(shadow, shadow_map, shadow_pos);
     *out_color = color * frag;
-    out_color.x *= material_color.r;
-    out_color.y *= material_color.g;
-    out_color.z *= material_color.b;
+    out_color.x *= material_color.r * lit;
+    out_color.y *= material_color.g * lit;
+    out_color.z *= material_color.b * lit;
     out_color.w *= material_color.a;
 
     out_normal.x = (normal.x + 1.0) / 2.0;
@@ -76,34 +152,214 @@ pub fn main_fs(
     out_normal.w = 1.0;
 }
 
+/// Converts a depth buffer value to a linear distance from the camera, see `DepthRange::linearize`.
+/// With reverse-Z the near plane is at 1 and the far plane at 0, otherwise the other way around.
+fn linearize_depth(depth: f32, near: f32, far: f32, reverse_z: u32) -> f32 {
+    if reverse_z == 1 {
+        far * near / (near + depth * (far - near))
+    } else {
+        far * near / (far - depth * (far - near))
+    }
+}
+
+/// Opacity factor of a particle fragment, 0 where it touches opaque geometry,
+/// see `SoftParticles::get_fade`
+fn soft_fade(scene_depth: f32, particle_depth: f32, fade_distance: f32) -> f32 {
+    if fade_distance <= 0.0 {
+        return 1.0;
+    }
+    ((scene_depth - particle_depth) / fade_distance)
+        .max(0.0)
+        .min(1.0)
+}
+
+/// Fades particles where they get close to the opaque geometry behind them
+#[allow(unused_attributes)]
+#[spirv(fragment)]
+pub fn particle_fs(
+    #[spirv(push_constant)] constants: &SoftParticle,
+    #[spirv(descriptor_set = 2, binding = 0)] scene_depth: &SampledImage<Image2d>,
+    #[spirv(frag_coord)] frag_coord: Vec4,
+    color: Vec4,
+    out_color: &mut Vec4,
+) {
+    let uv = Vec2::new(
+        frag_coord.x / constants.width,
+        frag_coord.y / constants.height,
+    );
+    let depth: Vec4 = unsafe { scene_depth.sample(uv) };
+    let scene = linearize_depth(depth.x, constants.near, constants.far, constants.reverse_z);
+    // The w of the fragment is the inverse of the view-space distance along the view direction
+    let particle = 1.0 / frag_coord.w;
+    *out_color = color;
+    out_color.w *= soft_fade(scene, particle, constants.fade_distance);
+}
+
+/// Weight of a blended fragment at view-space `distance` with `alpha`, see `Wboit::get_weight`
+fn get_oit_weight(distance: f32, alpha: f32) -> f32 {
+    let near = distance / 5.0;
+    let far = distance / 200.0;
+    let falloff = 1e-5 + near * near + far * far * far * far * far * far;
+    alpha * (10.0 / falloff).max(1e-2).min(3e3)
+}
+
+/// Accumulates blended fragments for weighted-blended order-independent transparency
+#[allow(unused_attributes)]
+#[spirv(fragment)]
+pub fn oit_fs(
+    #[spirv(uniform, descriptor_set = 2, binding = 0)] material: &Material,
+    #[spirv(descriptor_set = 2, binding = 1)] material_albedo: &SampledImage<Image2dArray>,
+    #[spirv(uniform, descriptor_set = 3, binding = 0)] shadow: &ShadowUniform,
+    #[spirv(descriptor_set = 3, binding = 1)] shadow_map: &SampledImage<Image2d>,
+    #[spirv(frag_coord)] frag_coord: Vec4,
+    color: Vec4,
+    _normal: Vec3,
+    uv: Vec2,
+    uv1: Vec2,
+    shadow_pos: Vec4,
+    out_accum: &mut Vec4,
+    out_revealage: &mut Vec4,
+) {
+    let albedo_uv = if material.albedo_uv == 1 { uv1 } else { uv };
+    let albedo_coord = Vec3::new(albedo_uv.x, albedo_uv.y, material.albedo_layer as f32);
+    let frag: Vec4 = unsafe { material_albedo.sample(albedo_coord) };
+    let material_color = &material.color;
+    let lit = get_shadow(shadow, shadow_map, shadow_pos);
+    let frag = color * frag;
+    let alpha = frag.w * material_color.a;
+
+    // The w of the fragment is the inverse of the view-space distance along the view direction
+    let weight = get_oit_weight(1.0 / frag_coord.w, alpha);
+    out_accum.x = frag.x * material_color.r * lit * alpha * weight;
+    out_accum.y = frag.y * material_color.g * lit * alpha * weight;
+    out_accum.z = frag.z * material_color.b * lit * alpha * weight;
+    out_accum.w = alpha * weight;
+    // Blending multiplies revealage by one minus this
+    *out_revealage = vec4(alpha, 0.0, 0.0, 0.0);
+}
+
+/// Composites the average color of blended fragments over the scene
+#[allow(unused_attributes)]
+#[spirv(fragment)]
+pub fn oit_resolve_fs(
+    #[spirv(descriptor_set = 0, binding = 0, input_attachment_index = 0)] accum: &Image!(subpass, type=f32, sampled=false),
+    #[spirv(descriptor_set = 0, binding = 1, input_attachment_index = 1)] revealage: &Image!(subpass, type=f32, sampled=false),
+    out_color: &mut Vec4,
+) {
+    let accum: Vec4 = accum.read_subpass(IVec2::new(0, 0));
+    let revealage: Vec4 = revealage.read_subpass(IVec2::new(0, 0));
+    let weight = if accum.w > 1e-5 { accum.w } else { 1e-5 };
+    *out_color = vec4(
+        accum.x / weight,
+        accum.y / weight,
+        accum.z / weight,
+        1.0 - revealage.x,
+    );
+}
+
 #[spirv(vertex)]
 pub fn main_vs(
     #[spirv(uniform, descriptor_set = 0, binding = 0)] model: &Mat,
-    #[spirv(uniform, descriptor_set = 0, binding = 1)] model_view: &Mat,
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] model_view: &NormalMat,
+    #[spirv(uniform, descriptor_set = 0, binding = 2)] morph: &Weights,
     #[spirv(uniform, descriptor_set = 1, binding = 0)] view: &Mat,
     #[spirv(uniform, descriptor_set = 1, binding = 1)] proj: &Mat,
+    #[spirv(uniform, descriptor_set = 3, binding = 0)] shadow: &ShadowUniform,
     in_pos: Vec3,
     in_color: Vec4,
     in_normal: Vec3,
     in_uv: Vec2,
+    in_uv1: Vec2,
+    in_pos_delta0: Vec3,
+    in_pos_delta1: Vec3,
+    in_pos_delta2: Vec3,
+    in_pos_delta3: Vec3,
+    in_normal_delta0: Vec3,
+    in_normal_delta1: Vec3,
+    in_normal_delta2: Vec3,
+    in_normal_delta3: Vec3,
+    in_tangent: Vec4,
     color: &mut Vec4,
     normal: &mut Vec3,
     uv: &mut Vec2,
+    uv1: &mut Vec2,
+    shadow_pos: &mut Vec4,
+    tangent: &mut Vec4,
     #[spirv(position)] out_pos: &mut Vec4,
 ) {
-    *out_pos = proj.matrix * view.matrix * model.matrix * vec4(in_pos.x, in_pos.y, in_pos.z, 1.0);
+    // Add weighted morph target deltas to the base position and normal
+    let w = morph.weights;
+    let pos = in_pos
+        + in_pos_delta0 * w.x
+        + in_pos_delta1 * w.y
+        + in_pos_delta2 * w.z
+        + in_pos_delta3 * w.w;
+    let norm = in_normal
+        + in_normal_delta0 * w.x
+        + in_normal_delta1 * w.y
+        + in_normal_delta2 * w.z
+        + in_normal_delta3 * w.w;
+
+    let world = model.matrix * vec4(pos.x, pos.y, pos.z, 1.0);
+    *out_pos = proj.matrix * view.matrix * world;
+    *shadow_pos = shadow.light_space * world;
 
     *color = in_color;
 
-    let temp_normal = model_view.matrix * vec4(in_normal.x, in_normal.y, in_normal.z, 1.0);
-    normal.x = temp_normal.x;
-    normal.y = temp_normal.y;
-    normal.z = temp_normal.z;
+    *normal = model_view.get_matrix() * norm;
+    // Tangents follow the surface, unlike normals. Handedness is kept as is.
+    let tan = view.matrix * model.matrix * vec4(in_tangent.x, in_tangent.y, in_tangent.z, 0.0);
+    *tangent = vec4(tan.x, tan.y, tan.z, in_tangent.w);
 
     uv.x = in_uv.x;
     uv.y = in_uv.y;
+    *uv1 = in_uv1;
+}
+
+/// Writes the product of the coordinates of each element of a 32×32 grid
+#[allow(unused_attributes)]
+#[spirv(compute(threads(8, 8)))]
+pub fn main_cs(
+    #[spirv(global_invocation_id)] id: UVec3,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] values: &mut [u32],
+) {
+    let size = 32;
+    if id.x < size && id.y < size {
+        values[(id.y * size + id.x) as usize] = id.x * id.y;
+    }
 }
 
+/// Renders the scene from the camera of a light, morphed like `main_vs`
+#[spirv(vertex)]
+pub fn shadow_vs(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] model: &Mat,
+    #[spirv(uniform, descriptor_set = 0, binding = 2)] morph: &Weights,
+    #[spirv(uniform, descriptor_set = 1, binding = 0)] view: &Mat,
+    #[spirv(uniform, descriptor_set = 1, binding = 1)] proj: &Mat,
+    in_pos: Vec3,
+    _in_color: Vec4,
+    _in_normal: Vec3,
+    _in_uv: Vec2,
+    _in_uv1: Vec2,
+    in_pos_delta0: Vec3,
+    in_pos_delta1: Vec3,
+    in_pos_delta2: Vec3,
+    in_pos_delta3: Vec3,
+    #[spirv(position)] out_pos: &mut Vec4,
+) {
+    let w = morph.weights;
+    let pos = in_pos
+        + in_pos_delta0 * w.x
+        + in_pos_delta1 * w.y
+        + in_pos_delta2 * w.z
+        + in_pos_delta3 * w.w;
+    *out_pos = proj.matrix * view.matrix * model.matrix * vec4(pos.x, pos.y, pos.z, 1.0);
+}
+
+/// Shadow maps only need the depth written by the rasterizer
+#[spirv(fragment)]
+pub fn shadow_fs() {}
+
 #[allow(unused_attributes)]
 #[spirv(fragment)]
 pub fn normal_fs(
@@ -119,19 +375,155 @@ pub fn normal_fs(
     out_color.w = 1.0;
 }
 
+pub struct LightConstants {
+    /// View-space position and radius
+    position: Vec4,
+    /// Color and intensity
+    color: Vec4,
+    inv_proj: Mat4,
+    extent: Vec2,
+    offset: Vec2,
+}
+
+#[allow(unused_attributes)]
+#[spirv(fragment)]
+pub fn light_fs(
+    #[spirv(push_constant)] light: &LightConstants,
+    #[spirv(frag_coord)] frag_coord: Vec4,
+    #[spirv(descriptor_set = 0, binding = 0, input_attachment_index = 0)] albedo: &Image!(subpass, type=f32, sampled=false),
+    #[spirv(descriptor_set = 0, binding = 1, input_attachment_index = 1)] normal: &Image!(subpass, type=f32, sampled=false),
+    #[spirv(descriptor_set = 0, binding = 2, input_attachment_index = 2)] depth: &Image!(subpass, type=f32, sampled=false),
+    out_color: &mut Vec4,
+) {
+    let frag: Vec4 = albedo.read_subpass(IVec2::new(0, 0));
+    let norm: Vec4 = normal.read_subpass(IVec2::new(0, 0));
+    let depth: Vec4 = depth.read_subpass(IVec2::new(0, 0));
+
+    // Reconstruct view-space position, the inverse projection undoes reverse-Z if any
+    let ndc = vec4(
+        (frag_coord.x - light.offset.x) / light.extent.x * 2.0 - 1.0,
+        (frag_coord.y - light.offset.y) / light.extent.y * 2.0 - 1.0,
+        depth.x,
+        1.0,
+    );
+    let view = light.inv_proj * ndc;
+    let pos = Vec3::new(view.x, view.y, view.z) / view.w;
+
+    let n = Vec3::new(norm.x * 2.0 - 1.0, norm.y * 2.0 - 1.0, norm.z * 2.0 - 1.0).normalize();
+    let to_light = Vec3::new(light.position.x, light.position.y, light.position.z) - pos;
+    let distance = to_light.length();
+    let radius = light.position.w;
+
+    // Inverse-square falloff which reaches zero at the volume radius
+    let falloff = (1.0 - distance / radius).max(0.0);
+    let attenuation = light.color.w * falloff * falloff / (distance * distance).max(0.0001);
+    let lambert = n.dot(to_light / distance).max(0.0);
+
+    let lit = lambert * attenuation;
+    *out_color = vec4(
+        frag.x * light.color.x * lit,
+        frag.y * light.color.y * lit,
+        frag.z * light.color.z * lit,
+        0.0,
+    );
+}
+
+pub struct GridConstants {
+    inv_view_proj: Mat4,
+    /// World-space camera position
+    eye: Vec4,
+    color: Vec4,
+    extent: Vec2,
+    spacing: f32,
+    fade_distance: f32,
+    offset: Vec2,
+    /// Whether depth values follow the reverse-Z convention
+    reverse_z: u32,
+}
+
+/// Returns the distance in grid cells from `x` to the closest grid line
+fn get_line_distance(x: f32) -> f32 {
+    let mut f = x - (x as i32) as f32;
+    if f < 0.0 {
+        f += 1.0;
+    }
+    f.min(1.0 - f)
+}
+
+#[allow(unused_attributes)]
+#[spirv(fragment)]
+pub fn grid_fs(
+    #[spirv(push_constant)] grid: &GridConstants,
+    #[spirv(frag_coord)] frag_coord: Vec4,
+    #[spirv(descriptor_set = 0, binding = 2, input_attachment_index = 2)] depth: &Image!(subpass, type=f32, sampled=false),
+    out_color: &mut Vec4,
+) {
+    let depth: Vec4 = depth.read_subpass(IVec2::new(0, 0));
+
+    // Reconstruct world-space positions, the far plane is at 0 with reverse-Z
+    let (far_z, cleared) = if grid.reverse_z != 0 {
+        (0.0, depth.x <= 0.0)
+    } else {
+        (1.0, depth.x >= 1.0)
+    };
+    let ndc_x = (frag_coord.x - grid.offset.x) / grid.extent.x * 2.0 - 1.0;
+    let ndc_y = (frag_coord.y - grid.offset.y) / grid.extent.y * 2.0 - 1.0;
+    let far = grid.inv_view_proj * vec4(ndc_x, ndc_y, far_z, 1.0);
+    let far = Vec3::new(far.x, far.y, far.z) / far.w;
+    let scene = grid.inv_view_proj * vec4(ndc_x, ndc_y, depth.x, 1.0);
+    let scene = Vec3::new(scene.x, scene.y, scene.z) / scene.w;
+
+    // Intersect the view ray with the ground plane y = 0
+    let eye = Vec3::new(grid.eye.x, grid.eye.y, grid.eye.z);
+    let dir = far - eye;
+    if dir.y == 0.0 {
+        *out_color = vec4(0.0, 0.0, 0.0, 0.0);
+        return;
+    }
+    let t = -eye.y / dir.y;
+    let hit = eye + dir * t;
+    let distance = (hit - eye).length();
+
+    // Hidden behind the scene, cleared depth means nothing was drawn there
+    let occluded = !cleared && (scene - eye).length() < distance;
+    if t <= 0.0 || occluded {
+        *out_color = vec4(0.0, 0.0, 0.0, 0.0);
+        return;
+    }
+
+    // Lines get thicker with distance so that they cover about a pixel
+    let width = (distance / grid.extent.y / grid.spacing).max(0.02);
+    let line_x = get_line_distance(hit.x / grid.spacing);
+    let line_z = get_line_distance(hit.z / grid.spacing);
+    let line = (1.0 - line_x.min(line_z) / width).max(0.0);
+    let fade = (1.0 - distance / grid.fade_distance).max(0.0);
+
+    let alpha = grid.color.w * line * fade;
+    *out_color = vec4(grid.color.x, grid.color.y, grid.color.z, alpha);
+}
+
 #[allow(unused_attributes)]
 #[spirv(fragment)]
 pub fn present_fs(
     #[spirv(descriptor_set = 0, binding = 0, input_attachment_index = 0)] albedo: &Image!(subpass, type=f32, sampled=false),
     #[spirv(descriptor_set = 0, binding = 1, input_attachment_index = 1)] normal: &Image!(subpass, type=f32, sampled=false),
+    #[spirv(descriptor_set = 0, binding = 2, input_attachment_index = 2)] light: &Image!(subpass, type=f32, sampled=false),
     out_color: &mut Vec4,
 ) {
     let frag: Vec4 = albedo.read_subpass(IVec2::new(0, 0));
     let _norm: Vec4 = normal.read_subpass(IVec2::new(0, 0));
-    *out_color = frag;
+    let light: Vec4 = light.read_subpass(IVec2::new(0, 0));
+    // Unlit albedo acts as ambient term, accumulated lights are added on top
+    *out_color = vec4(frag.x + light.x, frag.y + light.y, frag.z + light.z, frag.w);
 }
 
 #[spirv(vertex)]
-pub fn present_vs(in_pos: Vec2, #[spirv(position, invariant)] out_pos: &mut Vec4) {
+pub fn present_vs(
+    in_pos: Vec2,
+    in_uv: Vec2,
+    uv: &mut Vec2,
+    #[spirv(position, invariant)] out_pos: &mut Vec4,
+) {
     *out_pos = vec4(in_pos.x, in_pos.y, 0.0, 1.0);
+    *uv = in_uv;
 }
@@ -0,0 +1,118 @@
+// Copyright © 2021
+// Author: Antonio Caggiano <info@antoniocaggiano.eu>
+// SPDX-License-Identifier: MIT
+#![cfg_attr(
+    target_arch = "spirv",
+    no_std,
+    feature(register_attr, lang_items),
+    register_attr(spirv)
+)]
+// HACK(eddyb) can't easily see warnings otherwise from `spirv-builder` builds.
+#![deny(warnings)]
+
+#[cfg(not(target_arch = "spirv"))]
+use spirv_std::macros::spirv;
+
+use spirv_std::glam::{vec4, Mat3, Mat4, Vec2, Vec3, Vec4};
+
+pub struct Mat {
+    matrix: Mat4,
+}
+
+/// Inverse-transpose of the model-view 3x3, with columns padded as std140 requires.
+/// Views only differ by a translation, hence it is the same for all of them.
+pub struct NormalMat {
+    x_axis: Vec4,
+    y_axis: Vec4,
+    z_axis: Vec4,
+}
+
+impl NormalMat {
+    fn get_matrix(&self) -> Mat3 {
+        Mat3::from_cols(
+            Vec3::new(self.x_axis.x, self.x_axis.y, self.x_axis.z),
+            Vec3::new(self.y_axis.x, self.y_axis.y, self.y_axis.z),
+            Vec3::new(self.z_axis.x, self.z_axis.y, self.z_axis.z),
+        )
+    }
+}
+
+/// Weights of up to four morph targets
+pub struct Weights {
+    weights: Vec4,
+}
+
+/// Transform to the clip space of the light rendering the shadow map, the rest of the uniform
+/// is only read by `main_fs`
+pub struct ShadowUniform {
+    light_space: Mat4,
+}
+
+/// Matrices of each view, indexed by view index
+pub struct Views {
+    matrices: [Mat4; 2],
+}
+
+/// Like `main_vs`, rendering each view with its own camera matrices, hence it is paired
+/// with `main_fs`. These shaders live in their own module as they need the multiview capability.
+#[allow(unused_attributes)]
+#[spirv(vertex)]
+pub fn main_multiview_vs(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] model: &Mat,
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] model_view: &NormalMat,
+    #[spirv(uniform, descriptor_set = 0, binding = 2)] morph: &Weights,
+    #[spirv(uniform, descriptor_set = 1, binding = 0)] views: &Views,
+    #[spirv(uniform, descriptor_set = 1, binding = 1)] projs: &Views,
+    #[spirv(uniform, descriptor_set = 3, binding = 0)] shadow: &ShadowUniform,
+    #[spirv(view_index)] view_index: i32,
+    in_pos: Vec3,
+    in_color: Vec4,
+    in_normal: Vec3,
+    in_uv: Vec2,
+    in_uv1: Vec2,
+    in_pos_delta0: Vec3,
+    in_pos_delta1: Vec3,
+    in_pos_delta2: Vec3,
+    in_pos_delta3: Vec3,
+    in_normal_delta0: Vec3,
+    in_normal_delta1: Vec3,
+    in_normal_delta2: Vec3,
+    in_normal_delta3: Vec3,
+    in_tangent: Vec4,
+    color: &mut Vec4,
+    normal: &mut Vec3,
+    uv: &mut Vec2,
+    uv1: &mut Vec2,
+    shadow_pos: &mut Vec4,
+    tangent: &mut Vec4,
+    #[spirv(position)] out_pos: &mut Vec4,
+) {
+    let view = views.matrices[view_index as usize];
+    let proj = projs.matrices[view_index as usize];
+
+    // Add weighted morph target deltas to the base position and normal
+    let w = morph.weights;
+    let pos = in_pos
+        + in_pos_delta0 * w.x
+        + in_pos_delta1 * w.y
+        + in_pos_delta2 * w.z
+        + in_pos_delta3 * w.w;
+    let norm = in_normal
+        + in_normal_delta0 * w.x
+        + in_normal_delta1 * w.y
+        + in_normal_delta2 * w.z
+        + in_normal_delta3 * w.w;
+
+    let world = model.matrix * vec4(pos.x, pos.y, pos.z, 1.0);
+    *out_pos = proj * view * world;
+    *shadow_pos = shadow.light_space * world;
+
+    *color = in_color;
+
+    *normal = model_view.get_matrix() * norm;
+    let tan = view * model.matrix * vec4(in_tangent.x, in_tangent.y, in_tangent.z, 0.0);
+    *tangent = vec4(tan.x, tan.y, tan.z, in_tangent.w);
+
+    *uv = in_uv;
+    *uv1 = in_uv1;
+}
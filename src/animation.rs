@@ -0,0 +1,340 @@
+// Copyright © 2021
+// Author: Antonio Caggiano <info@antoniocaggiano.eu>
+// SPDX-License-Identifier: MIT
+
+use std::collections::HashMap;
+
+use super::*;
+
+/// Values of a node property at each keyframe
+pub enum Keyframes {
+    Translation(Vec<na::Vector3<f32>>),
+    Rotation(Vec<na::UnitQuaternion<f32>>),
+    Scale(Vec<na::Vector3<f32>>),
+    /// Morph target weights
+    Weights(Vec<MorphWeights>),
+}
+
+/// A channel animates one property of a target node
+pub struct Channel {
+    pub target: Handle<Node>,
+    /// Time in seconds of each keyframe, in ascending order
+    pub times: Vec<f32>,
+    pub keyframes: Keyframes,
+}
+
+impl Channel {
+    pub fn new(target: Handle<Node>, times: Vec<f32>, keyframes: Keyframes) -> Self {
+        Self {
+            target,
+            times,
+            keyframes,
+        }
+    }
+
+    /// Returns the indices of the keyframes surrounding `time` and the interpolation factor between them
+    fn find_keyframes(&self, time: f32) -> (usize, usize, f32) {
+        assert!(!self.times.is_empty());
+
+        let last = self.times.len() - 1;
+        if time <= self.times[0] {
+            return (0, 0, 0.0);
+        }
+        if time >= self.times[last] {
+            return (last, last, 0.0);
+        }
+
+        let next = self.times.iter().position(|&t| t > time).unwrap();
+        let prev = next - 1;
+        let factor = (time - self.times[prev]) / (self.times[next] - self.times[prev]);
+        (prev, next, factor)
+    }
+
+    /// Samples this channel at `time` writing the result into `pose`
+    fn sample(&self, time: f32, pose: &mut Pose) {
+        let (prev, next, factor) = self.find_keyframes(time);
+        match &self.keyframes {
+            Keyframes::Translation(values) => {
+                pose.translation = Some(values[prev].lerp(&values[next], factor));
+            }
+            Keyframes::Rotation(values) => {
+                pose.rotation = Some(values[prev].slerp(&values[next], factor));
+            }
+            Keyframes::Scale(values) => {
+                pose.scale = Some(values[prev].lerp(&values[next], factor));
+            }
+            Keyframes::Weights(values) => {
+                pose.morph_weights = Some(values[prev].lerp(&values[next], factor));
+            }
+        }
+    }
+}
+
+/// A clip is a collection of channels played together, such as a walk cycle
+pub struct Clip {
+    pub channels: Vec<Channel>,
+}
+
+impl Clip {
+    pub fn new(channels: Vec<Channel>) -> Self {
+        Self { channels }
+    }
+
+    /// Duration in seconds, which is the time of the last keyframe
+    pub fn get_duration(&self) -> f32 {
+        self.channels
+            .iter()
+            .filter_map(|channel| channel.times.last())
+            .fold(0.0, |duration: f32, &time| duration.max(time))
+    }
+
+    /// Samples all channels at `time`, which wraps around the clip duration
+    pub fn sample(&self, time: f32) -> HashMap<Handle<Node>, Pose> {
+        let duration = self.get_duration();
+        let time = if duration > 0.0 { time % duration } else { 0.0 };
+
+        let mut poses = HashMap::new();
+        for channel in &self.channels {
+            let pose = poses.entry(channel.target).or_insert_with(Pose::default);
+            channel.sample(time, pose);
+        }
+        poses
+    }
+}
+
+/// Animated properties of a node. Properties not animated by a clip are `None`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Pose {
+    pub translation: Option<na::Vector3<f32>>,
+    pub rotation: Option<na::UnitQuaternion<f32>>,
+    pub scale: Option<na::Vector3<f32>>,
+    pub morph_weights: Option<MorphWeights>,
+}
+
+impl Pose {
+    /// Blends two poses where `weight` 0 means `self` and 1 means `other`.
+    /// When a property is animated only by one of them, that value is used.
+    pub fn blend(&self, other: &Pose, weight: f32) -> Pose {
+        let translation = match (self.translation, other.translation) {
+            (Some(a), Some(b)) => Some(a.lerp(&b, weight)),
+            (a, b) => a.or(b),
+        };
+        let rotation = match (self.rotation, other.rotation) {
+            (Some(a), Some(b)) => Some(a.slerp(&b, weight)),
+            (a, b) => a.or(b),
+        };
+        let scale = match (self.scale, other.scale) {
+            (Some(a), Some(b)) => Some(a.lerp(&b, weight)),
+            (a, b) => a.or(b),
+        };
+        let morph_weights = match (self.morph_weights, other.morph_weights) {
+            (Some(a), Some(b)) => Some(a.lerp(&b, weight)),
+            (a, b) => a.or(b),
+        };
+        Pose {
+            translation,
+            rotation,
+            scale,
+            morph_weights,
+        }
+    }
+
+    pub fn apply(&self, node: &mut Node) {
+        if let Some(translation) = &self.translation {
+            node.trs.set_translation(translation);
+        }
+        if let Some(rotation) = &self.rotation {
+            node.trs.set_rotation(rotation);
+        }
+        if let Some(scale) = &self.scale {
+            node.trs.scale(scale);
+        }
+        if let Some(morph_weights) = self.morph_weights {
+            node.morph_weights = morph_weights;
+        }
+    }
+}
+
+/// State of a clip being played
+#[derive(Clone, Copy)]
+struct Playback {
+    clip: Handle<Clip>,
+    time: f32,
+}
+
+/// Plays clips on nodes, optionally cross-fading from one clip to another
+pub struct AnimationPlayer {
+    current: Option<Playback>,
+    /// Clip we are fading into, which replaces current one when the fade is over
+    next: Option<Playback>,
+    fade_time: f32,
+    fade_duration: f32,
+}
+
+impl AnimationPlayer {
+    pub fn new() -> Self {
+        Self {
+            current: None,
+            next: None,
+            fade_time: 0.0,
+            fade_duration: 0.0,
+        }
+    }
+
+    /// Starts playing `clip` immediately, without blending
+    pub fn play(&mut self, clip: Handle<Clip>) {
+        self.current = Some(Playback { clip, time: 0.0 });
+        self.next = None;
+    }
+
+    /// Starts playing `clip` while fading out the current one over `duration` seconds
+    pub fn crossfade_to(&mut self, clip: Handle<Clip>, duration: f32) {
+        if self.current.is_none() || duration <= 0.0 {
+            return self.play(clip);
+        }
+        self.next = Some(Playback { clip, time: 0.0 });
+        self.fade_time = 0.0;
+        self.fade_duration = duration;
+    }
+
+    /// Returns the weight of the clip we are fading into
+    pub fn get_blend_weight(&self) -> f32 {
+        if self.next.is_some() {
+            (self.fade_time / self.fade_duration).min(1.0)
+        } else {
+            0.0
+        }
+    }
+
+    /// Advances playback by `delta` seconds
+    pub fn advance(&mut self, delta: f32) {
+        if let Some(current) = &mut self.current {
+            current.time += delta;
+        }
+        if let Some(next) = &mut self.next {
+            next.time += delta;
+            self.fade_time += delta;
+            if self.fade_time >= self.fade_duration {
+                self.current = self.next.take();
+            }
+        }
+    }
+
+    /// Samples the clips being played, blending them when cross-fading
+    pub fn sample(&self, clips: &Pack<Clip>) -> HashMap<Handle<Node>, Pose> {
+        let sample = |playback: &Option<Playback>| match playback {
+            Some(playback) => match clips.get(playback.clip) {
+                Some(clip) => clip.sample(playback.time),
+                None => HashMap::new(),
+            },
+            None => HashMap::new(),
+        };
+
+        let mut poses = sample(&self.current);
+        if self.next.is_none() {
+            return poses;
+        }
+
+        let weight = self.get_blend_weight();
+        for (node, next_pose) in sample(&self.next) {
+            let pose = poses.entry(node).or_insert_with(Pose::default);
+            *pose = pose.blend(&next_pose, weight);
+        }
+        poses
+    }
+
+    /// Advances playback and applies the sampled poses to the target nodes
+    pub fn update(&mut self, delta: f32, clips: &Pack<Clip>, nodes: &mut Pack<Node>) {
+        self.advance(delta);
+        for (node, pose) in self.sample(clips) {
+            if let Some(node) = nodes.get_mut(node) {
+                pose.apply(node);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn crossfade_half() {
+        let node = Handle::<Node>::new(0);
+
+        let mut clips = Pack::new();
+        let walk = clips.push(Clip::new(vec![Channel::new(
+            node,
+            vec![0.0],
+            Keyframes::Translation(vec![na::Vector3::new(0.0, 0.0, 0.0)]),
+        )]));
+        let run = clips.push(Clip::new(vec![Channel::new(
+            node,
+            vec![0.0],
+            Keyframes::Translation(vec![na::Vector3::new(2.0, 4.0, 0.0)]),
+        )]));
+
+        let mut player = AnimationPlayer::new();
+        player.play(walk);
+        player.crossfade_to(run, 1.0);
+        player.advance(0.5);
+        assert_eq!(player.get_blend_weight(), 0.5);
+
+        let poses = player.sample(&clips);
+        let translation = poses[&node].translation.unwrap();
+        assert!((translation - na::Vector3::new(1.0, 2.0, 0.0)).norm() < 1e-6);
+
+        // Fade is over
+        player.advance(0.5);
+        assert_eq!(player.get_blend_weight(), 0.0);
+        let translation = player.sample(&clips)[&node].translation.unwrap();
+        assert!((translation - na::Vector3::new(2.0, 4.0, 0.0)).norm() < 1e-6);
+    }
+
+    #[test]
+    fn crossfade_rotation_slerp() {
+        let node = Handle::<Node>::new(0);
+
+        let mut clips = Pack::new();
+        let a = clips.push(Clip::new(vec![Channel::new(
+            node,
+            vec![0.0],
+            Keyframes::Rotation(vec![na::UnitQuaternion::identity()]),
+        )]));
+        let quarter = std::f32::consts::FRAC_PI_2;
+        let b = clips.push(Clip::new(vec![Channel::new(
+            node,
+            vec![0.0],
+            Keyframes::Rotation(vec![na::UnitQuaternion::from_axis_angle(
+                &na::Vector3::y_axis(),
+                quarter,
+            )]),
+        )]));
+
+        let mut player = AnimationPlayer::new();
+        player.play(a);
+        player.crossfade_to(b, 2.0);
+        player.advance(1.0);
+
+        let rotation = player.sample(&clips)[&node].rotation.unwrap();
+        let expected = na::UnitQuaternion::from_axis_angle(&na::Vector3::y_axis(), quarter / 2.0);
+        assert!(rotation.angle_to(&expected) < 1e-5);
+    }
+
+    #[test]
+    fn sample_between_keyframes() {
+        let node = Handle::<Node>::new(0);
+        let clip = Clip::new(vec![Channel::new(
+            node,
+            vec![0.0, 2.0],
+            Keyframes::Scale(vec![
+                na::Vector3::new(1.0, 1.0, 1.0),
+                na::Vector3::new(3.0, 3.0, 3.0),
+            ]),
+        )]);
+        assert_eq!(clip.get_duration(), 2.0);
+
+        let scale = clip.sample(0.5)[&node].scale.unwrap();
+        assert!((scale - na::Vector3::new(1.5, 1.5, 1.5)).norm() < 1e-6);
+    }
+}
@@ -0,0 +1,512 @@
+// Copyright © 2021
+// Author: Antonio Caggiano <info@antoniocaggiano.eu>
+// SPDX-License-Identifier: MIT
+
+use ash::vk;
+use std::{cell::RefCell, rc::Rc};
+
+use super::*;
+
+/// Memory which the host can map to write into it
+pub trait MemoryMap {
+    fn map_memory(&mut self) -> *mut u8;
+    fn unmap_memory(&mut self);
+    fn get_size(&self) -> vk::DeviceSize;
+}
+
+/// Linear allocator of uniforms within a single block of memory.
+/// Memory is mapped once when uploads begin, and unmapped once when they end,
+/// instead of mapping and unmapping a buffer for every upload.
+/// Allocations are kept until `reset`, so that descriptor sets referring to them can be reused.
+pub struct UniformArena<M: MemoryMap = Buffer> {
+    memory: M,
+    /// Mapped pointer, available between `begin` and `end`
+    data: Option<*mut u8>,
+    /// Offset where next allocation will be placed
+    offset: vk::DeviceSize,
+    /// Uniforms offsets should be multiple of `minUniformBufferOffsetAlignment`
+    alignment: vk::DeviceSize,
+}
+
+impl<M: MemoryMap> UniformArena<M> {
+    pub fn new(memory: M, alignment: vk::DeviceSize) -> Self {
+        assert!(alignment.is_power_of_two());
+        Self {
+            memory,
+            data: None,
+            offset: 0,
+            alignment,
+        }
+    }
+
+    /// Reserves space for a `T`, returning its offset within the memory
+    pub fn allocate<T>(&mut self) -> vk::DeviceSize {
+        let size = std::mem::size_of::<T>() as vk::DeviceSize;
+        let offset = (self.offset + self.alignment - 1) & !(self.alignment - 1);
+        assert!(
+            offset + size <= self.memory.get_size(),
+            "Failed to allocate uniform: arena is full"
+        );
+        self.offset = offset + size;
+        offset
+    }
+
+    /// Maps the memory, so that following writes do not need to map it again
+    pub fn begin(&mut self) {
+        assert!(self.data.is_none(), "Uploads already begun");
+        self.data = Some(self.memory.map_memory());
+    }
+
+    /// Writes `value` at `offset`, which should come from `allocate::<T>`
+    pub fn write<T>(&mut self, offset: vk::DeviceSize, value: &T) {
+        let data = self
+            .data
+            .expect("Uploads should happen between begin and end");
+
+        let size = std::mem::size_of::<T>() as vk::DeviceSize;
+        assert!(
+            offset + size <= self.offset,
+            "Failed to write uniform: not allocated"
+        );
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                value as *const T as *const u8,
+                data.add(offset as usize),
+                size as usize,
+            );
+        }
+    }
+
+    /// Allocates and writes `value`, returning its offset within the memory
+    pub fn upload<T>(&mut self, value: &T) -> vk::DeviceSize {
+        let offset = self.allocate::<T>();
+        self.write(offset, value);
+        offset
+    }
+
+    /// Unmaps the memory, making the uploads available to the device
+    pub fn end(&mut self) {
+        if self.data.take().is_some() {
+            self.memory.unmap_memory();
+        }
+    }
+
+    /// Discards all the allocations. The device should not be using them anymore.
+    pub fn reset(&mut self) {
+        self.offset = 0;
+    }
+
+    pub fn get_memory(&self) -> &M {
+        &self.memory
+    }
+}
+
+impl<M: MemoryMap> Drop for UniformArena<M> {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
+
+/// Pool of descriptor sets which can only be freed all at once
+pub trait DescriptorAllocator {
+    fn allocate_sets(&mut self, layouts: &[vk::DescriptorSetLayout]) -> Vec<vk::DescriptorSet>;
+    fn reset_sets(&mut self);
+}
+
+/// Descriptor sets of throwaway draws, such as the GUI, which are all freed when a frame begins.
+/// Persistent sets should go in the per-frame caches keyed by handles instead.
+pub struct TransientDescriptors<P: DescriptorAllocator = TransientPool> {
+    pool: P,
+    /// Number of sets allocated since last reset
+    count: usize,
+}
+
+impl<P: DescriptorAllocator> TransientDescriptors<P> {
+    pub fn new(pool: P) -> Self {
+        Self { pool, count: 0 }
+    }
+
+    /// Returns sets valid until next `reset`, which should be written before use
+    pub fn allocate(&mut self, layouts: &[vk::DescriptorSetLayout]) -> Vec<vk::DescriptorSet> {
+        let sets = self.pool.allocate_sets(layouts);
+        self.count += sets.len();
+        sets
+    }
+
+    /// Frees all the sets. The device should not be using them anymore.
+    pub fn reset(&mut self) {
+        if self.count > 0 {
+            self.pool.reset_sets();
+            self.count = 0;
+        }
+    }
+
+    pub fn get_count(&self) -> usize {
+        self.count
+    }
+
+    pub fn get_pool(&self) -> &P {
+        &self.pool
+    }
+}
+
+/// Host-visible memory where throwaway vertex and index data is written linearly,
+/// and which is reused from the beginning every frame. When full, it is replaced by a bigger
+/// buffer while the previous one is kept alive until reset, as commands may still refer to it.
+pub struct TransientBuffer {
+    buffer: Buffer,
+    usage: vk::BufferUsageFlags,
+    /// Bytes written since last reset
+    offset: vk::DeviceSize,
+    /// Buffers replaced since last reset
+    retired: Vec<Buffer>,
+}
+
+impl TransientBuffer {
+    pub fn new(
+        allocator: &Rc<RefCell<vk_mem::Allocator>>,
+        usage: vk::BufferUsageFlags,
+        size: vk::DeviceSize,
+    ) -> Self {
+        Self {
+            buffer: Buffer::new_with_size(allocator, usage, size),
+            usage,
+            offset: 0,
+            retired: vec![],
+        }
+    }
+
+    /// Returns `offset` rounded up to a multiple of `alignment`
+    pub fn get_aligned_offset(offset: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+        (offset + alignment - 1) / alignment * alignment
+    }
+
+    /// Writes `data` at the next offset aligned to `alignment`, returning the buffer holding it
+    /// and that offset. They should be bound straight away, as the following pushes may go to
+    /// another buffer, valid until next `reset`.
+    pub fn push<T: Pod>(
+        &mut self,
+        data: &[T],
+        alignment: vk::DeviceSize,
+    ) -> (&Buffer, vk::DeviceSize) {
+        let size = (std::mem::size_of::<T>() * data.len()) as vk::DeviceSize;
+        let mut offset = Self::get_aligned_offset(self.offset, alignment);
+        if offset + size > self.buffer.size {
+            let new_size = (self.buffer.size * 2).max(size);
+            let buffer = Buffer::new_with_size(&self.buffer.allocator, self.usage, new_size);
+            let retired = std::mem::replace(&mut self.buffer, buffer);
+            self.retired.push(retired);
+            offset = 0;
+        }
+
+        let dst = self.buffer.map_memory();
+        unsafe {
+            dst.add(offset as usize)
+                .copy_from(data.as_ptr() as *const u8, size as usize)
+        };
+        self.buffer.unmap_memory();
+
+        self.offset = offset + size;
+        (&self.buffer, offset)
+    }
+
+    /// Makes the whole buffer available again. The device should not be using it anymore.
+    pub fn reset(&mut self) {
+        self.retired.clear();
+        self.offset = 0;
+    }
+
+    /// Returns the number of bytes written since last reset to the current buffer
+    pub fn get_offset(&self) -> vk::DeviceSize {
+        self.offset
+    }
+
+    pub fn get_buffer(&self) -> &Buffer {
+        &self.buffer
+    }
+}
+
+/// Vertex buffer filled with zeros, bound in place of the attributes a primitive does not have,
+/// such as morph deltas or a second set of texture coordinates. It is shared by all primitives,
+/// growing to the largest size requested, while the previous buffer is kept alive until reset.
+#[derive(Default)]
+pub struct ZeroBuffer {
+    buffer: Option<Buffer>,
+    /// Buffers replaced since last reset
+    retired: Vec<Buffer>,
+}
+
+impl ZeroBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a buffer with at least `size` bytes of zeros, valid until next `reset`
+    pub fn get(
+        &mut self,
+        allocator: &Rc<RefCell<vk_mem::Allocator>>,
+        size: vk::DeviceSize,
+    ) -> &Buffer {
+        let current_size = self.buffer.as_ref().map_or(0, |buffer| buffer.size);
+        if current_size < size {
+            let new_size = (current_size * 2).max(size).max(32);
+            let mut buffer =
+                Buffer::new_with_size(allocator, vk::BufferUsageFlags::VERTEX_BUFFER, new_size);
+            let dst = buffer.map_memory();
+            unsafe { dst.write_bytes(0, new_size as usize) };
+            buffer.unmap_memory();
+
+            if let Some(retired) = self.buffer.replace(buffer) {
+                self.retired.push(retired);
+            }
+        }
+        self.buffer.as_ref().unwrap()
+    }
+
+    /// Releases the buffers replaced since last reset. The device should not be using them anymore.
+    pub fn reset(&mut self) {
+        self.retired.clear();
+    }
+}
+
+/// Throwaway resources of a frame, such as the ones of the GUI, which are all reset when the frame
+/// begins recording again. Persistent resources should go in the per-frame caches keyed by handles.
+pub struct TransientArena {
+    /// Command buffers allocated from it are reset all together with the pool
+    pub commands: CommandPool,
+    pub descriptors: TransientDescriptors,
+    /// Vertices and indices
+    pub buffer: TransientBuffer,
+}
+
+impl TransientArena {
+    /// Initial size of the transient buffer
+    pub const BUFFER_SIZE: vk::DeviceSize = 64 * 1024;
+
+    pub fn new(dev: &Dev) -> Self {
+        Self {
+            commands: CommandPool::transient(&dev.device, dev.graphics_queue.family_index),
+            descriptors: TransientDescriptors::new(TransientPool::new(dev)),
+            buffer: TransientBuffer::new(
+                &dev.allocator,
+                vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::INDEX_BUFFER,
+                Self::BUFFER_SIZE,
+            ),
+        }
+    }
+
+    /// Resets everything allocated since last reset. The device should not be using it anymore.
+    pub fn reset(&mut self) {
+        self.commands.reset();
+        self.descriptors.reset();
+        self.buffer.reset();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Counts how many times the memory is mapped and unmapped
+    struct CountingMemory {
+        data: Vec<u8>,
+        maps: u32,
+        unmaps: u32,
+    }
+
+    impl MemoryMap for CountingMemory {
+        fn map_memory(&mut self) -> *mut u8 {
+            self.maps += 1;
+            self.data.as_mut_ptr()
+        }
+
+        fn unmap_memory(&mut self) {
+            self.unmaps += 1;
+        }
+
+        fn get_size(&self) -> vk::DeviceSize {
+            self.data.len() as vk::DeviceSize
+        }
+    }
+
+    impl CountingMemory {
+        fn new(size: usize) -> Self {
+            Self {
+                data: vec![0; size],
+                maps: 0,
+                unmaps: 0,
+            }
+        }
+    }
+
+    #[test]
+    fn single_map() {
+        let mut arena = UniformArena::new(CountingMemory::new(1024), 64);
+
+        arena.begin();
+        let mut offsets = vec![];
+        for i in 0..8 {
+            offsets.push(arena.upload(&na::Matrix4::new_scaling(i as f32)));
+        }
+        arena.end();
+
+        assert_eq!(arena.get_memory().maps, 1);
+        assert_eq!(arena.get_memory().unmaps, 1);
+
+        // Each matrix takes 64 bytes, which is already aligned
+        assert_eq!(offsets, (0..8).map(|i| i * 64).collect::<Vec<_>>());
+        assert_eq!(arena.get_memory().data[64], 1.0f32.to_ne_bytes()[0]);
+    }
+
+    #[test]
+    fn aligned_offsets() {
+        let mut arena = UniformArena::new(CountingMemory::new(1024), 256);
+
+        arena.begin();
+        assert_eq!(arena.upload(&1.0f32), 0);
+        assert_eq!(arena.upload(&2.0f32), 256);
+        arena.end();
+
+        // Allocations are kept across uploads, until reset
+        arena.begin();
+        assert_eq!(arena.upload(&3.0f32), 512);
+        arena.end();
+        arena.reset();
+        assert_eq!(arena.allocate::<f32>(), 0);
+        assert_eq!(arena.get_memory().maps, 2);
+    }
+
+    #[test]
+    fn rewrite_allocations() {
+        let mut arena = UniformArena::new(CountingMemory::new(1024), 64);
+        let offsets: Vec<vk::DeviceSize> = (0..4).map(|_| arena.allocate::<f32>()).collect();
+
+        // Every frame writes the same allocations again
+        for frame in 0..2 {
+            arena.begin();
+            for (i, offset) in offsets.iter().enumerate() {
+                arena.write(*offset, &((frame * 4 + i) as f32));
+            }
+            arena.end();
+        }
+
+        assert_eq!(arena.get_memory().maps, 2);
+        assert_eq!(arena.get_memory().unmaps, 2);
+        let last = &arena.get_memory().data[192..196];
+        assert_eq!(last, 7.0f32.to_ne_bytes());
+    }
+
+    #[test]
+    #[should_panic(expected = "arena is full")]
+    fn full_arena() {
+        let mut arena = UniformArena::new(CountingMemory::new(128), 64);
+        for _ in 0..3 {
+            arena.allocate::<na::Matrix4<f32>>();
+        }
+    }
+
+    /// Keeps track of the sets which have not been freed
+    struct FakePool {
+        next: u64,
+        live: Vec<vk::DescriptorSet>,
+    }
+
+    impl DescriptorAllocator for FakePool {
+        fn allocate_sets(&mut self, layouts: &[vk::DescriptorSetLayout]) -> Vec<vk::DescriptorSet> {
+            use ash::vk::Handle;
+            let sets: Vec<vk::DescriptorSet> = layouts
+                .iter()
+                .map(|_| {
+                    self.next += 1;
+                    vk::DescriptorSet::from_raw(self.next)
+                })
+                .collect();
+            self.live.extend(&sets);
+            sets
+        }
+
+        fn reset_sets(&mut self) {
+            self.live.clear();
+        }
+    }
+
+    #[test]
+    fn transient_sets_reset() {
+        let pool = FakePool {
+            next: 0,
+            live: vec![],
+        };
+        let mut transient = TransientDescriptors::new(pool);
+
+        let layouts = [vk::DescriptorSetLayout::null(); 2];
+        let first = transient.allocate(&layouts);
+        assert_eq!(transient.get_count(), 2);
+        assert_eq!(transient.get_pool().live, first);
+
+        // Next frame
+        transient.reset();
+        assert_eq!(transient.get_count(), 0);
+        assert!(transient.get_pool().live.is_empty());
+
+        let second = transient.allocate(&layouts[..1]);
+        assert_eq!(transient.get_pool().live, second);
+        assert!(first.iter().all(|set| !second.contains(set)));
+    }
+
+    #[test]
+    fn aligned_offsets() {
+        assert_eq!(TransientBuffer::get_aligned_offset(0, 4), 0);
+        assert_eq!(TransientBuffer::get_aligned_offset(1, 4), 4);
+        assert_eq!(TransientBuffer::get_aligned_offset(6, 2), 6);
+        assert_eq!(TransientBuffer::get_aligned_offset(6, 4), 8);
+    }
+
+    #[test]
+    fn transient_buffer_reset() {
+        let ctx = Ctx::headless();
+        let dev = Dev::headless(&ctx);
+        let mut buffer =
+            TransientBuffer::new(&dev.allocator, vk::BufferUsageFlags::VERTEX_BUFFER, 64);
+
+        let (_, first) = buffer.push(&[1u16, 2, 3], 2);
+        assert_eq!(first, 0);
+        let (_, second) = buffer.push(&[4u32], 4);
+        assert_eq!(second, 8);
+        assert_eq!(buffer.get_offset(), 12);
+
+        // Does not fit, hence a bigger buffer is used
+        let old = buffer.get_buffer().buffer;
+        let (grown, offset) = buffer.push(&[0u8; 100], 4);
+        assert_eq!(offset, 0);
+        assert_ne!(grown.buffer, old);
+        assert!(grown.size >= 100);
+
+        // Next frame starts from the beginning of the last buffer
+        buffer.reset();
+        assert_eq!(buffer.get_offset(), 0);
+        let (_, offset) = buffer.push(&[1u32], 4);
+        assert_eq!(offset, 0);
+    }
+
+    #[test]
+    fn zero_buffer_growth() {
+        let ctx = Ctx::headless();
+        let dev = Dev::headless(&ctx);
+        let mut zeros = ZeroBuffer::new();
+
+        let first = zeros.get(&dev.allocator, 64).buffer;
+        // Smaller requests share the same buffer
+        assert_eq!(zeros.get(&dev.allocator, 16).buffer, first);
+
+        // Bigger ones replace it, keeping the previous one alive until reset
+        let grown = zeros.get(&dev.allocator, 100);
+        assert_ne!(grown.buffer, first);
+        assert!(grown.size >= 100);
+        assert_eq!(zeros.retired.len(), 1);
+
+        zeros.reset();
+        assert!(zeros.retired.is_empty());
+        assert!(zeros.get(&dev.allocator, 100).size >= 100);
+    }
+}
@@ -2,23 +2,195 @@
 // Author: Antonio Caggiano <info@antoniocaggiano.eu>
 // SPDX-License-Identifier: MIT
 
-use std::rc::Rc;
+use std::{cell::RefCell, collections::BTreeMap, fmt, rc::Rc};
 
 use super::*;
 
 use ash::{vk::BufferImageCopy, *};
 
+/// Arguments of a `cmd_bind_vertex_buffers` call
+#[derive(Debug, Clone, PartialEq)]
+struct VertexBufferBindings {
+    first_binding: u32,
+    buffers: Vec<vk::Buffer>,
+    offsets: Vec<vk::DeviceSize>,
+}
+
+/// Colors the attachments of a `Pass` are cleared to when it begins
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClearValues {
+    /// Presentation image
+    pub color: [f32; 4],
+    pub albedo: [f32; 4],
+    /// Encoded as the geometry subpass does, see `encode_normal`
+    pub normal: [f32; 4],
+    /// Far plane of the depth convention, see `DepthConfig::get_clear_depth`
+    pub depth: f32,
+}
+
+impl Default for ClearValues {
+    fn default() -> Self {
+        Self {
+            color: Frame::DEFAULT_CLEAR_COLOR,
+            albedo: [0.0, 0.0, 0.0, 1.0],
+            normal: Self::NEUTRAL_NORMAL,
+            depth: DepthConfig::default().get_clear_depth(),
+        }
+    }
+}
+
+impl ClearValues {
+    /// A normal towards the viewer, so that background pixels untouched by geometry
+    /// decode to a sensible normal rather than to (-1, -1, -1)
+    pub const NEUTRAL_NORMAL: [f32; 4] = [0.5, 0.5, 1.0, 1.0];
+
+    /// Maps the components of `normal` from [-1, 1] to [0, 1]
+    pub fn encode_normal(normal: &na::Vector3<f32>) -> [f32; 4] {
+        let encoded = normal.map(|n| (n + 1.0) / 2.0);
+        [encoded.x, encoded.y, encoded.z, 1.0]
+    }
+
+    /// Maps an encoded normal back to [-1, 1], as the lighting subpass does
+    pub fn decode_normal(encoded: [f32; 4]) -> na::Vector3<f32> {
+        na::Vector3::new(encoded[0], encoded[1], encoded[2]).map(|n| n * 2.0 - 1.0)
+    }
+
+    /// Returns the clear values of the attachments of a `Pass`, in order
+    pub fn get_values(&self) -> [vk::ClearValue; 5] {
+        let mut present_clear = vk::ClearValue::default();
+        present_clear.color.float32 = self.color;
+
+        let mut depth_clear = vk::ClearValue::default();
+        depth_clear.depth_stencil.depth = self.depth;
+        depth_clear.depth_stencil.stencil = 0;
+
+        let mut albedo_clear = vk::ClearValue::default();
+        albedo_clear.color.float32 = self.albedo;
+
+        let mut normal_clear = vk::ClearValue::default();
+        normal_clear.color.float32 = self.normal;
+
+        // Lights accumulate on black
+        let mut light_clear = vk::ClearValue::default();
+        light_clear.color.float32 = [0.0, 0.0, 0.0, 0.0];
+
+        [
+            present_clear,
+            depth_clear,
+            albedo_clear,
+            normal_clear,
+            light_clear,
+        ]
+    }
+}
+
+/// State bound to a command buffer since it began recording, to debug draws which do not show up.
+/// It is only tracked in debug builds, hence it is always empty in release ones.
+#[derive(Debug, Default, Clone)]
+pub struct FrameDebugState {
+    /// Name of the bound graphics pipeline, see `Pipeline::name`
+    pub pipeline: Option<String>,
+    /// Descriptor sets by set index
+    pub descriptor_sets: BTreeMap<u32, vk::DescriptorSet>,
+    /// Vertex buffers and their offsets by binding
+    pub vertex_buffers: BTreeMap<u32, (vk::Buffer, vk::DeviceSize)>,
+    pub index_buffer: Option<(vk::Buffer, vk::DeviceSize, vk::IndexType)>,
+    pub viewport: Option<vk::Viewport>,
+    pub scissor: Option<vk::Rect2D>,
+}
+
+impl FrameDebugState {
+    fn bind_pipeline(&mut self, name: &str) {
+        self.pipeline = Some(name.to_string());
+    }
+
+    fn bind_descriptor_sets(&mut self, first_set: u32, sets: &[vk::DescriptorSet]) {
+        for (index, set) in (first_set..).zip(sets) {
+            self.descriptor_sets.insert(index, *set);
+        }
+    }
+
+    fn bind_vertex_buffers(
+        &mut self,
+        first_binding: u32,
+        buffers: &[(vk::Buffer, vk::DeviceSize)],
+    ) {
+        for (binding, buffer) in (first_binding..).zip(buffers) {
+            self.vertex_buffers.insert(binding, *buffer);
+        }
+    }
+}
+
+impl fmt::Display for FrameDebugState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Pipeline: {}",
+            self.pipeline.as_deref().unwrap_or("none")
+        )?;
+        for (index, set) in &self.descriptor_sets {
+            writeln!(f, " · set {}: {:?}", index, set)?;
+        }
+        for (binding, (buffer, offset)) in &self.vertex_buffers {
+            writeln!(f, " · vertex {}: {:?} + {}", binding, buffer, offset)?;
+        }
+        if let Some((buffer, offset, index_type)) = &self.index_buffer {
+            writeln!(f, " · index: {:?} + {} {:?}", buffer, offset, index_type)?;
+        }
+        if let Some(viewport) = &self.viewport {
+            writeln!(
+                f,
+                " · viewport: ({}, {}) {}x{} depth {}..{}",
+                viewport.x,
+                viewport.y,
+                viewport.width,
+                viewport.height,
+                viewport.min_depth,
+                viewport.max_depth
+            )?;
+        }
+        if let Some(scissor) = &self.scissor {
+            write!(
+                f,
+                " · scissor: ({}, {}) {}x{}",
+                scissor.offset.x, scissor.offset.y, scissor.extent.width, scissor.extent.height
+            )?;
+        }
+        Ok(())
+    }
+}
+
 pub struct CommandBuffer {
     pub command_buffer: vk::CommandBuffer,
     pool: vk::CommandPool,
     device: Rc<Device>,
+    /// Used to validate typed push constants
+    max_push_constants_size: u32,
+    /// Bound state tracked while recording, only in debug builds
+    bound: RefCell<FrameDebugState>,
+    /// Alignment of vertex buffer offsets required by the bound pipeline, indexed by binding,
+    /// which are only checked in debug builds
+    vertex_alignments: RefCell<Vec<vk::DeviceSize>>,
 }
 
 impl CommandBuffer {
+    /// Push constants size guaranteed by every Vulkan implementation
+    pub const MIN_PUSH_CONSTANTS_SIZE: u32 = 128;
+
     pub fn new(pool: &CommandPool) -> Self {
+        Self::with_level(pool, vk::CommandBufferLevel::PRIMARY)
+    }
+
+    /// Secondary command buffers are recorded with `begin_secondary` and then executed by
+    /// a primary command buffer with `execute_commands`
+    pub fn secondary(pool: &CommandPool) -> Self {
+        Self::with_level(pool, vk::CommandBufferLevel::SECONDARY)
+    }
+
+    fn with_level(pool: &CommandPool, level: vk::CommandBufferLevel) -> Self {
         let create_info = vk::CommandBufferAllocateInfo::builder()
             .command_pool(pool.pool)
-            .level(vk::CommandBufferLevel::PRIMARY)
+            .level(level)
             .command_buffer_count(1)
             .build();
 
@@ -30,11 +202,43 @@ impl CommandBuffer {
             command_buffer,
             pool: pool.pool,
             device: pool.device.clone(),
+            max_push_constants_size: Self::MIN_PUSH_CONSTANTS_SIZE,
+            bound: RefCell::new(FrameDebugState::default()),
+            vertex_alignments: RefCell::new(vec![]),
+        }
+    }
+
+    /// Returns the state bound since this command buffer began recording, which is empty
+    /// in release builds
+    pub fn get_bound_state(&self) -> FrameDebugState {
+        self.bound.borrow().clone()
+    }
+
+    /// Updates the bound state with `track`, only in debug builds, so that release ones
+    /// do not pay for it on every bind
+    fn track_bound<F: FnOnce(&mut FrameDebugState)>(&self, track: F) {
+        if cfg!(debug_assertions) {
+            track(&mut self.bound.borrow_mut());
         }
     }
 
+    /// Returns the begin info of a command buffer. `ONE_TIME_SUBMIT` suits command buffers
+    /// re-recorded after each submission, while `SIMULTANEOUS_USE` allows a recording to be
+    /// pending more than once. Secondary command buffers need their `inheritance`.
+    pub fn get_begin_info(
+        flags: vk::CommandBufferUsageFlags,
+        inheritance: Option<&vk::CommandBufferInheritanceInfo>,
+    ) -> vk::CommandBufferBeginInfo {
+        let mut begin_info = vk::CommandBufferBeginInfo::builder().flags(flags);
+        if let Some(inheritance) = inheritance {
+            begin_info = begin_info.inheritance_info(inheritance);
+        }
+        begin_info.build()
+    }
+
     pub fn begin(&self, flags: vk::CommandBufferUsageFlags) {
-        let begin_info = vk::CommandBufferBeginInfo::builder().flags(flags).build();
+        self.track_bound(|bound| *bound = FrameDebugState::default());
+        let begin_info = Self::get_begin_info(flags, None);
         unsafe {
             self.device
                 .begin_command_buffer(self.command_buffer, &begin_info)
@@ -42,21 +246,53 @@ impl CommandBuffer {
         .expect("Failed to begin Vulkan command buffer");
     }
 
-    pub fn begin_render_pass(&self, pass: &Pass, framebuffer: &Framebuffer, area: vk::Rect2D) {
-        let mut present_clear = vk::ClearValue::default();
-        present_clear.color.float32 = [0.0, 10.0 / 255.0, 28.0 / 255.0, 1.0];
-
-        let mut depth_clear = vk::ClearValue::default();
-        depth_clear.depth_stencil.depth = 0.0;
-        depth_clear.depth_stencil.stencil = 0;
+    /// Begins a secondary command buffer. With `RENDER_PASS_CONTINUE` it is executed within
+    /// `subpass` of `pass`, and `framebuffer` may be given when it is known.
+    pub fn begin_secondary(
+        &self,
+        flags: vk::CommandBufferUsageFlags,
+        pass: &Pass,
+        subpass: u32,
+        framebuffer: Option<&Framebuffer>,
+    ) {
+        let mut inheritance = vk::CommandBufferInheritanceInfo::builder()
+            .render_pass(pass.render)
+            .subpass(subpass);
+        if let Some(framebuffer) = framebuffer {
+            inheritance = inheritance.framebuffer(framebuffer.framebuffer);
+        }
+        let inheritance = inheritance.build();
 
-        let mut albedo_clear = vk::ClearValue::default();
-        albedo_clear.color.float32 = [0.0, 0.0, 0.0, 1.0];
+        self.track_bound(|bound| *bound = FrameDebugState::default());
+        let begin_info = Self::get_begin_info(flags, Some(&inheritance));
+        unsafe {
+            self.device
+                .begin_command_buffer(self.command_buffer, &begin_info)
+        }
+        .expect("Failed to begin Vulkan secondary command buffer");
+    }
 
-        let mut normal_clear = vk::ClearValue::default();
-        normal_clear.color.float32 = [0.0, 0.0, 0.0, 1.0];
+    /// Executes secondary command buffers. Within a render pass, the current subpass
+    /// should have been started with `SECONDARY_COMMAND_BUFFERS` contents.
+    pub fn execute_commands(&self, secondaries: &[&CommandBuffer]) {
+        let command_buffers: Vec<vk::CommandBuffer> = secondaries
+            .iter()
+            .map(|secondary| secondary.command_buffer)
+            .collect();
+        unsafe {
+            self.device
+                .cmd_execute_commands(self.command_buffer, &command_buffers)
+        };
+    }
 
-        let clear_values = [present_clear, depth_clear, albedo_clear, normal_clear];
+    pub fn begin_render_pass(
+        &self,
+        pass: &Pass,
+        framebuffer: &Framebuffer,
+        area: vk::Rect2D,
+        clears: &ClearValues,
+    ) {
+        let clear_values = clears.get_values();
         let create_info = vk::RenderPassBeginInfo::builder()
             .framebuffer(framebuffer.framebuffer)
             .render_pass(pass.render)
@@ -71,14 +307,106 @@ impl CommandBuffer {
         };
     }
 
+    /// Begins a shadow pass, clearing depth to the far plane of the reversed depth range
+    pub fn begin_shadow_pass(&self, pass: &Pass, shadow: &ShadowFramebuffer) {
+        let mut depth_clear = vk::ClearValue::default();
+        depth_clear.depth_stencil.depth = DepthConfig::reverse_z().get_clear_depth();
+        let clear_values = [depth_clear];
+
+        let create_info = vk::RenderPassBeginInfo::builder()
+            .framebuffer(shadow.framebuffer)
+            .render_pass(pass.render)
+            .render_area(shadow.config.get_scissor())
+            .clear_values(&clear_values)
+            .build();
+        unsafe {
+            self.device.cmd_begin_render_pass(
+                self.command_buffer,
+                &create_info,
+                vk::SubpassContents::INLINE,
+            )
+        };
+    }
+
+    /// Begins a pass created by `Pass::new_oit`, clearing the attachments of `wboit`
+    /// while keeping color and depth
+    pub fn begin_oit_pass(&self, pass: &Pass, wboit: &Wboit) {
+        let [accum_clear, revealage_clear] = Wboit::get_clear_values();
+        let clear_values = [
+            vk::ClearValue::default(),
+            vk::ClearValue::default(),
+            accum_clear,
+            revealage_clear,
+        ];
+
+        let create_info = vk::RenderPassBeginInfo::builder()
+            .framebuffer(wboit.framebuffer)
+            .render_pass(pass.render)
+            .render_area(wboit.get_area())
+            .clear_values(&clear_values)
+            .build();
+        unsafe {
+            self.device.cmd_begin_render_pass(
+                self.command_buffer,
+                &create_info,
+                vk::SubpassContents::INLINE,
+            )
+        };
+    }
+
+    /// Begins a pass created by `Pass::new_particles`, keeping color and depth
+    pub fn begin_particle_pass(&self, pass: &Pass, particles: &ParticleFramebuffer) {
+        let create_info = vk::RenderPassBeginInfo::builder()
+            .framebuffer(particles.framebuffer)
+            .render_pass(pass.render)
+            .render_area(particles.get_area())
+            .build();
+        unsafe {
+            self.device.cmd_begin_render_pass(
+                self.command_buffer,
+                &create_info,
+                vk::SubpassContents::INLINE,
+            )
+        };
+    }
+
     pub fn next_subpass(&self) {
+        self.next_subpass_with_contents(vk::SubpassContents::INLINE);
+    }
+
+    /// Starts the next subpass, whose commands are either recorded inline
+    /// or executed from secondary command buffers
+    pub fn next_subpass_with_contents(&self, contents: vk::SubpassContents) {
+        unsafe { self.device.cmd_next_subpass(self.command_buffer, contents) };
+    }
+
+    /// Clears the color attachment at `attachment` of the current subpass within `rects`
+    pub fn clear_color_attachment(&self, attachment: u32, color: [f32; 4], rects: &[vk::Rect2D]) {
+        let mut value = vk::ClearValue::default();
+        value.color.float32 = color;
+        let clear = vk::ClearAttachment::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .color_attachment(attachment)
+            .clear_value(value)
+            .build();
+        let rects: Vec<vk::ClearRect> = rects
+            .iter()
+            .map(|rect| {
+                vk::ClearRect::builder()
+                    .rect(*rect)
+                    .base_array_layer(0)
+                    .layer_count(1)
+                    .build()
+            })
+            .collect();
         unsafe {
             self.device
-                .cmd_next_subpass(self.command_buffer, vk::SubpassContents::INLINE)
+                .cmd_clear_attachments(self.command_buffer, &[clear], &rects)
         };
     }
 
     pub fn set_viewport(&self, viewport: &vk::Viewport) {
+        self.track_bound(|bound| bound.viewport = Some(*viewport));
         unsafe {
             self.device
                 .cmd_set_viewport(self.command_buffer, 0, &[*viewport])
@@ -86,6 +414,7 @@ impl CommandBuffer {
     }
 
     pub fn set_scissor(&self, scissor: &vk::Rect2D) {
+        self.track_bound(|bound| bound.scissor = Some(*scissor));
         unsafe {
             self.device
                 .cmd_set_scissor(self.command_buffer, 0, &[*scissor])
@@ -93,6 +422,12 @@ impl CommandBuffer {
     }
 
     pub fn bind_pipeline(&self, pipeline: &Pipeline) {
+        self.track_bound(|bound| bound.bind_pipeline(&pipeline.name));
+        if cfg!(debug_assertions) {
+            self.vertex_alignments
+                .borrow_mut()
+                .clone_from(&pipeline.vertex_alignments);
+        }
         let graphics_bind_point = vk::PipelineBindPoint::GRAPHICS;
         unsafe {
             self.device.cmd_bind_pipeline(
@@ -103,6 +438,35 @@ impl CommandBuffer {
         }
     }
 
+    pub fn bind_compute_pipeline(&self, pipeline: &ComputePipeline) {
+        unsafe {
+            self.device.cmd_bind_pipeline(
+                self.command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                pipeline.compute,
+            );
+        }
+    }
+
+    /// Like `bind_descriptor_sets`, but for the compute bind point
+    pub fn bind_compute_descriptor_sets(
+        &self,
+        pipeline: &ComputePipeline,
+        sets: &[vk::DescriptorSet],
+        set_index: u32,
+    ) {
+        unsafe {
+            self.device.cmd_bind_descriptor_sets(
+                self.command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                pipeline.layout,
+                set_index,
+                sets,
+                &[],
+            )
+        };
+    }
+
     /// Set index here corresponds to the descriptor_set index in the shader
     pub fn bind_descriptor_sets(
         &self,
@@ -110,6 +474,7 @@ impl CommandBuffer {
         sets: &[vk::DescriptorSet],
         set_index: u32,
     ) {
+        self.track_bound(|bound| bound.bind_descriptor_sets(set_index, sets));
         let graphics_bind_point = vk::PipelineBindPoint::GRAPHICS;
         unsafe {
             self.device.cmd_bind_descriptor_sets(
@@ -123,31 +488,137 @@ impl CommandBuffer {
         };
     }
 
-    pub fn bind_vertex_buffer(&self, buffer: &Buffer) {
-        let first_binding = 0;
-        let buffers = [buffer.buffer];
-        let offsets = [vk::DeviceSize::default()];
+    /// Binds descriptor sets at the index conventionally used for their update frequency
+    pub fn bind_frequency_sets(
+        &self,
+        pipeline: &Pipeline,
+        sets: &[vk::DescriptorSet],
+        frequency: DescriptorFrequency,
+    ) {
+        self.bind_descriptor_sets(pipeline, sets, frequency.get_set_index());
+    }
+
+    /// Returns the size in bytes of an index, which is the alignment of index buffer offsets
+    pub fn get_index_size(index_type: vk::IndexType) -> vk::DeviceSize {
+        match index_type {
+            vk::IndexType::UINT8_EXT => 1,
+            vk::IndexType::UINT32 => 4,
+            _ => 2,
+        }
+    }
+
+    fn debug_assert_aligned(offset: vk::DeviceSize, alignment: vk::DeviceSize) {
+        debug_assert!(
+            offset % alignment == 0,
+            "Buffer offset {} is not aligned to {}",
+            offset,
+            alignment
+        );
+    }
+
+    /// Returns the arguments of `cmd_bind_vertex_buffers` binding `buffers`
+    /// at consecutive bindings starting from `first_binding`. Offsets should be aligned
+    /// to the `alignments` of their bindings, which are not checked when missing.
+    fn get_vertex_buffer_bindings(
+        first_binding: u32,
+        buffers: &[(vk::Buffer, vk::DeviceSize)],
+        alignments: &[vk::DeviceSize],
+    ) -> VertexBufferBindings {
+        for (binding, (_, offset)) in (first_binding..).zip(buffers) {
+            let alignment = alignments.get(binding as usize).copied().unwrap_or(1);
+            Self::debug_assert_aligned(*offset, alignment);
+        }
+        VertexBufferBindings {
+            first_binding,
+            buffers: buffers.iter().map(|(buffer, _)| *buffer).collect(),
+            offsets: buffers.iter().map(|(_, offset)| *offset).collect(),
+        }
+    }
+
+    /// Binds a vertex buffer, which may be suballocated from a shared buffer at `offset`
+    pub fn bind_vertex_buffer(&self, buffer: &Buffer, offset: vk::DeviceSize) {
+        self.bind_vertex_buffers(0, &[(buffer, offset)]);
+    }
+
+    /// Binds vertex buffers at consecutive bindings starting from `first_binding`, e.g. a
+    /// per-vertex buffer at binding 0 and a per-instance one at binding 1. Offsets should be
+    /// aligned to the formats of the attributes of the bound pipeline.
+    pub fn bind_vertex_buffers(&self, first_binding: u32, buffers: &[(&Buffer, vk::DeviceSize)]) {
+        let buffers: Vec<(vk::Buffer, vk::DeviceSize)> = buffers
+            .iter()
+            .map(|(buffer, offset)| (buffer.buffer, *offset))
+            .collect();
+        let bindings = Self::get_vertex_buffer_bindings(
+            first_binding,
+            &buffers,
+            &self.vertex_alignments.borrow(),
+        );
+        self.track_bound(|bound| bound.bind_vertex_buffers(first_binding, &buffers));
         unsafe {
             self.device.cmd_bind_vertex_buffers(
                 self.command_buffer,
-                first_binding,
-                &buffers,
-                &offsets,
+                bindings.first_binding,
+                &bindings.buffers,
+                &bindings.offsets,
             );
         }
     }
 
-    pub fn bind_index_buffer(&self, buffer: &Buffer) {
+    /// Binds an index buffer at `offset`, which must be aligned to the index size
+    pub fn bind_index_buffer(
+        &self,
+        buffer: &Buffer,
+        offset: vk::DeviceSize,
+        index_type: vk::IndexType,
+    ) {
+        Self::debug_assert_aligned(offset, Self::get_index_size(index_type));
+        self.track_bound(|bound| bound.index_buffer = Some((buffer.buffer, offset, index_type)));
         unsafe {
             self.device.cmd_bind_index_buffer(
                 self.command_buffer,
                 buffer.buffer,
-                0,
-                vk::IndexType::UINT16,
+                offset,
+                index_type,
             );
         }
     }
 
+    /// Sets the limit checked by `push_constants_typed`, which is the minimum guaranteed by default
+    pub fn set_max_push_constants_size(&mut self, size: u32) {
+        self.max_push_constants_size = size;
+    }
+
+    /// Returns the bytes of `constants`, panicking when they do not fit within `max_size`
+    /// at `offset`, or when offset and size are not multiples of 4 as Vulkan requires.
+    pub fn get_constants_bytes<T: Pod>(constants: &T, offset: u32, max_size: u32) -> &[u8] {
+        let size = std::mem::size_of::<T>() as u32;
+        assert!(
+            offset % 4 == 0 && size % 4 == 0,
+            "Push constants offset {} and size {} should be multiples of 4",
+            offset,
+            size
+        );
+        assert!(
+            offset + size <= max_size,
+            "Push constants at offset {} with size {} exceed the limit of {} bytes",
+            offset,
+            size,
+            max_size
+        );
+        as_bytes(constants)
+    }
+
+    pub fn push_constants_typed<T: Pod>(
+        &self,
+        pipeline: &Pipeline,
+        stages: vk::ShaderStageFlags,
+        offset: u32,
+        constants: &T,
+    ) {
+        let bytes = Self::get_constants_bytes(constants, offset, self.max_push_constants_size);
+        self.push_constants(pipeline, stages, offset, bytes);
+    }
+
     pub fn push_constants(
         &self,
         pipeline: &Pipeline,
@@ -186,6 +657,26 @@ impl CommandBuffer {
         }
     }
 
+    /// Draws `instance_count` instances, whose per-instance attributes
+    /// are read starting from `first_instance`
+    pub fn draw_instanced(&self, vertex_count: u32, instance_count: u32, first_instance: u32) {
+        unsafe {
+            self.device.cmd_draw(
+                self.command_buffer,
+                vertex_count,
+                instance_count,
+                0,
+                first_instance,
+            );
+        }
+    }
+
+    pub fn dispatch(&self, x: u32, y: u32, z: u32) {
+        unsafe {
+            self.device.cmd_dispatch(self.command_buffer, x, y, z);
+        }
+    }
+
     pub fn end_render_pass(&self) {
         unsafe {
             self.device.cmd_end_render_pass(self.command_buffer);
@@ -217,6 +708,91 @@ impl CommandBuffer {
         }
     }
 
+    pub fn buffer_barriers(
+        &self,
+        src_stage_mask: vk::PipelineStageFlags,
+        dst_stage_mask: vk::PipelineStageFlags,
+        buffer_memory_barriers: &[vk::BufferMemoryBarrier],
+    ) {
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                self.command_buffer,
+                src_stage_mask,
+                dst_stage_mask,
+                vk::DependencyFlags::default(),
+                &[],
+                buffer_memory_barriers,
+                &[],
+            );
+        }
+    }
+
+    pub fn copy_buffer(&self, src: &Buffer, dst: &Buffer, size: vk::DeviceSize) {
+        let region = vk::BufferCopy::builder().size(size).build();
+        unsafe {
+            self.device
+                .cmd_copy_buffer(self.command_buffer, src.buffer, dst.buffer, &[region]);
+        }
+    }
+
+    pub fn copy_image_to_buffer(&self, image: &Image, buffer: &Buffer, region: &BufferImageCopy) {
+        unsafe {
+            self.device.cmd_copy_image_to_buffer(
+                self.command_buffer,
+                image.image,
+                image.layout,
+                buffer.buffer,
+                &[*region],
+            );
+        }
+    }
+
+    /// Copies `region` of `src` into `dst`, which should be in transfer layouts
+    pub fn copy_image(&self, src: &Image, dst: &Image, region: &vk::ImageCopy) {
+        unsafe {
+            self.device.cmd_copy_image(
+                self.command_buffer,
+                src.image,
+                src.layout,
+                dst.image,
+                dst.layout,
+                &[*region],
+            );
+        }
+    }
+
+    /// Blits `region` of `src` into `dst`, scaling and converting formats when they differ
+    pub fn blit_image(&self, src: &Image, dst: &Image, region: &vk::ImageBlit, filter: vk::Filter) {
+        unsafe {
+            self.device.cmd_blit_image(
+                self.command_buffer,
+                src.image,
+                src.layout,
+                dst.image,
+                dst.layout,
+                &[*region],
+                filter,
+            );
+        }
+    }
+
+    pub fn copy_buffer_to_image_regions(
+        &self,
+        buffer: &Buffer,
+        image: &Image,
+        regions: &[BufferImageCopy],
+    ) {
+        unsafe {
+            self.device.cmd_copy_buffer_to_image(
+                self.command_buffer,
+                buffer.buffer,
+                image.image,
+                image.layout,
+                regions,
+            );
+        }
+    }
+
     pub fn copy_buffer_to_image(&self, buffer: &Buffer, image: &Image, region: &BufferImageCopy) {
         unsafe {
             self.device.cmd_copy_buffer_to_image(
@@ -241,14 +817,27 @@ impl Drop for CommandBuffer {
 
 pub struct CommandPool {
     pool: vk::CommandPool,
+    /// Flags this pool has been created with
+    pub flags: vk::CommandPoolCreateFlags,
     device: Rc<Device>,
 }
 
 impl CommandPool {
-    pub fn new(device: &Rc<Device>, queue_family_index: u32) -> Self {
+    /// Command buffers allocated by this pool are reset and recorded again every frame
+    pub const GRAPHICS_FRAME_FLAGS: vk::CommandPoolCreateFlags =
+        vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER;
+
+    /// Command buffers allocated by this pool are short-lived and submitted only once
+    pub const TRANSIENT_FLAGS: vk::CommandPoolCreateFlags = vk::CommandPoolCreateFlags::TRANSIENT;
+
+    pub fn new_with_flags(
+        device: &Rc<Device>,
+        queue_family_index: u32,
+        flags: vk::CommandPoolCreateFlags,
+    ) -> Self {
         let device = device.clone();
         let create_info = vk::CommandPoolCreateInfo::builder()
-            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+            .flags(flags)
             .queue_family_index(queue_family_index);
 
         let pool = {
@@ -259,7 +848,35 @@ impl CommandPool {
             }
         };
 
-        Self { pool, device }
+        Self {
+            pool,
+            flags,
+            device,
+        }
+    }
+
+    /// Returns a pool suitable for per-frame rendering command buffers
+    pub fn graphics_frame(device: &Rc<Device>, queue_family_index: u32) -> Self {
+        Self::new_with_flags(device, queue_family_index, Self::GRAPHICS_FRAME_FLAGS)
+    }
+
+    /// Returns a pool suitable for one-time-submit command buffers, such as uploads
+    pub fn transient(device: &Rc<Device>, queue_family_index: u32) -> Self {
+        Self::new_with_flags(device, queue_family_index, Self::TRANSIENT_FLAGS)
+    }
+
+    pub fn new(device: &Rc<Device>, queue_family_index: u32) -> Self {
+        Self::graphics_frame(device, queue_family_index)
+    }
+
+    /// Resets all the command buffers allocated by this pool to the initial state.
+    /// None of them should be pending execution.
+    pub fn reset(&self) {
+        unsafe {
+            self.device
+                .reset_command_pool(self.pool, vk::CommandPoolResetFlags::empty())
+        }
+        .expect("Failed to reset Vulkan command pool");
     }
 
     pub fn destroy(&mut self) {
@@ -277,3 +894,195 @@ impl Drop for CommandPool {
         self.destroy();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn vertex_and_instance_buffers() {
+        use ash::vk::Handle;
+
+        let vertices = vk::Buffer::from_raw(1);
+        let instances = vk::Buffer::from_raw(2);
+        let bindings = CommandBuffer::get_vertex_buffer_bindings(
+            0,
+            &[(vertices, 0), (instances, 64)],
+            &[4, 4],
+        );
+        assert_eq!(
+            bindings,
+            VertexBufferBindings {
+                first_binding: 0,
+                buffers: vec![vertices, instances],
+                offsets: vec![0, 64],
+            }
+        );
+    }
+
+    #[test]
+    fn begin_flags() {
+        let flags = vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT;
+        let begin_info = CommandBuffer::get_begin_info(flags, None);
+        assert_eq!(begin_info.flags, flags);
+        assert!(begin_info.p_inheritance_info.is_null());
+
+        let flags = vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE
+            | vk::CommandBufferUsageFlags::SIMULTANEOUS_USE;
+        let inheritance = vk::CommandBufferInheritanceInfo::builder()
+            .subpass(Pass::LIGHTING_SUBPASS)
+            .build();
+        let begin_info = CommandBuffer::get_begin_info(flags, Some(&inheritance));
+        assert_eq!(begin_info.flags, flags);
+        assert_eq!(unsafe { (*begin_info.p_inheritance_info).subpass }, 1);
+    }
+
+    #[test]
+    fn pool_presets() {
+        let ctx = Ctx::headless();
+        let dev = Dev::headless(&ctx);
+        let family = dev.graphics_queue.family_index;
+
+        let frame = CommandPool::graphics_frame(&dev.device, family);
+        assert!(frame
+            .flags
+            .contains(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER));
+        assert!(!frame.flags.contains(vk::CommandPoolCreateFlags::TRANSIENT));
+
+        let transient = CommandPool::transient(&dev.device, family);
+        assert!(transient
+            .flags
+            .contains(vk::CommandPoolCreateFlags::TRANSIENT));
+        assert!(!transient
+            .flags
+            .contains(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER));
+    }
+
+    #[test]
+    fn aligned_index_offset() {
+        let alignment = CommandBuffer::get_index_size(vk::IndexType::UINT16);
+        CommandBuffer::debug_assert_aligned(0, alignment);
+        CommandBuffer::debug_assert_aligned(6, alignment);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn misaligned_vertex_offset() {
+        use ash::vk::Handle;
+
+        // Instances have 32-bit attributes
+        let instances = vk::Buffer::from_raw(2);
+        CommandBuffer::get_vertex_buffer_bindings(1, &[(instances, 2)], &[2, 4]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn misaligned_index_offset() {
+        let alignment = CommandBuffer::get_index_size(vk::IndexType::UINT32);
+        CommandBuffer::debug_assert_aligned(6, alignment);
+    }
+
+    #[test]
+    fn typed_constants() {
+        let matrix = na::Matrix4::<f32>::new_scaling(2.0);
+        let bytes =
+            CommandBuffer::get_constants_bytes(&matrix, 0, CommandBuffer::MIN_PUSH_CONSTANTS_SIZE);
+        assert_eq!(bytes.len(), 64);
+        assert_eq!(bytes[0..4], 2.0f32.to_ne_bytes());
+        assert_eq!(bytes[60..64], 1.0f32.to_ne_bytes());
+
+        // Fits exactly at the end of the range
+        CommandBuffer::get_constants_bytes(&matrix, 64, CommandBuffer::MIN_PUSH_CONSTANTS_SIZE);
+    }
+
+    #[test]
+    #[should_panic]
+    fn typed_constants_over_limit() {
+        let matrix = na::Matrix4::<f32>::identity();
+        CommandBuffer::get_constants_bytes(&matrix, 68, CommandBuffer::MIN_PUSH_CONSTANTS_SIZE);
+    }
+
+    #[test]
+    fn clear_values() {
+        let white = [1.0, 1.0, 1.0, 1.0];
+        let clears = ClearValues {
+            color: white,
+            ..Default::default()
+        }
+        .get_values();
+        unsafe {
+            assert_eq!(clears[0].color.float32, white);
+            // Far plane of standard depth
+            assert_eq!(clears[1].depth_stencil.depth, 1.0);
+            assert_eq!(clears[2].color.float32, [0.0, 0.0, 0.0, 1.0]);
+            assert_eq!(clears[3].color.float32, ClearValues::NEUTRAL_NORMAL);
+            assert_eq!(clears[4].color.float32, [0.0, 0.0, 0.0, 0.0]);
+        }
+        let clears = ClearValues {
+            depth: DepthConfig::reverse_z().get_clear_depth(),
+            ..Default::default()
+        }
+        .get_values();
+        unsafe {
+            assert_eq!(clears[1].depth_stencil.depth, 0.0);
+        }
+        let clears = ClearValues::default().get_values();
+        unsafe {
+            assert_eq!(
+                clears[0].color.float32,
+                [0.0, 10.0 / 255.0, 28.0 / 255.0, 1.0]
+            );
+        }
+    }
+
+    #[test]
+    fn neutral_normal_clear() {
+        let clears = ClearValues::default().get_values();
+        let normal = ClearValues::decode_normal(unsafe { clears[3].color.float32 });
+        assert_eq!(normal, na::Vector3::new(0.0, 0.0, 1.0));
+        assert!(normal.iter().any(|&n| n >= 0.0));
+
+        let up = na::Vector3::new(0.0, 1.0, 0.0);
+        let encoded = ClearValues::encode_normal(&up);
+        assert_eq!(encoded, [0.5, 1.0, 0.5, 1.0]);
+        assert_eq!(ClearValues::decode_normal(encoded), up);
+    }
+
+    #[test]
+    fn debug_state() {
+        use ash::vk::Handle;
+
+        let mut state = FrameDebugState::default();
+        assert!(state.pipeline.is_none());
+        assert!(state.to_string().starts_with("Pipeline: none"));
+
+        // Binding a pipeline and a vertex buffer, as `CommandBuffer` does
+        let buffer = vk::Buffer::from_raw(7);
+        state.bind_pipeline("main_vs+main_fs");
+        state.bind_vertex_buffers(0, &[(buffer, 16)]);
+        assert_eq!(state.pipeline.as_deref(), Some("main_vs+main_fs"));
+        assert_eq!(state.vertex_buffers[&0], (buffer, 16));
+
+        // Later bindings replace the ones at the same index only
+        let instances = vk::Buffer::from_raw(8);
+        state.bind_vertex_buffers(1, &[(instances, 0)]);
+        state.bind_vertex_buffers(0, &[(buffer, 32)]);
+        assert_eq!(state.vertex_buffers.len(), 2);
+        assert_eq!(state.vertex_buffers[&0], (buffer, 32));
+
+        let sets = [
+            vk::DescriptorSet::from_raw(1),
+            vk::DescriptorSet::from_raw(2),
+        ];
+        state.bind_descriptor_sets(2, &sets);
+        assert_eq!(state.descriptor_sets[&3], sets[1]);
+        assert!(!state.descriptor_sets.contains_key(&0));
+
+        let text = state.to_string();
+        assert!(text.starts_with("Pipeline: main_vs+main_fs"));
+        assert!(text.contains("vertex 1"));
+        assert!(text.contains("set 3"));
+    }
+}
@@ -43,8 +43,8 @@ pub fn main() {
     lines.mesh = lines_mesh;
     let lines = model.nodes.push(lines);
 
-    let image = Image::load(&vkr.dev, "res/image/test.png");
-    let view = ImageView::new(&vkr.dev.device, &image);
+    let image = Image::load(&vkr.dev, "res/image/test.png", None);
+    let view = ImageView::array(&vkr.dev.device, &image, 0..image.array_layers);
     model.images.push(image);
     let view = model.views.push(view);
     let sampler = model.samplers.push(Sampler::new(&vkr.dev.device));
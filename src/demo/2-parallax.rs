@@ -6,8 +6,8 @@ fn create_texture(
     sampler: Handle<Sampler>,
     path: &str,
 ) -> Handle<Texture> {
-    let image = Image::load(&vkr.dev, path);
-    let view = ImageView::new(&vkr.dev.device, &image);
+    let image = Image::load(&vkr.dev, path, None);
+    let view = ImageView::array(&vkr.dev.device, &image, 0..image.array_layers);
     model.images.push(image);
     let view = model.views.push(view);
 
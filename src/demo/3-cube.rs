@@ -7,10 +7,15 @@ use vkr::*;
 pub fn main() {
     let win = Win::new("Cube", 480, 480);
     let mut vkr = Vkr::new(win);
+    // Run uncapped with `--no-vsync`
+    if std::env::args().any(|arg| arg == "--no-vsync") {
+        vkr.set_vsync(false);
+    }
     let mut model = Model::new();
 
-    let image = Image::load(&vkr.dev, "res/image/test.png");
-    let view = model.views.push(ImageView::new(&vkr.dev.device, &image));
+    let image = Image::load(&vkr.dev, "res/image/test.png", None);
+    let view = ImageView::array(&vkr.dev.device, &image, 0..image.array_layers);
+    let view = model.views.push(view);
     model.images.push(image);
     let sampler = model.samplers.push(Sampler::new(&vkr.dev.device));
     let lena_texture = model.textures.push(Texture::new(view, sampler));
@@ -19,7 +24,7 @@ pub fn main() {
     green_material.color = Color::new(0.8, 0.6, 0.7, 0.3);
     let green_material = model.materials.push(green_material);
 
-    let mut cube_primitive = Primitive::cube(&vkr.dev.allocator);
+    let mut cube_primitive = Primitive::cube(&vkr.dev.allocator, 1.0);
     cube_primitive.material = green_material;
     let cube_primitive = model.primitives.push(cube_primitive);
 
@@ -62,7 +67,8 @@ pub fn main() {
         frame.draw::<Vertex>(&vkr.pipelines, &model, cube_node);
 
         vkr.end_scene(&mut frame);
-        vkr.gui.draw_debug_window(delta, &mut frame, &mut vkr.pipelines, &model, camera_node);
+        vkr.gui
+            .draw_debug_window(delta, &mut frame, &mut vkr.pipelines, &model, camera_node);
         vkr.end_frame(frame);
     }
 
@@ -9,7 +9,7 @@ pub fn main() {
     let mut vkr = Vkr::new(win);
     let mut model = Model::new();
 
-    let cube_primitive = Primitive::cube(&vkr.dev.allocator);
+    let cube_primitive = Primitive::cube(&vkr.dev.allocator, 1.0);
     let cube_primitive = model.primitives.push(cube_primitive);
 
     let cube_mesh = Mesh::new(vec![cube_primitive]);
@@ -0,0 +1,49 @@
+// Copyright © 2021
+// Author: Antonio Caggiano <info@antoniocaggiano.eu>
+// SPDX-License-Identifier: MIT
+
+use ash::vk;
+use vkr::*;
+
+pub fn main() {
+    let win = Win::new("Compute", 480, 480);
+    let mut vkr = Vkr::new(win);
+
+    let pipeline = ComputePipeline::main(&vkr.dev);
+
+    let size = ComputePipeline::MAIN_CS_SIZE;
+    let values = vec![0u32; (size * size) as usize];
+    let usage = vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_SRC;
+    let buffer = Buffer::device_local(&vkr.dev, usage, &values, None);
+
+    let mut descriptors = Descriptors::new(&mut vkr.dev);
+    let sets = descriptors.allocate(&pipeline.set_layouts);
+    let mut writer = DescriptorWriter::new();
+    writer.write_storage_buffer(sets[0], 0, &buffer);
+    writer.flush(&vkr.dev.device);
+
+    let command_buffer = CommandBuffer::new(&vkr.dev.transient_command_pool);
+    command_buffer.begin(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+    command_buffer.bind_compute_pipeline(&pipeline);
+    command_buffer.bind_compute_descriptor_sets(&pipeline, &sets, 0);
+    pipeline.record_dispatch(&command_buffer, size, size, 1);
+    command_buffer.end();
+
+    let mut fence = Fence::unsignaled(&vkr.dev.device);
+    let submits = [vk::SubmitInfo::builder()
+        .command_buffers(&[command_buffer.command_buffer])
+        .build()];
+    vkr.dev.graphics_queue.submit(&submits, Some(&mut fence));
+    fence.wait();
+
+    // Each element on the diagonal is the square of its coordinate
+    let values = buffer.read_back::<u32>(&vkr.dev, (size * size) as usize);
+    let diagonal: Vec<u32> = (0..size).map(|i| values[(i * size + i) as usize]).collect();
+    println!("Diagonal: {:?}", diagonal);
+    assert!(diagonal
+        .iter()
+        .enumerate()
+        .all(|(i, &v)| v == (i * i) as u32));
+
+    vkr.dev.wait();
+}
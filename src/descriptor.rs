@@ -8,14 +8,357 @@ use ash::*;
 
 use super::*;
 
+/// Kind of resources bound by a descriptor set, which determines the index of the set in a
+/// pipeline layout. Every layout starts with the per-object and per-view sets, so pipelines
+/// without materials or shadows only declare the first indices. As sets from the first
+/// incompatible index are disturbed by a pipeline switch, the per-frame set comes last and
+/// is bound again after the model sets of every node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DescriptorFrequency {
+    /// Model matrices of a node
+    PerObject = 0,
+    /// View and projection matrices of a camera
+    PerView = 1,
+    /// Color and textures of a material
+    PerMaterial = 2,
+    /// Resources shared by everything drawn in a frame
+    PerFrame = 3,
+}
+
+impl DescriptorFrequency {
+    pub const ALL: [DescriptorFrequency; 4] = [
+        DescriptorFrequency::PerObject,
+        DescriptorFrequency::PerView,
+        DescriptorFrequency::PerMaterial,
+        DescriptorFrequency::PerFrame,
+    ];
+
+    pub fn get_set_index(self) -> u32 {
+        self as u32
+    }
+
+    pub fn from_set_index(index: u32) -> Option<Self> {
+        Self::ALL.get(index as usize).copied()
+    }
+
+    /// Orders set layouts by frequency, asserting they cover contiguous set indices from 0
+    pub fn sort_set_layouts(
+        mut layouts: Vec<(DescriptorFrequency, vk::DescriptorSetLayout)>,
+    ) -> Vec<vk::DescriptorSetLayout> {
+        layouts.sort_by_key(|(frequency, _)| frequency.get_set_index());
+        for (index, (frequency, _)) in layouts.iter().enumerate() {
+            assert!(
+                frequency.get_set_index() == index as u32,
+                "Missing or duplicate set layout before {:?}",
+                frequency
+            );
+        }
+        layouts.into_iter().map(|(_, layout)| layout).collect()
+    }
+}
+
+/// Resource referred by a pending descriptor write
+#[derive(Clone, Copy)]
+enum DescriptorInfo {
+    Buffer(vk::DescriptorBufferInfo),
+    Image(vk::DescriptorImageInfo),
+}
+
+/// Resource written to a binding whose type is only known at runtime, such as a reflected one
+#[derive(Debug, Clone, Copy)]
+pub enum DescriptorResource {
+    Buffer(vk::Buffer),
+    Image(vk::ImageView),
+    Sampler(vk::Sampler),
+    SampledImage(vk::ImageView, vk::Sampler),
+    InputAttachment(vk::ImageView, vk::ImageLayout),
+}
+
+impl DescriptorResource {
+    /// Returns the info of this resource for a binding of type `ty`, or `None` when it does not
+    /// fit. Storage images are expected in `GENERAL` layout, sampled ones in read-only layout.
+    fn get_info(self, ty: vk::DescriptorType) -> Option<DescriptorInfo> {
+        let image_info = |view, sampler, layout| {
+            DescriptorInfo::Image(
+                vk::DescriptorImageInfo::builder()
+                    .image_view(view)
+                    .sampler(sampler)
+                    .image_layout(layout)
+                    .build(),
+            )
+        };
+        let info = match (ty, self) {
+            (vk::DescriptorType::UNIFORM_BUFFER, DescriptorResource::Buffer(buffer))
+            | (vk::DescriptorType::STORAGE_BUFFER, DescriptorResource::Buffer(buffer)) => {
+                DescriptorInfo::Buffer(
+                    vk::DescriptorBufferInfo::builder()
+                        .buffer(buffer)
+                        .range(vk::WHOLE_SIZE)
+                        .build(),
+                )
+            }
+            (vk::DescriptorType::STORAGE_IMAGE, DescriptorResource::Image(view)) => {
+                image_info(view, vk::Sampler::null(), vk::ImageLayout::GENERAL)
+            }
+            (vk::DescriptorType::SAMPLED_IMAGE, DescriptorResource::Image(view)) => image_info(
+                view,
+                vk::Sampler::null(),
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            ),
+            (vk::DescriptorType::SAMPLER, DescriptorResource::Sampler(sampler)) => {
+                image_info(vk::ImageView::null(), sampler, vk::ImageLayout::UNDEFINED)
+            }
+            (
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                DescriptorResource::SampledImage(view, sampler),
+            ) => image_info(view, sampler, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+            (
+                vk::DescriptorType::INPUT_ATTACHMENT,
+                DescriptorResource::InputAttachment(view, layout),
+            ) => image_info(view, vk::Sampler::null(), layout),
+            _ => return None,
+        };
+        Some(info)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct PendingWrite {
+    set: vk::DescriptorSet,
+    binding: u32,
+    ty: vk::DescriptorType,
+    info: DescriptorInfo,
+}
+
+/// Accumulates descriptor writes, so that all the bindings of one or more sets
+/// are updated with a single call to `update_descriptor_sets`
+#[derive(Default)]
+pub struct DescriptorWriter {
+    writes: Vec<PendingWrite>,
+}
+
+impl DescriptorWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_buffer(
+        &mut self,
+        set: vk::DescriptorSet,
+        binding: u32,
+        ty: vk::DescriptorType,
+        info: vk::DescriptorBufferInfo,
+    ) -> &mut Self {
+        self.writes.push(PendingWrite {
+            set,
+            binding,
+            ty,
+            info: DescriptorInfo::Buffer(info),
+        });
+        self
+    }
+
+    pub fn push_image(
+        &mut self,
+        set: vk::DescriptorSet,
+        binding: u32,
+        ty: vk::DescriptorType,
+        info: vk::DescriptorImageInfo,
+    ) -> &mut Self {
+        self.writes.push(PendingWrite {
+            set,
+            binding,
+            ty,
+            info: DescriptorInfo::Image(info),
+        });
+        self
+    }
+
+    /// Writes a uniform buffer containing a `T`
+    pub fn write_uniform<T>(
+        &mut self,
+        set: vk::DescriptorSet,
+        binding: u32,
+        buffer: &Buffer,
+    ) -> &mut Self {
+        self.write_uniform_at::<T>(set, binding, buffer, 0)
+    }
+
+    /// Writes a uniform `T` at `offset` within a buffer holding more of them, such as an arena
+    pub fn write_uniform_at<T>(
+        &mut self,
+        set: vk::DescriptorSet,
+        binding: u32,
+        buffer: &Buffer,
+        offset: vk::DeviceSize,
+    ) -> &mut Self {
+        let info = vk::DescriptorBufferInfo::builder()
+            .offset(offset)
+            .range(std::mem::size_of::<T>() as vk::DeviceSize)
+            .buffer(buffer.buffer)
+            .build();
+        self.push_buffer(set, binding, vk::DescriptorType::UNIFORM_BUFFER, info)
+    }
+
+    /// Writes a storage buffer, the whole of it can be read and written by shaders
+    pub fn write_storage_buffer(
+        &mut self,
+        set: vk::DescriptorSet,
+        binding: u32,
+        buffer: &Buffer,
+    ) -> &mut Self {
+        let info = vk::DescriptorBufferInfo::builder()
+            .range(vk::WHOLE_SIZE)
+            .buffer(buffer.buffer)
+            .build();
+        self.push_buffer(set, binding, vk::DescriptorType::STORAGE_BUFFER, info)
+    }
+
+    /// Writes an image view with a sampler to be read in `SHADER_READ_ONLY_OPTIMAL` layout
+    pub fn write_sampled_image(
+        &mut self,
+        set: vk::DescriptorSet,
+        binding: u32,
+        view: &ImageView,
+        sampler: &Sampler,
+    ) -> &mut Self {
+        let info = vk::DescriptorImageInfo::builder()
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .image_view(view.view)
+            .sampler(sampler.sampler)
+            .build();
+        self.push_image(
+            set,
+            binding,
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            info,
+        )
+    }
+
+    /// Writes a depth image view with a sampler to be read in `DEPTH_STENCIL_READ_ONLY_OPTIMAL`
+    /// layout, which is the one depth attachments are left in by passes reading them afterwards
+    pub fn write_depth_image(
+        &mut self,
+        set: vk::DescriptorSet,
+        binding: u32,
+        view: &ImageView,
+        sampler: &Sampler,
+    ) -> &mut Self {
+        let info = vk::DescriptorImageInfo::builder()
+            .image_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+            .image_view(view.view)
+            .sampler(sampler.sampler)
+            .build();
+        self.push_image(
+            set,
+            binding,
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            info,
+        )
+    }
+
+    /// Writes an attachment of the current render pass read in `layout`
+    pub fn write_input_attachment(
+        &mut self,
+        set: vk::DescriptorSet,
+        binding: u32,
+        view: &ImageView,
+        layout: vk::ImageLayout,
+    ) -> &mut Self {
+        let info = vk::DescriptorImageInfo::builder()
+            .image_layout(layout)
+            .image_view(view.view)
+            .build();
+        self.push_image(set, binding, vk::DescriptorType::INPUT_ATTACHMENT, info)
+    }
+
+    /// Writes a storage image view, read and written by shaders in `GENERAL` layout
+    pub fn write_storage_image(
+        &mut self,
+        set: vk::DescriptorSet,
+        binding: u32,
+        view: &ImageView,
+    ) -> &mut Self {
+        self.write_reflected(
+            set,
+            binding,
+            vk::DescriptorType::STORAGE_IMAGE,
+            DescriptorResource::Image(view.view),
+        )
+    }
+
+    /// Writes `resource` to a binding of type `ty`, as found by `ShaderModule::get_bindings`.
+    /// Buffers are written whole.
+    pub fn write_reflected(
+        &mut self,
+        set: vk::DescriptorSet,
+        binding: u32,
+        ty: vk::DescriptorType,
+        resource: DescriptorResource,
+    ) -> &mut Self {
+        let info = resource.get_info(ty).unwrap_or_else(|| {
+            panic!(
+                "Failed to write {:?} to {:?} binding {}",
+                resource, ty, binding
+            )
+        });
+        self.writes.push(PendingWrite {
+            set,
+            binding,
+            ty,
+            info,
+        });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Returns the pending writes, which point into this writer and are valid while it is borrowed
+    pub fn get_writes(&self) -> Vec<vk::WriteDescriptorSet> {
+        self.writes
+            .iter()
+            .map(|write| {
+                let builder = vk::WriteDescriptorSet::builder()
+                    .dst_set(write.set)
+                    .dst_binding(write.binding)
+                    .dst_array_element(0)
+                    .descriptor_type(write.ty);
+                match &write.info {
+                    DescriptorInfo::Buffer(info) => {
+                        builder.buffer_info(std::slice::from_ref(info)).build()
+                    }
+                    DescriptorInfo::Image(info) => {
+                        builder.image_info(std::slice::from_ref(info)).build()
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Updates all the pending writes at once
+    pub fn flush(&mut self, device: &Device) {
+        if self.writes.is_empty() {
+            return;
+        }
+        let writes = self.get_writes();
+        unsafe {
+            device.update_descriptor_sets(&writes, &[]);
+        }
+        self.writes.clear();
+    }
+}
+
 type SetCache<T> = HashMap<(vk::DescriptorSetLayout, Handle<T>), Vec<vk::DescriptorSet>>;
 
 /// Per-frame resource which contains a descriptor pool and a vector
 /// of descriptor sets of each pipeline layout used for rendering.
 pub struct Descriptors {
-    /// Descriptor sets for the GUI
-    pub gui_sets: Vec<vk::DescriptorSet>,
-
     /// These descriptor sets are for camera view and proj uniform, therefore we need NxM descriptor sets
     /// where N is the number of pipeline layouts, and M is the number of nodes with cameras
     pub view_sets: SetCache<Node>,
@@ -28,22 +371,48 @@ pub struct Descriptors {
     /// where N is the number of pipeline layouts, and M is the number of materials
     pub material_sets: SetCache<Material>,
 
+    /// Descriptor sets for resources shared by a frame, such as the shadow map, one for each
+    /// pipeline layout. These need to be recreated when the shadow map is recreated.
+    pub frame_sets: HashMap<vk::DescriptorSetLayout, Vec<vk::DescriptorSet>>,
+
     /// Descriptor sets for the present subpass
     /// These need to be recreated when the swapchain goes out of date
     pub present_sets: Vec<vk::DescriptorSet>,
 
+    /// Descriptor sets for the lighting subpass, recreated with the present ones
+    pub light_sets: Vec<vk::DescriptorSet>,
+
     /// Descriptor pools should be per-pipeline layout as weel as they could differ in terms of uniforms and samplers?
     /// Or can we provide sufficient descriptors for all supported pipeline layouts? Trying this approach.
-    pool: vk::DescriptorPool,
+    /// Another pool is added whenever the last one is exhausted.
+    pools: Vec<vk::DescriptorPool>,
+
+    /// Pool each allocated set comes from, so that it can be freed
+    set_pools: HashMap<vk::DescriptorSet, vk::DescriptorPool>,
 
     pub device: Rc<Device>,
 }
 
 impl Descriptors {
     pub fn new(dev: &mut Dev) -> Self {
-        let pool = unsafe {
+        Self {
+            view_sets: SetCache::new(),
+            model_sets: SetCache::new(),
+            material_sets: SetCache::new(),
+            frame_sets: HashMap::new(),
+            present_sets: vec![],
+            light_sets: vec![],
+            pools: vec![Self::create_pool(&dev.device)],
+            set_pools: HashMap::new(),
+            device: dev.device.clone(),
+        }
+    }
+
+    fn create_pool(device: &Device) -> vk::DescriptorPool {
+        unsafe {
             // Support 1 model matrix, 1 view matrix, 1 proj matrix?
-            let uniform_count = 32;
+            // Shadow casters need their own sets with the shadow pipeline
+            let uniform_count = 64;
             let uniform_pool_size = vk::DescriptorPoolSize::builder()
                 .descriptor_count(uniform_count)
                 .ty(vk::DescriptorType::UNIFORM_BUFFER)
@@ -56,56 +425,628 @@ impl Descriptors {
                 .ty(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
                 .build();
 
-            // Support 3 input attachments
-            let input_count = 3;
+            // Support 3 input attachments for lighting and 3 for presentation
+            let input_count = 6;
             let input_pool_size = vk::DescriptorPoolSize::builder()
                 .descriptor_count(input_count)
                 .ty(vk::DescriptorType::INPUT_ATTACHMENT)
                 .build();
 
-            let set_count = 16; // 5 nodes, 1 camera, 5 materials, 1 gui?
-            let pool_sizes = vec![uniform_pool_size, sampler_pool_size, input_pool_size];
+            // Support a few compute shaders
+            let storage_count = 8;
+            let storage_pool_size = vk::DescriptorPoolSize::builder()
+                .descriptor_count(storage_count)
+                .ty(vk::DescriptorType::STORAGE_BUFFER)
+                .build();
+
+            let set_count = 32; // 5 nodes, 1 camera, 5 materials, 1 gui, and shadows?
+            let pool_sizes = vec![
+                uniform_pool_size,
+                sampler_pool_size,
+                input_pool_size,
+                storage_pool_size,
+            ];
             let create_info = vk::DescriptorPoolCreateInfo::builder()
                 .pool_sizes(&pool_sizes)
                 .max_sets(set_count)
                 .flags(vk::DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET)
                 .build();
-            dev.device.create_descriptor_pool(&create_info, None)
+            device.create_descriptor_pool(&create_info, None)
         }
-        .expect("Failed to create Vulkan descriptor pool");
+        .expect("Failed to create Vulkan descriptor pool")
+    }
 
-        Self {
-            gui_sets: vec![],
-            view_sets: SetCache::new(),
-            model_sets: SetCache::new(),
-            material_sets: SetCache::new(),
-            present_sets: vec![],
-            pool,
-            device: dev.device.clone(),
-        }
+    /// Returns whether an allocation failed because its pool has no room left,
+    /// in which case it may succeed with a new pool
+    fn is_pool_exhausted(result: vk::Result) -> bool {
+        result == vk::Result::ERROR_OUT_OF_POOL_MEMORY
+            || result == vk::Result::ERROR_FRAGMENTED_POOL
     }
 
-    pub fn allocate(&mut self, layouts: &[vk::DescriptorSetLayout]) -> Vec<vk::DescriptorSet> {
+    fn allocate_from(
+        &self,
+        pool: vk::DescriptorPool,
+        layouts: &[vk::DescriptorSetLayout],
+    ) -> Result<Vec<vk::DescriptorSet>, vk::Result> {
         let create_info = vk::DescriptorSetAllocateInfo::builder()
-            .descriptor_pool(self.pool)
+            .descriptor_pool(pool)
             .set_layouts(layouts)
             .build();
 
         unsafe { self.device.allocate_descriptor_sets(&create_info) }
-            .expect("Failed to allocate Vulkan descriptor sets")
     }
 
-    pub fn free(&self, descriptors: &[vk::DescriptorSet]) {
-        unsafe {
-            self.device
-                .free_descriptor_sets(self.pool, descriptors)
-                .expect("msFailed to free descriptor sets");
+    /// Allocates sets with `layouts` from the last pool, adding a new one when it is exhausted
+    pub fn allocate(&mut self, layouts: &[vk::DescriptorSetLayout]) -> Vec<vk::DescriptorSet> {
+        let mut pool = *self.pools.last().unwrap();
+        let sets = match self.allocate_from(pool, layouts) {
+            Ok(sets) => sets,
+            Err(result) if Self::is_pool_exhausted(result) => {
+                pool = Self::create_pool(&self.device);
+                self.pools.push(pool);
+                self.allocate_from(pool, layouts)
+                    .expect("Failed to allocate Vulkan descriptor sets")
+            }
+            Err(result) => panic!("Failed to allocate Vulkan descriptor sets: {:?}", result),
+        };
+
+        for set in &sets {
+            self.set_pools.insert(*set, pool);
+        }
+        sets
+    }
+
+    /// Removes `descriptors` from `set_pools`, returning them grouped by the pool
+    /// they were allocated from, in order of pool
+    fn take_by_pool(
+        descriptors: &[vk::DescriptorSet],
+        set_pools: &mut HashMap<vk::DescriptorSet, vk::DescriptorPool>,
+    ) -> Vec<(vk::DescriptorPool, Vec<vk::DescriptorSet>)> {
+        let mut groups: Vec<(vk::DescriptorPool, Vec<vk::DescriptorSet>)> = vec![];
+        for set in descriptors {
+            let pool = set_pools
+                .remove(set)
+                .expect("Failed to find pool of descriptor set");
+            match groups
+                .iter_mut()
+                .find(|(group_pool, _)| *group_pool == pool)
+            {
+                Some((_, sets)) => sets.push(*set),
+                None => groups.push((pool, vec![*set])),
+            }
+        }
+        groups
+    }
+
+    /// Frees `descriptors` into their pools, forgetting which pools they came from
+    pub fn free(&mut self, descriptors: &[vk::DescriptorSet]) {
+        for (pool, sets) in Self::take_by_pool(descriptors, &mut self.set_pools) {
+            unsafe {
+                self.device
+                    .free_descriptor_sets(pool, &sets)
+                    .expect("Failed to free descriptor sets");
+            }
         }
     }
 }
 
 impl Drop for Descriptors {
     fn drop(&mut self) {
-        unsafe { self.device.destroy_descriptor_pool(self.pool, None) };
+        for pool in &self.pools {
+            unsafe { self.device.destroy_descriptor_pool(*pool, None) };
+        }
+    }
+}
+
+/// Descriptor pools whose sets are never freed individually, but reset all together every frame.
+/// When a pool is exhausted, the next one is used, and a new one is created when there is none.
+pub struct TransientPool {
+    pools: Vec<vk::DescriptorPool>,
+    /// Index of the pool sets are currently allocated from
+    current: usize,
+    device: Rc<Device>,
+}
+
+impl TransientPool {
+    /// Maximum number of sets of each pool
+    pub const MAX_SETS: u32 = 16;
+
+    pub fn new(dev: &Dev) -> Self {
+        let device = dev.device.clone();
+        Self {
+            pools: vec![Self::create_pool(&device)],
+            current: 0,
+            device,
+        }
+    }
+
+    fn create_pool(device: &Device) -> vk::DescriptorPool {
+        let uniform_pool_size = vk::DescriptorPoolSize::builder()
+            .descriptor_count(Self::MAX_SETS)
+            .ty(vk::DescriptorType::UNIFORM_BUFFER)
+            .build();
+        let sampler_pool_size = vk::DescriptorPoolSize::builder()
+            .descriptor_count(Self::MAX_SETS)
+            .ty(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .build();
+        // Accumulation and revealage read by the resolve subpass of weighted-blended transparency
+        let input_pool_size = vk::DescriptorPoolSize::builder()
+            .descriptor_count(2)
+            .ty(vk::DescriptorType::INPUT_ATTACHMENT)
+            .build();
+
+        let pool_sizes = [uniform_pool_size, sampler_pool_size, input_pool_size];
+        // Without the free descriptor set flag, as sets are only freed by resetting the pool
+        let create_info = vk::DescriptorPoolCreateInfo::builder()
+            .pool_sizes(&pool_sizes)
+            .max_sets(Self::MAX_SETS)
+            .build();
+        unsafe { device.create_descriptor_pool(&create_info, None) }
+            .expect("Failed to create Vulkan descriptor pool")
+    }
+
+    fn allocate_from(
+        &self,
+        pool: vk::DescriptorPool,
+        layouts: &[vk::DescriptorSetLayout],
+    ) -> Result<Vec<vk::DescriptorSet>, vk::Result> {
+        let create_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(pool)
+            .set_layouts(layouts)
+            .build();
+        unsafe { self.device.allocate_descriptor_sets(&create_info) }
+    }
+
+    /// Returns the number of pools created so far
+    pub fn get_pool_count(&self) -> usize {
+        self.pools.len()
+    }
+}
+
+impl DescriptorAllocator for TransientPool {
+    fn allocate_sets(&mut self, layouts: &[vk::DescriptorSetLayout]) -> Vec<vk::DescriptorSet> {
+        // Sets which do not fit in an empty pool would never fit in any other
+        let mut created = false;
+        loop {
+            match self.allocate_from(self.pools[self.current], layouts) {
+                Ok(sets) => return sets,
+                Err(result) if Descriptors::is_pool_exhausted(result) && !created => {
+                    self.current += 1;
+                    if self.current == self.pools.len() {
+                        self.pools.push(Self::create_pool(&self.device));
+                        created = true;
+                    }
+                }
+                Err(result) => panic!("Failed to allocate Vulkan descriptor sets: {:?}", result),
+            }
+        }
+    }
+
+    fn reset_sets(&mut self) {
+        for pool in &self.pools[..=self.current] {
+            unsafe {
+                self.device
+                    .reset_descriptor_pool(*pool, vk::DescriptorPoolResetFlags::empty())
+            }
+            .expect("Failed to reset Vulkan descriptor pool");
+        }
+        self.current = 0;
+    }
+}
+
+impl Drop for TransientPool {
+    fn drop(&mut self) {
+        for pool in &self.pools {
+            unsafe { self.device.destroy_descriptor_pool(*pool, None) };
+        }
+    }
+}
+
+/// Array of combined image samplers indexed by shaders, so that textures can be added without
+/// binding other sets. With update-after-bind, elements can be written while the set is bound
+/// by command buffers being recorded or executed, as textures stream in. Elements should all be
+/// written before the set is used, as partially bound arrays are not enabled.
+pub struct TextureArray {
+    pub layout: vk::DescriptorSetLayout,
+    pub set: vk::DescriptorSet,
+    /// Number of elements of the array
+    pub capacity: u32,
+    /// Whether elements can be written while the set is bound
+    pub update_after_bind: bool,
+    pool: vk::DescriptorPool,
+    device: Rc<Device>,
+}
+
+impl TextureArray {
+    /// Returns the flags of the array binding
+    fn get_binding_flags(update_after_bind: bool) -> vk::DescriptorBindingFlags {
+        if update_after_bind {
+            vk::DescriptorBindingFlags::UPDATE_AFTER_BIND
+        } else {
+            vk::DescriptorBindingFlags::empty()
+        }
+    }
+
+    /// Returns the flags of the set layout, which needs the update-after-bind pool flag
+    /// when any of its bindings can be updated after bind
+    fn get_layout_flags(update_after_bind: bool) -> vk::DescriptorSetLayoutCreateFlags {
+        if update_after_bind {
+            vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL
+        } else {
+            vk::DescriptorSetLayoutCreateFlags::empty()
+        }
+    }
+
+    /// Returns the flags of the pool the set is allocated from
+    fn get_pool_flags(update_after_bind: bool) -> vk::DescriptorPoolCreateFlags {
+        if update_after_bind {
+            vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND
+        } else {
+            vk::DescriptorPoolCreateFlags::empty()
+        }
+    }
+
+    /// Creates an array of `capacity` textures at binding 0, which can be updated after bind
+    /// when the device supports it
+    pub fn new(dev: &Dev, capacity: u32, stage_flags: vk::ShaderStageFlags) -> Self {
+        let update_after_bind = dev.update_after_bind;
+
+        let bindings = [vk::DescriptorSetLayoutBinding::builder()
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(capacity)
+            .stage_flags(stage_flags)
+            .build()];
+        let binding_flags = [Self::get_binding_flags(update_after_bind)];
+        let mut binding_flags_info = vk::DescriptorSetLayoutBindingFlagsCreateInfo::builder()
+            .binding_flags(&binding_flags)
+            .build();
+        let layout_info = vk::DescriptorSetLayoutCreateInfo::builder()
+            .bindings(&bindings)
+            .flags(Self::get_layout_flags(update_after_bind))
+            .push_next(&mut binding_flags_info)
+            .build();
+        let layout = unsafe { dev.device.create_descriptor_set_layout(&layout_info, None) }
+            .expect("Failed to create Vulkan descriptor set layout");
+
+        let pool_sizes = [vk::DescriptorPoolSize::builder()
+            .descriptor_count(capacity)
+            .ty(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .build()];
+        let pool_info = vk::DescriptorPoolCreateInfo::builder()
+            .pool_sizes(&pool_sizes)
+            .max_sets(1)
+            .flags(Self::get_pool_flags(update_after_bind))
+            .build();
+        let pool = unsafe { dev.device.create_descriptor_pool(&pool_info, None) }
+            .expect("Failed to create Vulkan descriptor pool");
+
+        let layouts = [layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(pool)
+            .set_layouts(&layouts)
+            .build();
+        let set = unsafe { dev.device.allocate_descriptor_sets(&allocate_info) }
+            .expect("Failed to allocate Vulkan descriptor sets")[0];
+
+        Self {
+            layout,
+            set,
+            capacity,
+            update_after_bind,
+            pool,
+            device: dev.device.clone(),
+        }
+    }
+
+    /// Writes the texture at `index`. Without update-after-bind, the set should not be bound
+    /// by command buffers being recorded or executed.
+    pub fn write(&self, index: u32, view: &ImageView, sampler: &Sampler) {
+        assert!(
+            index < self.capacity,
+            "Texture index {} out of bounds",
+            index
+        );
+
+        let image_info = [vk::DescriptorImageInfo::builder()
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .image_view(view.view)
+            .sampler(sampler.sampler)
+            .build()];
+        let write = vk::WriteDescriptorSet::builder()
+            .dst_set(self.set)
+            .dst_binding(0)
+            .dst_array_element(index)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(&image_info)
+            .build();
+        unsafe { self.device.update_descriptor_sets(&[write], &[]) };
+    }
+}
+
+impl Drop for TextureArray {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_descriptor_pool(self.pool, None);
+            self.device.destroy_descriptor_set_layout(self.layout, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ash::vk::Handle;
+
+    #[test]
+    fn frequency_set_indices() {
+        assert_eq!(DescriptorFrequency::PerObject.get_set_index(), 0);
+        assert_eq!(DescriptorFrequency::PerView.get_set_index(), 1);
+        assert_eq!(DescriptorFrequency::PerMaterial.get_set_index(), 2);
+        assert_eq!(DescriptorFrequency::PerFrame.get_set_index(), 3);
+
+        for frequency in DescriptorFrequency::ALL.iter() {
+            let index = frequency.get_set_index();
+            assert_eq!(DescriptorFrequency::from_set_index(index), Some(*frequency));
+        }
+        assert_eq!(DescriptorFrequency::from_set_index(4), None);
+    }
+
+    #[test]
+    fn sort_set_layouts_by_frequency() {
+        let model = vk::DescriptorSetLayout::from_raw(1);
+        let camera = vk::DescriptorSetLayout::from_raw(2);
+        let material = vk::DescriptorSetLayout::from_raw(3);
+
+        let layouts = DescriptorFrequency::sort_set_layouts(vec![
+            (DescriptorFrequency::PerMaterial, material),
+            (DescriptorFrequency::PerObject, model),
+            (DescriptorFrequency::PerView, camera),
+        ]);
+        assert_eq!(layouts, vec![model, camera, material]);
+    }
+
+    #[test]
+    fn batched_writes() {
+        let view_set = vk::DescriptorSet::from_raw(1);
+        let material_set = vk::DescriptorSet::from_raw(2);
+        let buffer_info = |raw| {
+            vk::DescriptorBufferInfo::builder()
+                .buffer(vk::Buffer::from_raw(raw))
+                .range(64)
+                .build()
+        };
+        let uniform = vk::DescriptorType::UNIFORM_BUFFER;
+
+        let mut writer = DescriptorWriter::new();
+        writer
+            .push_buffer(view_set, 0, uniform, buffer_info(10))
+            .push_buffer(view_set, 1, uniform, buffer_info(11))
+            .push_image(
+                material_set,
+                1,
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                vk::DescriptorImageInfo::builder()
+                    .image_view(vk::ImageView::from_raw(12))
+                    .build(),
+            );
+
+        // View, proj, and material end up in the same update
+        let writes = writer.get_writes();
+        assert_eq!(writes.len(), 3);
+        let targets: Vec<(vk::DescriptorSet, u32)> = writes
+            .iter()
+            .map(|write| (write.dst_set, write.dst_binding))
+            .collect();
+        assert_eq!(
+            targets,
+            vec![(view_set, 0), (view_set, 1), (material_set, 1)]
+        );
+
+        let proj = unsafe { &*writes[1].p_buffer_info };
+        assert_eq!(proj.buffer, vk::Buffer::from_raw(11));
+        let albedo = unsafe { &*writes[2].p_image_info };
+        assert_eq!(albedo.image_view, vk::ImageView::from_raw(12));
+        assert_eq!(writes[2].descriptor_count, 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn sort_set_layouts_with_gap() {
+        DescriptorFrequency::sort_set_layouts(vec![
+            (
+                DescriptorFrequency::PerObject,
+                vk::DescriptorSetLayout::null(),
+            ),
+            (
+                DescriptorFrequency::PerMaterial,
+                vk::DescriptorSetLayout::null(),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn texture_array_update_after_bind() {
+        assert_eq!(
+            TextureArray::get_binding_flags(true),
+            vk::DescriptorBindingFlags::UPDATE_AFTER_BIND
+        );
+        assert!(TextureArray::get_layout_flags(true)
+            .contains(vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL));
+        assert!(TextureArray::get_pool_flags(true)
+            .contains(vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND));
+
+        // Without the feature, the array is a plain one
+        assert!(TextureArray::get_binding_flags(false).is_empty());
+        assert!(TextureArray::get_layout_flags(false).is_empty());
+        assert!(TextureArray::get_pool_flags(false).is_empty());
+    }
+
+    #[test]
+    fn texture_array_creation() {
+        let ctx = Ctx::headless();
+        let dev = Dev::headless(&ctx);
+        let array = TextureArray::new(&dev, 4, vk::ShaderStageFlags::FRAGMENT);
+
+        // Follows the support of the device
+        assert_eq!(array.update_after_bind, dev.update_after_bind);
+        assert_eq!(array.capacity, 4);
+        assert_ne!(array.layout, vk::DescriptorSetLayout::null());
+        assert_ne!(array.set, vk::DescriptorSet::null());
+
+        // Every element can be written
+        let image = Image::sampled(&dev.allocator, 1, 1, vk::Format::R8G8B8A8_UNORM);
+        let view = ImageView::new(&dev.device, &image);
+        let sampler = Sampler::new(&dev.device);
+        for index in 0..array.capacity {
+            array.write(index, &view, &sampler);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn texture_array_out_of_bounds() {
+        let ctx = Ctx::headless();
+        let dev = Dev::headless(&ctx);
+        let array = TextureArray::new(&dev, 1, vk::ShaderStageFlags::FRAGMENT);
+        let image = Image::sampled(&dev.allocator, 1, 1, vk::Format::R8G8B8A8_UNORM);
+        let view = ImageView::new(&dev.device, &image);
+        let sampler = Sampler::new(&dev.device);
+        array.write(1, &view, &sampler);
+    }
+
+    #[test]
+    fn descriptor_pool_growth() {
+        assert!(Descriptors::is_pool_exhausted(
+            vk::Result::ERROR_OUT_OF_POOL_MEMORY
+        ));
+        assert!(Descriptors::is_pool_exhausted(
+            vk::Result::ERROR_FRAGMENTED_POOL
+        ));
+        assert!(!Descriptors::is_pool_exhausted(
+            vk::Result::ERROR_OUT_OF_DEVICE_MEMORY
+        ));
+
+        // Sets are freed from the pool they were allocated from
+        let pool = |raw| vk::DescriptorPool::from_raw(raw);
+        let set = |raw| vk::DescriptorSet::from_raw(raw);
+        let mut set_pools = HashMap::new();
+        set_pools.insert(set(1), pool(10));
+        set_pools.insert(set(2), pool(20));
+        set_pools.insert(set(3), pool(10));
+
+        set_pools.insert(set(4), pool(20));
+
+        let groups = Descriptors::take_by_pool(&[set(1), set(2), set(3)], &mut set_pools);
+        assert_eq!(
+            groups,
+            vec![(pool(10), vec![set(1), set(3)]), (pool(20), vec![set(2)])]
+        );
+        // Only the sets which are still allocated are left
+        assert_eq!(set_pools.len(), 1);
+        assert_eq!(set_pools.get(&set(4)), Some(&pool(20)));
+    }
+
+    #[test]
+    fn transient_pool_growth() {
+        let ctx = Ctx::headless();
+        let dev = Dev::headless(&ctx);
+        let binding = vk::DescriptorSetLayoutBinding::builder()
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::VERTEX)
+            .build();
+        let layout = create_set_layout(&dev.device, &[binding]);
+
+        // More sets than a single pool can hold
+        let mut pool = TransientPool::new(&dev);
+        let count = TransientPool::MAX_SETS as usize * 2 + 1;
+        let sets: Vec<vk::DescriptorSet> = (0..count)
+            .flat_map(|_| pool.allocate_sets(&[layout]))
+            .collect();
+        assert_eq!(sets.len(), count);
+        assert_eq!(pool.get_pool_count(), 3);
+
+        // Pools are reused after reset instead of creating new ones
+        pool.reset_sets();
+        for _ in 0..count {
+            pool.allocate_sets(&[layout]);
+        }
+        assert_eq!(pool.get_pool_count(), 3);
+
+        drop(pool);
+        unsafe { dev.device.destroy_descriptor_set_layout(layout, None) };
+    }
+
+    #[test]
+    fn reflected_writes() {
+        let set = vk::DescriptorSet::from_raw(1);
+        let buffer = vk::Buffer::from_raw(2);
+        let view = vk::ImageView::from_raw(3);
+
+        let mut writer = DescriptorWriter::new();
+        writer
+            .write_reflected(
+                set,
+                0,
+                vk::DescriptorType::STORAGE_BUFFER,
+                DescriptorResource::Buffer(buffer),
+            )
+            .write_reflected(
+                set,
+                1,
+                vk::DescriptorType::STORAGE_IMAGE,
+                DescriptorResource::Image(view),
+            );
+
+        let writes = writer.get_writes();
+        assert_eq!(
+            writes[0].descriptor_type,
+            vk::DescriptorType::STORAGE_BUFFER
+        );
+        let buffer_info = unsafe { &*writes[0].p_buffer_info };
+        assert_eq!(buffer_info.buffer, buffer);
+        assert_eq!(buffer_info.range, vk::WHOLE_SIZE);
+        assert_eq!(writes[1].descriptor_type, vk::DescriptorType::STORAGE_IMAGE);
+        let image_info = unsafe { &*writes[1].p_image_info };
+        assert_eq!(image_info.image_view, view);
+        assert_eq!(image_info.image_layout, vk::ImageLayout::GENERAL);
+
+        // Resources must fit the type of the binding
+        let sampler = vk::Sampler::from_raw(4);
+        assert!(DescriptorResource::Buffer(buffer)
+            .get_info(vk::DescriptorType::STORAGE_IMAGE)
+            .is_none());
+        assert!(DescriptorResource::Image(view)
+            .get_info(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .is_none());
+        for (ty, resource) in &[
+            (
+                vk::DescriptorType::UNIFORM_BUFFER,
+                DescriptorResource::Buffer(buffer),
+            ),
+            (
+                vk::DescriptorType::SAMPLED_IMAGE,
+                DescriptorResource::Image(view),
+            ),
+            (
+                vk::DescriptorType::SAMPLER,
+                DescriptorResource::Sampler(sampler),
+            ),
+            (
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                DescriptorResource::SampledImage(view, sampler),
+            ),
+            (
+                vk::DescriptorType::INPUT_ATTACHMENT,
+                DescriptorResource::InputAttachment(
+                    view,
+                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                ),
+            ),
+        ] {
+            assert!(resource.get_info(*ty).is_some());
+        }
     }
 }
@@ -3,10 +3,9 @@
 // SPDX-License-Identifier: MIT
 
 use ash::*;
-use std::{borrow::Borrow, cell::RefCell, collections::HashMap, rc::Rc};
+use std::{cell::RefCell, rc::Rc};
 
 use super::*;
-use imgui as im;
 
 /// This is the one that is going to be recreated
 /// when the swapchain goes out of date
@@ -19,20 +18,67 @@ pub struct Framebuffer {
     pub depth_image: Image,
     pub albedo_view: ImageView,
     pub albedo_image: Image,
+    /// Accumulation of the light volumes drawn in the lighting subpass
+    pub light_view: ImageView,
+    pub light_image: Image,
     /// Image view into a swapchain image
     pub swapchain_view: vk::ImageView,
+    /// Swapchain image, needed to transfer its ownership to the present queue
+    pub swapchain_image: vk::Image,
     pub width: u32,
     pub height: u32,
+    /// Compatibility of the render pass this framebuffer was created for
+    pub pass_compatibility: u64,
     device: Rc<Device>,
 }
 
 impl Framebuffer {
+    /// Default format of the normal attachment of the G-buffer
+    pub const NORMAL_FORMAT: vk::Format = vk::Format::A2R10G10B10_UNORM_PACK32;
+
+    /// Default format of the light accumulation attachment, with enough range to sum many lights
+    pub const LIGHT_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
     pub fn new(dev: &Dev, image: &Image, pass: &Pass) -> Self {
+        Self::with_layers(dev, image, pass, 1)
+    }
+
+    /// Creates a layered framebuffer for a pass created by `Pass::new_multiview`, where each view
+    /// renders to its own array layer. The color `image` should have a layer for each view.
+    pub fn new_multiview(
+        dev: &Dev,
+        image: &Image,
+        pass: &Pass,
+        multiview: &MultiviewConfig,
+    ) -> Self {
+        assert_eq!(
+            image.array_layers, multiview.view_count,
+            "Color image should have a layer for each view"
+        );
+        Self::with_layers(dev, image, pass, multiview.view_count)
+    }
+
+    /// Returns a view of the first `layers` of an attachment, which is an array view when layered
+    fn create_attachment_view(dev: &Dev, image: &Image, layers: u32) -> ImageView {
+        if layers > 1 {
+            ImageView::array(&dev.device, image, 0..layers)
+        } else {
+            ImageView::new(&dev.device, image)
+        }
+    }
+
+    fn with_layers(dev: &Dev, image: &Image, pass: &Pass, layers: u32) -> Self {
+        let view_type = if layers > 1 {
+            vk::ImageViewType::TYPE_2D_ARRAY
+        } else {
+            vk::ImageViewType::TYPE_2D
+        };
+
         // Image view into a swapchain images (device, image, format)
         let swapchain_view = {
             let create_info = vk::ImageViewCreateInfo::builder()
                 .image(image.image)
-                .view_type(vk::ImageViewType::TYPE_2D)
+                .view_type(view_type)
                 .format(image.format)
                 .components(
                     vk::ComponentMapping::builder()
@@ -48,47 +94,64 @@ impl Framebuffer {
                         .base_mip_level(0)
                         .level_count(1)
                         .base_array_layer(0)
-                        .layer_count(1)
+                        .layer_count(layers)
                         .build(),
                 );
             unsafe { dev.device.create_image_view(&create_info, None) }
                 .expect("Failed to create Vulkan image view")
         };
 
-        // Albedo image with the same settings as the swapchain image
-        let mut albedo_image = Image::attachment(
+        // G-buffer formats should match the attachments of the pass
+        let formats = &pass.formats;
+
+        // Albedo image with the same extent as the swapchain image
+        let mut albedo_image = Image::layered_attachment(
             &dev.allocator,
             image.extent.width,
             image.extent.height,
-            image.format,
+            formats.albedo,
+            layers,
         );
         albedo_image.transition(&dev, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
 
-        let albedo_view = ImageView::new(&dev.device, &albedo_image);
+        let albedo_view = Self::create_attachment_view(dev, &albedo_image, layers);
 
         // Depth image
-        let depth_format = vk::Format::D32_SFLOAT;
-        let mut depth_image = Image::attachment(
+        let depth_format = dev.depth_format;
+        let mut depth_image = Image::layered_attachment(
             &dev.allocator,
             image.extent.width,
             image.extent.height,
             depth_format,
+            layers,
         );
         depth_image.transition(&dev, vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
 
-        let depth_view = ImageView::new(&dev.device, &depth_image);
+        let depth_view = Self::create_attachment_view(dev, &depth_image, layers);
 
         // Normal image
-        let normal_format = vk::Format::A2R10G10B10_UNORM_PACK32;
-        let mut normal_image = Image::attachment(
+        let mut normal_image = Image::layered_attachment(
             &dev.allocator,
             image.extent.width,
             image.extent.height,
-            normal_format,
+            formats.normal,
+            layers,
         );
         normal_image.transition(&dev, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
 
-        let normal_view = ImageView::new(&dev.device, &normal_image);
+        let normal_view = Self::create_attachment_view(dev, &normal_image, layers);
+
+        // Light accumulation image
+        let mut light_image = Image::layered_attachment(
+            &dev.allocator,
+            image.extent.width,
+            image.extent.height,
+            formats.light,
+            layers,
+        );
+        light_image.transition(&dev, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+
+        let light_view = Self::create_attachment_view(dev, &light_image, layers);
 
         // Framebuffers (image_views, renderpass)
         let framebuffer = {
@@ -98,6 +161,7 @@ impl Framebuffer {
                 depth_view.view,
                 albedo_view.view,
                 normal_view.view,
+                light_view.view,
             ];
 
             let create_info = vk::FramebufferCreateInfo::builder()
@@ -120,9 +184,13 @@ impl Framebuffer {
             depth_image,
             albedo_view,
             albedo_image,
+            light_view,
+            light_image,
             swapchain_view,
+            swapchain_image: image.image,
             width: image.extent.width,
             height: image.extent.height,
+            pass_compatibility: pass.compatibility,
             device: Rc::clone(&dev.device),
         }
     }
@@ -140,11 +208,228 @@ impl Drop for Framebuffer {
     }
 }
 
+/// Settings of the shadow maps rendered by swapchain frames
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShadowConfig {
+    /// Resolution of the shadow map
+    pub extent: vk::Extent2D,
+}
+
+impl Default for ShadowConfig {
+    fn default() -> Self {
+        Self::new(512, 512)
+    }
+}
+
+impl ShadowConfig {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            extent: vk::Extent2D::builder().width(width).height(height).build(),
+        }
+    }
+
+    /// Returns whether the shadow map fits within the device `maxImageDimension2D` limit
+    pub fn is_supported(&self, max_image_dimension: u32) -> bool {
+        let extent = self.extent;
+        extent.width > 0
+            && extent.height > 0
+            && extent.width <= max_image_dimension
+            && extent.height <= max_image_dimension
+    }
+
+    /// Viewport covering the whole shadow map
+    pub fn get_viewport(&self) -> vk::Viewport {
+        DepthConfig::get_viewport(&self.get_scissor())
+    }
+
+    pub fn get_scissor(&self) -> vk::Rect2D {
+        vk::Rect2D::builder()
+            .offset(vk::Offset2D::builder().x(0).y(0).build())
+            .extent(self.extent)
+            .build()
+    }
+}
+
+/// Depth-only framebuffer where the scene is rendered from the point of view of a light
+pub struct ShadowFramebuffer {
+    pub framebuffer: vk::Framebuffer,
+    pub depth_view: ImageView,
+    pub depth_image: Image,
+    pub config: ShadowConfig,
+    device: Rc<Device>,
+}
+
+impl ShadowFramebuffer {
+    pub fn new(dev: &Dev, pass: &Pass, config: &ShadowConfig) -> Self {
+        let max_image_dimension = dev.get_properties().limits.max_image_dimension2_d;
+        assert!(
+            config.is_supported(max_image_dimension),
+            "Shadow map extent {}x{} exceeds max image dimension {}",
+            config.extent.width,
+            config.extent.height,
+            max_image_dimension
+        );
+
+        let depth_image = Image::attachment(
+            &dev.allocator,
+            config.extent.width,
+            config.extent.height,
+            dev.depth_format,
+        );
+        let depth_view = ImageView::new(&dev.device, &depth_image);
+
+        let framebuffer = {
+            let attachments = [depth_view.view];
+
+            let create_info = vk::FramebufferCreateInfo::builder()
+                .render_pass(pass.render)
+                .attachments(&attachments)
+                .width(config.extent.width)
+                .height(config.extent.height)
+                .layers(1)
+                .build();
+
+            unsafe { dev.device.create_framebuffer(&create_info, None) }
+                .expect("Failed to create Vulkan framebuffer")
+        };
+
+        Self {
+            framebuffer,
+            depth_view,
+            depth_image,
+            config: *config,
+            device: Rc::clone(&dev.device),
+        }
+    }
+}
+
+impl Drop for ShadowFramebuffer {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_framebuffer(self.framebuffer, None);
+        }
+    }
+}
+
+/// Uniform read by the main pipelines to look up the shadow map
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct ShadowUniform {
+    /// Transforms world space into the clip space of the light
+    pub light_space: na::Matrix4<f32>,
+    /// Added to the depth of a fragment before comparing it, to avoid shadow acne
+    pub bias: f32,
+    /// How much shadowed fragments are darkened, zero when no shadow map has been rendered
+    pub strength: f32,
+}
+
+// Safe as it is made of 18 contiguous floats
+unsafe impl Pod for ShadowUniform {}
+
+impl ShadowUniform {
+    pub const DEFAULT_BIAS: f32 = 0.005;
+    pub const DEFAULT_STRENGTH: f32 = 0.5;
+
+    pub fn new(light_space: na::Matrix4<f32>) -> Self {
+        Self {
+            light_space,
+            bias: Self::DEFAULT_BIAS,
+            strength: Self::DEFAULT_STRENGTH,
+        }
+    }
+
+    /// Used when no shadow map has been rendered, so that nothing is shadowed
+    pub fn disabled() -> Self {
+        Self {
+            light_space: na::Matrix4::identity(),
+            bias: 0.0,
+            strength: 0.0,
+        }
+    }
+
+    /// Returns the texture coordinates of `position` in the shadow map, and its depth, reversed
+    /// by the projection of the light. This is the CPU reference of `main_fs`.
+    pub fn get_coord(&self, position: &na::Point3<f32>) -> (na::Vector2<f32>, f32) {
+        let clip = self.light_space * position.to_homogeneous();
+        let ndc = clip.xyz() / clip.w;
+        let uv = na::Vector2::new(ndc.x * 0.5 + 0.5, ndc.y * 0.5 + 0.5);
+        (uv, ndc.z)
+    }
+
+    /// Returns one when a fragment at `depth` is lit, and one minus `strength` when the
+    /// shadow map stores an occluder closer to the light, hence with a greater depth
+    pub fn get_factor(&self, occluder_depth: f32, depth: f32) -> f32 {
+        if depth + self.bias >= occluder_depth {
+            1.0
+        } else {
+            1.0 - self.strength
+        }
+    }
+}
+
+/// Shadow map of a frame, with the pass and pipeline rendering the scene depth into it
+pub struct ShadowMap {
+    pub framebuffer: ShadowFramebuffer,
+    pub pipeline: Pipeline,
+    pub pass: Pass,
+    /// Depth is compared in the shader, hence it is sampled without filtering
+    pub sampler: Sampler,
+    /// Whether the shadow map has been rendered since the frame began
+    rendered: bool,
+}
+
+impl ShadowMap {
+    pub fn new(dev: &mut Dev, config: &ShadowConfig) -> Self {
+        let pass = Pass::new_shadow(dev);
+        let framebuffer = ShadowFramebuffer::new(dev, &pass, config);
+        let pipeline = Pipeline::shadow(dev, &pass, config.extent.width, config.extent.height);
+        let sampler = Sampler::with_info(
+            dev,
+            &SamplerInfo {
+                address_mode: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+                ..Default::default()
+            },
+        );
+
+        Self {
+            framebuffer,
+            pipeline,
+            pass,
+            sampler,
+            rendered: false,
+        }
+    }
+
+    pub fn get_depth_view(&self) -> &ImageView {
+        &self.framebuffer.depth_view
+    }
+
+    pub fn get_set_layout_bindings() -> Vec<vk::DescriptorSetLayoutBinding> {
+        let shadow = vk::DescriptorSetLayoutBinding::builder()
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT)
+            .build();
+
+        let shadow_map = vk::DescriptorSetLayoutBinding::builder()
+            .binding(1)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            .build();
+
+        vec![shadow, shadow_map]
+    }
+}
+
 /// Container of fallback resources for a frame such as
 /// A white 1x1 pixel texture (image, view, and sampler)
 pub struct Fallback {
     _white_image: Image,
     white_view: ImageView,
+    /// Array view of the white texture, as sampled by materials
+    white_array_view: ImageView,
     /// A default sampler
     pub white_sampler: Sampler,
     white_material: Material,
@@ -155,20 +440,17 @@ pub struct Fallback {
 impl Fallback {
     fn new(dev: &Dev) -> Self {
         let white = [255, 255, 255, 255];
-        let white_image = Image::from_data(&dev, &white, 1, 1, vk::Format::R8G8B8A8_SRGB);
+        let white_image = Image::from_data(&dev, &white, 1, 1, vk::Format::R8G8B8A8_SRGB, None);
 
         let white_view = ImageView::new(&dev.device, &white_image);
+        let white_array_view = ImageView::array(&dev.device, &white_image, 0..1);
 
         let white_sampler = Sampler::new(&dev.device);
 
         let white_material = Material::new(Color::white());
 
-        // Y pointing down
-        let present_vertices = vec![
-            PresentVertex::new(-1.0, -1.0),
-            PresentVertex::new(-1.0, 3.0),
-            PresentVertex::new(3.0, -1.0),
-        ];
+        // Viewports are not flipped, hence Y pointing down
+        let present_vertices = PresentVertex::fullscreen_triangle(ClipY::Down);
         let present_buffer = Buffer::new_arr(
             &dev.allocator,
             vk::BufferUsageFlags::VERTEX_BUFFER,
@@ -178,6 +460,7 @@ impl Fallback {
         Self {
             _white_image: white_image,
             white_view,
+            white_array_view,
             white_sampler,
             white_material,
             present_buffer,
@@ -185,19 +468,38 @@ impl Fallback {
     }
 }
 
-type BufferCache<T> = HashMap<Handle<T>, Buffer>;
+type BufferCache<T> = HandleMap<T, Buffer>;
+
+/// Offsets of the uniforms of a node within the uniform arena of a frame
+#[derive(Debug, Clone, Copy)]
+pub struct NodeUniforms {
+    pub model: vk::DeviceSize,
+    pub model_view: vk::DeviceSize,
+    pub morph_weights: vk::DeviceSize,
+}
+
+impl NodeUniforms {
+    pub fn new(arena: &mut UniformArena) -> Self {
+        Self {
+            model: arena.allocate::<na::Matrix4<f32>>(),
+            model_view: arena.allocate::<NormalMatrix>(),
+            morph_weights: arena.allocate::<MorphWeights>(),
+        }
+    }
+}
 
 /// Frame resources that do not need to be recreated
 /// when the swapchain goes out of date
 pub struct Frameres {
-    pub gui_vertex_buffer: Buffer,
-    pub gui_index_buffer: Buffer,
+    /// Shared buffer where the uniforms of nodes are uploaded with a single map
+    pub uniforms: UniformArena,
 
-    /// Uniform buffers for model matrices associated to nodes
-    pub model_buffers: BufferCache<Node>,
+    /// Model matrices, normal matrices of model-view transforms, and morph weights
+    /// of nodes within `uniforms`
+    pub node_uniforms: HandleMap<Node, NodeUniforms>,
 
-    /// Uniform buffers for model-view matrices associated to nodes
-    pub model_view_buffers: BufferCache<Node>,
+    /// Zero morph deltas and second texture coordinates for primitives without them
+    pub zero_buffer: ZeroBuffer,
 
     /// Uniform buffers for view matrices associated to nodes with cameras
     pub view_buffers: BufferCache<Node>,
@@ -208,10 +510,28 @@ pub struct Frameres {
     // Uniform buffers for materials
     pub material_buffers: BufferCache<Material>,
 
+    /// Uniform buffer for the lookup of the shadow map
+    pub shadow_buffer: Buffer,
+
     pub descriptors: Descriptors,
     pub command_buffer: CommandBuffer,
+    /// Command buffers added to this frame, submitted in order before the main one.
+    /// Kept across frames to be reused, only the first `added_count` are part of this frame.
+    pub added_command_buffers: Vec<CommandBuffer>,
+    /// Each signaled by the submission of the added command buffer at the same index,
+    /// and waited by the next submission
+    pub added_links: Vec<Semaphore>,
+    pub added_count: usize,
+    /// How many of the added command buffers have already been submitted, see `submit_added`
+    pub submitted_count: usize,
+    /// Resources of throwaway draws, reset when the frame begins. Declared after the added
+    /// command buffers, so that they are freed before their pool is destroyed.
+    pub transient: TransientArena,
 
     pub fence: Fence,
+    /// Run when the fence of this frame is waited before the frame is reused.
+    /// Declared after the fence, so that on drop they run after waiting for it.
+    pub completion: CompletionCallbacks,
 
     // The image ready semaphore is used by the acquire next image function and it will be signaled
     // then the image is ready to be rendered onto. Indeed it is also used by the submit draw
@@ -223,379 +543,1483 @@ pub struct Frameres {
     // is waiting on this sempahore before presenting the back-buffer to screen.
     pub image_drawn: Semaphore,
 
+    /// Only swapchain frames need it, when graphics and present queue families differ
+    pub present_acquire: Option<PresentAcquire>,
+
     pub fallback: Fallback,
 }
 
+/// Resources to acquire the ownership of a swapchain image on the present queue
+pub struct PresentAcquire {
+    pub transfer: QueueTransfer,
+    /// Recorded on the present queue with the acquire barrier
+    pub command_buffer: CommandBuffer,
+    /// Signaled when the image has been acquired, presentation waits on it
+    pub image_acquired: Semaphore,
+}
+
+impl PresentAcquire {
+    /// Returns `None` when graphics and present queues are from the same family
+    pub fn new(dev: &Dev) -> Option<Self> {
+        let transfer = dev.get_queue_transfer()?;
+        let pool = dev
+            .present_command_pool
+            .as_ref()
+            .expect("Failed to get present command pool");
+        Some(Self {
+            transfer,
+            command_buffer: CommandBuffer::new(pool),
+            image_acquired: Semaphore::new(&dev.device),
+        })
+    }
+
+    /// Records the acquire barrier matching the release one recorded by the graphics queue
+    pub fn record(&self, image: vk::Image) {
+        self.command_buffer
+            .begin(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        self.command_buffer.pipeline_barriers(
+            vk::PipelineStageFlags::ALL_COMMANDS,
+            vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            vk::DependencyFlags::default(),
+            &[self.transfer.get_acquire_barrier(image)],
+        );
+        self.command_buffer.end();
+    }
+}
+
 impl Frameres {
+    /// Size in bytes of the uniform arena
+    const UNIFORM_ARENA_SIZE: vk::DeviceSize = 4 * 1024 * 1024;
+
     pub fn new(dev: &mut Dev) -> Self {
         // Graphics command buffer (device, command pool)
-        let command_buffer = CommandBuffer::new(&mut dev.graphics_command_pool);
+        let mut command_buffer = CommandBuffer::new(&mut dev.graphics_command_pool);
+        command_buffer
+            .set_max_push_constants_size(dev.get_properties().limits.max_push_constants_size);
 
         // Fence (device)
         let fence = Fence::signaled(&dev.device);
 
-        let gui_vertex_buffer =
-            Buffer::new::<im::DrawVert>(&dev.allocator, vk::BufferUsageFlags::VERTEX_BUFFER);
-        let gui_index_buffer =
-            Buffer::new::<u16>(&dev.allocator, vk::BufferUsageFlags::INDEX_BUFFER);
+        let uniforms = {
+            let buffer = Buffer::new_with_size(
+                &dev.allocator,
+                vk::BufferUsageFlags::UNIFORM_BUFFER,
+                Self::UNIFORM_ARENA_SIZE,
+            );
+            let alignment = dev
+                .get_properties()
+                .limits
+                .min_uniform_buffer_offset_alignment;
+            UniformArena::new(buffer, alignment)
+        };
 
         Self {
-            gui_vertex_buffer,
-            gui_index_buffer,
-            model_buffers: BufferCache::new(),
-            model_view_buffers: BufferCache::new(),
+            uniforms,
+            node_uniforms: HandleMap::new(),
+            zero_buffer: ZeroBuffer::new(),
             view_buffers: BufferCache::new(),
             proj_buffers: BufferCache::new(),
             material_buffers: BufferCache::new(),
+            shadow_buffer: Buffer::new::<ShadowUniform>(
+                &dev.allocator,
+                vk::BufferUsageFlags::UNIFORM_BUFFER,
+            ),
             descriptors: Descriptors::new(dev),
             command_buffer,
+            added_command_buffers: vec![],
+            added_links: vec![],
+            added_count: 0,
+            submitted_count: 0,
+            transient: TransientArena::new(dev),
             fence,
+            completion: CompletionCallbacks::new(),
             image_ready: Semaphore::new(&dev.device),
             image_drawn: Semaphore::new(&dev.device),
+            present_acquire: None,
             fallback: Fallback::new(&dev),
         }
     }
 
+    /// Returns the command buffers of this frame not submitted yet in submission order,
+    /// the added ones in the order they were added, then the main one
+    pub fn get_command_buffers(&self) -> Vec<vk::CommandBuffer> {
+        self.added_command_buffers[self.submitted_count..self.added_count]
+            .iter()
+            .chain(std::iter::once(&self.command_buffer))
+            .map(|command_buffer| command_buffer.command_buffer)
+            .collect()
+    }
+
+    /// Returns the link signaled by the last submitted command buffer, which the next submission
+    /// of this frame should wait for
+    fn get_submitted_link(&self) -> Option<vk::Semaphore> {
+        self.submitted_count
+            .checked_sub(1)
+            .map(|i| self.added_links[i].semaphore)
+    }
+
+    /// Chains the submission of `chain` after the command buffers already submitted, if any
+    fn chain_after_submitted(&self, chain: &mut [ChainedSubmit]) {
+        if let Some(link) = self.get_submitted_link() {
+            chain[0].waits.insert(0, link);
+            chain[0]
+                .wait_stages
+                .insert(0, vk::PipelineStageFlags::ALL_COMMANDS);
+        }
+    }
+
+    /// Submits the added command buffers not submitted yet, which should have ended recording.
+    /// Each of them signals its link, so that the next submission of this frame waits for it.
+    pub fn submit_added(&mut self, queue: &Queue) {
+        if self.submitted_count == self.added_count {
+            return;
+        }
+
+        let command_buffers: Vec<vk::CommandBuffer> = self.added_command_buffers
+            [self.submitted_count..self.added_count]
+            .iter()
+            .map(|command_buffer| command_buffer.command_buffer)
+            .collect();
+        let links: Vec<vk::Semaphore> = self.added_links[self.submitted_count..self.added_count]
+            .iter()
+            .map(|link| link.semaphore)
+            .collect();
+        // The last one signals its link as well, for the submission coming next
+        let last = links.last().copied();
+        let mut chain =
+            ChainedSubmit::chain(&command_buffers, &links[..links.len() - 1], None, last);
+        self.chain_after_submitted(&mut chain);
+        queue.submit_chain(&chain, None);
+        self.submitted_count = self.added_count;
+    }
+
+    /// Submits the command buffers of this frame not submitted yet in order, after the ones
+    /// submitted by `submit_added`. The main one waits for `wait` and signals `signal`,
+    /// and the fence of this frame when `signal_fence` is set.
+    pub fn submit(
+        &mut self,
+        queue: &Queue,
+        wait: Option<vk::Semaphore>,
+        signal: Option<vk::Semaphore>,
+        signal_fence: bool,
+    ) {
+        let links: Vec<vk::Semaphore> = self.added_links[self.submitted_count..self.added_count]
+            .iter()
+            .map(|link| link.semaphore)
+            .collect();
+        let mut chain = ChainedSubmit::chain(&self.get_command_buffers(), &links, wait, signal);
+        self.chain_after_submitted(&mut chain);
+        let fence = if signal_fence {
+            Some(&mut self.fence)
+        } else {
+            None
+        };
+        queue.submit_chain(&chain, fence);
+        self.added_count = 0;
+        self.submitted_count = 0;
+    }
+
+    /// Waits for the previous submission of this frame, then runs its completion callbacks
     pub fn wait(&mut self) {
         self.fence.wait();
         self.fence.reset();
+        self.completion.run();
+    }
+
+    /// Maps the uniform arena, so that following uploads do not need to map memory again
+    pub fn begin_uploads(&mut self) {
+        self.uniforms.begin();
+    }
+
+    /// Unmaps the uniform arena once all the uploads of this frame are done
+    pub fn end_uploads(&mut self) {
+        self.uniforms.end();
+    }
+
+    /// Replaces the semaphores of this frame. An acquire or a present interrupted by an out of date
+    /// swapchain might have left them signaled with nobody waiting on them, and signaling them again
+    /// would be invalid. Should be called only when the device is idle.
+    pub fn reset_semaphores(&mut self, device: &Rc<Device>) {
+        self.image_ready = Semaphore::new(device);
+        self.image_drawn = Semaphore::new(device);
+        if let Some(acquire) = &mut self.present_acquire {
+            acquire.image_acquired = Semaphore::new(device);
+        }
+    }
+}
+
+/// Returns the largest rectangle with `target_aspect` centered within a framebuffer of
+/// `width` × `height`, leaving bars on the sides or at the top and bottom.
+/// Without a target aspect, the whole framebuffer is returned.
+pub fn get_letterbox_rect(width: u32, height: u32, target_aspect: Option<f32>) -> vk::Rect2D {
+    let full = vk::Rect2D::builder()
+        .extent(vk::Extent2D::builder().width(width).height(height).build())
+        .build();
+    let target_aspect = match target_aspect {
+        Some(aspect) if aspect > 0.0 && width > 0 && height > 0 => aspect,
+        _ => return full,
+    };
+
+    let aspect = width as f32 / height as f32;
+    let (inner_width, inner_height) = if aspect > target_aspect {
+        // Window is wider, bars on the left and right
+        let inner = (height as f32 * target_aspect).round() as u32;
+        (inner.min(width), height)
+    } else {
+        // Window is narrower, bars at the top and bottom
+        let inner = (width as f32 / target_aspect).round() as u32;
+        (width, inner.min(height))
+    };
+
+    vk::Rect2D::builder()
+        .offset(
+            vk::Offset2D::builder()
+                .x(((width - inner_width) / 2) as i32)
+                .y(((height - inner_height) / 2) as i32)
+                .build(),
+        )
+        .extent(
+            vk::Extent2D::builder()
+                .width(inner_width)
+                .height(inner_height)
+                .build(),
+        )
+        .build()
+}
+
+/// Stack of scissor rectangles constraining draws to sub-rectangles of a framebuffer
+pub struct ScissorStack {
+    stack: Vec<vk::Rect2D>,
+    /// Rectangle which pushed ones are clamped within
+    bounds: vk::Rect2D,
+}
+
+impl ScissorStack {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            stack: vec![],
+            bounds: get_letterbox_rect(width, height, None),
+        }
+    }
+
+    /// Clears the stack, so that the whole framebuffer of `width` × `height` is drawable again
+    pub fn reset(&mut self, width: u32, height: u32) {
+        self.reset_to(&get_letterbox_rect(width, height, None));
+    }
+
+    /// Clears the stack, so that the whole `bounds` rectangle is drawable again
+    pub fn reset_to(&mut self, bounds: &vk::Rect2D) {
+        self.stack.clear();
+        self.bounds = *bounds;
+    }
+
+    /// Returns the rectangle draws are currently constrained to
+    pub fn get_current(&self) -> vk::Rect2D {
+        match self.stack.last() {
+            Some(rect) => *rect,
+            None => self.bounds,
+        }
+    }
+
+    /// Returns `rect` clamped within the bounds
+    fn clamp(&self, rect: &vk::Rect2D) -> vk::Rect2D {
+        let clamp_range = |offset: i32, size: u32, bound_offset: i32, bound_size: u32| {
+            let min = bound_offset as i64;
+            let max = min + bound_size as i64;
+            let begin = (offset as i64).max(min).min(max);
+            let end = (offset as i64 + size as i64).max(begin).min(max);
+            (begin as i32, (end - begin) as u32)
+        };
+        let bounds = &self.bounds;
+        let (x, width) = clamp_range(
+            rect.offset.x,
+            rect.extent.width,
+            bounds.offset.x,
+            bounds.extent.width,
+        );
+        let (y, height) = clamp_range(
+            rect.offset.y,
+            rect.extent.height,
+            bounds.offset.y,
+            bounds.extent.height,
+        );
+
+        vk::Rect2D::builder()
+            .offset(vk::Offset2D::builder().x(x).y(y).build())
+            .extent(vk::Extent2D::builder().width(width).height(height).build())
+            .build()
+    }
+
+    /// Pushes `rect`, clamped within the framebuffer, and returns it
+    pub fn push(&mut self, rect: &vk::Rect2D) -> vk::Rect2D {
+        let rect = self.clamp(rect);
+        self.stack.push(rect);
+        rect
+    }
+
+    /// Pops the current rectangle and returns the previous one
+    pub fn pop(&mut self) -> vk::Rect2D {
+        self.stack.pop();
+        self.get_current()
     }
 }
 
 pub struct Frame {
     /// Used to compute the model-view matrix when rendering a mesh
     pub current_view: na::Matrix4<f32>,
+    /// Projection of the camera bound to this frame, used to draw light volumes
+    pub current_proj: na::Matrix4<f32>,
+    /// View-space positions of the lights met while drawing, accumulated by the lighting subpass
+    pub lights: Vec<(na::Vector3<f32>, Light)>,
     pub buffer: Framebuffer,
     pub res: Frameres,
+    pub scissors: ScissorStack,
+    /// Only swapchain frames render shadows
+    pub shadow: Option<ShadowMap>,
+    /// Aspect ratio the scene is letterboxed to, if any
+    target_aspect: Option<f32>,
+    /// Sub-rectangle of the framebuffer the scene is rendered to
+    scene_rect: vk::Rect2D,
+    /// Colors used to clear the attachments when the frame begins
+    clear_values: ClearValues,
+    /// Depth convention of viewports, which should match the one of the pipelines
+    depth_config: DepthConfig,
+    /// Views rendered at once, whose camera matrices are uploaded as arrays
+    multiview: Option<MultiviewConfig>,
+    /// Whether the command buffer is recording, as the shadow pass can begin it before `begin`
+    recording: bool,
+    /// Whether a pass is in progress, begun by `begin` or `begin_pass` and not ended yet
+    in_pass: bool,
+    /// Whether a node whose normal matrix can not be computed has already been logged
+    singular_logged: bool,
+    /// Writes of the descriptor sets prepared for drawing, flushed before binding them
+    writer: DescriptorWriter,
     /// A frame should be able to allocate a uniform buffer on draw
     allocator: Rc<RefCell<vk_mem::Allocator>>,
     pub device: Rc<Device>,
 }
 
 impl Frame {
+    pub const DEFAULT_CLEAR_COLOR: [f32; 4] = [0.0, 10.0 / 255.0, 28.0 / 255.0, 1.0];
+
     pub fn new(dev: &mut Dev, image: &Image, pass: &Pass) -> Self {
         let buffer = Framebuffer::new(dev, image, pass);
+        Self::with_framebuffer(dev, buffer, None)
+    }
+
+    /// Creates a frame rendering every view of `multiview` into the array layers of `image`,
+    /// with a pass created by `Pass::new_multiview` and the pipelines of `DefaultPipelines::new_multiview`
+    pub fn new_multiview(
+        dev: &mut Dev,
+        image: &Image,
+        pass: &Pass,
+        multiview: &MultiviewConfig,
+    ) -> Self {
+        let buffer = Framebuffer::new_multiview(dev, image, pass, multiview);
+        Self::with_framebuffer(dev, buffer, Some(*multiview))
+    }
+
+    fn with_framebuffer(
+        dev: &mut Dev,
+        buffer: Framebuffer,
+        multiview: Option<MultiviewConfig>,
+    ) -> Self {
         let res = Frameres::new(dev);
 
+        let scissors = ScissorStack::new(buffer.width, buffer.height);
+        let scene_rect = scissors.get_current();
+
         Frame {
             current_view: na::Matrix4::identity(),
+            current_proj: na::Matrix4::identity(),
+            lights: vec![],
             buffer,
             res,
+            scissors,
+            shadow: None,
+            target_aspect: None,
+            scene_rect,
+            clear_values: ClearValues::default(),
+            depth_config: DepthConfig::default(),
+            multiview,
+            recording: false,
+            in_pass: false,
+            singular_logged: false,
+            writer: DescriptorWriter::new(),
             allocator: dev.allocator.clone(),
             device: Rc::clone(&dev.device),
         }
     }
 
-    pub fn begin(&self, pass: &Pass, width: u32, height: u32) {
-        self.res
-            .command_buffer
-            .begin(vk::CommandBufferUsageFlags::default());
-
-        // Needed by cmd_begin_render_pass
-        let area = vk::Rect2D::builder()
-            .offset(vk::Offset2D::builder().x(0).y(0).build())
-            .extent(vk::Extent2D::builder().width(width).height(height).build())
-            .build();
+    /// Albedo attachment of the G-buffer. It is stored and `SHADER_READ_ONLY_OPTIMAL` at the end
+    /// of the render pass, hence it can be sampled by passes following it.
+    pub fn get_albedo_view(&self) -> &ImageView {
+        &self.buffer.albedo_view
+    }
 
-        self.res
-            .command_buffer
-            .begin_render_pass(pass, &self.buffer, area);
+    /// Normal attachment of the G-buffer, with the same layout as the albedo one
+    pub fn get_normal_view(&self) -> &ImageView {
+        &self.buffer.normal_view
+    }
 
-        let viewport = vk::Viewport::builder()
-            .width(width as f32)
-            .height(height as f32)
-            .max_depth(0.0)
-            .min_depth(1.0)
-            .build();
-        self.res.command_buffer.set_viewport(&viewport);
+    /// Depth attachment. Its content is preserved only by a pass created with depth read enabled,
+    /// and in that case it is `DEPTH_STENCIL_READ_ONLY_OPTIMAL` at the end of the render pass.
+    pub fn get_depth_view(&self) -> &ImageView {
+        &self.buffer.depth_view
+    }
 
-        let scissor = vk::Rect2D::builder()
-            .extent(vk::Extent2D::builder().width(width).height(height).build())
-            .build();
+    /// Constrains following draws within `rect`, which is clamped within the framebuffer
+    pub fn push_scissor(&mut self, rect: &vk::Rect2D) {
+        let scissor = self.scissors.push(rect);
         self.res.command_buffer.set_scissor(&scissor);
     }
 
-    pub fn bind(&mut self, pipeline: &Pipeline, model: &Model, camera_node: Handle<Node>) {
-        self.res.command_buffer.bind_pipeline(pipeline);
-
-        let width = self.buffer.width as f32;
-        let height = self.buffer.height as f32;
-        let viewport = vk::Viewport::builder()
-            .width(width)
-            .height(height)
-            .max_depth(0.0)
-            .min_depth(1.0)
-            .build();
-        self.res.command_buffer.set_viewport(&viewport);
-
-        let scissor = vk::Rect2D::builder()
-            .extent(
-                vk::Extent2D::builder()
-                    .width(self.buffer.width)
-                    .height(self.buffer.height)
-                    .build(),
-            )
-            .build();
+    /// Restores the scissor rectangle active before the last push
+    pub fn pop_scissor(&mut self) {
+        let scissor = self.scissors.pop();
         self.res.command_buffer.set_scissor(&scissor);
+    }
 
-        let node = model.nodes.get(camera_node).unwrap();
-        self.current_view = node.trs.get_view_matrix();
-        let camera = model.cameras.get(node.camera).unwrap();
+    pub fn get_target_aspect(&self) -> Option<f32> {
+        self.target_aspect
+    }
 
-        if let Some(sets) = self
-            .res
-            .descriptors
-            .view_sets
-            .get(&(pipeline.set_layouts[1], camera_node))
-        {
-            self.res
-                .command_buffer
-                .bind_descriptor_sets(pipeline, sets, 1);
+    /// Letterboxes the scene to `aspect` from the next `begin`, or stretches it to the whole
+    /// framebuffer when `None`
+    pub fn set_target_aspect(&mut self, aspect: Option<f32>) {
+        self.target_aspect = aspect;
+    }
 
-            // If there is a descriptor set, there must be a buffer
-            let view_buffer = self.res.view_buffers.get_mut(&camera_node).unwrap();
-            view_buffer.upload(&self.current_view);
+    /// Returns the sub-rectangle of the framebuffer the scene is rendered to,
+    /// which is the whole framebuffer unless letterboxed
+    pub fn get_scene_rect(&self) -> vk::Rect2D {
+        self.scene_rect
+    }
 
-            let proj_buffer = self.res.proj_buffers.get_mut(&node.camera).unwrap();
-            proj_buffer.upload(&camera.proj);
-        } else {
-            // Allocate and write desc set for camera view
-            // Camera set layout is at index 1 (use a constant?)
-            let sets = self.res.descriptors.allocate(&[pipeline.set_layouts[1]]);
-
-            if let Some(view_buffer) = self.res.view_buffers.get_mut(&camera_node) {
-                // Buffer already there, just make the set pointing to it
-                Camera::write_set_view(self.device.borrow(), sets[0], &view_buffer);
-            } else {
-                // Create a new buffer for this node's view matrix
-                let mut view_buffer = Buffer::new::<na::Matrix4<f32>>(
-                    &self.allocator,
-                    vk::BufferUsageFlags::UNIFORM_BUFFER,
-                );
-                view_buffer.upload(&self.current_view);
-                Camera::write_set_view(self.device.borrow(), sets[0], &view_buffer);
-                self.res.view_buffers.insert(camera_node, view_buffer);
-            }
+    /// Returns whether the scene does not cover the whole framebuffer
+    pub fn is_letterboxed(&self) -> bool {
+        self.scene_rect.offset.x != 0
+            || self.scene_rect.offset.y != 0
+            || self.scene_rect.extent.width != self.buffer.width
+            || self.scene_rect.extent.height != self.buffer.height
+    }
 
-            if let Some(proj_buffer) = self.res.proj_buffers.get_mut(&node.camera) {
-                // Buffer already there, just make the set pointing to it
-                Camera::write_set_proj(self.device.borrow(), sets[0], &proj_buffer);
-            } else {
-                // Create a new buffer for this camera proj matrix
-                let mut proj_buffer = Buffer::new::<na::Matrix4<f32>>(
-                    &self.allocator,
-                    vk::BufferUsageFlags::UNIFORM_BUFFER,
-                );
-                proj_buffer.upload(&camera.proj);
-                Camera::write_set_proj(self.device.borrow(), sets[0], &proj_buffer);
-                self.res.proj_buffers.insert(node.camera, proj_buffer);
-            }
+    /// Registers `callback` to run once the GPU work of this frame is complete, which is checked
+    /// when this frame is reused, or dropped
+    pub fn on_complete(&mut self, callback: Box<dyn FnOnce()>) {
+        self.res.completion.push(callback);
+    }
 
-            self.res
-                .command_buffer
-                .bind_descriptor_sets(pipeline, &sets, 1);
+    /// Returns the viewport covering the scene rectangle
+    fn get_scene_viewport(&self) -> vk::Viewport {
+        DepthConfig::get_viewport(&self.scene_rect)
+    }
 
-            self.res
-                .descriptors
-                .view_sets
-                .insert((pipeline.set_layouts[1], camera_node), sets);
-        }
+    /// Returns the pipeline, descriptor sets, buffers, viewport, and scissor bound since
+    /// the command buffer of this frame began recording, to debug draws which do not show up.
+    /// It is only tracked in debug builds.
+    pub fn debug_state(&self) -> FrameDebugState {
+        self.res.command_buffer.get_bound_state()
     }
 
-    pub fn draw<T: VertexInput>(
-        &mut self,
-        pipelines: &DefaultPipelines,
-        model: &Model,
-        node: Handle<Node>,
-    ) {
-        let pipeline = pipelines.get_for::<T>();
-        self.res.command_buffer.bind_pipeline(pipeline);
+    pub fn get_depth_config(&self) -> DepthConfig {
+        self.depth_config
+    }
 
-        let children = model.nodes.get(node).unwrap().children.clone();
-        for child in children {
-            self.draw::<T>(pipelines, model, child);
-        }
+    /// Sets the depth convention of camera projections and of the depth clear value
+    /// from the next `begin`.
+    /// Pipelines drawing in this frame should be created with the same convention, such as
+    /// `DefaultPipelines::new` or `RasterState::depth`.
+    pub fn set_depth_config(&mut self, depth_config: DepthConfig) {
+        self.depth_config = depth_config;
+        self.clear_values.depth = depth_config.get_clear_depth();
+    }
+
+    pub fn get_clear_color(&self) -> [f32; 4] {
+        self.clear_values.color
+    }
+
+    /// Sets the color used to clear the presentation image from the next `begin`
+    pub fn set_clear_color(&mut self, color: [f32; 4]) {
+        self.clear_values.color = color;
+    }
+
+    pub fn get_clear_values(&self) -> &ClearValues {
+        &self.clear_values
+    }
 
-        let cnode = model.nodes.get(node).unwrap();
+    /// Sets the colors used to clear all the attachments from the next `begin`
+    pub fn set_clear_values(&mut self, clear_values: ClearValues) {
+        self.clear_values = clear_values;
+    }
 
-        let mesh = model.meshes.get(cnode.mesh);
-        if mesh.is_none() {
-            return ();
+    /// Begins recording the command buffer, unless it is already recording, for example because
+    /// the shadow pass began it. Passes can then be recorded with `begin_pass` and `end_pass`.
+    pub fn begin_command_buffer(&mut self) {
+        if self.recording {
+            return;
         }
-        let mesh = mesh.unwrap();
+        self.recording = true;
 
-        let model_view_matrix = (self.current_view * cnode.trs.get_matrix())
-            .try_inverse()
-            .unwrap()
-            .transpose();
+        // Re-recorded every time the frame is used
+        self.res
+            .command_buffer
+            .begin(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+
+        // Previous submission of this frame is finished, therefore its transient resources can go
+        self.res.transient.reset();
+        self.res.zero_buffer.reset();
+
+        // Uniforms of nodes are written while recording
+        self.res.begin_uploads();
+    }
+
+    /// Returns a new command buffer, ready to record, submitted before the main one of this frame
+    /// and after the ones added previously. Its recording ends with the main command buffer,
+    /// or earlier with `submit_added`, so that it runs while the rest is still being recorded.
+    pub fn add_command_buffer(&mut self, dev: &Dev) -> &CommandBuffer {
+        // Added command buffers come from the transient pool, which is reset when beginning
+        self.begin_command_buffer();
+
+        let res = &mut self.res;
+        if res.added_count == res.added_command_buffers.len() {
+            let mut command_buffer = CommandBuffer::new(&res.transient.commands);
+            command_buffer
+                .set_max_push_constants_size(dev.get_properties().limits.max_push_constants_size);
+            res.added_command_buffers.push(command_buffer);
+            res.added_links.push(Semaphore::new(&dev.device));
+        }
+
+        let command_buffer = &res.added_command_buffers[res.added_count];
+        res.added_count += 1;
+        command_buffer.begin(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        command_buffer
+    }
+
+    /// Begins the command buffer and `pass` rendering into the framebuffer of this frame, with the
+    /// scene letterboxed to the target aspect if any. The shadow map is cleared when not rendered.
+    pub fn begin(&mut self, pass: &Pass, width: u32, height: u32) {
+        self.begin_command_buffer();
+
+        // Without a shadow pass, the shadow map is only cleared, so that it can be sampled
+        let shadow_rendered = match &mut self.shadow {
+            Some(shadow) => {
+                if !shadow.rendered {
+                    self.res
+                        .command_buffer
+                        .begin_shadow_pass(&shadow.pass, &shadow.framebuffer);
+                    self.res.command_buffer.end_render_pass();
+                }
+                std::mem::replace(&mut shadow.rendered, false)
+            }
+            None => false,
+        };
+        if !shadow_rendered {
+            self.res.shadow_buffer.upload(&ShadowUniform::disabled());
+        }
+
+        // Needed by cmd_begin_render_pass
+        let area = vk::Rect2D::builder()
+            .offset(vk::Offset2D::builder().x(0).y(0).build())
+            .extent(vk::Extent2D::builder().width(width).height(height).build())
+            .build();
+
+        assert!(!self.in_pass, "Failed to begin: a pass is in progress");
+        self.res
+            .command_buffer
+            .begin_render_pass(pass, &self.buffer, area, &self.clear_values);
+        self.in_pass = true;
+        self.set_scene_rect(&get_letterbox_rect(width, height, self.target_aspect));
+    }
 
-        if let Some(sets) = self
+    /// Begins `pass` rendering into `framebuffer` within `area`, clearing its attachments
+    /// with the clear values of this frame. Viewport and scissor cover `area`.
+    /// It should be called after `begin_command_buffer`, and the pass ended with `end_pass`.
+    pub fn begin_pass(&mut self, pass: &Pass, framebuffer: &Framebuffer, area: vk::Rect2D) {
+        assert!(
+            self.recording,
+            "Failed to begin pass: command buffer is not recording"
+        );
+        assert!(
+            !self.in_pass,
+            "Failed to begin pass: another pass is in progress"
+        );
+        self.res
+            .command_buffer
+            .begin_render_pass(pass, framebuffer, area, &self.clear_values);
+        self.in_pass = true;
+        self.set_scene_rect(&area);
+    }
+
+    /// Sets viewport and scissor back to the scene rectangle, as subpasses after the geometry
+    /// one may have scissored draws, for example light volumes
+    pub fn reset_scene_viewport(&mut self) {
+        self.res
+            .command_buffer
+            .set_viewport(&self.get_scene_viewport());
+        self.scissors.reset_to(&self.scene_rect);
+        let scissor = self.scissors.get_current();
+        self.res.command_buffer.set_scissor(&scissor);
+    }
+
+    /// Renders the scene within `rect`, resetting viewport, scissors, and lights
+    fn set_scene_rect(&mut self, rect: &vk::Rect2D) {
+        self.scene_rect = *rect;
+        self.reset_scene_viewport();
+        self.lights.clear();
+    }
+
+    /// Begins `pass`, created by `Pass::new_oit`, into the framebuffer of `wboit` created for this
+    /// frame. Blended primitives can then be accumulated with `Pipeline::weighted_blended`, before
+    /// compositing them with `resolve_oit`. The pass of this frame should be ended before.
+    pub fn begin_oit_pass(&mut self, pass: &Pass, wboit: &Wboit) {
+        assert!(
+            self.recording,
+            "Failed to begin OIT pass: command buffer is not recording"
+        );
+        assert!(
+            !self.in_pass,
+            "Failed to begin OIT pass: another pass is in progress"
+        );
+        self.res.command_buffer.begin_oit_pass(pass, wboit);
+        self.in_pass = true;
+        self.reset_scene_viewport();
+    }
+
+    /// Composites the fragments accumulated into `wboit` over the color of this frame with
+    /// `pipeline`, created by `Pipeline::wboit_resolve`, then ends the pass begun by `begin_oit_pass`
+    pub fn resolve_oit(&mut self, pipeline: &Pipeline, wboit: &Wboit) {
+        let command_buffer = &self.res.command_buffer;
+        command_buffer.next_subpass();
+        command_buffer.bind_pipeline(pipeline);
+
+        // Transient, as the attachments of `wboit` are recreated with the framebuffer
+        let sets = self
             .res
+            .transient
             .descriptors
-            .model_sets
-            .get(&(pipeline.set_layouts[0], node))
+            .allocate(&pipeline.set_layouts);
+        let mut writer = DescriptorWriter::new();
+        wboit.write_set(&mut writer, sets[0]);
+        writer.flush(&self.device);
+
+        let command_buffer = &self.res.command_buffer;
+        command_buffer.bind_descriptor_sets(pipeline, &sets, 0);
+        command_buffer.bind_vertex_buffer(&self.res.fallback.present_buffer, 0);
+        command_buffer.draw(3);
+        self.end_pass();
+    }
+
+    /// Begins `pass`, created by `Pass::new_particles`, to draw soft particles over the color
+    /// of this frame with `draw_particles`. The scene pass should have ended, preserving depth.
+    pub fn begin_particle_pass(&mut self, pass: &Pass, particles: &ParticleFramebuffer) {
+        assert!(
+            self.recording,
+            "Failed to begin particle pass: command buffer is not recording"
+        );
+        assert!(
+            !self.in_pass,
+            "Failed to begin particle pass: another pass is in progress"
+        );
+        self.res.command_buffer.begin_particle_pass(pass, particles);
+        self.in_pass = true;
+        self.reset_scene_viewport();
+    }
+
+    /// Draws `vertices`, triangles in the space of `node`, as seen by `camera_node` with
+    /// `pipeline`, created by `Pipeline::soft_particles`. Fragments fade out where they get
+    /// close to the scene depth of this frame, within the fade distance of `particles`.
+    pub fn draw_particles(
+        &mut self,
+        pipeline: &Pipeline,
+        particles: &SoftParticles,
+        model: &Model,
+        camera_node: Handle<Node>,
+        node: Handle<Node>,
+        vertices: &[Point],
+    ) {
+        let cnode = match model.nodes.try_get(node) {
+            Some(cnode) => cnode,
+            None => return,
+        };
+        let camera = match model
+            .nodes
+            .try_get(camera_node)
+            .and_then(|camera_node| model.cameras.try_get(camera_node.camera))
         {
-            // If there is a descriptor set, there must be a uniform buffer
-            let ubo = self.res.model_buffers.get_mut(&node).unwrap();
-            ubo.upload(&cnode.trs.get_matrix());
+            Some(camera) => camera,
+            None => return,
+        };
 
-            let model_view_buffer = self.res.model_view_buffers.get_mut(&node).unwrap();
-            model_view_buffer.upload(&model_view_matrix);
+        self.res.command_buffer.bind_pipeline(pipeline);
+        self.bind_view(pipeline, model, camera_node, self.depth_config);
+        self.bind_model_sets::<SoftParticles>(pipeline, node, cnode);
+
+        // Transient, as the depth attachment is recreated with the framebuffer
+        let layout = pipeline.get_set_layout(DescriptorFrequency::PerMaterial);
+        let sets = self.res.transient.descriptors.allocate(&[layout]);
+        // The default sampler does not filter, as depth should not be
+        SoftParticles::write_set(
+            &mut self.writer,
+            sets[0],
+            &self.buffer.depth_view,
+            &self.res.fallback.white_sampler,
+        );
+        self.flush_writes();
+        self.res.command_buffer.bind_frequency_sets(
+            pipeline,
+            &sets,
+            DescriptorFrequency::PerMaterial,
+        );
 
-            self.res
-                .command_buffer
-                .bind_descriptor_sets(pipeline, sets, 0);
+        let constants = particles.get_constants(
+            camera.get_znear(),
+            camera.get_zfar(),
+            self.buffer.width,
+            self.buffer.height,
+        );
+        self.res.command_buffer.push_constants_typed(
+            pipeline,
+            vk::ShaderStageFlags::FRAGMENT,
+            0,
+            &constants,
+        );
+
+        let (buffer, offset) = self.res.transient.buffer.push(vertices, 4);
+        self.res.command_buffer.bind_vertex_buffer(buffer, offset);
+        self.res.command_buffer.draw(vertices.len() as u32);
+    }
+
+    /// Ends the pass begun by `begin` or `begin_pass`, so that another one can be recorded
+    pub fn end_pass(&mut self) {
+        assert!(self.in_pass, "Failed to end pass: no pass in progress");
+        self.res.command_buffer.end_render_pass();
+        self.in_pass = false;
+    }
+
+    /// Recreates the shadow map when `config` differs from its current one.
+    /// It should be called when the previous submission of this frame is finished.
+    pub fn set_shadow_config(&mut self, dev: &Dev, config: &ShadowConfig) {
+        let shadow = match &mut self.shadow {
+            Some(shadow) if shadow.framebuffer.config != *config => shadow,
+            _ => return,
+        };
+        shadow.framebuffer = ShadowFramebuffer::new(dev, &shadow.pass, config);
+
+        // Frame sets refer to the previous shadow map
+        let descriptors = &mut self.res.descriptors;
+        let frame_sets = std::mem::take(&mut descriptors.frame_sets);
+        for sets in frame_sets.values() {
+            descriptors.free(sets);
+        }
+    }
+
+    /// Renders the depth of the scene, as seen by the camera of `light_node`, into the shadow map
+    /// of this frame. Main pipelines then darken the fragments it occludes. It should be called
+    /// before `begin`, and it does nothing when the frame has no shadow map or the node no camera.
+    pub fn render_shadow_pass(&mut self, model: &Model, light_node: Handle<Node>) {
+        let has_camera = model
+            .nodes
+            .try_get(light_node)
+            .map_or(false, |node| model.cameras.try_get(node.camera).is_some());
+        if !has_camera {
+            return;
+        }
+        // Taken, so that the frame can be borrowed while drawing with the shadow pipeline
+        let mut shadow = match self.shadow.take() {
+            Some(shadow) => shadow,
+            None => return,
+        };
+
+        self.begin_command_buffer();
+        self.res
+            .command_buffer
+            .begin_shadow_pass(&shadow.pass, &shadow.framebuffer);
+        self.res.command_buffer.bind_pipeline(&shadow.pipeline);
+        let config = shadow.framebuffer.config;
+        self.res.command_buffer.set_viewport(&config.get_viewport());
+        self.res.command_buffer.set_scissor(&config.get_scissor());
+
+        // Shadow maps always use reverse-Z, as `main_fs` expects
+        self.bind_view(
+            &shadow.pipeline,
+            model,
+            light_node,
+            DepthConfig::reverse_z(),
+        );
+        let light_space = self.current_proj * self.current_view;
+
+        // Like `draw`, the subtrees of invisible nodes are skipped
+        for root in model.get_roots() {
+            for node in model.get_visible_nodes(root) {
+                let cnode = model.nodes.get(node).unwrap();
+                let mesh = match model.meshes.try_get(cnode.mesh) {
+                    Some(mesh) => mesh,
+                    None => continue,
+                };
+                self.bind_model_sets::<Vertex>(&shadow.pipeline, node, cnode);
+                for hprimitive in &mesh.primitives {
+                    match model.primitives.try_get(*hprimitive) {
+                        Some(primitive) if primitive.casts_shadow() => {
+                            self.draw_primitive::<Vertex>(primitive)
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        }
+
+        self.res.command_buffer.end_render_pass();
+        self.res
+            .shadow_buffer
+            .upload(&ShadowUniform::new(light_space));
+
+        shadow.rendered = true;
+        self.shadow = Some(shadow);
+    }
+
+    pub fn bind(&mut self, pipeline: &Pipeline, model: &Model, camera_node: Handle<Node>) {
+        assert!(pipeline.is_compatible_with_framebuffer(&self.buffer));
+        self.res.command_buffer.bind_pipeline(pipeline);
+
+        self.res
+            .command_buffer
+            .set_viewport(&self.get_scene_viewport());
+
+        let scissor = self.scissors.get_current();
+        self.res.command_buffer.set_scissor(&scissor);
+
+        self.bind_view(pipeline, model, camera_node, self.depth_config);
+    }
+
+    /// Binds the view and projection of the camera of `camera_node`, which become the current ones.
+    /// The projection follows the `depth` convention, whatever the one of the camera.
+    fn bind_view(
+        &mut self,
+        pipeline: &Pipeline,
+        model: &Model,
+        camera_node: Handle<Node>,
+        depth: DepthConfig,
+    ) {
+        let node = match model.nodes.try_get(camera_node) {
+            Some(node) => node,
+            None => return,
+        };
+        self.current_view = node.trs.get_view_matrix();
+        let camera = match model.cameras.try_get(node.camera) {
+            Some(camera) => camera,
+            None => return,
+        };
+        self.current_proj = camera.get_proj_for(depth);
+
+        self.prepare_view_sets(pipeline, camera_node, node.camera);
+        self.flush_writes();
+
+        // If there is a descriptor set, there must be a buffer
+        let view_buffer = self.res.view_buffers.get_mut(camera_node).unwrap();
+        let proj_buffer = self.res.proj_buffers.get_mut(node.camera).unwrap();
+        Self::upload_view_proj(
+            view_buffer,
+            proj_buffer,
+            &self.current_view,
+            &self.current_proj,
+            self.multiview.as_ref(),
+        );
+
+        let layout = pipeline.get_set_layout(DescriptorFrequency::PerView);
+        self.res.command_buffer.bind_frequency_sets(
+            pipeline,
+            &self.res.descriptors.view_sets[&(layout, camera_node)],
+            DescriptorFrequency::PerView,
+        );
+    }
+
+    /// Creates the view and proj buffers of a camera, then allocates their descriptor set
+    /// and queues its writes, unless it already exists
+    fn prepare_view_sets(
+        &mut self,
+        pipeline: &Pipeline,
+        camera_node: Handle<Node>,
+        camera: Handle<Camera>,
+    ) {
+        let layout = pipeline.get_set_layout(DescriptorFrequency::PerView);
+        if self
+            .res
+            .descriptors
+            .view_sets
+            .contains_key(&(layout, camera_node))
+        {
+            return;
+        }
+
+        let allocator = &self.allocator;
+        let multiview = self.multiview.as_ref();
+        let create_buffer = || match multiview {
+            Some(_) => Buffer::new::<[na::Matrix4<f32>; MAX_VIEWS]>(
+                allocator,
+                vk::BufferUsageFlags::UNIFORM_BUFFER,
+            ),
+            None => {
+                Buffer::new::<na::Matrix4<f32>>(allocator, vk::BufferUsageFlags::UNIFORM_BUFFER)
+            }
+        };
+
+        // Create a new buffer for this node's view matrix, unless it is already there,
+        // and the same for this camera proj matrix
+        let view_buffer = self
+            .res
+            .view_buffers
+            .get_or_insert_with(camera_node, create_buffer);
+        let proj_buffer = self
+            .res
+            .proj_buffers
+            .get_or_insert_with(camera, create_buffer);
+
+        let sets = self.res.descriptors.allocate(&[layout]);
+        if multiview.is_some() {
+            MultiviewMatrices::write_set(&mut self.writer, sets[0], view_buffer, proj_buffer);
         } else {
-            // Check the model buffer already exists
-            let model_buffer = match self.res.model_buffers.get_mut(&node) {
-                Some(b) => b,
-                None => {
-                    // Create a new uniform buffer for this node's model matrix
-                    let buffer = Buffer::new::<na::Matrix4<f32>>(
-                        &self.allocator,
-                        vk::BufferUsageFlags::UNIFORM_BUFFER,
-                    );
-                    self.res.model_buffers.insert(node, buffer);
-                    self.res.model_buffers.get_mut(&node).unwrap()
+            Camera::write_set_view(&mut self.writer, sets[0], view_buffer);
+            Camera::write_set_proj(&mut self.writer, sets[0], proj_buffer);
+        }
+        self.res
+            .descriptors
+            .view_sets
+            .insert((layout, camera_node), sets);
+    }
+
+    /// Updates the descriptor sets prepared so far with a single call
+    fn flush_writes(&mut self) {
+        self.writer.flush(&self.device);
+    }
+
+    /// Uploads the view and projection of a camera, or the matrices of each of the views
+    /// of `multiview` derived from them
+    fn upload_view_proj(
+        view_buffer: &mut Buffer,
+        proj_buffer: &mut Buffer,
+        view: &na::Matrix4<f32>,
+        proj: &na::Matrix4<f32>,
+        multiview: Option<&MultiviewConfig>,
+    ) {
+        match multiview {
+            Some(multiview) => {
+                let matrices = multiview.get_matrices(view, proj);
+                view_buffer.upload(&matrices.views);
+                proj_buffer.upload(&matrices.projs);
+            }
+            None => {
+                view_buffer.upload(view);
+                proj_buffer.upload(proj);
+            }
+        }
+    }
+
+    /// Binds the shadow map and its uniform, or a white texture when the frame has no shadow map
+    fn bind_frame_sets(&mut self, pipeline: &Pipeline) {
+        self.prepare_frame_sets(pipeline);
+        self.flush_writes();
+
+        let layout = pipeline.get_set_layout(DescriptorFrequency::PerFrame);
+        let sets = &self.res.descriptors.frame_sets[&layout];
+        self.res
+            .command_buffer
+            .bind_frequency_sets(pipeline, sets, DescriptorFrequency::PerFrame);
+    }
+
+    /// Allocates the set of the shadow map and queues its writes, unless it already exists
+    fn prepare_frame_sets(&mut self, pipeline: &Pipeline) {
+        let layout = pipeline.get_set_layout(DescriptorFrequency::PerFrame);
+        if self.res.descriptors.frame_sets.contains_key(&layout) {
+            return;
+        }
+
+        let sets = self.res.descriptors.allocate(&[layout]);
+        self.writer
+            .write_uniform::<ShadowUniform>(sets[0], 0, &self.res.shadow_buffer);
+        match &self.shadow {
+            Some(shadow) => {
+                self.writer
+                    .write_depth_image(sets[0], 1, shadow.get_depth_view(), &shadow.sampler)
+            }
+            None => self.writer.write_sampled_image(
+                sets[0],
+                1,
+                &self.res.fallback.white_view,
+                &self.res.fallback.white_sampler,
+            ),
+        };
+        self.res.descriptors.frame_sets.insert(layout, sets);
+    }
+
+    /// Uploads the matrices of `node` and binds their descriptor set
+    fn bind_model_sets<T: VertexInput>(
+        &mut self,
+        pipeline: &Pipeline,
+        node: Handle<Node>,
+        cnode: &Node,
+    ) {
+        // Nodes scaled to zero, e.g. when animated in or out, have no inverse
+        let model_view = self.current_view * cnode.trs.get_matrix();
+        let normal_matrix = NormalMatrix::try_new(&model_view).unwrap_or_else(|| {
+            if !self.singular_logged {
+                eprintln!("Singular model-view matrix of node {}", node.id);
+                self.singular_logged = true;
+            }
+            NormalMatrix::identity()
+        });
+
+        self.prepare_model_sets::<T>(pipeline, node);
+        self.flush_writes();
+
+        // If there is a descriptor set, there must be room in the arena
+        let offsets = *self.res.node_uniforms.get(node).unwrap();
+        let uniforms = &mut self.res.uniforms;
+        uniforms.write(offsets.model, &cnode.trs.get_matrix());
+        uniforms.write(offsets.model_view, &normal_matrix);
+        uniforms.write(offsets.morph_weights, &cnode.morph_weights);
+
+        let layout = pipeline.get_set_layout(DescriptorFrequency::PerObject);
+        self.res.command_buffer.bind_frequency_sets(
+            pipeline,
+            &self.res.descriptors.model_sets[&(layout, node)],
+            DescriptorFrequency::PerObject,
+        );
+    }
+
+    /// Allocates the uniforms of `node` in the arena, then allocates their descriptor set
+    /// and queues its writes, unless it already exists
+    fn prepare_model_sets<T: VertexInput>(&mut self, pipeline: &Pipeline, node: Handle<Node>) {
+        let layout = pipeline.get_set_layout(DescriptorFrequency::PerObject);
+        if self
+            .res
+            .descriptors
+            .model_sets
+            .contains_key(&(layout, node))
+        {
+            return;
+        }
+
+        // Allocate the uniforms of this node, unless another layout already did
+        let uniforms = &mut self.res.uniforms;
+        let offsets = *self
+            .res
+            .node_uniforms
+            .get_or_insert_with(node, || NodeUniforms::new(uniforms));
+
+        // Allocate and write descriptors
+        let sets = self.res.descriptors.allocate(&[layout]);
+        let buffer = self.res.uniforms.get_memory();
+        T::write_set_model(&mut self.writer, sets[0], buffer, offsets.model);
+        T::write_set_model_view(&mut self.writer, sets[0], buffer, offsets.model_view);
+        T::write_set_morph_weights(&mut self.writer, sets[0], buffer, offsets.morph_weights);
+        self.res.descriptors.model_sets.insert((layout, node), sets);
+    }
+
+    pub fn draw<T: VertexInput>(
+        &mut self,
+        pipelines: &DefaultPipelines,
+        model: &Model,
+        node: Handle<Node>,
+    ) {
+        // Skip nodes removed while still referenced, for example by a script
+        let cnode = match model.nodes.try_get(node) {
+            Some(cnode) => cnode,
+            None => return,
+        };
+        if !cnode.visible {
+            return;
+        }
+
+        let pipeline = pipelines.get_for::<T>();
+        self.res.command_buffer.bind_pipeline(pipeline);
+
+        for child in &cnode.children {
+            self.draw::<T>(pipelines, model, *child);
+        }
+
+        if let Some(light) = model.lights.try_get(cnode.light) {
+            let model_view = self.current_view * cnode.trs.get_matrix();
+            let view_position = na::Vector3::new(model_view.m14, model_view.m24, model_view.m34);
+            self.lights.push((view_position, *light));
+        }
+
+        let mesh = match model.meshes.try_get(cnode.mesh) {
+            Some(mesh) => mesh,
+            None => return,
+        };
+
+        self.bind_model_sets::<T>(pipeline, node, cnode);
+
+        // Does this pipeline sample the shadow map?
+        if pipeline.has_set_layout(DescriptorFrequency::PerFrame) {
+            self.bind_frame_sets(pipeline);
+        }
+
+        for hprimitive in &mesh.primitives {
+            let primitive = match model.primitives.try_get(*hprimitive) {
+                Some(primitive) => primitive,
+                None => continue,
+            };
+
+            self.bind_material_sets(pipeline, model, primitive);
+
+            // Strips are drawn by a pipeline with primitive restart,
+            // while blended materials are drawn by a pipeline not writing depth
+            let blend_mode = model
+                .materials
+                .get(primitive.material)
+                .map(|material| material.blend_mode)
+                .unwrap_or_default();
+            let primitive_pipeline = pipelines.get_for_primitive::<T>(primitive, blend_mode);
+            let rebind = primitive_pipeline.graphics != pipeline.graphics;
+            if rebind {
+                self.res.command_buffer.bind_pipeline(primitive_pipeline);
+            }
+
+            self.draw_primitive::<T>(primitive);
+
+            if rebind {
+                self.res.command_buffer.bind_pipeline(pipeline);
+            }
+        }
+    }
+
+    /// Draws all the visible primitives of `model` as seen by `camera_node`, each one with the
+    /// pipeline of its vertex type, topology, and material. Lights of visible nodes are collected
+    /// for the lighting subpass. The `debug` pipeline of `pipelines` only affects presentation.
+    /// Primitives whose pipeline is not supported by the device, such as packed ones, are skipped.
+    /// Use `bind` and `draw` instead for custom draw loops.
+    pub fn draw_model(
+        &mut self,
+        pipelines: &DefaultPipelines,
+        model: &Model,
+        camera_node: Handle<Node>,
+    ) {
+        self.gather_lights(model, camera_node);
+
+        let draws = model.get_draws();
+        self.prepare_model(pipelines, model, camera_node, &draws);
+        self.flush_writes();
+
+        let mut bound: Option<(Pipelines, Option<Handle<Node>>)> = None;
+        for draw in draws {
+            let (input, pipeline) = match Self::get_draw_pipeline(pipelines, &draw) {
+                Some(draw_pipeline) => draw_pipeline,
+                None => continue,
+            };
+            let bound_node = match bound {
+                Some((variant, node)) if variant == draw.pipeline => node,
+                _ => {
+                    self.bind(pipeline, model, camera_node);
+                    None
                 }
             };
-            model_buffer.upload(&cnode.trs.get_matrix());
 
-            // Check whether the view-model buffer already exists
-            let model_view_buffer = match self.res.model_view_buffers.get_mut(&node) {
-                Some(b) => b,
-                None => {
-                    // Create a new uniform buffer for this node's model view matrix
-                    let buffer = Buffer::new::<na::Matrix4<f32>>(
-                        &self.allocator,
-                        vk::BufferUsageFlags::UNIFORM_BUFFER,
-                    );
-                    self.res.model_view_buffers.insert(node, buffer);
-                    self.res.model_view_buffers.get_mut(&node).unwrap()
+            // Nodes sets are bound once for all their primitives drawn in a row
+            let bind_node = bound_node != Some(draw.node);
+            match input {
+                Pipelines::LINE => {
+                    self.draw_model_primitive::<Line>(pipeline, model, &draw, bind_node)
+                }
+                Pipelines::PACKED => {
+                    self.draw_model_primitive::<PackedVertex>(pipeline, model, &draw, bind_node)
+                }
+                _ => self.draw_model_primitive::<Vertex>(pipeline, model, &draw, bind_node),
+            }
+            bound = Some((draw.pipeline, Some(draw.node)));
+        }
+    }
+
+    /// Collects the lights of the visible nodes of `model` with their positions in the space
+    /// of the camera of `camera_node`, whose view becomes the current one
+    fn gather_lights(&mut self, model: &Model, camera_node: Handle<Node>) {
+        if let Some(node) = model.nodes.try_get(camera_node) {
+            self.current_view = node.trs.get_view_matrix();
+        }
+        for root in model.get_roots() {
+            for node in model.get_visible_nodes(root) {
+                let cnode = model.nodes.get(node).unwrap();
+                if let Some(light) = model.lights.try_get(cnode.light) {
+                    let model_view = self.current_view * cnode.trs.get_matrix();
+                    let view_position =
+                        na::Vector3::new(model_view.m14, model_view.m24, model_view.m34);
+                    self.lights.push((view_position, *light));
                 }
+            }
+        }
+    }
+
+    /// Returns the pipeline of the vertex type of the primitives of `draw`, with the pipeline
+    /// to draw them with. It is `None` when `draw` is not drawn by a scene pipeline, which is
+    /// the only kind drawing model primitives, or when the device does not support its pipeline.
+    fn get_draw_pipeline<'p>(
+        pipelines: &'p DefaultPipelines,
+        draw: &ModelDraw,
+    ) -> Option<(Pipelines, &'p Pipeline)> {
+        let input = match draw.pipeline {
+            Pipelines::LINE => Pipelines::LINE,
+            Pipelines::MAIN | Pipelines::TERRAIN | Pipelines::BLEND => Pipelines::MAIN,
+            Pipelines::PACKED => Pipelines::PACKED,
+            _ => return None,
+        };
+        pipelines
+            .try_get(draw.pipeline)
+            .map(|pipeline| (input, pipeline))
+    }
+
+    /// Prepares the descriptor sets of all the `draws` of `model`, so that their writes
+    /// can be flushed at once and drawing them only binds sets which already exist
+    fn prepare_model(
+        &mut self,
+        pipelines: &DefaultPipelines,
+        model: &Model,
+        camera_node: Handle<Node>,
+        draws: &[ModelDraw],
+    ) {
+        let camera = model
+            .nodes
+            .try_get(camera_node)
+            .map(|node| node.camera)
+            .filter(|camera| model.cameras.try_get(*camera).is_some());
+
+        for draw in draws {
+            let (input, pipeline) = match Self::get_draw_pipeline(pipelines, draw) {
+                Some(draw_pipeline) => draw_pipeline,
+                None => continue,
             };
+            if let Some(camera) = camera {
+                self.prepare_view_sets(pipeline, camera_node, camera);
+            }
+            match input {
+                Pipelines::LINE => self.prepare_model_sets::<Line>(pipeline, draw.node),
+                Pipelines::PACKED => self.prepare_model_sets::<PackedVertex>(pipeline, draw.node),
+                _ => self.prepare_model_sets::<Vertex>(pipeline, draw.node),
+            }
+            if pipeline.has_set_layout(DescriptorFrequency::PerFrame) {
+                self.prepare_frame_sets(pipeline);
+            }
+            if pipeline.has_set_layout(DescriptorFrequency::PerMaterial) {
+                let primitive = model.primitives.get(draw.primitive).unwrap();
+                self.prepare_material_sets(pipeline, model, primitive);
+            }
+        }
+    }
 
-            // Allocate and write descriptors
-            let sets = self.res.descriptors.allocate(&[pipeline.set_layouts[0]]);
-            T::write_set_model(self.device.borrow(), sets[0], &model_buffer);
-            T::write_set_model_view(self.device.borrow(), sets[0], &model_view_buffer);
+    fn draw_model_primitive<T: VertexInput>(
+        &mut self,
+        pipeline: &Pipeline,
+        model: &Model,
+        draw: &ModelDraw,
+        bind_node: bool,
+    ) {
+        if bind_node {
+            let cnode = model.nodes.get(draw.node).unwrap();
+            self.bind_model_sets::<T>(pipeline, draw.node, cnode);
+            if pipeline.has_set_layout(DescriptorFrequency::PerFrame) {
+                self.bind_frame_sets(pipeline);
+            }
+        }
 
-            self.res
-                .command_buffer
-                .bind_descriptor_sets(pipeline, &sets, 0);
+        let primitive = model.primitives.get(draw.primitive).unwrap();
+        self.bind_material_sets(pipeline, model, primitive);
+        self.draw_primitive::<T>(primitive);
+    }
 
-            self.res
-                .descriptors
-                .model_sets
-                .insert((pipeline.set_layouts[0], node), sets);
+    /// Uploads the material of `primitive` and binds its descriptor set,
+    /// unless the pipeline does not support materials at all
+    fn bind_material_sets(&mut self, pipeline: &Pipeline, model: &Model, primitive: &Primitive) {
+        if !pipeline.has_set_layout(DescriptorFrequency::PerMaterial) {
+            return;
         }
 
-        for hprimitive in &mesh.primitives {
-            let primitive = model.primitives.get(*hprimitive).unwrap();
-
-            // Does this pipeline support materials at all?
-            if pipeline.set_layouts.len() > 2 {
-                // How about grouping by material?
-                let material = match model.materials.get(primitive.material) {
-                    Some(m) => m,
-                    None => &self.res.fallback.white_material,
+        self.prepare_material_sets(pipeline, model, primitive);
+        self.flush_writes();
+
+        // How about grouping by material?
+        let material = match model.materials.get(primitive.material) {
+            Some(m) => m,
+            None => &self.res.fallback.white_material,
+        };
+        let (albedo_view, _) = Self::get_albedo(&self.res.fallback, model, material);
+
+        // If there is a descriptor set, there must be a uniform buffer
+        let ubo = self
+            .res
+            .material_buffers
+            .get_mut(primitive.material)
+            .unwrap();
+        ubo.upload(&material.get_uniform_for(&albedo_view.range));
+
+        let layout = pipeline.get_set_layout(DescriptorFrequency::PerMaterial);
+        self.res.command_buffer.bind_frequency_sets(
+            pipeline,
+            &self.res.descriptors.material_sets[&(layout, primitive.material)],
+            DescriptorFrequency::PerMaterial,
+        );
+    }
+
+    /// Returns the albedo texture of `material`, or a default white one when it has none
+    fn get_albedo<'a>(
+        fallback: &'a Fallback,
+        model: &'a Model,
+        material: &Material,
+    ) -> (&'a ImageView, &'a Sampler) {
+        match model.textures.get(material.albedo) {
+            Some(texture) => {
+                let view = model.views.get(texture.view).unwrap();
+                let sampler = match model.samplers.try_get(material.sampler) {
+                    Some(sampler) => sampler,
+                    None => model.samplers.get(texture.sampler).unwrap(),
                 };
+                (view, sampler)
+            }
+            _ => (&fallback.white_array_view, &fallback.white_sampler),
+        }
+    }
 
-                if let Some(sets) = self
-                    .res
-                    .descriptors
-                    .material_sets
-                    .get(&(pipeline.set_layouts[2], primitive.material))
-                {
-                    // If there is a descriptor set, there must be a uniform buffer
-                    let ubo = self
-                        .res
-                        .material_buffers
-                        .get_mut(&primitive.material)
-                        .unwrap();
-                    ubo.upload(material);
-
-                    // @todo Use a constant or something that is not a magic number (2)
-                    self.res
-                        .command_buffer
-                        .bind_descriptor_sets(pipeline, sets, 2);
-                } else {
-                    // Check if material uniform buffer already exists
-                    let material_buffer =
-                        match self.res.material_buffers.get_mut(&primitive.material) {
-                            Some(buffer) => buffer,
-                            None => {
-                                // Create a new uniform buffer for this material
-                                let material_buffer = Buffer::new::<Color>(
-                                    &self.allocator,
-                                    vk::BufferUsageFlags::UNIFORM_BUFFER,
-                                );
-
-                                self.res
-                                    .material_buffers
-                                    .insert(primitive.material, material_buffer);
-
-                                self.res
-                                    .material_buffers
-                                    .get_mut(&primitive.material)
-                                    .unwrap()
-                            }
-                        };
-
-                    material_buffer.upload(material);
-
-                    let (albedo_view, albedo_sampler) = match model.textures.get(material.albedo) {
-                        Some(texture) => {
-                            let view = model.views.get(texture.view).unwrap();
-                            let sampler = model.samplers.get(texture.sampler).unwrap();
-                            (view, sampler)
-                        }
-                        _ => (
-                            // Bind a default white albedo
-                            &self.res.fallback.white_view,
-                            &self.res.fallback.white_sampler,
-                        ),
-                    };
-
-                    let sets = self.res.descriptors.allocate(&[pipeline.set_layouts[2]]); // 1 is for material
-                    Material::write_set(
-                        &self.device,
-                        sets[0],
-                        &material_buffer,
-                        albedo_view,
-                        albedo_sampler,
-                    );
+    /// Creates the uniform buffer of the material of `primitive`, then allocates its descriptor
+    /// set and queues its writes, unless it already exists
+    fn prepare_material_sets(&mut self, pipeline: &Pipeline, model: &Model, primitive: &Primitive) {
+        let layout = pipeline.get_set_layout(DescriptorFrequency::PerMaterial);
+        if self
+            .res
+            .descriptors
+            .material_sets
+            .contains_key(&(layout, primitive.material))
+        {
+            return;
+        }
 
+        let material = match model.materials.get(primitive.material) {
+            Some(m) => m,
+            None => &self.res.fallback.white_material,
+        };
+        let (albedo_view, albedo_sampler) = Self::get_albedo(&self.res.fallback, model, material);
+
+        // Create a new uniform buffer for this material, unless it already exists
+        let allocator = &self.allocator;
+        let material_buffer = self
+            .res
+            .material_buffers
+            .get_or_insert_with(primitive.material, || {
+                Buffer::new::<MaterialUniform>(allocator, vk::BufferUsageFlags::UNIFORM_BUFFER)
+            });
+
+        let sets = self.res.descriptors.allocate(&[layout]);
+        Material::write_set(
+            &mut self.writer,
+            sets[0],
+            &material_buffer,
+            albedo_view,
+            albedo_sampler,
+        );
+        self.res
+            .descriptors
+            .material_sets
+            .insert((layout, primitive.material), sets);
+    }
+
+    /// Binds the vertex and index buffers of `primitive` and draws it
+    fn draw_primitive<T: VertexInput>(&mut self, primitive: &Primitive) {
+        self.res
+            .command_buffer
+            .bind_vertex_buffer(&primitive.vertices, 0);
+
+        // Does this pipeline expect morph target deltas?
+        if T::get_bindings().len() > 1 {
+            let morph_deltas = match &primitive.morph_deltas {
+                Some(deltas) => deltas,
+                None => {
+                    // Primitives without morph targets use zero deltas
+                    let size = std::mem::size_of::<MorphDeltas>() * primitive.vertex_count as usize;
                     self.res
-                        .command_buffer
-                        .bind_descriptor_sets(pipeline, &sets, 2);
+                        .zero_buffer
+                        .get(&self.allocator, size as vk::DeviceSize)
+                }
+            };
+            self.res
+                .command_buffer
+                .bind_vertex_buffers(1, &[(morph_deltas, 0)]);
+        }
 
+        // And a second texture coordinate set?
+        if T::get_bindings().len() > Vertex::UV1_BINDING as usize {
+            let uv1 = match &primitive.uv1 {
+                Some(uv1) => uv1,
+                None => {
+                    let size =
+                        std::mem::size_of::<na::Vector2<f32>>() * primitive.vertex_count as usize;
                     self.res
-                        .descriptors
-                        .material_sets
-                        .insert((pipeline.set_layouts[2], primitive.material), sets);
+                        .zero_buffer
+                        .get(&self.allocator, size as vk::DeviceSize)
                 }
-            }
+            };
+            self.res
+                .command_buffer
+                .bind_vertex_buffers(Vertex::UV1_BINDING, &[(uv1, 0)]);
+        }
 
+        if let Some(indices) = &primitive.indices {
+            // Draw indexed if primitive has indices
             self.res
                 .command_buffer
-                .bind_vertex_buffer(&primitive.vertices);
+                .bind_index_buffer(indices, 0, primitive.index_type);
+            self.res
+                .command_buffer
+                .draw_indexed(primitive.index_count, 0, 0);
+        } else {
+            // Draw without indices
+            self.res.command_buffer.draw(primitive.vertex_count);
+        }
+    }
 
-            if let Some(indices) = &primitive.indices {
-                // Draw indexed if primitive has indices
-                self.res.command_buffer.bind_index_buffer(indices);
+    /// Ends the current pass and the command buffer, see `end_pass` and `end_command_buffer`
+    pub fn end(&mut self) {
+        self.end_pass();
+        self.end_command_buffer();
+    }
 
-                let index_count = indices.size as u32 / std::mem::size_of::<u16>() as u32;
-                self.res.command_buffer.draw_indexed(index_count, 0, 0);
-            } else {
-                // Draw without indices
-                self.res.command_buffer.draw(primitive.vertex_count);
-            }
+    /// Ends recording the added command buffers not submitted yet
+    fn end_added(&self) {
+        let res = &self.res;
+        for command_buffer in &res.added_command_buffers[res.submitted_count..res.added_count] {
+            command_buffer.end();
         }
     }
 
-    pub fn end(&self) {
-        self.res.command_buffer.end_render_pass();
+    /// Ends and submits the command buffers added so far, without waiting for the main one
+    /// to be recorded. The ones added later, and the main one, are chained after them.
+    pub fn submit_added(&mut self, dev: &Dev) {
+        assert!(
+            self.recording,
+            "Failed to submit added command buffers: the frame is not recording"
+        );
+        self.end_added();
+        self.res.submit_added(&dev.graphics_queue);
+    }
+
+    /// Ends recording the command buffer, which should have no pass in progress.
+    /// A swapchain image is released to the present queue when its family differs.
+    pub fn end_command_buffer(&mut self) {
+        assert!(
+            !self.in_pass,
+            "Failed to end command buffer: a pass is in progress"
+        );
+        self.recording = false;
+        self.res.end_uploads();
+        self.end_added();
+        if let Some(acquire) = &self.res.present_acquire {
+            let barrier = acquire
+                .transfer
+                .get_release_barrier(self.buffer.swapchain_image);
+            self.res.command_buffer.pipeline_barriers(
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                vk::DependencyFlags::default(),
+                &[barrier],
+            );
+        }
         self.res.command_buffer.end()
     }
 
@@ -604,16 +2028,49 @@ impl Frame {
         dev: &Dev,
         swapchain: &Swapchain,
         image_index: u32,
+        present_id: Option<u64>,
     ) -> Result<(), vk::Result> {
-        dev.graphics_queue.submit_draw(
-            &self.res.command_buffer,
-            self.res.image_ready.semaphore,
-            self.res.image_drawn.semaphore,
-            Some(&mut self.res.fence),
-        );
+        let acquire = match &self.res.present_acquire {
+            Some(acquire) => acquire,
+            None => {
+                let wait = Some(self.res.image_ready.semaphore);
+                let signal = Some(self.res.image_drawn.semaphore);
+                self.res.submit(&dev.graphics_queue, wait, signal, true);
+
+                return dev.graphics_queue.present(
+                    image_index,
+                    swapchain,
+                    self.res.image_drawn.semaphore,
+                    present_id,
+                );
+            }
+        };
 
-        dev.graphics_queue
-            .present(image_index, swapchain, self.res.image_drawn.semaphore)
+        // The fence is signaled by the acquire submission, which waits for the draw one
+        let wait = Some(self.res.image_ready.semaphore);
+        let signal = Some(self.res.image_drawn.semaphore);
+        self.res.submit(&dev.graphics_queue, wait, signal, false);
+
+        acquire.record(self.buffer.swapchain_image);
+        let waits = [self.res.image_drawn.semaphore];
+        let wait_dst_stage_mask = [vk::PipelineStageFlags::ALL_COMMANDS];
+        let command_buffers = [acquire.command_buffer.command_buffer];
+        let signals = [acquire.image_acquired.semaphore];
+        let submits = [vk::SubmitInfo::builder()
+            .wait_semaphores(&waits)
+            .wait_dst_stage_mask(&wait_dst_stage_mask)
+            .command_buffers(&command_buffers)
+            .signal_semaphores(&signals)
+            .build()];
+        dev.present_queue
+            .submit(&submits, Some(&mut self.res.fence));
+
+        dev.present_queue.present(
+            image_index,
+            swapchain,
+            acquire.image_acquired.semaphore,
+            present_id,
+        )
     }
 }
 
@@ -628,9 +2085,14 @@ impl Drop for Frame {
 }
 
 pub trait Frames {
-    fn next_frame(&mut self, win: &Win, surface: &Surface, dev: &Dev, pass: &Pass)
-        -> Option<Frame>;
-    fn present(&mut self, frame: Frame, win: &Win, surface: &Surface, dev: &Dev, pass: &Pass);
+    fn next_frame(
+        &mut self,
+        win: &Win,
+        surface: &Surface,
+        dev: &mut Dev,
+        pass: &Pass,
+    ) -> Option<Frame>;
+    fn present(&mut self, frame: Frame, win: &Win, surface: &Surface, dev: &mut Dev, pass: &Pass);
 }
 
 /// Offscreen frames work on user allocated images
@@ -644,17 +2106,82 @@ impl Frames for OffscreenFrames {
         &mut self,
         _win: &Win,
         _surface: &Surface,
-        _dev: &Dev,
+        _dev: &mut Dev,
         _pass: &Pass,
     ) -> Option<Frame> {
         unimplemented!("Offscreen next frame");
     }
 
-    fn present(&mut self, _frame: Frame, _win: &Win, _surface: &Surface, _dev: &Dev, _pass: &Pass) {
+    fn present(
+        &mut self,
+        _frame: Frame,
+        _win: &Win,
+        _surface: &Surface,
+        _dev: &mut Dev,
+        _pass: &Pass,
+    ) {
         unimplemented!("Offscreen present");
     }
 }
 
+/// Bookkeeping of the ids tagging present requests, so that a present can be waited for.
+/// Ids start from one and always increase, zero meaning no present.
+#[derive(Debug, Default)]
+pub struct PresentIds {
+    last: u64,
+    /// Ids up to this one belong to swapchains which have been recreated
+    retired: u64,
+    /// Id of the last present of each frame
+    frames: Vec<u64>,
+}
+
+impl PresentIds {
+    pub fn new(frame_count: usize) -> Self {
+        Self {
+            last: 0,
+            retired: 0,
+            frames: vec![0; frame_count],
+        }
+    }
+
+    /// Returns a new id for a present of `frame`
+    pub fn next(&mut self, frame: usize) -> u64 {
+        self.last += 1;
+        self.frames[frame] = self.last;
+        self.last
+    }
+
+    /// Returns the id of the last present requested, or zero if none
+    pub fn get_last(&self) -> u64 {
+        self.last
+    }
+
+    /// Returns the id of the last present of `frame`, or zero if none
+    pub fn get_frame_id(&self, frame: usize) -> u64 {
+        self.frames[frame]
+    }
+
+    /// Returns the frame whose last present has `id`, or `None` if the frame
+    /// has been presented again since then
+    pub fn get_frame(&self, id: u64) -> Option<usize> {
+        if id == 0 || self.is_retired(id) {
+            return None;
+        }
+        self.frames.iter().position(|&frame_id| frame_id == id)
+    }
+
+    pub fn is_retired(&self, id: u64) -> bool {
+        id <= self.retired
+    }
+
+    /// Retires all the ids given so far, to be called when the swapchain is recreated
+    /// with `frame_count` images
+    pub fn retire(&mut self, frame_count: usize) {
+        self.retired = self.last;
+        self.frames = vec![0; frame_count];
+    }
+}
+
 /// Swapchain frames work on swapchain images
 pub struct SwapchainFrames {
     /// This is the frame index. It is updated in a round-robin fashon.
@@ -670,9 +2197,26 @@ pub struct SwapchainFrames {
     /// When the frame is returned for presenting, we put it back in its original position.
     pub frames: Vec<Option<Frame>>,
     pub swapchain: Swapchain,
+
+    /// Each frame has its own shadow map, so that frames in flight do not overwrite each other's
+    shadow_config: ShadowConfig,
+
+    /// Ids of the presents requested so far
+    pub present_ids: PresentIds,
+    /// Only available when the device supports VK_KHR_present_wait
+    present_wait: Option<PresentWait>,
+
+    /// Nanoseconds to wait for a swapchain image to become available
+    acquire_timeout: u64,
+
+    /// Preferred present mode, the swapchain falls back to FIFO when it is not supported
+    present_mode: vk::PresentModeKHR,
 }
 
 impl SwapchainFrames {
+    /// Blocks until an image is available
+    pub const DEFAULT_ACQUIRE_TIMEOUT: u64 = u64::MAX;
+
     pub fn new(
         ctx: &Ctx,
         surface: &Surface,
@@ -680,33 +2224,135 @@ impl SwapchainFrames {
         width: u32,
         height: u32,
         pass: &Pass,
+        shadow: &ShadowConfig,
+        present_mode: vk::PresentModeKHR,
     ) -> Self {
-        let swapchain = Swapchain::new(ctx, surface, dev, width, height);
+        let swapchain = Swapchain::new(ctx, surface, dev, width, height, present_mode);
 
         let mut frames = Vec::new();
-        for image in swapchain.images.iter() {
-            let frame = Frame::new(dev, image, pass);
-            frames.push(Some(frame));
-        }
+        Self::recreate_frames(&mut frames, &swapchain.images, dev, pass, shadow);
 
+        let present_ids = PresentIds::new(frames.len());
         Self {
             current: 0,
             image_index: 0,
             frames,
             swapchain,
+            shadow_config: *shadow,
+            present_ids,
+            present_wait: dev.present_wait.clone(),
+            acquire_timeout: Self::DEFAULT_ACQUIRE_TIMEOUT,
+            present_mode,
         }
     }
 
-    pub fn recreate(&mut self, win: &Win, surface: &Surface, dev: &Dev, pass: &Pass) {
+    /// Returns the preferred present mode, which may differ from the one of the swapchain
+    pub fn get_present_mode(&self) -> vk::PresentModeKHR {
+        self.present_mode
+    }
+
+    /// The swapchain uses `present_mode` from the next time it is recreated
+    pub fn set_present_mode(&mut self, present_mode: vk::PresentModeKHR) {
+        self.present_mode = present_mode;
+    }
+
+    pub fn get_acquire_timeout(&self) -> u64 {
+        self.acquire_timeout
+    }
+
+    /// Sets how many nanoseconds `next_frame` waits for a swapchain image to become available
+    /// before giving up and returning no frame. Zero does not wait at all.
+    pub fn set_acquire_timeout(&mut self, timeout: u64) {
+        self.acquire_timeout = timeout;
+    }
+
+    pub fn get_shadow_config(&self) -> &ShadowConfig {
+        &self.shadow_config
+    }
+
+    /// Shadow maps are recreated with `config` when their frames are next acquired,
+    /// once their previous submissions are finished
+    pub fn set_shadow_config(&mut self, config: &ShadowConfig) {
+        self.shadow_config = *config;
+    }
+
+    /// Recreates the swapchain and everything depending on it. This is the only place where it
+    /// happens, either because the window has been resized or the swapchain is out of date.
+    pub fn recreate(&mut self, win: &Win, surface: &Surface, dev: &mut Dev, pass: &Pass) {
         dev.wait();
         self.current = 0;
+        self.image_index = 0;
+
         let (width, height) = win.window.drawable_size();
-        self.swapchain.recreate(&surface, &dev, width, height);
-        for i in 0..self.swapchain.images.len() {
-            let frame = self.frames[i].as_mut().unwrap();
-            frame.res.descriptors.free(&frame.res.descriptors.present_sets);
-            frame.res.descriptors.present_sets.clear();
-            frame.buffer = Framebuffer::new(&dev, &self.swapchain.images[i], &pass);
+        self.swapchain
+            .recreate(&surface, &dev, width, height, self.present_mode);
+        // Presents to the old swapchain can not be waited anymore, and the device is idle
+        self.present_ids.retire(self.swapchain.images.len());
+
+        Self::recreate_frames(
+            &mut self.frames,
+            &self.swapchain.images,
+            dev,
+            pass,
+            &self.shadow_config,
+        );
+    }
+
+    /// Makes `frames` render to swapchain `images`. Existing frames get new framebuffers and
+    /// semaphores, while frames are created or dropped when the number of images changes,
+    /// for example with a different present mode. Should be called only when the device is idle.
+    fn recreate_frames(
+        frames: &mut Vec<Option<Frame>>,
+        images: &[Image],
+        dev: &mut Dev,
+        pass: &Pass,
+        shadow: &ShadowConfig,
+    ) {
+        frames.truncate(images.len());
+
+        for (frame, image) in frames.iter_mut().zip(images.iter()) {
+            let frame = frame.as_mut().expect("Failed to recreate a frame in use");
+            frame.res.reset_semaphores(&dev.device);
+            let descriptors = &mut frame.res.descriptors;
+            let present_sets = std::mem::take(&mut descriptors.present_sets);
+            descriptors.free(&present_sets);
+            let light_sets = std::mem::take(&mut descriptors.light_sets);
+            descriptors.free(&light_sets);
+            // New swapchain images start in undefined layout, as the render pass expects
+            frame.buffer = Framebuffer::new(&dev, image, &pass);
+        }
+
+        for image in &images[frames.len()..] {
+            let mut frame = Frame::new(dev, image, pass);
+            frame.res.present_acquire = PresentAcquire::new(dev);
+            frame.shadow = Some(ShadowMap::new(dev, shadow));
+            frames.push(Some(frame));
+        }
+    }
+
+    /// Waits up to `timeout` nanoseconds for the present with `id` to be shown on screen,
+    /// returning `TIMEOUT` if it is not yet. Without VK_KHR_present_wait, it falls back to
+    /// waiting for the fence of the draw presented with `id`, which happens earlier.
+    pub fn wait_for_present(&mut self, id: u64, timeout: u64) -> Result<(), vk::Result> {
+        assert!(
+            id <= self.present_ids.get_last(),
+            "Failed to wait for present {} which has not been requested yet",
+            id
+        );
+        if self.present_ids.is_retired(id) {
+            return Ok(());
+        }
+        if let Some(present_wait) = &self.present_wait {
+            return present_wait.wait(&self.swapchain, id, timeout);
+        }
+        match self.present_ids.get_frame(id) {
+            Some(index) => match &mut self.frames[index] {
+                Some(frame) => frame.res.fence.wait_timeout(timeout),
+                // A frame taken for drawing has already waited for its previous draw
+                None => Ok(()),
+            },
+            // The frame has been presented again, hence its previous draw has finished
+            None => Ok(()),
         }
     }
 }
@@ -716,7 +2362,7 @@ impl Frames for SwapchainFrames {
         &mut self,
         win: &Win,
         surface: &Surface,
-        dev: &Dev,
+        dev: &mut Dev,
         pass: &Pass,
     ) -> Option<Frame> {
         // Let us create a new semaphore for next image
@@ -725,42 +2371,50 @@ impl Frames for SwapchainFrames {
         let acquire_res = unsafe {
             self.swapchain.ext.acquire_next_image(
                 self.swapchain.swapchain,
-                u64::MAX,
+                self.acquire_timeout,
                 image_ready.semaphore,
                 vk::Fence::null(),
             )
         };
 
         match acquire_res {
-            Ok((image_index, false)) => {
+            // A suboptimal image has been acquired and its semaphore will be signaled,
+            // so it is drawn and presented anyway, and present will recreate the swapchain
+            Ok((image_index, _)) => {
                 self.image_index = image_index;
                 self.current = image_index as usize;
                 let mut frame = self.frames[self.current].take().unwrap();
                 // When previous draw is finished, the fence is signaled, let us wait for it.
                 frame.res.wait();
+                frame.set_shadow_config(dev, &self.shadow_config);
                 // At this point the image should be ready and we can safely overwrite previous semaphore.
                 frame.res.image_ready = image_ready;
                 Some(frame)
             }
-            // Suboptimal
-            Ok((_, true)) | Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
                 self.recreate(win, surface, dev, pass);
                 None
             }
+            // No image became available within the timeout, drawing should be skipped
+            Err(vk::Result::TIMEOUT) | Err(vk::Result::NOT_READY) => None,
             Err(result) => {
                 panic!("{:?}", result);
             }
         }
     }
 
-    fn present(&mut self, frame: Frame, win: &Win, surface: &Surface, dev: &Dev, pass: &Pass) {
+    fn present(&mut self, frame: Frame, win: &Win, surface: &Surface, dev: &mut Dev, pass: &Pass) {
         assert!(self.frames[self.current as usize].is_none());
         self.frames[self.current as usize].replace(frame);
 
+        let present_id = self.present_ids.next(self.current);
+        // Ids are only passed to the driver when it can wait for them
+        let present_id = self.present_wait.as_ref().map(|_| present_id);
+
         match self.frames[self.current as usize]
             .as_mut()
             .unwrap()
-            .present(dev, &self.swapchain, self.image_index)
+            .present(dev, &self.swapchain, self.image_index, present_id)
         {
             Ok(()) => {}
             // Recreate swapchain
@@ -771,3 +2425,483 @@ impl Frames for SwapchainFrames {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn rect(x: i32, y: i32, width: u32, height: u32) -> vk::Rect2D {
+        vk::Rect2D::builder()
+            .offset(vk::Offset2D::builder().x(x).y(y).build())
+            .extent(vk::Extent2D::builder().width(width).height(height).build())
+            .build()
+    }
+
+    #[test]
+    fn shadow_config_extent() {
+        let config = ShadowConfig::new(2048, 1024);
+        let viewport = config.get_viewport();
+        assert_eq!(viewport.width, 2048.0);
+        assert_eq!(viewport.height, 1024.0);
+        assert_eq!(config.get_scissor(), rect(0, 0, 2048, 1024));
+
+        assert_eq!(ShadowConfig::default().extent.width, 512);
+        assert!(config.is_supported(4096));
+        assert!(!config.is_supported(1024));
+        assert!(!ShadowConfig::new(0, 512).is_supported(4096));
+    }
+
+    #[test]
+    fn shadow_lookup() {
+        // Directional light above the origin, looking down
+        let camera = Camera::orthographic(-1.0, 1.0, -1.0, 1.0, 0.1, 10.0);
+        let proj = camera.get_proj_for(DepthConfig::reverse_z());
+        let mut trs = Trs::new();
+        trs.look_at(
+            &na::Point3::new(0.0, 5.0, 0.0),
+            &na::Point3::origin(),
+            &na::Vector3::z(),
+        );
+        let shadow = ShadowUniform::new(proj * trs.get_view_matrix());
+
+        let (uv, occluder) = shadow.get_coord(&na::Point3::new(0.0, 1.0, 0.0));
+        assert!((uv - na::Vector2::new(0.5, 0.5)).norm() < 1e-5);
+
+        // Depth is reversed, so fragments further from the light have smaller depth
+        let (_, depth) = shadow.get_coord(&na::Point3::origin());
+        assert!(depth < occluder);
+        assert_eq!(
+            shadow.get_factor(occluder, depth),
+            1.0 - ShadowUniform::DEFAULT_STRENGTH
+        );
+        assert_eq!(shadow.get_factor(occluder, occluder), 1.0);
+        // The occluder itself is not shadowed within the bias
+        assert_eq!(
+            shadow.get_factor(occluder, occluder - shadow.bias / 2.0),
+            1.0
+        );
+
+        assert_eq!(ShadowUniform::disabled().get_factor(1.0, 0.0), 1.0);
+    }
+
+    #[test]
+    fn present_id_bookkeeping() {
+        let mut ids = PresentIds::new(2);
+        assert_eq!(ids.get_last(), 0);
+        assert_eq!(ids.get_frame(0), None);
+
+        // Frames are presented round-robin with increasing ids
+        assert_eq!(ids.next(0), 1);
+        assert_eq!(ids.next(1), 2);
+        assert_eq!(ids.get_frame(1), Some(0));
+        assert_eq!(ids.get_frame(2), Some(1));
+
+        // Presenting a frame again replaces its previous id
+        assert_eq!(ids.next(0), 3);
+        assert_eq!(ids.get_frame_id(0), 3);
+        assert_eq!(ids.get_frame(1), None);
+        assert_eq!(ids.get_frame(3), Some(0));
+        assert_eq!(ids.get_last(), 3);
+
+        // Ids keep increasing across swapchain recreation
+        ids.retire(3);
+        assert!(ids.is_retired(3));
+        assert_eq!(ids.get_frame(3), None);
+        assert_eq!(ids.get_frame_id(0), 0);
+        assert_eq!(ids.next(1), 4);
+        assert!(!ids.is_retired(4));
+        assert_eq!(ids.get_frame(4), Some(1));
+        assert_eq!(ids.next(2), 5);
+    }
+
+    #[test]
+    fn scissor_push_pop() {
+        let mut scissors = ScissorStack::new(800, 600);
+        let full = scissors.get_current();
+        assert_eq!(full, rect(0, 0, 800, 600));
+
+        scissors.push(&rect(10, 10, 100, 100));
+        scissors.push(&rect(20, 20, 50, 50));
+        assert_eq!(scissors.get_current(), rect(20, 20, 50, 50));
+
+        assert_eq!(scissors.pop(), rect(10, 10, 100, 100));
+        assert_eq!(scissors.pop(), full);
+        // Popping an empty stack keeps the full framebuffer
+        assert_eq!(scissors.pop(), full);
+    }
+
+    #[test]
+    fn scissor_clamp() {
+        let mut scissors = ScissorStack::new(800, 600);
+        assert_eq!(scissors.push(&rect(-10, -20, 100, 100)), rect(0, 0, 90, 80));
+        assert_eq!(
+            scissors.push(&rect(700, 500, 200, 200)),
+            rect(700, 500, 100, 100)
+        );
+        assert_eq!(scissors.push(&rect(900, 0, 10, 10)), rect(800, 0, 0, 10));
+
+        // Letterboxed scene
+        scissors.reset_to(&rect(100, 0, 600, 600));
+        assert_eq!(scissors.get_current(), rect(100, 0, 600, 600));
+        assert_eq!(scissors.push(&rect(0, 10, 200, 20)), rect(100, 10, 100, 20));
+    }
+
+    #[test]
+    fn letterbox_rect() {
+        // Without a target the scene is stretched to the window
+        assert_eq!(get_letterbox_rect(800, 600, None), rect(0, 0, 800, 600));
+
+        // Wider window, bars on the sides
+        assert_eq!(
+            get_letterbox_rect(1920, 1080, Some(4.0 / 3.0)),
+            rect(240, 0, 1440, 1080)
+        );
+
+        // Narrower window, bars at the top and bottom
+        assert_eq!(
+            get_letterbox_rect(800, 600, Some(16.0 / 9.0)),
+            rect(0, 75, 800, 450)
+        );
+
+        // Matching aspect, no bars
+        assert_eq!(
+            get_letterbox_rect(1280, 720, Some(16.0 / 9.0)),
+            rect(0, 0, 1280, 720)
+        );
+    }
+
+    #[test]
+    fn gbuffer_views() {
+        let ctx = Ctx::headless();
+        let mut dev = Dev::headless(&ctx);
+        let pass = Pass::new_offscreen(&mut dev);
+        let image = Image::attachment(&dev.allocator, 64, 64, dev.surface_format.format);
+        let frame = Frame::new(&mut dev, &image, &pass);
+
+        // Views have the formats of the attachments of the pass
+        assert_eq!(frame.get_albedo_view().format, pass.formats.albedo);
+        assert_eq!(frame.get_normal_view().format, pass.formats.normal);
+        assert_eq!(frame.get_depth_view().format, pass.depth_format);
+        assert_eq!(
+            frame.get_albedo_view().format,
+            frame.buffer.albedo_image.format
+        );
+        assert_eq!(
+            frame.get_normal_view().format,
+            frame.buffer.normal_image.format
+        );
+
+        // And can be sampled
+        let sampled = vk::ImageUsageFlags::SAMPLED;
+        assert!(Image::get_attachment_usage(pass.formats.albedo).contains(sampled));
+        assert!(Image::get_attachment_usage(pass.formats.normal).contains(sampled));
+    }
+
+    #[test]
+    fn recreate_frames_count() {
+        let ctx = Ctx::headless();
+        let mut dev = Dev::headless(&ctx);
+        let pass = Pass::new_offscreen(&mut dev);
+        let shadow = ShadowConfig::new(64, 64);
+        let images: Vec<Image> = (0..3)
+            .map(|_| Image::attachment(&dev.allocator, 64, 64, dev.surface_format.format))
+            .collect();
+
+        let mut frames = vec![];
+        SwapchainFrames::recreate_frames(&mut frames, &images[..2], &mut dev, &pass, &shadow);
+        assert_eq!(frames.len(), 2);
+        let image_ready = frames[0].as_ref().unwrap().res.image_ready.semaphore;
+
+        // A swapchain with more images, for example after switching to mailbox
+        SwapchainFrames::recreate_frames(&mut frames, &images, &mut dev, &pass, &shadow);
+        assert_eq!(frames.len(), 3);
+        for frame in &frames {
+            let frame = frame.as_ref().unwrap();
+            assert!(frame.shadow.is_some());
+            assert_eq!(frame.buffer.width, 64);
+        }
+        // Existing frames get fresh semaphores
+        let frame = frames[0].as_ref().unwrap();
+        assert_ne!(frame.res.image_ready.semaphore, image_ready);
+
+        // And with fewer images
+        SwapchainFrames::recreate_frames(&mut frames, &images[..1], &mut dev, &pass, &shadow);
+        assert_eq!(frames.len(), 1);
+    }
+
+    #[test]
+    fn batched_model_writes() {
+        let ctx = Ctx::headless();
+        let mut dev = Dev::headless(&ctx);
+        let pass = Pass::new_offscreen(&mut dev);
+        let pipelines = DefaultPipelines::new(&dev, &pass, 64, 64, DepthConfig::default());
+        let image = Image::attachment(&dev.allocator, 64, 64, dev.surface_format.format);
+        let mut frame = Frame::new(&mut dev, &image, &pass);
+
+        let mut model = Model::new();
+        let material = model
+            .materials
+            .push(Material::new(Color::new(1.0, 0.0, 0.0, 1.0)));
+        let mut primitive = Primitive::cube(&dev.allocator, 1.0);
+        primitive.material = material;
+        let mesh = Mesh::new(vec![model.primitives.push(primitive)]);
+        let mesh = model.meshes.push(mesh);
+        for _ in 0..2 {
+            let mut node = Node::new();
+            node.mesh = mesh;
+            model.nodes.push(node);
+        }
+        let mut camera_node = Node::new();
+        camera_node.camera = model.cameras.push(Camera::perspective(1.0));
+        let camera_node = model.nodes.push(camera_node);
+
+        // Sets of the camera, of each node, of the shared material, and of the shadow map
+        let draws = model.get_draws();
+        frame.prepare_model(&pipelines, &model, camera_node, &draws);
+        let pipeline = pipelines.get(Pipelines::MAIN);
+        let frame_sets = pipeline.has_set_layout(DescriptorFrequency::PerFrame) as usize;
+        let sets: HashSet<vk::DescriptorSet> = frame
+            .writer
+            .get_writes()
+            .iter()
+            .map(|write| write.dst_set)
+            .collect();
+        assert_eq!(sets.len(), 1 + 2 + 1 + frame_sets);
+
+        // All of them are updated at once, and not written again on the next frame
+        frame.flush_writes();
+        assert!(frame.writer.get_writes().is_empty());
+        frame.prepare_model(&pipelines, &model, camera_node, &draws);
+        assert!(frame.writer.get_writes().is_empty());
+    }
+
+    #[test]
+    fn shadow_pass_hidden_subtree() {
+        let ctx = Ctx::headless();
+        let mut dev = Dev::headless(&ctx);
+        let pass = Pass::new_offscreen(&mut dev);
+        let image = Image::attachment(&dev.allocator, 64, 64, dev.surface_format.format);
+        let mut frame = Frame::new(&mut dev, &image, &pass);
+        frame.shadow = Some(ShadowMap::new(&mut dev, &ShadowConfig::default()));
+
+        let mut model = Model::new();
+        let primitive = model.primitives.push(Primitive::cube(&dev.allocator, 1.0));
+        let mesh = model.meshes.push(Mesh::new(vec![primitive]));
+        let mut child = Node::new();
+        child.mesh = mesh;
+        let child = model.nodes.push(child);
+        let mut sibling = Node::new();
+        sibling.mesh = mesh;
+        let sibling = model.nodes.push(sibling);
+        let mut parent = Node::new();
+        parent.children.push(child);
+        parent.set_visible(false);
+        let parent = model.nodes.push(parent);
+        let mut root = Node::new();
+        root.children = vec![parent, sibling];
+        model.nodes.push(root);
+        let mut light_node = Node::new();
+        light_node.camera = model
+            .cameras
+            .push(Camera::orthographic(-1.0, 1.0, -1.0, 1.0, 0.1, 10.0));
+        let light_node = model.nodes.push(light_node);
+
+        // The child of the hidden parent is visible itself, but it casts no shadow
+        frame.render_shadow_pass(&model, light_node);
+        assert!(frame.res.node_uniforms.get(sibling).is_some());
+        assert!(frame.res.node_uniforms.get(child).is_none());
+        frame.end_command_buffer();
+    }
+
+    #[test]
+    fn two_passes() {
+        let ctx = Ctx::headless();
+        let mut dev = Dev::headless(&ctx);
+        let pass = Pass::new_offscreen(&mut dev);
+        let image = Image::attachment(&dev.allocator, 64, 64, dev.surface_format.format);
+        let other_image = Image::attachment(&dev.allocator, 32, 32, dev.surface_format.format);
+        let other_buffer = Framebuffer::new(&dev, &other_image, &pass);
+        let mut frame = Frame::new(&mut dev, &image, &pass);
+
+        // Both passes are recorded in the same command buffer
+        frame.begin(&pass, 64, 64);
+        frame.res.command_buffer.next_subpass();
+        frame.res.command_buffer.next_subpass();
+        frame.end_pass();
+        frame.begin_pass(&pass, &other_buffer, rect(0, 0, 32, 32));
+        assert_eq!(frame.get_scene_rect(), rect(0, 0, 32, 32));
+        frame.res.command_buffer.next_subpass();
+        frame.res.command_buffer.next_subpass();
+        frame.end();
+
+        frame.res.submit(&dev.graphics_queue, None, None, true);
+        frame.res.wait();
+    }
+
+    #[test]
+    fn submit_added_early() {
+        let ctx = Ctx::headless();
+        let mut dev = Dev::headless(&ctx);
+        let pass = Pass::new_offscreen(&mut dev);
+        let image = Image::attachment(&dev.allocator, 64, 64, dev.surface_format.format);
+        let mut frame = Frame::new(&mut dev, &image, &pass);
+
+        let data: Vec<u32> = (0..8).collect();
+        let size = (std::mem::size_of::<u32>() * data.len()) as vk::DeviceSize;
+        let mut src =
+            Buffer::new_with_size(&dev.allocator, vk::BufferUsageFlags::TRANSFER_SRC, size);
+        src.upload_arr(&data);
+        let mid = Buffer::new_with_size(
+            &dev.allocator,
+            vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST,
+            size,
+        );
+        let mut dst =
+            Buffer::new_with_size(&dev.allocator, vk::BufferUsageFlags::TRANSFER_DST, size);
+
+        // The first copy runs while the second one is being recorded
+        frame.add_command_buffer(&dev).copy_buffer(&src, &mid, size);
+        frame.submit_added(&dev);
+        assert_eq!(frame.res.submitted_count, 1);
+
+        let command_buffer = frame.add_command_buffer(&dev);
+        command_buffer.copy_buffer(&mid, &dst, size);
+        command_buffer.buffer_barriers(
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::HOST,
+            &[Buffer::get_host_read_barrier(dst.buffer)],
+        );
+        assert_eq!(frame.res.get_command_buffers().len(), 2);
+
+        frame.end_command_buffer();
+        frame.res.submit(&dev.graphics_queue, None, None, true);
+        frame.res.wait();
+        assert_eq!(frame.res.submitted_count, 0);
+        assert_eq!(frame.res.added_count, 0);
+
+        assert_eq!(dst.read_mapped::<u32>(data.len()), data);
+    }
+
+    #[test]
+    #[should_panic(expected = "no pass in progress")]
+    fn end_pass_without_pass() {
+        let ctx = Ctx::headless();
+        let mut dev = Dev::headless(&ctx);
+        let pass = Pass::new_offscreen(&mut dev);
+        let image = Image::attachment(&dev.allocator, 64, 64, dev.surface_format.format);
+        let mut frame = Frame::new(&mut dev, &image, &pass);
+
+        frame.begin(&pass, 64, 64);
+        frame.res.command_buffer.next_subpass();
+        frame.res.command_buffer.next_subpass();
+        frame.end_pass();
+        frame.end_pass();
+    }
+
+    #[test]
+    fn draw_pipelines() {
+        let ctx = Ctx::headless();
+        let mut dev = Dev::headless(&ctx);
+        let pass = Pass::new_offscreen(&mut dev);
+        let pipelines = DefaultPipelines::new(&dev, &pass, 64, 64, DepthConfig::default());
+        let draw = ModelDraw {
+            node: Handle::none(),
+            primitive: Handle::none(),
+            pipeline: Pipelines::PACKED,
+        };
+
+        // Packed primitives are drawn as such, only when the device can fetch their attributes
+        let supported = PackedVertex::is_supported(&dev);
+        assert_eq!(pipelines.try_get(Pipelines::PACKED).is_some(), supported);
+        match Frame::get_draw_pipeline(&pipelines, &draw) {
+            Some((input, _)) => {
+                assert!(supported);
+                assert_eq!(input, Pipelines::PACKED);
+            }
+            None => assert!(!supported),
+        }
+
+        let blend = ModelDraw {
+            pipeline: Pipelines::BLEND,
+            ..draw
+        };
+        let (input, _) = Frame::get_draw_pipeline(&pipelines, &blend).unwrap();
+        assert_eq!(input, Pipelines::MAIN);
+
+        let present = ModelDraw {
+            pipeline: Pipelines::PRESENT,
+            ..draw
+        };
+        assert!(Frame::get_draw_pipeline(&pipelines, &present).is_none());
+    }
+
+    #[test]
+    fn lights_in_camera_space() {
+        let ctx = Ctx::headless();
+        let mut dev = Dev::headless(&ctx);
+        let pass = Pass::new_offscreen(&mut dev);
+        let image = Image::attachment(&dev.allocator, 64, 64, dev.surface_format.format);
+        let mut frame = Frame::new(&mut dev, &image, &pass);
+
+        let mut model = Model::new();
+        let mut light_node = Node::new();
+        light_node.light = model
+            .lights
+            .push(Light::new(na::Vector3::new(1.0, 1.0, 1.0), 1.0));
+        light_node
+            .trs
+            .set_translation(&na::Vector3::new(1.0, 0.0, 0.0));
+        model.nodes.push(light_node);
+        let mut camera_node = Node::new();
+        camera_node.camera = model.cameras.push(Camera::perspective(1.0));
+        camera_node
+            .trs
+            .set_translation(&na::Vector3::new(0.0, 0.0, 5.0));
+        let camera_node = model.nodes.push(camera_node);
+
+        // Even before the camera is bound for the first time
+        frame.gather_lights(&model, camera_node);
+        assert_eq!(frame.lights.len(), 1);
+        let (position, _) = frame.lights[0];
+        assert!((position - na::Vector3::new(1.0, 0.0, -5.0)).norm() < 1e-5);
+    }
+
+    #[test]
+    fn multiview_layers() {
+        let ctx = Ctx::headless();
+        let mut dev = Dev::headless(&ctx);
+        if !dev.multiview {
+            return;
+        }
+        let multiview = MultiviewConfig::stereo();
+        let pass = Pass::new_multiview(&mut dev, false, &multiview);
+        let pipelines =
+            DefaultPipelines::new_multiview(&dev, &pass, 64, 64, DepthConfig::default());
+        let image = Image::layered_attachment(
+            &dev.allocator,
+            64,
+            64,
+            dev.surface_format.format,
+            multiview.view_count,
+        );
+        let frame = Frame::new_multiview(&mut dev, &image, &pass, &multiview);
+
+        // Each view renders to its own layer of every attachment
+        for (view, image) in [
+            (&frame.buffer.albedo_view, &frame.buffer.albedo_image),
+            (&frame.buffer.depth_view, &frame.buffer.depth_image),
+            (&frame.buffer.normal_view, &frame.buffer.normal_image),
+            (&frame.buffer.light_view, &frame.buffer.light_image),
+        ]
+        .iter()
+        {
+            assert_eq!(image.array_layers, multiview.view_count);
+            assert_eq!(view.view_type, vk::ImageViewType::TYPE_2D_ARRAY);
+            assert_eq!(view.range.layer_count, multiview.view_count);
+        }
+        assert!(pipelines
+            .get(Pipelines::MAIN)
+            .is_compatible_with_framebuffer(&frame.buffer));
+    }
+}
@@ -0,0 +1,416 @@
+// Copyright © 2021
+// Author: Antonio Caggiano <info@antoniocaggiano.eu>
+// SPDX-License-Identifier: MIT
+
+use ash::vk;
+
+use super::*;
+
+/// Convention of the depth buffer, which decides where projection matrices map the near and far
+/// planes. Viewports keep the depth range from 0 to 1, hence depth buffer values are the same
+/// as depths in normalized device coordinates:
+/// - standard, near plane at 0 and far plane at 1, closer fragments pass with `LESS`,
+///   and depth is cleared to 1;
+/// - reverse-Z, near plane at 1 and far plane at 0, closer fragments pass with `GREATER`,
+///   and depth is cleared to 0. Float precision is spread more evenly over distance.
+///
+/// Standard is the default, and reverse-Z is opt-in with `Frame::set_depth_config` and
+/// `DefaultPipelines::new`, or `RasterState::depth` for other pipelines. Frames project with
+/// the camera matrices of their convention, see `Camera::get_proj_for`, and built-in shaders
+/// which reconstruct positions from depth, such as lighting and grid, receive them.
+/// Shadow maps always use reverse-Z.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct DepthConfig {
+    pub reverse_z: bool,
+}
+
+impl DepthConfig {
+    pub fn standard() -> Self {
+        Self { reverse_z: false }
+    }
+
+    pub fn reverse_z() -> Self {
+        Self { reverse_z: true }
+    }
+
+    /// Returns the viewport covering `rect`, with a depth range from 0 to 1 for both conventions,
+    /// as it is the projection which reverses depth
+    pub fn get_viewport(rect: &vk::Rect2D) -> vk::Viewport {
+        vk::Viewport::builder()
+            .x(rect.offset.x as f32)
+            .y(rect.offset.y as f32)
+            .width(rect.extent.width as f32)
+            .height(rect.extent.height as f32)
+            .min_depth(0.0)
+            .max_depth(1.0)
+            .build()
+    }
+
+    /// Returns `proj`, which maps the near plane to 0 and the far plane to 1, mapping them
+    /// according to this convention instead. Reversing depth is its own inverse, so a reverse-Z
+    /// projection goes back to mapping the near plane to 0 and the far plane to 1.
+    pub fn get_projection(&self, proj: &na::Matrix4<f32>) -> na::Matrix4<f32> {
+        if !self.reverse_z {
+            return *proj;
+        }
+        // Clip z becomes w - z, hence depth in normalized device coordinates becomes 1 - depth
+        let reverse = na::Matrix4::new(
+            1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, -1.0, 1.0, 0.0, 0.0, 0.0, 1.0,
+        );
+        reverse * proj
+    }
+
+    /// Depth is cleared to the far plane
+    pub fn get_clear_depth(&self) -> f32 {
+        if self.reverse_z {
+            0.0
+        } else {
+            1.0
+        }
+    }
+
+    /// Returns the compare op of this convention, given the `reverse_op` of reverse-Z
+    pub fn get_compare_op(&self, reverse_op: vk::CompareOp) -> vk::CompareOp {
+        if self.reverse_z {
+            return reverse_op;
+        }
+        match reverse_op {
+            vk::CompareOp::GREATER => vk::CompareOp::LESS,
+            vk::CompareOp::GREATER_OR_EQUAL => vk::CompareOp::LESS_OR_EQUAL,
+            vk::CompareOp::LESS => vk::CompareOp::GREATER,
+            vk::CompareOp::LESS_OR_EQUAL => vk::CompareOp::GREATER_OR_EQUAL,
+            op => op,
+        }
+    }
+
+    /// Returns `depth_state`, written for reverse-Z, with the compare op of this convention
+    pub fn get_depth_state(
+        &self,
+        depth_state: vk::PipelineDepthStencilStateCreateInfo,
+    ) -> vk::PipelineDepthStencilStateCreateInfo {
+        vk::PipelineDepthStencilStateCreateInfo {
+            depth_compare_op: self.get_compare_op(depth_state.depth_compare_op),
+            ..depth_state
+        }
+    }
+
+    /// Returns the range of the values written to the depth buffer
+    pub fn get_depth_range(&self) -> DepthRange {
+        if self.reverse_z {
+            DepthRange::ReverseZ
+        } else {
+            DepthRange::ZeroToOne
+        }
+    }
+}
+
+/// Range of depth values in normalized device coordinates produced by a projection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DepthRange {
+    /// Near plane at -1 and far plane at 1, like OpenGL projections
+    NegativeOneToOne,
+    /// Near plane at 0 and far plane at 1
+    ZeroToOne,
+    /// Near plane at 1 and far plane at 0, which is what reversed-Z projections do
+    ReverseZ,
+}
+
+impl DepthRange {
+    /// Converts a depth buffer value written by a perspective projection with this range
+    /// back to a linear distance from the camera
+    pub fn linearize(&self, depth: f32, near: f32, far: f32) -> f32 {
+        match self {
+            DepthRange::NegativeOneToOne => {
+                let ndc = depth * 2.0 - 1.0;
+                2.0 * far * near / (far + near - ndc * (far - near))
+            }
+            DepthRange::ZeroToOne => far * near / (far - depth * (far - near)),
+            DepthRange::ReverseZ => far * near / (near + depth * (far - near)),
+        }
+    }
+}
+
+/// Axis-aligned bounding box
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: na::Vector3<f32>,
+    pub max: na::Vector3<f32>,
+}
+
+impl Aabb {
+    pub fn new(min: na::Vector3<f32>, max: na::Vector3<f32>) -> Self {
+        Self { min, max }
+    }
+
+    /// Returns a box containing nothing, which is the identity of `union`
+    pub fn empty() -> Self {
+        Self {
+            min: na::Vector3::repeat(f32::INFINITY),
+            max: na::Vector3::repeat(f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn from_points<I: IntoIterator<Item = na::Vector3<f32>>>(points: I) -> Self {
+        points.into_iter().fold(Self::empty(), |aabb, point| Self {
+            min: aabb.min.inf(&point),
+            max: aabb.max.sup(&point),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Self {
+            min: self.min.inf(&other.min),
+            max: self.max.sup(&other.max),
+        }
+    }
+
+    pub fn get_center(&self) -> na::Vector3<f32> {
+        (self.min + self.max) / 2.0
+    }
+
+    pub fn get_corners(&self) -> [na::Vector3<f32>; 8] {
+        let (a, b) = (self.min, self.max);
+        [
+            na::Vector3::new(a.x, a.y, a.z),
+            na::Vector3::new(b.x, a.y, a.z),
+            na::Vector3::new(a.x, b.y, a.z),
+            na::Vector3::new(b.x, b.y, a.z),
+            na::Vector3::new(a.x, a.y, b.z),
+            na::Vector3::new(b.x, a.y, b.z),
+            na::Vector3::new(a.x, b.y, b.z),
+            na::Vector3::new(b.x, b.y, b.z),
+        ]
+    }
+
+    /// Returns the box containing this one after being transformed by `matrix`
+    pub fn transform(&self, matrix: &na::Matrix4<f32>) -> Aabb {
+        if self.is_empty() {
+            return *self;
+        }
+        Self::from_points(
+            self.get_corners()
+                .iter()
+                .map(|corner| matrix.transform_point(&na::Point3::from(*corner)).coords),
+        )
+    }
+}
+
+/// A plane where points satisfying `normal · p + distance >= 0` are on the inner side
+#[derive(Debug, Clone, Copy)]
+pub struct Plane {
+    pub normal: na::Vector3<f32>,
+    pub distance: f32,
+}
+
+impl Plane {
+    /// Creates a normalized plane from its equation coefficients
+    fn from_row(row: na::RowVector4<f32>) -> Self {
+        let normal = na::Vector3::new(row[0], row[1], row[2]);
+        let length = normal.norm();
+        Self {
+            normal: normal / length,
+            distance: row[3] / length,
+        }
+    }
+
+    pub fn distance_to(&self, point: &na::Point3<f32>) -> f32 {
+        self.normal.dot(&point.coords) + self.distance
+    }
+}
+
+/// Six planes bounding the volume visible by a camera
+pub struct Frustum {
+    pub left: Plane,
+    pub right: Plane,
+    pub bottom: Plane,
+    pub top: Plane,
+    pub near: Plane,
+    pub far: Plane,
+}
+
+impl Frustum {
+    /// Extracts the planes from a `proj * view` matrix following Gribb-Hartmann.
+    /// Near and far planes depend on the depth range of the projection.
+    pub fn from_matrix(matrix: &na::Matrix4<f32>, depth_range: DepthRange) -> Self {
+        let x = matrix.row(0);
+        let y = matrix.row(1);
+        let z = matrix.row(2);
+        let w = matrix.row(3);
+
+        let (near, far) = match depth_range {
+            // -w <= z <= w
+            DepthRange::NegativeOneToOne => (w + z, w - z),
+            // 0 <= z <= w
+            DepthRange::ZeroToOne => (z.into_owned(), w - z),
+            // Near maps to w, far maps to 0
+            DepthRange::ReverseZ => (w - z, z.into_owned()),
+        };
+
+        Self {
+            left: Plane::from_row(w + x),
+            right: Plane::from_row(w - x),
+            bottom: Plane::from_row(w + y),
+            top: Plane::from_row(w - y),
+            near: Plane::from_row(near),
+            far: Plane::from_row(far),
+        }
+    }
+
+    pub fn get_planes(&self) -> [&Plane; 6] {
+        [
+            &self.left,
+            &self.right,
+            &self.bottom,
+            &self.top,
+            &self.near,
+            &self.far,
+        ]
+    }
+
+    pub fn contains_point(&self, point: &na::Point3<f32>) -> bool {
+        self.get_planes()
+            .iter()
+            .all(|plane| plane.distance_to(point) >= 0.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Perspective projection mapping near to 1 and far to 0
+    fn reverse_z_perspective(near: f32, far: f32) -> na::Matrix4<f32> {
+        let standard = na::Perspective3::new(1.0, std::f32::consts::FRAC_PI_2, near, far);
+        // From [-1, 1] to [0, 1]
+        #[rustfmt::skip]
+        let to_zero_one = na::Matrix4::new(
+            1.0, 0.0, 0.0, 0.0,
+            0.0, 1.0, 0.0, 0.0,
+            0.0, 0.0, 0.5, 0.5,
+            0.0, 0.0, 0.0, 1.0,
+        );
+        // z' = w - z
+        #[rustfmt::skip]
+        let reverse = na::Matrix4::new(
+            1.0, 0.0, 0.0, 0.0,
+            0.0, 1.0, 0.0, 0.0,
+            0.0, 0.0, -1.0, 1.0,
+            0.0, 0.0, 0.0, 1.0,
+        );
+        reverse * to_zero_one * standard.to_homogeneous()
+    }
+
+    #[test]
+    fn reverse_z_near_far() {
+        let proj = reverse_z_perspective(0.1, 100.0);
+        let frustum = Frustum::from_matrix(&proj, DepthRange::ReverseZ);
+
+        // Camera looks towards -z
+        assert!(frustum.contains_point(&na::Point3::new(0.0, 0.0, -0.11)));
+        assert!(frustum.contains_point(&na::Point3::new(0.0, 0.0, -99.0)));
+        assert!(!frustum.contains_point(&na::Point3::new(0.0, 0.0, -0.09)));
+        assert!(!frustum.contains_point(&na::Point3::new(0.0, 0.0, 1.0)));
+        assert!(!frustum.contains_point(&na::Point3::new(0.0, 0.0, -101.0)));
+
+        // Near plane faces away from the camera
+        assert!(frustum.near.normal.z < 0.0);
+        assert!(frustum.far.normal.z > 0.0);
+    }
+
+    #[test]
+    fn aabb_transform() {
+        let aabb = Aabb::new(
+            na::Vector3::new(-1.0, -1.0, -1.0),
+            na::Vector3::new(1.0, 1.0, 1.0),
+        );
+        let matrix = na::Matrix4::new_translation(&na::Vector3::new(2.0, 0.0, 0.0))
+            * na::Matrix4::new_nonuniform_scaling(&na::Vector3::new(2.0, 1.0, 1.0));
+        let transformed = aabb.transform(&matrix);
+        assert_eq!(transformed.min, na::Vector3::new(0.0, -1.0, -1.0));
+        assert_eq!(transformed.max, na::Vector3::new(4.0, 1.0, 1.0));
+
+        assert!(Aabb::empty().transform(&matrix).is_empty());
+        assert_eq!(Aabb::empty().union(&aabb), aabb);
+    }
+
+    #[test]
+    fn standard_near_far() {
+        let proj = na::Perspective3::new(1.0, std::f32::consts::FRAC_PI_2, 0.1, 100.0);
+        let frustum = Frustum::from_matrix(&proj.to_homogeneous(), DepthRange::NegativeOneToOne);
+
+        assert!(frustum.contains_point(&na::Point3::new(0.0, 0.0, -0.11)));
+        assert!(!frustum.contains_point(&na::Point3::new(0.0, 0.0, 1.0)));
+        assert!(!frustum.contains_point(&na::Point3::new(0.0, 0.0, -101.0)));
+        assert!(!frustum.contains_point(&na::Point3::new(2.0, 0.0, -1.0)));
+    }
+
+    #[test]
+    fn depth_config() {
+        let rect = vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: vk::Extent2D {
+                width: 4,
+                height: 2,
+            },
+        };
+
+        // Reverse-Z is opt-in
+        assert_eq!(DepthConfig::default(), DepthConfig::standard());
+
+        // Both conventions keep the depth range of the viewport
+        let viewport = DepthConfig::get_viewport(&rect);
+        assert_eq!((viewport.min_depth, viewport.max_depth), (0.0, 1.0));
+        assert_eq!(viewport.width, 4.0);
+
+        let standard = DepthConfig::standard();
+        assert_eq!(standard.get_clear_depth(), 1.0);
+        assert_eq!(
+            standard.get_compare_op(vk::CompareOp::GREATER),
+            vk::CompareOp::LESS
+        );
+        assert_eq!(standard.get_depth_range(), DepthRange::ZeroToOne);
+
+        let reverse = DepthConfig::reverse_z();
+        assert_eq!(reverse.get_clear_depth(), 0.0);
+        assert_eq!(
+            reverse.get_compare_op(vk::CompareOp::GREATER),
+            vk::CompareOp::GREATER
+        );
+        assert_eq!(reverse.get_depth_range(), DepthRange::ReverseZ);
+
+        // Tests which always pass or fail do not depend on the convention
+        assert_eq!(
+            standard.get_compare_op(vk::CompareOp::ALWAYS),
+            vk::CompareOp::ALWAYS
+        );
+    }
+
+    #[test]
+    fn reverse_projection() {
+        let proj = Camera::perspective(1.0).proj;
+        let project = |proj: &na::Matrix4<f32>, z: f32| {
+            let clip = proj * na::Vector4::new(0.0, 0.0, z, 1.0);
+            clip.z / clip.w
+        };
+
+        let standard = DepthConfig::standard();
+        assert_eq!(standard.get_projection(&proj), proj);
+
+        // Near plane at 1 and far plane at 0
+        let reverse = DepthConfig::reverse_z();
+        let reversed = reverse.get_projection(&proj);
+        assert!((project(&reversed, -0.1) - 1.0).abs() < 1e-5);
+        assert!(project(&reversed, -100.0).abs() < 1e-5);
+        let depth = project(&reversed, -10.0);
+        assert!((depth - (1.0 - project(&proj, -10.0))).abs() < 1e-5);
+        let linear = reverse.get_depth_range().linearize(depth, 0.1, 100.0);
+        assert!((linear - 10.0).abs() < 1e-3);
+
+        // Reversing twice gives the original projection back
+        assert!((reverse.get_projection(&reversed) - proj).norm() < 1e-6);
+    }
+}
@@ -7,7 +7,9 @@ use sdl2 as sdl;
 use std::{
     borrow::{Borrow, Cow},
     cell::RefCell,
+    collections::HashMap,
     ffi::{CStr, CString},
+    hash::{Hash, Hasher},
     ops::Deref,
     rc::Rc,
 };
@@ -105,9 +107,23 @@ impl Ctx {
             .window
             .vulkan_instance_extensions()
             .expect("Failed to get SDL vulkan extensions");
+        let extensions: Vec<CString> = extensions
+            .iter()
+            .map(|ext| CString::new(*ext).expect("Failed to convert SDL vulkan extension"))
+            .collect();
+        Self::with_extensions(&extensions)
+    }
+
+    /// Creates a context without any window, hence without surface extensions,
+    /// for offscreen rendering and tests
+    pub fn headless() -> Self {
+        Self::with_extensions(&[])
+    }
+
+    fn with_extensions(extensions: &[CString]) -> Self {
         let mut extensions_names = vec![DebugUtils::name().as_ptr()];
         for ext in extensions.iter() {
-            extensions_names.push(ext.as_ptr() as *const i8);
+            extensions_names.push(ext.as_ptr());
         }
         let layers = [CString::new("VK_LAYER_KHRONOS_validation").unwrap()];
         let layer_names: Vec<*const i8> = layers.iter().map(|name| name.as_ptr()).collect();
@@ -127,11 +143,228 @@ impl Ctx {
 
         Self { entry, instance }
     }
+
+    /// Returns the physical devices available, without creating any logical device,
+    /// so that an application can choose which one to pass to `Dev::with_selector`
+    pub fn enumerate_devices(&self, surface: &Surface) -> Vec<PhysicalDeviceInfo> {
+        self.get_device_infos(Some(surface))
+    }
+
+    /// Without a surface, devices are suitable when they have a graphics queue
+    fn get_device_infos(&self, surface: Option<&Surface>) -> Vec<PhysicalDeviceInfo> {
+        let phydevs = unsafe { self.instance.enumerate_physical_devices() }
+            .expect("Failed to enumerate Vulkan physical devices");
+
+        phydevs
+            .iter()
+            .enumerate()
+            .map(|(index, physical)| {
+                let properties = unsafe { self.instance.get_physical_device_properties(*physical) };
+                let families = Dev::get_queue_families(&self.instance, *physical, surface);
+                let supports_surface =
+                    Dev::select_queue_families(&families, surface.is_some()).is_some();
+                PhysicalDeviceInfo::new(index, &properties, supports_surface)
+            })
+            .collect()
+    }
+}
+
+/// Description of a physical device, useful to present a device picker
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhysicalDeviceInfo {
+    /// Position of this device in the list returned by `Ctx::enumerate_devices`
+    pub index: usize,
+    pub name: String,
+    pub device_type: vk::PhysicalDeviceType,
+    pub vendor_id: u32,
+    /// Whether the device has graphics and present queues for the surface
+    pub supports_surface: bool,
+}
+
+impl PhysicalDeviceInfo {
+    pub fn new(
+        index: usize,
+        properties: &vk::PhysicalDeviceProperties,
+        supports_surface: bool,
+    ) -> Self {
+        let name = unsafe { CStr::from_ptr(properties.device_name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+        Self {
+            index,
+            name,
+            device_type: properties.device_type,
+            vendor_id: properties.vendor_id,
+            supports_surface,
+        }
+    }
+
+    /// Returns the name of the vendor of this device when its PCI vendor ID is known
+    pub fn get_vendor_name(&self) -> &'static str {
+        match self.vendor_id {
+            0x1002 => "AMD",
+            0x1010 => "ImgTec",
+            0x10DE => "NVIDIA",
+            0x13B5 => "ARM",
+            0x5143 => "Qualcomm",
+            0x8086 => "Intel",
+            _ => "Unknown",
+        }
+    }
+
+    pub fn is_discrete(&self) -> bool {
+        self.device_type == vk::PhysicalDeviceType::DISCRETE_GPU
+    }
+
+    /// Returns how suitable this device is, the higher the better, or `None` when
+    /// it can not render to the surface. Discrete GPUs are preferred to integrated ones.
+    pub fn get_score(&self) -> Option<u32> {
+        if !self.supports_surface {
+            return None;
+        }
+        let score = match self.device_type {
+            vk::PhysicalDeviceType::DISCRETE_GPU => 3,
+            vk::PhysicalDeviceType::INTEGRATED_GPU => 2,
+            vk::PhysicalDeviceType::VIRTUAL_GPU => 1,
+            _ => 0,
+        };
+        Some(score)
+    }
+}
+
+/// How to choose the physical device a `Dev` is created from
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceSelection {
+    /// The one with the highest `PhysicalDeviceInfo::get_score`
+    Best,
+    /// Position in the list returned by `Ctx::enumerate_devices`
+    Index(usize),
+    /// First device whose name contains this string, ignoring case
+    Name(String),
+}
+
+impl Default for DeviceSelection {
+    fn default() -> Self {
+        DeviceSelection::Best
+    }
+}
+
+impl DeviceSelection {
+    /// Environment variable which forces a device by index or name
+    pub const ENV: &'static str = "VKR_DEVICE";
+
+    /// Returns the selection forced by the `VKR_DEVICE` environment variable, if any
+    pub fn from_env() -> Self {
+        match std::env::var(Self::ENV) {
+            Ok(value) => Self::parse(&value),
+            Err(_) => DeviceSelection::Best,
+        }
+    }
+
+    fn parse(value: &str) -> Self {
+        let value = value.trim();
+        if value.is_empty() {
+            DeviceSelection::Best
+        } else if let Ok(index) = value.parse() {
+            DeviceSelection::Index(index)
+        } else {
+            DeviceSelection::Name(value.to_string())
+        }
+    }
+
+    /// Returns the index of the selected device, which must be able to render to the surface.
+    /// Forced devices which can not are ignored rather than chosen.
+    pub fn select(&self, infos: &[PhysicalDeviceInfo]) -> Option<usize> {
+        match self {
+            DeviceSelection::Best => infos
+                .iter()
+                .filter_map(|info| info.get_score().map(|score| (score, info)))
+                // The first of the devices with the same score
+                .fold(
+                    None,
+                    |best: Option<(u32, &PhysicalDeviceInfo)>, (score, info)| match best {
+                        Some((best_score, _)) if best_score >= score => best,
+                        _ => Some((score, info)),
+                    },
+                )
+                .map(|(_, info)| info.index),
+            DeviceSelection::Index(index) => infos
+                .iter()
+                .find(|info| info.index == *index && info.get_score().is_some())
+                .map(|info| info.index),
+            DeviceSelection::Name(name) => {
+                let name = name.to_lowercase();
+                infos
+                    .iter()
+                    .filter(|info| info.get_score().is_some())
+                    .find(|info| info.name.to_lowercase().contains(&name))
+                    .map(|info| info.index)
+            }
+        }
+    }
+}
+
+/// Offscreen pass and frame rendering scenes for `Vkr::render_to_texture`,
+/// kept for the following renders with the same extent
+pub struct TextureTarget {
+    pub frame: Frame,
+    pub pass: Pass,
+    /// Color attachment of the frame, copied into a new texture after each render
+    image: Image,
+}
+
+impl TextureTarget {
+    pub fn new(dev: &mut Dev, extent: vk::Extent2D) -> Self {
+        let usage = vk::ImageUsageFlags::COLOR_ATTACHMENT
+            | vk::ImageUsageFlags::SAMPLED
+            | vk::ImageUsageFlags::TRANSFER_SRC;
+        let image = Image::new(
+            &dev.allocator,
+            extent.width,
+            extent.height,
+            dev.surface_format.format,
+            usage,
+        );
+        let pass = Pass::new_offscreen(dev);
+        let frame = Frame::new(dev, &image, &pass);
+        Self { frame, pass, image }
+    }
+
+    /// Copies the color rendered by the frame into a new texture of `target`, ready to be sampled.
+    /// The frame should have been submitted and waited.
+    pub fn new_texture(&mut self, dev: &Dev, target: &mut Model) -> util::Handle<Texture> {
+        // Final layout of the offscreen pass
+        self.image.layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+
+        let usage = vk::ImageUsageFlags::SAMPLED
+            | vk::ImageUsageFlags::TRANSFER_DST
+            | vk::ImageUsageFlags::TRANSFER_SRC;
+        let mut image = Image::new(
+            &dev.allocator,
+            self.image.extent.width,
+            self.image.extent.height,
+            self.image.format,
+            usage,
+        );
+        // The copy goes back to the layout the texture had before
+        image.transition(dev, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+        self.image.copy_to(&mut image, dev);
+
+        let view = ImageView::array(&dev.device, &image, 0..image.array_layers);
+        let view = target.views.push(view);
+        target.images.push(image);
+        let sampler = target.samplers.push(Sampler::new(&dev.device));
+        target.textures.push(Texture::new(view, sampler))
+    }
 }
 
 pub struct Vkr {
     pub pipelines: DefaultPipelines,
     pub gui: Gui,
+    /// Ground grid drawn after the lights when set
+    pub grid: Option<GridRenderer>,
+    /// Targets of `render_to_texture` by extent
+    texture_targets: HashMap<(u32, u32), TextureTarget>,
     pub sfs: SwapchainFrames, // Use box of frames?
     pub pass: Pass,           // How about multiple passes?
     pub dev: Dev,
@@ -140,11 +373,24 @@ pub struct Vkr {
     pub ctx: Ctx,
     pub win: Option<Win>,
     pub resized: bool, // Whether the window has been resized or not
+    /// Aspect ratio the scene is letterboxed to, if any
+    target_aspect: Option<f32>,
+    /// Whether the target aspect changed since the last events were handled
+    target_aspect_changed: bool,
     pub timer: Timer,
 }
 
 impl Vkr {
     pub fn new(win: Win) -> Self {
+        Self::with_required_features(win, &RequiredFeatures::default())
+            .expect("Failed to create Vulkan renderer")
+    }
+
+    /// Creates a renderer on a device supporting all the `required` features
+    pub fn with_required_features(
+        win: Win,
+        required: &RequiredFeatures,
+    ) -> Result<Self, DeviceError> {
         let timer = Timer::new();
 
         let (width, height) = win.window.drawable_size();
@@ -153,18 +399,30 @@ impl Vkr {
         let debug = Debug::new(&ctx);
 
         let surface = Surface::new(&win, &ctx);
-        let mut dev = Dev::new(&ctx, &surface);
+        let mut dev = Dev::with_required_features(&ctx, &surface, required)?;
 
         let pass = Pass::new(&mut dev);
-        let sfs = SwapchainFrames::new(&ctx, &surface, &mut dev, width, height, &pass);
+        let sfs = SwapchainFrames::new(
+            &ctx,
+            &surface,
+            &mut dev,
+            width,
+            height,
+            &pass,
+            &ShadowConfig::default(),
+            vk::PresentModeKHR::FIFO,
+        );
 
         let gui = Gui::new(&win, &dev, &pass);
 
-        let pipelines = DefaultPipelines::new(&dev, &pass, width, height);
+        let pipelines =
+            DefaultPipelines::from_env(&dev, &pass, width, height, DepthConfig::default());
 
-        Self {
+        Ok(Self {
             pipelines,
             gui,
+            grid: None,
+            texture_targets: HashMap::new(),
             sfs,
             pass,
             dev,
@@ -173,14 +431,17 @@ impl Vkr {
             ctx,
             win: Some(win),
             resized: false,
+            target_aspect: None,
+            target_aspect_changed: false,
             timer,
-        }
+        })
     }
 
     pub fn handle_events(&mut self) -> bool {
         let win = self.win.as_mut().unwrap();
 
         self.resized = false;
+        self.target_aspect_changed = false;
 
         // Handle events
         for event in win.events.poll_iter() {
@@ -247,48 +508,187 @@ impl Vkr {
         true
     }
 
-    /// Returns a frame if available. When not available None is returned and drawing should be skipped
-    /// TODO: Another option would be to wait until the frame is available and then return it.
-    pub fn begin_frame(&mut self) -> Option<Frame> {
+    fn next_frame(&mut self) -> Option<Frame> {
         let win = self.win.as_ref().unwrap();
 
         if self.resized {
             self.gui.set_drawable_size(win);
-            self.sfs.recreate(win, &self.surface, &self.dev, &self.pass);
+            self.sfs
+                .recreate(win, &self.surface, &mut self.dev, &self.pass);
         }
 
-        match self
-            .sfs
-            .next_frame(win, &self.surface, &self.dev, &self.pass)
-        {
-            Some(frame) => {
-                let (width, height) = self.win.as_mut().unwrap().window.drawable_size();
-                frame.begin(&self.pass, width, height);
-                Some(frame)
-            }
-            None => None,
+        let (width, height) = win.window.drawable_size();
+        self.pipelines
+            .reload_if_changed(&self.dev, &self.pass, width, height);
+
+        self.sfs
+            .next_frame(win, &self.surface, &mut self.dev, &self.pass)
+    }
+
+    /// Recreates the swapchain with `present_mode`, or FIFO when it is not supported.
+    /// Must be called between frames.
+    pub fn set_present_mode(&mut self, present_mode: vk::PresentModeKHR) {
+        self.sfs.set_present_mode(present_mode);
+        let win = self.win.as_ref().unwrap();
+        self.sfs
+            .recreate(win, &self.surface, &mut self.dev, &self.pass);
+    }
+
+    /// Enables vsync with FIFO, or disables it with MAILBOX when supported, falling back
+    /// to IMMEDIATE which tears
+    pub fn set_vsync(&mut self, vsync: bool) {
+        let present_mode = if vsync {
+            vk::PresentModeKHR::FIFO
+        } else {
+            self.get_uncapped_present_mode()
+        };
+        self.set_present_mode(present_mode);
+    }
+
+    fn get_uncapped_present_mode(&self) -> vk::PresentModeKHR {
+        let present_modes = unsafe {
+            self.surface
+                .ext
+                .get_physical_device_surface_present_modes(self.dev.physical, self.surface.surface)
+        }
+        .expect("Failed to get Vulkan physical device surface present modes");
+        if present_modes.contains(&vk::PresentModeKHR::MAILBOX) {
+            vk::PresentModeKHR::MAILBOX
+        } else {
+            vk::PresentModeKHR::IMMEDIATE
+        }
+    }
+
+    /// Returns a frame if available. When not available None is returned and drawing should be skipped
+    /// TODO: Another option would be to wait until the frame is available and then return it.
+    pub fn begin_frame(&mut self) -> Option<Frame> {
+        let mut frame = self.next_frame()?;
+        frame.set_target_aspect(self.target_aspect);
+        let (width, height) = self.win.as_ref().unwrap().window.drawable_size();
+        frame.begin(&self.pass, width, height);
+        Some(frame)
+    }
+
+    /// Like `begin_frame`, but the scene is first rendered into the shadow map of the frame
+    /// from the camera of `light_node`, so that it can be shadowed by the main pipelines
+    pub fn begin_frame_with_shadow(
+        &mut self,
+        model: &Model,
+        light_node: util::Handle<Node>,
+    ) -> Option<Frame> {
+        let mut frame = self.next_frame()?;
+        frame.render_shadow_pass(model, light_node);
+        frame.set_target_aspect(self.target_aspect);
+        let (width, height) = self.win.as_ref().unwrap().window.drawable_size();
+        frame.begin(&self.pass, width, height);
+        Some(frame)
+    }
+
+    /// Draws the whole `model` as seen by `camera_node` in one call, see `Frame::draw_model`
+    pub fn draw_model(&self, frame: &mut Frame, model: &Model, camera_node: util::Handle<Node>) {
+        frame.draw_model(&self.pipelines, model, camera_node);
+    }
+
+    /// Accumulates the lights met while drawing the scene, each one scissored to its volume
+    fn draw_lights(&mut self, frame: &mut Frame) {
+        let light_pipeline = self.pipelines.get(Pipelines::LIGHT);
+        frame.res.command_buffer.bind_pipeline(light_pipeline);
+
+        if frame.res.descriptors.light_sets.is_empty() {
+            frame.res.descriptors.light_sets =
+                frame.res.descriptors.allocate(&light_pipeline.set_layouts);
+            let mut writer = DescriptorWriter::new();
+            Light::write_set(
+                &mut writer,
+                frame.res.descriptors.light_sets[0],
+                &frame.buffer.albedo_view,
+                &frame.buffer.normal_view,
+                &frame.buffer.depth_view,
+            );
+            writer.flush(&self.dev.device);
+        }
+        frame.res.command_buffer.bind_descriptor_sets(
+            light_pipeline,
+            &frame.res.descriptors.light_sets,
+            0,
+        );
+        frame
+            .res
+            .command_buffer
+            .bind_vertex_buffer(&frame.res.fallback.present_buffer, 0);
+
+        let viewport = frame.get_scene_rect();
+        let lights = std::mem::take(&mut frame.lights);
+        for (view_position, light) in &lights {
+            let mut rect =
+                match light.get_screen_rect(view_position, &frame.current_proj, viewport.extent) {
+                    Some(rect) => rect,
+                    None => continue,
+                };
+            // From the scene viewport to the framebuffer
+            rect.offset.x += viewport.offset.x;
+            rect.offset.y += viewport.offset.y;
+            frame.push_scissor(&rect);
+
+            let constants = light.get_constants(view_position, &frame.current_proj, &viewport);
+            frame.res.command_buffer.push_constants_typed(
+                light_pipeline,
+                vk::ShaderStageFlags::FRAGMENT,
+                0,
+                &constants,
+            );
+            frame.res.command_buffer.draw(3);
+
+            frame.pop_scissor();
         }
+        frame.lights = lights;
     }
 
-    /// Finish rendering a 3D scene and starts next (present) subpass
+    /// Finish rendering a 3D scene, accumulates lights and the grid, and starts the present subpass
     pub fn end_scene(&mut self, frame: &mut Frame) {
         frame.res.command_buffer.next_subpass();
+        self.draw_lights(frame);
+        if let Some(grid) = &self.grid {
+            grid.draw(frame);
+        }
+        frame.res.command_buffer.next_subpass();
+
+        if frame.is_letterboxed() {
+            // Bars around the scene
+            let full = vk::Rect2D::builder()
+                .extent(
+                    vk::Extent2D::builder()
+                        .width(frame.buffer.width)
+                        .height(frame.buffer.height)
+                        .build(),
+                )
+                .build();
+            frame
+                .res
+                .command_buffer
+                .clear_color_attachment(0, [0.0, 0.0, 0.0, 1.0], &[full]);
+        }
 
         let present_pipeline = self.pipelines.get_presentation();
+        assert!(present_pipeline.is_compatible_with(&self.pass));
         frame.res.command_buffer.bind_pipeline(present_pipeline);
+        frame.reset_scene_viewport();
 
         if frame.res.descriptors.present_sets.is_empty() {
             frame.res.descriptors.present_sets = frame
                 .res
                 .descriptors
                 .allocate(&present_pipeline.set_layouts);
+            let mut writer = DescriptorWriter::new();
             PresentVertex::write_set(
-                &self.dev.device,
+                &mut writer,
                 frame.res.descriptors.present_sets[0],
                 &frame.buffer.albedo_view,
                 &frame.buffer.normal_view,
+                &frame.buffer.light_view,
                 &frame.res.fallback.white_sampler,
             );
+            writer.flush(&self.dev.device);
         }
         frame.res.command_buffer.bind_descriptor_sets(
             present_pipeline,
@@ -298,29 +698,88 @@ impl Vkr {
         frame
             .res
             .command_buffer
-            .bind_vertex_buffer(&frame.res.fallback.present_buffer);
+            .bind_vertex_buffer(&frame.res.fallback.present_buffer, 0);
         frame.res.command_buffer.draw(3);
     }
 
-    pub fn end_frame(&mut self, frame: Frame) {
+    /// Improves the filtering of textures of models loaded from now on. Their samplers use:
+    /// - anisotropic filtering at the maximum supported by the device, if any;
+    /// - trilinear filtering, with linear minification and interpolation between mips;
+    /// - all the mip levels of their images.
+    ///
+    /// Magnification filter and address mode are still the ones chosen by the content.
+    pub fn enable_quality_defaults(&mut self) {
+        self.dev.quality_defaults = true;
+    }
+
+    pub fn end_frame(&mut self, mut frame: Frame) {
         frame.end();
 
         self.sfs.present(
             frame,
             &self.win.as_ref().unwrap(),
             &self.surface,
-            &self.dev,
+            &mut self.dev,
             &self.pass,
         );
     }
 
+    /// Renders the scene rooted at `root`, as seen by `camera`, into a new texture of `target`.
+    /// The texture is ready to be sampled, for example for picture-in-picture, portals, or minimaps.
+    /// The pass and frame rendering it are kept for the following calls with the same `extent`.
+    pub fn render_to_texture(
+        &mut self,
+        model: &Model,
+        root: util::Handle<Node>,
+        camera: util::Handle<Node>,
+        extent: vk::Extent2D,
+        target: &mut Model,
+    ) -> util::Handle<Texture> {
+        // Taken, so that it can be rendered while borrowing the renderer
+        let key = (extent.width, extent.height);
+        let mut texture_target = match self.texture_targets.remove(&key) {
+            Some(texture_target) => texture_target,
+            None => TextureTarget::new(&mut self.dev, extent),
+        };
+
+        let frame = &mut texture_target.frame;
+        frame.begin(&texture_target.pass, extent.width, extent.height);
+        frame.bind(self.pipelines.get_for::<Vertex>(), model, camera);
+        frame.draw::<Vertex>(&self.pipelines, model, root);
+        self.end_scene(frame);
+        frame.end();
+
+        frame.res.submit(&self.dev.graphics_queue, None, None, true);
+        frame.res.wait();
+
+        let texture = texture_target.new_texture(&self.dev, target);
+        self.texture_targets.insert(key, texture_target);
+        texture
+    }
+
+    pub fn get_target_aspect(&self) -> Option<f32> {
+        self.target_aspect
+    }
+
+    /// Renders the scene of the next frames within the largest centered rectangle with `aspect`
+    /// ratio, clearing the bars around it to black, instead of stretching it to the window.
+    /// `None` restores stretching. Cameras get the new aspect through `update_camera`.
+    pub fn set_target_aspect(&mut self, aspect: Option<f32>) {
+        if self.target_aspect != aspect {
+            self.target_aspect = aspect;
+            self.target_aspect_changed = true;
+        }
+    }
+
     /// This function can be called before binding the camera to update it.
-    /// Internally it checks if a resize happened before doing anything.
+    /// Internally it checks if a resize, or a change of target aspect, happened before doing anything.
     pub fn update_camera(&self, model: &mut Model, camera_node: util::Handle<Node>) {
-        if self.resized {
+        if self.resized || self.target_aspect_changed {
             let camera_node = model.nodes.get(camera_node).unwrap();
             let camera = model.cameras.get_mut(camera_node.camera).unwrap();
-            if let Some(win) = self.win.as_ref() {
+            if let Some(aspect) = self.target_aspect {
+                camera.update_aspect(aspect);
+            } else if let Some(win) = self.win.as_ref() {
                 camera.update(win);
             }
         }
@@ -368,20 +827,114 @@ impl Drop for Surface {
     }
 }
 
+/// Entry point of VK_KHR_present_wait, only loaded when the device supports it
+#[derive(Clone)]
+pub struct PresentWait {
+    fp: vk::KhrPresentWaitFn,
+    device: Rc<ash::Device>,
+}
+
+impl PresentWait {
+    fn new(instance: &ash::Instance, device: &Rc<ash::Device>) -> Self {
+        let fp = vk::KhrPresentWaitFn::load(|name| unsafe {
+            std::mem::transmute(instance.get_device_proc_addr(device.handle(), name.as_ptr()))
+        });
+        Self {
+            fp,
+            device: device.clone(),
+        }
+    }
+
+    /// Waits up to `timeout` nanoseconds for the present with `id` to be shown on screen
+    pub fn wait(&self, swapchain: &Swapchain, id: u64, timeout: u64) -> Result<(), vk::Result> {
+        let result = unsafe {
+            self.fp
+                .wait_for_present_khr(self.device.handle(), swapchain.swapchain, id, timeout)
+        };
+        match result {
+            vk::Result::SUCCESS => Ok(()),
+            result => Err(result),
+        }
+    }
+}
+
 pub struct Swapchain {
+    /// Format of the swapchain images
+    pub format: ash::vk::Format,
+    /// Color space the swapchain images are presented in
+    pub color_space: ash::vk::ColorSpaceKHR,
+    /// Usage the swapchain images were actually created with
+    pub usage: ash::vk::ImageUsageFlags,
     pub images: Vec<Image>,
     pub swapchain: ash::vk::SwapchainKHR,
     pub ext: ash::extensions::khr::Swapchain,
+    /// Present mode the swapchain was actually created with
+    pub present_mode: ash::vk::PresentModeKHR,
 }
 
 impl Swapchain {
+    /// Usage swapchain images can not do without
+    pub const REQUIRED_USAGE: vk::ImageUsageFlags = vk::ImageUsageFlags::COLOR_ATTACHMENT;
+
+    /// Usage requested when supported by the surface, so that swapchain images
+    /// can be blitted into for upscaling, and read back for screenshots
+    pub const OPTIONAL_USAGE: vk::ImageUsageFlags = vk::ImageUsageFlags::from_raw(
+        vk::ImageUsageFlags::TRANSFER_DST.as_raw() | vk::ImageUsageFlags::TRANSFER_SRC.as_raw(),
+    );
+
+    /// Returns the required usage plus the optional usage supported by the surface,
+    /// or `None` when the required usage is not supported
+    fn select_usage(
+        required: vk::ImageUsageFlags,
+        optional: vk::ImageUsageFlags,
+        supported: vk::ImageUsageFlags,
+    ) -> Option<vk::ImageUsageFlags> {
+        if supported.contains(required) {
+            Some(required | (optional & supported))
+        } else {
+            None
+        }
+    }
+
+    /// Image count requested by default, one being presented, one queued, and one drawn
+    pub const PREFERRED_IMAGE_COUNT: u32 = 3;
+
+    /// Returns `preferred` clamped to the image counts supported by the surface,
+    /// where a `max` of zero means there is no limit
+    fn get_image_count(preferred: u32, min: u32, max: u32) -> u32 {
+        let count = preferred.max(min);
+        if max == 0 {
+            count
+        } else {
+            count.min(max)
+        }
+    }
+
+    /// Returns `preferred` when supported, otherwise FIFO which is always available.
+    /// MAILBOX does not tear and has lower latency than FIFO, IMMEDIATE is uncapped but tears.
+    fn select_present_mode(
+        preferred: vk::PresentModeKHR,
+        supported: &[vk::PresentModeKHR],
+    ) -> vk::PresentModeKHR {
+        if supported.contains(&preferred) {
+            preferred
+        } else {
+            vk::PresentModeKHR::FIFO
+        }
+    }
+
     fn create_swapchain(
         ext: &ash::extensions::khr::Swapchain,
         surface: &Surface,
         dev: &Dev,
         width: u32,
         height: u32,
-    ) -> ash::vk::SwapchainKHR {
+        present_mode: vk::PresentModeKHR,
+    ) -> (
+        ash::vk::SwapchainKHR,
+        ash::vk::ImageUsageFlags,
+        ash::vk::PresentModeKHR,
+    ) {
         // This needs to be queried to prevent validation layers complaining
         let surface_capabilities = unsafe {
             surface
@@ -390,9 +943,30 @@ impl Swapchain {
         }
         .expect("Failed to get Vulkan physical device surface capabilities");
 
+        let usage = Self::select_usage(
+            Self::REQUIRED_USAGE,
+            Self::OPTIONAL_USAGE,
+            surface_capabilities.supported_usage_flags,
+        )
+        .expect("Failed to find supported usage for Vulkan swapchain images");
+
+        let present_modes = unsafe {
+            surface
+                .ext
+                .get_physical_device_surface_present_modes(dev.physical, surface.surface)
+        }
+        .expect("Failed to get Vulkan physical device surface present modes");
+        let present_mode = Self::select_present_mode(present_mode, &present_modes);
+
+        let image_count = Self::get_image_count(
+            Self::PREFERRED_IMAGE_COUNT,
+            surface_capabilities.min_image_count,
+            surface_capabilities.max_image_count,
+        );
+
         let create_info = ash::vk::SwapchainCreateInfoKHR::builder()
             .surface(surface.surface)
-            .min_image_count(3)
+            .min_image_count(image_count)
             .image_format(dev.surface_format.format)
             .image_color_space(dev.surface_format.color_space)
             .image_extent(
@@ -402,22 +976,32 @@ impl Swapchain {
                     .build(),
             )
             .image_array_layers(1)
-            .image_usage(ash::vk::ImageUsageFlags::COLOR_ATTACHMENT)
+            .image_usage(usage)
             .image_sharing_mode(ash::vk::SharingMode::EXCLUSIVE)
             .pre_transform(surface_capabilities.current_transform)
             .composite_alpha(ash::vk::CompositeAlphaFlagsKHR::OPAQUE)
-            .present_mode(ash::vk::PresentModeKHR::FIFO)
+            .present_mode(present_mode)
             .clipped(true);
-        unsafe { ext.create_swapchain(&create_info, None) }
-            .expect("Failed to create Vulkan swapchain")
+        let swapchain = unsafe { ext.create_swapchain(&create_info, None) }
+            .expect("Failed to create Vulkan swapchain");
+        (swapchain, usage, present_mode)
     }
 
-    pub fn new(ctx: &Ctx, surface: &Surface, dev: &Dev, width: u32, height: u32) -> Self {
+    /// Creates a swapchain presenting with `present_mode`, or FIFO when it is not supported
+    pub fn new(
+        ctx: &Ctx,
+        surface: &Surface,
+        dev: &Dev,
+        width: u32,
+        height: u32,
+        present_mode: vk::PresentModeKHR,
+    ) -> Self {
         // Swapchain (instance, logical device, surface formats)
         let device: &ash::Device = dev.device.borrow();
         let ext = ash::extensions::khr::Swapchain::new(&ctx.instance, device);
 
-        let swapchain = Self::create_swapchain(&ext, surface, dev, width, height);
+        let (swapchain, usage, present_mode) =
+            Self::create_swapchain(&ext, surface, dev, width, height, present_mode);
 
         let swapchain_images = unsafe { ext.get_swapchain_images(swapchain) }
             .expect("Failed to get Vulkan swapchain images");
@@ -434,20 +1018,65 @@ impl Swapchain {
         }
 
         Self {
+            format: dev.surface_format.format,
+            color_space: dev.surface_format.color_space,
+            usage,
             images,
             swapchain,
             ext,
+            present_mode,
+        }
+    }
+
+    /// Returns whether the swapchain images are encoded to sRGB on write.
+    /// In that case, shaders are expected to output linear colors.
+    pub fn is_srgb(&self) -> bool {
+        Self::is_srgb_surface_format(self.get_surface_format())
+    }
+
+    pub fn get_surface_format(&self) -> vk::SurfaceFormatKHR {
+        vk::SurfaceFormatKHR {
+            format: self.format,
+            color_space: self.color_space,
         }
     }
 
-    pub fn recreate(&mut self, surface: &Surface, dev: &Dev, width: u32, height: u32) {
+    /// Returns whether swapchain images created with this surface format are encoded to sRGB on write
+    fn is_srgb_surface_format(surface_format: vk::SurfaceFormatKHR) -> bool {
+        Image::is_srgb_format(surface_format.format)
+    }
+
+    /// Returns whether swapchain images can be the destination of a blit, e.g. for upscaling
+    pub fn supports_blit(&self) -> bool {
+        self.usage.contains(vk::ImageUsageFlags::TRANSFER_DST)
+    }
+
+    /// Returns whether swapchain images can be copied from, e.g. for screenshots
+    pub fn supports_read_back(&self) -> bool {
+        self.usage.contains(vk::ImageUsageFlags::TRANSFER_SRC)
+    }
+
+    pub fn recreate(
+        &mut self,
+        surface: &Surface,
+        dev: &Dev,
+        width: u32,
+        height: u32,
+        present_mode: vk::PresentModeKHR,
+    ) {
         dev.wait();
 
         unsafe {
             self.ext.destroy_swapchain(self.swapchain, None);
         }
 
-        self.swapchain = Self::create_swapchain(&self.ext, surface, dev, width, height);
+        let (swapchain, usage, present_mode) =
+            Self::create_swapchain(&self.ext, surface, dev, width, height, present_mode);
+        self.swapchain = swapchain;
+        self.usage = usage;
+        self.present_mode = present_mode;
+        self.format = dev.surface_format.format;
+        self.color_space = dev.surface_format.color_space;
 
         let swapchain_images = unsafe { self.ext.get_swapchain_images(self.swapchain) }
             .expect("Failed to get Vulkan swapchain images");
@@ -473,52 +1102,377 @@ impl Drop for Swapchain {
     }
 }
 
+/// Device features an application can not run without. Features which are not required
+/// are still enabled when supported, and the renderer degrades gracefully without them.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RequiredFeatures {
+    pub sampler_anisotropy: bool,
+    pub wide_lines: bool,
+    pub fill_mode_non_solid: bool,
+    pub depth_clamp: bool,
+    pub multiview: bool,
+    pub geometry_shader: bool,
+    pub tessellation_shader: bool,
+}
+
+impl RequiredFeatures {
+    /// Returns the names of the required features which are not supported
+    pub fn get_missing(
+        &self,
+        supported: &vk::PhysicalDeviceFeatures,
+        multiview_supported: bool,
+    ) -> Vec<&'static str> {
+        let checks = [
+            (
+                self.sampler_anisotropy,
+                supported.sampler_anisotropy,
+                "samplerAnisotropy",
+            ),
+            (self.wide_lines, supported.wide_lines, "wideLines"),
+            (
+                self.fill_mode_non_solid,
+                supported.fill_mode_non_solid,
+                "fillModeNonSolid",
+            ),
+            (self.depth_clamp, supported.depth_clamp, "depthClamp"),
+            (
+                self.geometry_shader,
+                supported.geometry_shader,
+                "geometryShader",
+            ),
+            (
+                self.tessellation_shader,
+                supported.tessellation_shader,
+                "tessellationShader",
+            ),
+            (
+                self.multiview,
+                multiview_supported as vk::Bool32,
+                "multiview",
+            ),
+        ];
+        checks
+            .iter()
+            .filter(|(required, supported, _)| *required && *supported != vk::TRUE)
+            .map(|(_, _, name)| *name)
+            .collect()
+    }
+}
+
+/// Error returned when the device does not support some `RequiredFeatures`
+#[derive(Debug, Clone, PartialEq)]
+pub struct MissingFeatures {
+    pub names: Vec<&'static str>,
+}
+
+impl std::fmt::Display for MissingFeatures {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Missing required device features: {}",
+            self.names.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for MissingFeatures {}
+
+/// Error returned when a device can not be created from the physical device of choice
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceError {
+    /// There is no physical device at this index of `Ctx::enumerate_devices`
+    InvalidIndex(usize),
+    /// The physical device at this index has no graphics and present queues for the surface
+    UnsupportedSurface(usize),
+    MissingFeatures(MissingFeatures),
+    /// No physical device able to render to the surface matches this selection
+    NoMatchingDevice(DeviceSelection),
+}
+
+impl DeviceError {
+    /// Returns an error when the device at `index` of `infos` can not render to a surface
+    pub fn check_selection(infos: &[PhysicalDeviceInfo], index: usize) -> Result<(), Self> {
+        match infos.get(index) {
+            None => Err(DeviceError::InvalidIndex(index)),
+            Some(info) if !info.supports_surface => Err(DeviceError::UnsupportedSurface(index)),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+impl From<MissingFeatures> for DeviceError {
+    fn from(missing: MissingFeatures) -> Self {
+        DeviceError::MissingFeatures(missing)
+    }
+}
+
+impl std::fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceError::InvalidIndex(index) => {
+                write!(f, "Invalid physical device index {}", index)
+            }
+            DeviceError::UnsupportedSurface(index) => {
+                write!(f, "Physical device {} does not support the surface", index)
+            }
+            DeviceError::MissingFeatures(missing) => write!(f, "{}", missing),
+            DeviceError::NoMatchingDevice(selection) => {
+                write!(f, "No physical device matches {:?}", selection)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DeviceError {}
+
 pub struct Dev {
     pub surface_format: ash::vk::SurfaceFormatKHR,
+    /// Depth format selected once at creation, shared by passes and framebuffers
+    pub depth_format: ash::vk::Format,
+    /// Pool for command buffers recorded every frame
     pub graphics_command_pool: CommandPool,
+    /// Pool for one-time-submit command buffers, such as image uploads
+    pub transient_command_pool: CommandPool,
+    /// Pool for command buffers acquiring swapchain images on the present queue,
+    /// only when its family differs from the graphics one
+    pub present_command_pool: Option<CommandPool>,
+    /// Pool for one-time-submit uploads on the transfer queue,
+    /// only when its family differs from the graphics one
+    pub transfer_command_pool: Option<CommandPool>,
     pub graphics_queue: Queue,
+    /// Same queue as the graphics one when a family supports both graphics and presentation
+    pub present_queue: Queue,
+    /// Queue of a dedicated transfer family when available, otherwise the graphics one
+    pub transfer_queue: Queue,
     /// Needs to be public if we want to create buffers outside this module.
     /// The allocator is shared between the various buffers to release resources on drop.
     /// Moreover it needs to be inside a RefCell, so we can mutably borrow it on destroy.
     pub allocator: Rc<RefCell<vk_mem::Allocator>>,
+    /// Staging buffers recycled by uploads
+    pub staging_pool: RefCell<StagingPool>,
     pub device: Rc<ash::Device>,
     physical: ash::vk::PhysicalDevice,
+    /// Whether multiview rendering is supported and enabled
+    pub multiview: bool,
+    /// Whether depth clamp is supported and enabled, for shadow rendering
+    pub depth_clamp: bool,
+    /// Whether anisotropic filtering is supported and enabled
+    pub sampler_anisotropy: bool,
+    /// Whether polygon modes other than fill are supported and enabled, for wireframes
+    pub fill_mode_non_solid: bool,
+    /// Whether line widths other than 1.0 are supported and enabled
+    pub wide_lines: bool,
+    /// Whether geometry shader stages are supported and enabled
+    pub geometry_shader: bool,
+    /// Whether tessellation shader stages are supported and enabled
+    pub tessellation_shader: bool,
+    /// Whether sampled image descriptors can be updated after binding their sets,
+    /// as `descriptorBindingSampledImageUpdateAfterBind` is supported and enabled
+    pub update_after_bind: bool,
+    /// Whether samplers of materials created from now on use `SamplerInfo::with_quality_defaults`
+    pub quality_defaults: bool,
+    /// Available when VK_KHR_present_id and VK_KHR_present_wait are supported and enabled
+    pub present_wait: Option<PresentWait>,
+    /// Used to query properties of the physical device
+    instance: ash::Instance,
 }
 
 impl Dev {
-    fn get_graphics_queue_index(
+    /// Depth formats in order of preference
+    const DEPTH_FORMATS: [vk::Format; 4] = [
+        vk::Format::D32_SFLOAT,
+        vk::Format::D32_SFLOAT_S8_UINT,
+        vk::Format::D24_UNORM_S8_UINT,
+        vk::Format::D16_UNORM,
+    ];
+
+    /// Returns the first candidate which can be used as a depth attachment with optimal tiling
+    fn select_depth_format<F>(candidates: &[vk::Format], get_properties: F) -> Option<vk::Format>
+    where
+        F: Fn(vk::Format) -> vk::FormatProperties,
+    {
+        candidates.iter().copied().find(|&format| {
+            get_properties(format)
+                .optimal_tiling_features
+                .contains(vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT)
+        })
+    }
+
+    /// Surface format preferred for presentation, which encodes linear shader outputs to sRGB
+    pub const PREFERRED_SURFACE_FORMAT: vk::SurfaceFormatKHR = vk::SurfaceFormatKHR {
+        format: vk::Format::B8G8R8A8_SRGB,
+        color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+    };
+
+    /// Returns the preferred surface format when available, otherwise the first one.
+    /// A single `UNDEFINED` format means the surface has no preference.
+    fn select_surface_format(formats: &[vk::SurfaceFormatKHR]) -> vk::SurfaceFormatKHR {
+        let preferred = Self::PREFERRED_SURFACE_FORMAT;
+        match formats {
+            [] => panic!("Failed to find any Vulkan surface format"),
+            [only] if only.format == vk::Format::UNDEFINED => preferred,
+            _ => formats
+                .iter()
+                .copied()
+                .find(|f| f.format == preferred.format && f.color_space == preferred.color_space)
+                .unwrap_or(formats[0]),
+        }
+    }
+
+    /// Returns graphics and present queue family indices from the flags of each family and
+    /// whether it supports presentation. A family supporting both is preferred.
+    /// Without presentation, the graphics family is returned as the present one as well.
+    fn select_queue_families(
+        families: &[(vk::QueueFlags, bool)],
+        present: bool,
+    ) -> Option<(u32, u32)> {
+        let is_graphics = |flags: &vk::QueueFlags| flags.contains(vk::QueueFlags::GRAPHICS);
+
+        if !present {
+            let graphics = families.iter().position(|(flags, _)| is_graphics(flags))?;
+            return Some((graphics as u32, graphics as u32));
+        }
+
+        if let Some(i) = families
+            .iter()
+            .position(|(flags, present)| is_graphics(flags) && *present)
+        {
+            return Some((i as u32, i as u32));
+        }
+
+        let graphics = families.iter().position(|(flags, _)| is_graphics(flags))?;
+        let present = families.iter().position(|(_, present)| *present)?;
+        Some((graphics as u32, present as u32))
+    }
+
+    /// Returns the family of a queue dedicated to transfers, preferring one supporting neither
+    /// graphics nor compute, so that uploads do not compete with rendering. None when all
+    /// the families supporting transfers support graphics as well.
+    fn select_transfer_family(families: &[(vk::QueueFlags, bool)]) -> Option<u32> {
+        let is_transfer = |flags: &vk::QueueFlags| {
+            flags.contains(vk::QueueFlags::TRANSFER) && !flags.contains(vk::QueueFlags::GRAPHICS)
+        };
+
+        families
+            .iter()
+            .position(|(flags, _)| is_transfer(flags) && !flags.contains(vk::QueueFlags::COMPUTE))
+            .or_else(|| families.iter().position(|(flags, _)| is_transfer(flags)))
+            .map(|i| i as u32)
+    }
+
+    /// Returns the capabilities of each queue family and whether it can present to `surface`,
+    /// which is never the case without a surface
+    fn get_queue_families(
         instance: &ash::Instance,
         physical: ash::vk::PhysicalDevice,
-        surface: &Surface,
-    ) -> u32 {
+        surface: Option<&Surface>,
+    ) -> Vec<(vk::QueueFlags, bool)> {
         // Queue information (instance, physical device)
         let queue_properties =
             unsafe { instance.get_physical_device_queue_family_properties(physical) };
 
-        let mut graphics_queue_index = std::u32::MAX;
+        queue_properties
+            .iter()
+            .enumerate()
+            .map(|(i, queue)| {
+                let supports_presentation = match surface {
+                    Some(surface) => unsafe {
+                        surface.ext.get_physical_device_surface_support(
+                            physical,
+                            i as u32,
+                            surface.surface,
+                        )
+                    }
+                    .expect("Failed to check presentation support for Vulkan physical device"),
+                    None => false,
+                };
+                (queue.queue_flags, supports_presentation)
+            })
+            .collect()
+    }
 
-        for (i, queue) in queue_properties.iter().enumerate() {
-            let supports_presentation = unsafe {
-                surface
-                    .ext
-                    .get_physical_device_surface_support(physical, i as u32, surface.surface)
-            }
-            .expect("Failed to check presentation support for Vulkan physical device");
-            if queue.queue_flags.contains(ash::vk::QueueFlags::GRAPHICS) && supports_presentation {
-                graphics_queue_index = i as u32;
-                break;
-            }
+    fn get_queue_indices(
+        instance: &ash::Instance,
+        physical: ash::vk::PhysicalDevice,
+        surface: Option<&Surface>,
+    ) -> (u32, u32) {
+        let families = Self::get_queue_families(instance, physical, surface);
+        Self::select_queue_families(&families, surface.is_some())
+            .expect("Failed to find graphics and present queues")
+    }
+
+    pub fn new(ctx: &Ctx, surface: &Surface) -> Self {
+        Self::with_required_features(ctx, surface, &RequiredFeatures::default())
+            .expect("Failed to create Vulkan device")
+    }
+
+    /// Creates a device failing when any of the `required` features is not supported
+    pub fn with_required_features(
+        ctx: &Ctx,
+        surface: &Surface,
+        required: &RequiredFeatures,
+    ) -> Result<Self, DeviceError> {
+        Self::with_selection(ctx, surface, required, &DeviceSelection::from_env())
+    }
+
+    /// Creates a device without a surface, which can only render offscreen. Its present queue
+    /// is the graphics one, and its surface format is `PREFERRED_SURFACE_FORMAT`.
+    pub fn headless(ctx: &Ctx) -> Self {
+        Self::with_surface(
+            ctx,
+            None,
+            &RequiredFeatures::default(),
+            &DeviceSelection::from_env(),
+        )
+        .expect("Failed to create Vulkan device")
+    }
+
+    /// Creates a device from the physical device chosen by `selection`
+    pub fn with_selection(
+        ctx: &Ctx,
+        surface: &Surface,
+        required: &RequiredFeatures,
+        selection: &DeviceSelection,
+    ) -> Result<Self, DeviceError> {
+        Self::with_surface(ctx, Some(surface), required, selection)
+    }
+
+    fn with_surface(
+        ctx: &Ctx,
+        surface: Option<&Surface>,
+        required: &RequiredFeatures,
+        selection: &DeviceSelection,
+    ) -> Result<Self, DeviceError> {
+        let infos = ctx.get_device_infos(surface);
+        for info in &infos {
+            println!("Physical device: {:?}", info.name);
         }
 
-        assert!(
-            graphics_queue_index != std::u32::MAX,
-            "Failed to find graphics queue"
-        );
+        let index = selection
+            .select(&infos)
+            .ok_or_else(|| DeviceError::NoMatchingDevice(selection.clone()))?;
+        println!("Selected device: {:?}", infos[index].name);
+        Ok(Self::with_physical(ctx, surface, required, index)?)
+    }
 
-        graphics_queue_index
+    /// Creates a device from the physical device at `index` of `Ctx::enumerate_devices`,
+    /// failing when there is no such device or it does not support `surface`
+    pub fn with_selector(
+        ctx: &Ctx,
+        surface: &Surface,
+        required: &RequiredFeatures,
+        index: usize,
+    ) -> Result<Self, DeviceError> {
+        DeviceError::check_selection(&ctx.enumerate_devices(surface), index)?;
+        Ok(Self::with_physical(ctx, Some(surface), required, index)?)
     }
 
-    pub fn new(ctx: &Ctx, surface: &Surface) -> Self {
+    fn with_physical(
+        ctx: &Ctx,
+        surface: Option<&Surface>,
+        required: &RequiredFeatures,
+        index: usize,
+    ) -> Result<Self, MissingFeatures> {
         // Physical device
         let physical = {
             let phydevs = unsafe {
@@ -526,25 +1480,44 @@ impl Dev {
                     .enumerate_physical_devices()
                     .expect("Failed to enumerate Vulkan physical devices")
             };
-
-            for physical in &phydevs {
-                let properties = unsafe { ctx.instance.get_physical_device_properties(*physical) };
-                let name = unsafe { CStr::from_ptr(properties.device_name.as_ptr()) };
-                println!("Physical device: {:?}", name);
-            }
-
-            // Choose first one for now
-            phydevs[0]
+            assert!(
+                index < phydevs.len(),
+                "Invalid physical device index {}",
+                index
+            );
+            phydevs[index]
         };
 
-        let graphics_queue_index = Dev::get_graphics_queue_index(&ctx.instance, physical, surface);
+        let (graphics_queue_index, present_queue_index) =
+            Dev::get_queue_indices(&ctx.instance, physical, surface);
+        let transfer_queue_index =
+            Dev::select_transfer_family(&Dev::get_queue_families(&ctx.instance, physical, surface))
+                .unwrap_or(graphics_queue_index);
 
         // Logical device (physical device, surface, device required extensions (swapchain), queue information)
-        let queue_infos = vec![ash::vk::DeviceQueueCreateInfo::builder()
+        let mut queue_infos = vec![ash::vk::DeviceQueueCreateInfo::builder()
             .queue_family_index(graphics_queue_index)
             // Highest priority for a single graphics queue
             .queue_priorities(&[1.0])
             .build()];
+        if present_queue_index != graphics_queue_index {
+            queue_infos.push(
+                ash::vk::DeviceQueueCreateInfo::builder()
+                    .queue_family_index(present_queue_index)
+                    .queue_priorities(&[1.0])
+                    .build(),
+            );
+        }
+        if transfer_queue_index != graphics_queue_index
+            && transfer_queue_index != present_queue_index
+        {
+            queue_infos.push(
+                ash::vk::DeviceQueueCreateInfo::builder()
+                    .queue_family_index(transfer_queue_index)
+                    .queue_priorities(&[1.0])
+                    .build(),
+            );
+        }
 
         let mut device_create_info =
             ash::vk::DeviceCreateInfo::builder().queue_create_infos(&queue_infos);
@@ -557,6 +1530,8 @@ impl Dev {
                 .expect("Failed to enumerate Vulkan device extension properties");
 
         let mut vulkan_memory_model = false;
+        // Enabled only if their features are supported as well
+        let mut present_wait_extensions = vec![];
 
         for prop in extension_properties.iter() {
             let name = unsafe { CStr::from_ptr(prop.extension_name.as_ptr()) }
@@ -566,11 +1541,15 @@ impl Dev {
                 enabled_extensions.push(prop.extension_name.as_ptr());
                 vulkan_memory_model = true;
             }
+            // Only useful to present to a surface
+            if surface.is_some() && (name == "VK_KHR_present_id" || name == "VK_KHR_present_wait") {
+                present_wait_extensions.push(prop.extension_name.as_ptr());
+            }
             println!("\t{}", name);
         }
-        enabled_extensions.push(ash::extensions::khr::Swapchain::name().as_ptr());
-
-        device_create_info = device_create_info.enabled_extension_names(&enabled_extensions);
+        if surface.is_some() {
+            enabled_extensions.push(ash::extensions::khr::Swapchain::name().as_ptr());
+        }
 
         // Used only if extension is available
         let mut vulkan_memory_model_features =
@@ -581,6 +1560,102 @@ impl Dev {
             device_create_info = device_create_info.push_next(&mut vulkan_memory_model_features);
         }
 
+        // Enable multiview when supported, for stereo rendering
+        let mut supported_multiview_features = vk::PhysicalDeviceMultiviewFeatures::default();
+        let mut supported_indexing_features =
+            vk::PhysicalDeviceDescriptorIndexingFeatures::default();
+        let mut supported_present_id_features = vk::PhysicalDevicePresentIdFeaturesKHR::default();
+        let mut supported_present_wait_features =
+            vk::PhysicalDevicePresentWaitFeaturesKHR::default();
+        let mut features = vk::PhysicalDeviceFeatures2::builder()
+            .push_next(&mut supported_multiview_features)
+            .push_next(&mut supported_indexing_features);
+        if present_wait_extensions.len() == 2 {
+            features = features
+                .push_next(&mut supported_present_id_features)
+                .push_next(&mut supported_present_wait_features);
+        }
+        let mut features = features.build();
+        unsafe {
+            ctx.instance
+                .get_physical_device_features2(physical, &mut features)
+        };
+        let multiview = supported_multiview_features.multiview == vk::TRUE;
+        println!("Multiview: {}", multiview);
+
+        let missing = required.get_missing(&features.features, multiview);
+        if !missing.is_empty() {
+            return Err(MissingFeatures { names: missing });
+        }
+
+        let mut multiview_features = vk::PhysicalDeviceMultiviewFeatures::builder()
+            .multiview(true)
+            .build();
+        if multiview {
+            device_create_info = device_create_info.push_next(&mut multiview_features);
+        }
+
+        // Enable update-after-bind when supported, for streaming textures into a bound array
+        let update_after_bind = supported_indexing_features
+            .descriptor_binding_sampled_image_update_after_bind
+            == vk::TRUE;
+        println!("Update after bind: {}", update_after_bind);
+
+        let mut indexing_features = vk::PhysicalDeviceDescriptorIndexingFeatures::builder()
+            .descriptor_binding_sampled_image_update_after_bind(true)
+            .build();
+        if update_after_bind {
+            device_create_info = device_create_info.push_next(&mut indexing_features);
+        }
+
+        // Enable present wait when supported, for frame pacing
+        let present_wait = supported_present_id_features.present_id == vk::TRUE
+            && supported_present_wait_features.present_wait == vk::TRUE;
+        println!("Present wait: {}", present_wait);
+
+        let mut present_id_features = vk::PhysicalDevicePresentIdFeaturesKHR::builder()
+            .present_id(true)
+            .build();
+        let mut present_wait_features = vk::PhysicalDevicePresentWaitFeaturesKHR::builder()
+            .present_wait(true)
+            .build();
+        if present_wait {
+            enabled_extensions.extend(present_wait_extensions);
+            device_create_info = device_create_info
+                .push_next(&mut present_id_features)
+                .push_next(&mut present_wait_features);
+        }
+        device_create_info = device_create_info.enabled_extension_names(&enabled_extensions);
+
+        // Enable depth clamp when supported, for shadow casters outside the light's frustum
+        let depth_clamp = features.features.depth_clamp == vk::TRUE;
+        println!("Depth clamp: {}", depth_clamp);
+
+        let sampler_anisotropy = features.features.sampler_anisotropy == vk::TRUE;
+        println!("Sampler anisotropy: {}", sampler_anisotropy);
+
+        // Enable wireframe rasterization when supported
+        let fill_mode_non_solid = features.features.fill_mode_non_solid == vk::TRUE;
+        println!("Fill mode non solid: {}", fill_mode_non_solid);
+        let wide_lines = features.features.wide_lines == vk::TRUE;
+        println!("Wide lines: {}", wide_lines);
+
+        // Enable geometry and tessellation stages when supported, for pipelines using them
+        let geometry_shader = features.features.geometry_shader == vk::TRUE;
+        println!("Geometry shader: {}", geometry_shader);
+        let tessellation_shader = features.features.tessellation_shader == vk::TRUE;
+        println!("Tessellation shader: {}", tessellation_shader);
+
+        let enabled_features = vk::PhysicalDeviceFeatures::builder()
+            .depth_clamp(depth_clamp)
+            .sampler_anisotropy(sampler_anisotropy)
+            .geometry_shader(geometry_shader)
+            .tessellation_shader(tessellation_shader)
+            .wide_lines(wide_lines)
+            .fill_mode_non_solid(fill_mode_non_solid)
+            .build();
+        device_create_info = device_create_info.enabled_features(&enabled_features);
+
         let device_create_info = device_create_info.build();
 
         let device = unsafe {
@@ -590,24 +1665,55 @@ impl Dev {
         };
         let device = Rc::new(device);
 
+        let present_wait = if present_wait {
+            Some(PresentWait::new(&ctx.instance, &device))
+        } else {
+            None
+        };
+
         let graphics_queue = Queue::new(&device, graphics_queue_index);
+        let present_queue = Queue::new(&device, present_queue_index);
+        let transfer_queue = Queue::new(&device, transfer_queue_index);
+        println!("Transfer queue family: {}", transfer_queue_index);
+
+        // Command pools
+        let graphics_command_pool = CommandPool::graphics_frame(&device, graphics_queue_index);
+        let transient_command_pool = CommandPool::transient(&device, graphics_queue_index);
+        let present_command_pool = if present_queue_index != graphics_queue_index {
+            Some(CommandPool::graphics_frame(&device, present_queue_index))
+        } else {
+            None
+        };
+        let transfer_command_pool = if transfer_queue_index != graphics_queue_index {
+            Some(CommandPool::transient(&device, transfer_queue_index))
+        } else {
+            None
+        };
 
-        // Command pool
-        let graphics_command_pool = CommandPool::new(&device, graphics_queue_index);
+        // Surface format, also used by offscreen passes
+        let surface_format = match surface {
+            Some(surface) => {
+                let surface_formats = unsafe {
+                    surface
+                        .ext
+                        .get_physical_device_surface_formats(physical, surface.surface)
+                }
+                .expect("Failed to get Vulkan physical device surface formats");
 
-        // Surface format
-        let surface_format = {
-            let surface_formats = unsafe {
-                surface
-                    .ext
-                    .get_physical_device_surface_formats(physical, surface.surface)
+                Dev::select_surface_format(&surface_formats)
             }
-            .expect("Failed to get Vulkan physical device surface formats");
-
-            surface_formats[1]
+            None => Dev::PREFERRED_SURFACE_FORMAT,
         };
         println!("Surface format: {:?}", surface_format.format);
 
+        // Depth format
+        let depth_format = Dev::select_depth_format(&Dev::DEPTH_FORMATS, |format| unsafe {
+            ctx.instance
+                .get_physical_device_format_properties(physical, format)
+        })
+        .expect("Failed to find a supported Vulkan depth format");
+        println!("Depth format: {:?}", depth_format);
+
         let allocator = {
             let create_info = vk_mem::AllocatorCreateInfo {
                 physical_device: physical,
@@ -619,16 +1725,178 @@ impl Dev {
         }
         .expect("Failed to create Vulkan allocator");
 
-        Self {
+        Ok(Self {
             surface_format,
+            depth_format,
             graphics_command_pool,
+            transient_command_pool,
+            present_command_pool,
+            transfer_command_pool,
             graphics_queue,
+            present_queue,
+            transfer_queue,
             allocator: Rc::new(RefCell::new(allocator)),
+            staging_pool: RefCell::new(StagingPool::default()),
             device: device,
             physical,
+            multiview,
+            depth_clamp,
+            sampler_anisotropy,
+            fill_mode_non_solid,
+            wide_lines,
+            geometry_shader,
+            tessellation_shader,
+            update_after_bind,
+            quality_defaults: false,
+            present_wait,
+            instance: ctx.instance.clone(),
+        })
+    }
+
+    /// Returns the pool for command buffers submitted to the transfer queue
+    pub fn get_transfer_command_pool(&self) -> &CommandPool {
+        self.transfer_command_pool
+            .as_ref()
+            .unwrap_or(&self.transient_command_pool)
+    }
+
+    /// Returns the ownership transfer uploaded images need before the graphics queue uses them,
+    /// if any
+    pub fn get_upload_transfer(&self) -> Option<UploadTransfer> {
+        UploadTransfer::new(
+            self.transfer_queue.family_index,
+            self.graphics_queue.family_index,
+        )
+    }
+
+    /// Returns a staging buffer of at least `size` bytes from the pool, which should be
+    /// released with `release_staging` once the copies reading it are finished
+    pub fn acquire_staging(&self, size: vk::DeviceSize) -> Buffer {
+        self.staging_pool
+            .borrow_mut()
+            .acquire(&self.allocator, size)
+    }
+
+    pub fn release_staging(&self, staging: Buffer) {
+        self.staging_pool.borrow_mut().release(staging);
+    }
+
+    /// Records the operations of `record` into a single command buffer, which is submitted once
+    /// and waited with a single fence, for example to upload many textures at once
+    pub fn batch_submit<F>(&self, record: F)
+    where
+        F: FnOnce(&mut UploadContext),
+    {
+        let mut batch = UploadContext::new(self);
+        record(&mut batch);
+        batch.finish(self);
+    }
+
+    /// Returns the ownership transfer swapchain images need before presentation, if any
+    pub fn get_queue_transfer(&self) -> Option<QueueTransfer> {
+        QueueTransfer::new(
+            self.graphics_queue.family_index,
+            self.present_queue.family_index,
+        )
+    }
+
+    pub fn get_properties(&self) -> vk::PhysicalDeviceProperties {
+        unsafe { self.instance.get_physical_device_properties(self.physical) }
+    }
+
+    /// Returns `maxSamplerAnisotropy`, or 1 when anisotropic filtering is not supported
+    pub fn get_max_anisotropy(&self) -> f32 {
+        if self.sampler_anisotropy {
+            self.get_properties().limits.max_sampler_anisotropy
+        } else {
+            1.0
+        }
+    }
+
+    /// Returns the parameters a material sampler should be created with, which are `info`
+    /// with the quality defaults applied when enabled
+    pub fn get_material_sampler_info(&self, info: &SamplerInfo) -> SamplerInfo {
+        if self.quality_defaults {
+            info.with_quality_defaults(self.get_max_anisotropy())
+        } else {
+            *info
+        }
+    }
+
+    pub fn get_format_properties(&self, format: vk::Format) -> vk::FormatProperties {
+        unsafe {
+            self.instance
+                .get_physical_device_format_properties(self.physical, format)
         }
     }
 
+    /// Returns whether images with this `format` and `tiling` can be created with `usage`
+    pub fn supports_format(
+        &self,
+        format: vk::Format,
+        usage: vk::ImageUsageFlags,
+        tiling: vk::ImageTiling,
+    ) -> bool {
+        Self::is_format_supported(&self.get_format_properties(format), usage, tiling)
+    }
+
+    /// Returns the format features needed by images with `usage`
+    fn get_required_features(usage: vk::ImageUsageFlags) -> vk::FormatFeatureFlags {
+        let mapping = [
+            (
+                vk::ImageUsageFlags::COLOR_ATTACHMENT,
+                vk::FormatFeatureFlags::COLOR_ATTACHMENT,
+            ),
+            (
+                vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+                vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT,
+            ),
+            (
+                vk::ImageUsageFlags::SAMPLED,
+                vk::FormatFeatureFlags::SAMPLED_IMAGE,
+            ),
+            (
+                vk::ImageUsageFlags::STORAGE,
+                vk::FormatFeatureFlags::STORAGE_IMAGE,
+            ),
+            (
+                vk::ImageUsageFlags::TRANSFER_SRC,
+                vk::FormatFeatureFlags::TRANSFER_SRC,
+            ),
+            (
+                vk::ImageUsageFlags::TRANSFER_DST,
+                vk::FormatFeatureFlags::TRANSFER_DST,
+            ),
+        ];
+
+        let mut features = vk::FormatFeatureFlags::empty();
+        for (image_usage, feature) in mapping.iter() {
+            if usage.contains(*image_usage) {
+                features |= *feature;
+            }
+        }
+        features
+    }
+
+    /// Returns whether vertex attributes can be fetched from buffers with this `format`
+    pub fn supports_vertex_format(&self, format: vk::Format) -> bool {
+        self.get_format_properties(format)
+            .buffer_features
+            .contains(vk::FormatFeatureFlags::VERTEX_BUFFER)
+    }
+
+    fn is_format_supported(
+        properties: &vk::FormatProperties,
+        usage: vk::ImageUsageFlags,
+        tiling: vk::ImageTiling,
+    ) -> bool {
+        let features = match tiling {
+            vk::ImageTiling::LINEAR => properties.linear_tiling_features,
+            _ => properties.optimal_tiling_features,
+        };
+        features.contains(Self::get_required_features(usage))
+    }
+
     pub fn wait(&self) {
         unsafe {
             self.device
@@ -641,62 +1909,617 @@ impl Dev {
 impl Drop for Dev {
     fn drop(&mut self) {
         self.wait();
+        self.staging_pool.borrow_mut().clear();
         self.allocator.deref().borrow_mut().destroy();
         self.graphics_command_pool.destroy();
+        self.transient_command_pool.destroy();
+        if let Some(pool) = &mut self.present_command_pool {
+            pool.destroy();
+        }
+        if let Some(pool) = &mut self.transfer_command_pool {
+            pool.destroy();
+        }
         unsafe {
             self.device.destroy_device(None);
         }
     }
 }
 
+/// Load operations of the attachments of the main render pass, all `CLEAR` by default.
+/// `DONT_CARE` skips the clear, which is only correct when every pixel of an attachment is
+/// written before being read, e.g. albedo when the scene always covers the whole screen.
+/// Depth and light should always be cleared, as depth testing and additive blending read them.
+#[derive(Debug, Clone, Copy)]
+pub struct AttachmentLoadOps {
+    pub present: vk::AttachmentLoadOp,
+    pub depth: vk::AttachmentLoadOp,
+    pub albedo: vk::AttachmentLoadOp,
+    pub normal: vk::AttachmentLoadOp,
+    pub light: vk::AttachmentLoadOp,
+}
+
+impl Default for AttachmentLoadOps {
+    fn default() -> Self {
+        Self {
+            present: vk::AttachmentLoadOp::CLEAR,
+            depth: vk::AttachmentLoadOp::CLEAR,
+            albedo: vk::AttachmentLoadOp::CLEAR,
+            normal: vk::AttachmentLoadOp::CLEAR,
+            light: vk::AttachmentLoadOp::CLEAR,
+        }
+    }
+}
+
+/// Formats of the color attachments of the G-buffer of the main render pass.
+/// The presentation attachment always has the format of the surface.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AttachmentFormats {
+    pub albedo: vk::Format,
+    pub normal: vk::Format,
+    pub light: vk::Format,
+}
+
+impl AttachmentFormats {
+    /// Albedo has the same format as the surface, as it is the case by default
+    pub fn new(surface_format: vk::Format) -> Self {
+        Self {
+            albedo: surface_format,
+            normal: Framebuffer::NORMAL_FORMAT,
+            light: Framebuffer::LIGHT_FORMAT,
+        }
+    }
+
+    /// Albedo with enough range for a tone-mapping pipeline
+    pub fn hdr(surface_format: vk::Format) -> Self {
+        Self {
+            albedo: vk::Format::R16G16B16A16_SFLOAT,
+            ..Self::new(surface_format)
+        }
+    }
+}
+
 pub struct Pass {
     pub render: ash::vk::RenderPass,
+    /// Pipelines and framebuffers created with a pass can be used with other passes with the same value
+    pub compatibility: u64,
+    /// Whether depth is preserved after the pass so that post effects can read it
+    pub read_depth: bool,
+    /// Formats framebuffers should use for the attachments of this pass
+    pub formats: AttachmentFormats,
+    /// Format of the depth attachment, which is always `Dev::depth_format`
+    pub depth_format: vk::Format,
     device: Rc<ash::Device>,
 }
 
 impl Pass {
+    /// Subpass where light volumes are accumulated reading the G-buffer
+    pub const LIGHTING_SUBPASS: u32 = 1;
+    /// Subpass where the lit scene is composed into the presentation image
+    pub const PRESENT_SUBPASS: u32 = 2;
+
     pub fn new(dev: &mut Dev) -> Self {
-        // Render pass (swapchain surface format, device)
+        Self::new_with_depth_read(dev, false)
+    }
+
+    /// Returns a value which is the same for compatible render passes, namely passes
+    /// with the same number of subpasses and attachments with the same formats and samples
+    fn get_compatibility(
+        attachments: &[vk::AttachmentDescription],
+        subpass_count: usize,
+        view_mask: u32,
+    ) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        subpass_count.hash(&mut hasher);
+        view_mask.hash(&mut hasher);
+        for attachment in attachments {
+            attachment.format.hash(&mut hasher);
+            attachment.samples.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// Returns store op and final layout of the depth attachment.
+    /// Storing depth costs bandwidth, therefore it is done only when some effect needs to read it.
+    fn get_depth_store(read_depth: bool) -> (vk::AttachmentStoreOp, vk::ImageLayout) {
+        if read_depth {
+            (
+                vk::AttachmentStoreOp::STORE,
+                vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+            )
+        } else {
+            (
+                vk::AttachmentStoreOp::DONT_CARE,
+                vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+            )
+        }
+    }
+
+    /// Creates a render pass where depth can be preserved for depth-consuming effects,
+    /// such as SSAO, fog, or soft particles
+    pub fn new_with_depth_read(dev: &mut Dev, read_depth: bool) -> Self {
+        Self::new_with_load_ops(dev, read_depth, &AttachmentLoadOps::default())
+    }
+
+    /// Creates a render pass where attachments are loaded according to `load_ops`
+    pub fn new_with_load_ops(
+        dev: &mut Dev,
+        read_depth: bool,
+        load_ops: &AttachmentLoadOps,
+    ) -> Self {
+        let formats = AttachmentFormats::new(dev.surface_format.format);
+        Self::create(
+            dev,
+            read_depth,
+            None,
+            vk::ImageLayout::PRESENT_SRC_KHR,
+            load_ops,
+            &formats,
+        )
+    }
+
+    /// Creates a render pass whose G-buffer attachments have the specified `formats`
+    pub fn new_with_formats(dev: &mut Dev, read_depth: bool, formats: &AttachmentFormats) -> Self {
+        Self::create(
+            dev,
+            read_depth,
+            None,
+            vk::ImageLayout::PRESENT_SRC_KHR,
+            &AttachmentLoadOps::default(),
+            formats,
+        )
+    }
+
+    /// Creates a render pass which renders all the views of `multiview` at once.
+    /// Framebuffer attachments should have an array layer for each view.
+    pub fn new_multiview(dev: &mut Dev, read_depth: bool, multiview: &MultiviewConfig) -> Self {
+        assert!(dev.multiview, "Multiview is not supported by this device");
+        let formats = AttachmentFormats::new(dev.surface_format.format);
+        Self::create(
+            dev,
+            read_depth,
+            Some(multiview),
+            vk::ImageLayout::PRESENT_SRC_KHR,
+            &AttachmentLoadOps::default(),
+            &formats,
+        )
+    }
+
+    /// Creates a render pass which leaves the color image ready to be sampled, instead of presented.
+    /// It is compatible with the one created by `new`, hence it can be used with the same pipelines.
+    pub fn new_offscreen(dev: &mut Dev) -> Self {
+        let formats = AttachmentFormats::new(dev.surface_format.format);
+        Self::create(
+            dev,
+            false,
+            None,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            &AttachmentLoadOps::default(),
+            &formats,
+        )
+    }
+
+    /// Creates a depth-only render pass for shadow maps, leaving depth ready to be sampled
+    pub fn new_shadow(dev: &mut Dev) -> Self {
+        let depth_attachment = vk::AttachmentDescription::builder()
+            .format(dev.depth_format)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .load_op(vk::AttachmentLoadOp::CLEAR)
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .final_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+            .build();
+
+        let attachments = [depth_attachment];
+
+        let depth_ref = vk::AttachmentReference::builder()
+            .attachment(0)
+            .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+            .build();
+
+        let subpasses = [vk::SubpassDescription::builder()
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .depth_stencil_attachment(&depth_ref)
+            .build()];
+
+        // Previous reads of the shadow map should finish before writing it again
+        let init_dependency = vk::SubpassDependency::builder()
+            .src_subpass(vk::SUBPASS_EXTERNAL)
+            .dst_subpass(0)
+            .src_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+            .src_access_mask(vk::AccessFlags::SHADER_READ)
+            .dst_stage_mask(vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS)
+            .dst_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+            .build();
+
+        // Depth writes should be visible to the shaders sampling the shadow map
+        let depth_read_dependency = vk::SubpassDependency::builder()
+            .src_subpass(0)
+            .dst_subpass(vk::SUBPASS_EXTERNAL)
+            .src_stage_mask(vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
+            .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .build();
+
+        let dependencies = [init_dependency, depth_read_dependency];
+
+        let create_info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(&dependencies)
+            .build();
+        let render = unsafe { dev.device.create_render_pass(&create_info, None) }
+            .expect("Failed to create Vulkan render pass");
+
+        let compatibility = Self::get_compatibility(&attachments, subpasses.len(), 0);
+
+        Self {
+            render,
+            compatibility,
+            read_depth: true,
+            // There are no color attachments
+            formats: AttachmentFormats::new(dev.surface_format.format),
+            depth_format: dev.depth_format,
+            device: Rc::clone(&dev.device),
+        }
+    }
+
+    /// Creates a render pass compositing blended geometry over a color attachment of `color_format`,
+    /// left in `color_layout` by a previous pass such as the one of `new_offscreen`. Blended
+    /// fragments are accumulated into the attachments of `Wboit`, depth tested against the depth
+    /// of a pass which preserves it, then the resolve subpass composites them over the color.
+    /// Attachments are color, depth, accumulation, and revealage, see `Wboit::new`.
+    pub fn new_oit(dev: &mut Dev, color_format: vk::Format, color_layout: vk::ImageLayout) -> Self {
+        let color_attachment = vk::AttachmentDescription::builder()
+            .format(color_format)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .load_op(vk::AttachmentLoadOp::LOAD)
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(color_layout)
+            .final_layout(color_layout)
+            .build();
+
+        // Depth of the opaque scene, only tested
+        let depth_attachment = vk::AttachmentDescription::builder()
+            .format(dev.depth_format)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .load_op(vk::AttachmentLoadOp::LOAD)
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+            .final_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+            .build();
+
+        // Accumulation and revealage are only needed within the pass
+        let accum_attachment = vk::AttachmentDescription::builder()
+            .format(Wboit::ACCUM_FORMAT)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .load_op(vk::AttachmentLoadOp::CLEAR)
+            .store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .build();
+
+        let revealage_attachment = vk::AttachmentDescription {
+            format: Wboit::REVEALAGE_FORMAT,
+            ..accum_attachment
+        };
+
+        let attachments = [
+            color_attachment,
+            depth_attachment,
+            accum_attachment,
+            revealage_attachment,
+        ];
+
+        let color_ref = vk::AttachmentReference::builder()
+            .attachment(0)
+            .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .build();
+
+        let depth_ref = vk::AttachmentReference::builder()
+            .attachment(1)
+            .layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+            .build();
+
+        let accum_ref = vk::AttachmentReference::builder()
+            .attachment(2)
+            .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .build();
+
+        let revealage_ref = vk::AttachmentReference::builder()
+            .attachment(3)
+            .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .build();
+
+        let accum_input_ref = vk::AttachmentReference::builder()
+            .attachment(2)
+            .layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .build();
+
+        let revealage_input_ref = vk::AttachmentReference::builder()
+            .attachment(3)
+            .layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .build();
+
+        let accumulation_color_refs = [accum_ref, revealage_ref];
+        let resolve_color_refs = [color_ref];
+        let resolve_input_refs = [accum_input_ref, revealage_input_ref];
+
+        let subpasses = [
+            vk::SubpassDescription::builder()
+                .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+                .color_attachments(&accumulation_color_refs)
+                .depth_stencil_attachment(&depth_ref)
+                .build(),
+            vk::SubpassDescription::builder()
+                .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+                .color_attachments(&resolve_color_refs)
+                .input_attachments(&resolve_input_refs)
+                .build(),
+        ];
+
+        // Color and depth written by the previous pass should be visible to this one
+        let init_dependency = vk::SubpassDependency::builder()
+            .src_subpass(vk::SUBPASS_EXTERNAL)
+            .dst_subpass(Wboit::ACCUMULATION_SUBPASS)
+            .src_stage_mask(
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            )
+            .src_access_mask(
+                vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            )
+            .dst_stage_mask(
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+            )
+            .dst_access_mask(
+                vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ,
+            )
+            .build();
+
+        let color_dependency = vk::SubpassDependency::builder()
+            .src_subpass(vk::SUBPASS_EXTERNAL)
+            .dst_subpass(Wboit::RESOLVE_SUBPASS)
+            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .dst_access_mask(
+                vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            )
+            .build();
+
+        let output_to_input_dependency = vk::SubpassDependency::builder()
+            .src_subpass(Wboit::ACCUMULATION_SUBPASS)
+            .dst_subpass(Wboit::RESOLVE_SUBPASS)
+            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+            .dst_access_mask(vk::AccessFlags::INPUT_ATTACHMENT_READ)
+            .dependency_flags(vk::DependencyFlags::BY_REGION)
+            .build();
+
+        // The composited color should be visible to presentation or to passes sampling it
+        let resolve_dependency = vk::SubpassDependency::builder()
+            .src_subpass(Wboit::RESOLVE_SUBPASS)
+            .dst_subpass(vk::SUBPASS_EXTERNAL)
+            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_stage_mask(
+                vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            )
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::MEMORY_READ)
+            .build();
+
+        let dependencies = [
+            init_dependency,
+            color_dependency,
+            output_to_input_dependency,
+            resolve_dependency,
+        ];
+
+        let create_info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(&dependencies)
+            .build();
+        let render = unsafe { dev.device.create_render_pass(&create_info, None) }
+            .expect("Failed to create Vulkan render pass");
+
+        let compatibility = Self::get_compatibility(&attachments, subpasses.len(), 0);
+
+        Self {
+            render,
+            compatibility,
+            read_depth: true,
+            // There are no G-buffer attachments
+            formats: AttachmentFormats::new(color_format),
+            depth_format: dev.depth_format,
+            device: Rc::clone(&dev.device),
+        }
+    }
+
+    /// Creates a render pass drawing soft particles over a color attachment of `color_format`,
+    /// left in `color_layout` by a previous pass which preserves depth. Depth is tested without
+    /// being written, and it is sampled at the same time by the particle fragment shader.
+    /// Attachments are color and depth, see `ParticleFramebuffer::new`.
+    pub fn new_particles(
+        dev: &mut Dev,
+        color_format: vk::Format,
+        color_layout: vk::ImageLayout,
+    ) -> Self {
+        let color_attachment = vk::AttachmentDescription::builder()
+            .format(color_format)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .load_op(vk::AttachmentLoadOp::LOAD)
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(color_layout)
+            .final_layout(color_layout)
+            .build();
+
+        // Depth of the opaque scene, tested and sampled, hence read-only
+        let depth_attachment = vk::AttachmentDescription::builder()
+            .format(dev.depth_format)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .load_op(vk::AttachmentLoadOp::LOAD)
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+            .final_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+            .build();
+
+        let attachments = [color_attachment, depth_attachment];
+
+        let color_refs = [vk::AttachmentReference::builder()
+            .attachment(0)
+            .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .build()];
+
+        let depth_ref = vk::AttachmentReference::builder()
+            .attachment(1)
+            .layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+            .build();
+
+        let subpasses = [vk::SubpassDescription::builder()
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .color_attachments(&color_refs)
+            .depth_stencil_attachment(&depth_ref)
+            .build()];
+
+        // Color and depth written by the previous pass should be visible to this one,
+        // depth to both tests and fragment shaders
+        let init_dependency = vk::SubpassDependency::builder()
+            .src_subpass(vk::SUBPASS_EXTERNAL)
+            .dst_subpass(0)
+            .src_stage_mask(
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            )
+            .src_access_mask(
+                vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            )
+            .dst_stage_mask(
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+                    | vk::PipelineStageFlags::FRAGMENT_SHADER,
+            )
+            .dst_access_mask(
+                vk::AccessFlags::COLOR_ATTACHMENT_READ
+                    | vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                    | vk::AccessFlags::SHADER_READ,
+            )
+            .build();
+
+        // The blended color should be visible to presentation or to passes sampling it
+        let particles_dependency = vk::SubpassDependency::builder()
+            .src_subpass(0)
+            .dst_subpass(vk::SUBPASS_EXTERNAL)
+            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_stage_mask(
+                vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            )
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::MEMORY_READ)
+            .build();
+
+        let dependencies = [init_dependency, particles_dependency];
+
+        let create_info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(&dependencies)
+            .build();
+        let render = unsafe { dev.device.create_render_pass(&create_info, None) }
+            .expect("Failed to create Vulkan render pass");
+
+        let compatibility = Self::get_compatibility(&attachments, subpasses.len(), 0);
+
+        Self {
+            render,
+            compatibility,
+            read_depth: true,
+            // There are no G-buffer attachments
+            formats: AttachmentFormats::new(color_format),
+            depth_format: dev.depth_format,
+            device: Rc::clone(&dev.device),
+        }
+    }
+
+    /// Returns the attachments of the main render pass: present, depth, albedo, normal, and light
+    fn get_attachments(
+        surface_format: vk::Format,
+        depth_format: vk::Format,
+        read_depth: bool,
+        color_final_layout: vk::ImageLayout,
+        load_ops: &AttachmentLoadOps,
+        formats: &AttachmentFormats,
+    ) -> [vk::AttachmentDescription; 5] {
         let present_attachment = ash::vk::AttachmentDescription::builder()
             // @todo This format should come from a "framebuffer" object
-            .format(dev.surface_format.format)
+            .format(surface_format)
             .samples(ash::vk::SampleCountFlags::TYPE_1)
-            .load_op(ash::vk::AttachmentLoadOp::CLEAR)
+            .load_op(load_ops.present)
             .store_op(ash::vk::AttachmentStoreOp::STORE)
             .stencil_load_op(ash::vk::AttachmentLoadOp::DONT_CARE)
             .stencil_store_op(ash::vk::AttachmentStoreOp::DONT_CARE)
             .initial_layout(ash::vk::ImageLayout::UNDEFINED)
-            .final_layout(ash::vk::ImageLayout::PRESENT_SRC_KHR)
+            .final_layout(color_final_layout)
             .build();
 
+        let (depth_store_op, depth_final_layout) = Self::get_depth_store(read_depth);
         let depth_attachment = ash::vk::AttachmentDescription::builder()
-            // @todo This format should come from a "framebuffer" object
-            .format(ash::vk::Format::D32_SFLOAT)
+            .format(depth_format)
             .samples(ash::vk::SampleCountFlags::TYPE_1)
-            .load_op(ash::vk::AttachmentLoadOp::CLEAR)
-            .store_op(ash::vk::AttachmentStoreOp::DONT_CARE)
+            .load_op(load_ops.depth)
+            .store_op(depth_store_op)
             .stencil_load_op(ash::vk::AttachmentLoadOp::DONT_CARE)
             .stencil_store_op(ash::vk::AttachmentStoreOp::DONT_CARE)
             .initial_layout(ash::vk::ImageLayout::UNDEFINED)
-            .final_layout(ash::vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+            .final_layout(depth_final_layout)
             .build();
 
+        // Albedo and normal are stored, so that post-process passes can sample them
         let albedo_attachment = ash::vk::AttachmentDescription::builder()
-            // @todo This format should come from a "framebuffer" object
-            .format(dev.surface_format.format)
+            .format(formats.albedo)
             .samples(ash::vk::SampleCountFlags::TYPE_1)
-            .load_op(ash::vk::AttachmentLoadOp::CLEAR)
-            .store_op(ash::vk::AttachmentStoreOp::DONT_CARE)
+            .load_op(load_ops.albedo)
+            .store_op(ash::vk::AttachmentStoreOp::STORE)
             .stencil_load_op(ash::vk::AttachmentLoadOp::DONT_CARE)
             .stencil_store_op(ash::vk::AttachmentStoreOp::DONT_CARE)
             .initial_layout(ash::vk::ImageLayout::UNDEFINED)
-            .final_layout(ash::vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .final_layout(ash::vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
             .build();
 
         let normal_attachment = vk::AttachmentDescription::builder()
-            .format(vk::Format::A2R10G10B10_UNORM_PACK32)
+            .format(formats.normal)
             .samples(vk::SampleCountFlags::TYPE_1)
-            .load_op(vk::AttachmentLoadOp::CLEAR)
+            .load_op(load_ops.normal)
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .build();
+
+        let light_attachment = vk::AttachmentDescription::builder()
+            .format(formats.light)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .load_op(load_ops.light)
             .store_op(vk::AttachmentStoreOp::DONT_CARE)
             .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
             .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
@@ -704,12 +2527,31 @@ impl Pass {
             .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
             .build();
 
-        let attachments = [
+        [
             present_attachment,
             depth_attachment,
             albedo_attachment,
             normal_attachment,
-        ];
+            light_attachment,
+        ]
+    }
+
+    fn create(
+        dev: &mut Dev,
+        read_depth: bool,
+        multiview: Option<&MultiviewConfig>,
+        color_final_layout: vk::ImageLayout,
+        load_ops: &AttachmentLoadOps,
+        formats: &AttachmentFormats,
+    ) -> Self {
+        let attachments = Self::get_attachments(
+            dev.surface_format.format,
+            dev.depth_format,
+            read_depth,
+            color_final_layout,
+            load_ops,
+            formats,
+        );
 
         let present_ref = ash::vk::AttachmentReference::builder()
             .attachment(0)
@@ -731,8 +2573,14 @@ impl Pass {
             .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
             .build();
 
+        let light_ref = vk::AttachmentReference::builder()
+            .attachment(4)
+            .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .build();
+
         let first_color_refs = [albedo_ref, normal_ref];
-        let second_color_refs = [present_ref];
+        let lighting_color_refs = [light_ref];
+        let present_color_refs = [present_ref];
 
         let albedo_input_ref = ash::vk::AttachmentReference::builder()
             .attachment(2)
@@ -744,9 +2592,20 @@ impl Pass {
             .layout(ash::vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
             .build();
 
-        let input_refs = [albedo_input_ref, normal_input_ref];
+        let depth_input_ref = vk::AttachmentReference::builder()
+            .attachment(1)
+            .layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+            .build();
 
-        // Two subpasses
+        let light_input_ref = vk::AttachmentReference::builder()
+            .attachment(4)
+            .layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .build();
+
+        let lighting_input_refs = [albedo_input_ref, normal_input_ref, depth_input_ref];
+        let present_input_refs = [albedo_input_ref, normal_input_ref, light_input_ref];
+
+        // Three subpasses
         let subpasses = [
             // First subpass writes albedo and depth
             ash::vk::SubpassDescription::builder()
@@ -754,10 +2613,16 @@ impl Pass {
                 .color_attachments(&first_color_refs)
                 .depth_stencil_attachment(&depth_ref)
                 .build(),
+            // Lighting subpass accumulates light volumes reading the G-buffer
+            vk::SubpassDescription::builder()
+                .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+                .color_attachments(&lighting_color_refs)
+                .input_attachments(&lighting_input_refs)
+                .build(),
             ash::vk::SubpassDescription::builder()
                 .pipeline_bind_point(ash::vk::PipelineBindPoint::GRAPHICS)
-                .color_attachments(&second_color_refs)
-                .input_attachments(&input_refs)
+                .color_attachments(&present_color_refs)
+                .input_attachments(&present_input_refs)
                 .build(),
         ];
 
@@ -778,43 +2643,102 @@ impl Pass {
 
         let output_to_input_dependency = vk::SubpassDependency::builder()
             .src_subpass(0)
-            .dst_subpass(1)
+            .dst_subpass(Self::LIGHTING_SUBPASS)
+            .src_stage_mask(
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            )
+            .src_access_mask(
+                vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            )
+            .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+            .dst_access_mask(vk::AccessFlags::INPUT_ATTACHMENT_READ)
+            .dependency_flags(vk::DependencyFlags::BY_REGION)
+            .build();
+
+        let light_to_present_dependency = vk::SubpassDependency::builder()
+            .src_subpass(Self::LIGHTING_SUBPASS)
+            .dst_subpass(Self::PRESENT_SUBPASS)
             .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
             .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
             .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+            .dst_access_mask(vk::AccessFlags::INPUT_ATTACHMENT_READ)
+            .dependency_flags(vk::DependencyFlags::BY_REGION)
+            .build();
+
+        let present_dependency = vk::SubpassDependency::builder()
+            .src_subpass(Self::PRESENT_SUBPASS)
+            .dst_subpass(vk::SUBPASS_EXTERNAL)
+            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .src_access_mask(
+                vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            )
+            .dst_stage_mask(vk::PipelineStageFlags::BOTTOM_OF_PIPE)
+            .dst_access_mask(vk::AccessFlags::MEMORY_READ)
+            .dependency_flags(vk::DependencyFlags::BY_REGION)
+            .build();
+
+        // Depth writes should be visible to shaders reading depth after the pass
+        let depth_read_dependency = vk::SubpassDependency::builder()
+            .src_subpass(0)
+            .dst_subpass(vk::SUBPASS_EXTERNAL)
+            .src_stage_mask(vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
+            .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
             .dst_access_mask(vk::AccessFlags::SHADER_READ)
-            .dependency_flags(vk::DependencyFlags::BY_REGION)
             .build();
 
-        let present_dependency = vk::SubpassDependency::builder()
-            .src_subpass(1)
+        // G-buffer writes should be visible to post-process passes sampling it
+        let gbuffer_read_dependency = vk::SubpassDependency::builder()
+            .src_subpass(0)
             .dst_subpass(vk::SUBPASS_EXTERNAL)
             .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
-            .src_access_mask(
-                vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
-            )
-            .dst_stage_mask(vk::PipelineStageFlags::BOTTOM_OF_PIPE)
-            .dst_access_mask(vk::AccessFlags::MEMORY_READ)
-            .dependency_flags(vk::DependencyFlags::BY_REGION)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
             .build();
 
-        let dependencies = [
+        let mut dependencies = vec![
             init_dependency,
             output_to_input_dependency,
+            light_to_present_dependency,
             present_dependency,
+            gbuffer_read_dependency,
         ];
+        if read_depth {
+            dependencies.push(depth_read_dependency);
+        }
+
+        // Every subpass renders all the views
+        let view_mask = multiview.map_or(0, |multiview| multiview.get_view_mask());
+        let view_masks = vec![view_mask; subpasses.len()];
+        let correlation_masks = [multiview.map_or(0, |multiview| multiview.get_correlation_mask())];
+        let mut multiview_info = vk::RenderPassMultiviewCreateInfo::builder()
+            .view_masks(&view_masks)
+            .correlation_masks(&correlation_masks)
+            .build();
 
         // Build the render pass
-        let create_info = vk::RenderPassCreateInfo::builder()
+        let mut create_info = vk::RenderPassCreateInfo::builder()
             .attachments(&attachments)
             .subpasses(&subpasses)
-            .dependencies(&dependencies)
-            .build();
+            .dependencies(&dependencies);
+        if multiview.is_some() {
+            create_info = create_info.push_next(&mut multiview_info);
+        }
+        let create_info = create_info.build();
         let render = unsafe { dev.device.create_render_pass(&create_info, None) }
             .expect("Failed to create Vulkan render pass");
 
+        let compatibility = Self::get_compatibility(&attachments, subpasses.len(), view_mask);
+
         Self {
             render,
+            compatibility,
+            read_depth,
+            formats: *formats,
+            depth_format: dev.depth_format,
             device: Rc::clone(&dev.device),
         }
     }
@@ -828,19 +2752,79 @@ impl Drop for Pass {
     }
 }
 
+/// Remembers a hash of the bytes last uploaded to a buffer, so that uploading the same data
+/// again can be skipped
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct UploadTracker {
+    hash: Option<u64>,
+}
+
+impl UploadTracker {
+    fn get_hash(bytes: &[u8]) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        bytes.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Calls `write` with `bytes` only when they differ from the last ones.
+    /// Returns whether `write` has been called.
+    pub fn upload_if_changed<F: FnOnce(&[u8])>(&mut self, bytes: &[u8], write: F) -> bool {
+        let hash = Self::get_hash(bytes);
+        if self.hash == Some(hash) {
+            return false;
+        }
+        write(bytes);
+        self.hash = Some(hash);
+        true
+    }
+
+    /// Forgets the last upload, for when the content is written by other means
+    pub fn invalidate(&mut self) {
+        self.hash = None;
+    }
+}
+
 pub struct Buffer {
     allocation: vk_mem::Allocation,
     pub buffer: ash::vk::Buffer,
     usage: ash::vk::BufferUsageFlags,
     pub size: ash::vk::DeviceSize,
+    /// Device-local buffers can not be mapped, they are written by transfer commands
+    host_visible: bool,
+    /// Used by `upload` to skip writing data which is already there
+    tracker: UploadTracker,
     pub allocator: Rc<RefCell<vk_mem::Allocator>>,
 }
 
 impl Buffer {
+    /// Returns how to allocate memory which is either mappable or only accessible by the device
+    fn get_allocation_info(host_visible: bool) -> vk_mem::AllocationCreateInfo {
+        let mut create_info = vk_mem::AllocationCreateInfo::default();
+        if host_visible {
+            create_info.usage = vk_mem::MemoryUsage::CpuToGpu;
+            create_info.required_flags = ash::vk::MemoryPropertyFlags::HOST_VISIBLE;
+            create_info.preferred_flags = ash::vk::MemoryPropertyFlags::HOST_COHERENT
+                | ash::vk::MemoryPropertyFlags::HOST_CACHED;
+        } else {
+            create_info.usage = vk_mem::MemoryUsage::GpuOnly;
+            create_info.required_flags = ash::vk::MemoryPropertyFlags::DEVICE_LOCAL;
+        }
+        create_info
+    }
+
     pub fn create_buffer(
         allocator: &vk_mem::Allocator,
         size: ash::vk::DeviceSize,
         usage: ash::vk::BufferUsageFlags,
+    ) -> (ash::vk::Buffer, vk_mem::Allocation) {
+        Self::create_buffer_with_memory(allocator, size, usage, true)
+    }
+
+    fn create_buffer_with_memory(
+        allocator: &vk_mem::Allocator,
+        size: ash::vk::DeviceSize,
+        usage: ash::vk::BufferUsageFlags,
+        host_visible: bool,
     ) -> (ash::vk::Buffer, vk_mem::Allocation) {
         assert!(size >= 32);
 
@@ -851,11 +2835,7 @@ impl Buffer {
             .build();
 
         // Vulkan memory
-        let mut create_info = vk_mem::AllocationCreateInfo::default();
-        create_info.usage = vk_mem::MemoryUsage::CpuToGpu;
-        create_info.required_flags = ash::vk::MemoryPropertyFlags::HOST_VISIBLE;
-        create_info.preferred_flags =
-            ash::vk::MemoryPropertyFlags::HOST_COHERENT | ash::vk::MemoryPropertyFlags::HOST_CACHED;
+        let create_info = Self::get_allocation_info(host_visible);
 
         let (buffer, allocation, _) = allocator
             .create_buffer(&buffer_info, &create_info)
@@ -894,6 +2874,8 @@ impl Buffer {
             buffer,
             usage,
             size: size as ash::vk::DeviceSize,
+            host_visible: true,
+            tracker: UploadTracker::default(),
             allocator: allocator.clone(),
         }
     }
@@ -912,10 +2894,70 @@ impl Buffer {
             buffer,
             size,
             usage,
+            host_visible: true,
+            tracker: UploadTracker::default(),
             allocator,
         }
     }
 
+    /// Creates a buffer in device-local memory filled with `data` through a staging buffer.
+    /// The copy is recorded into `upload` when available, so that the buffer can only be used
+    /// after it is finished, otherwise it is waited and the buffer can be used straight away.
+    pub fn device_local<T>(
+        dev: &Dev,
+        usage: ash::vk::BufferUsageFlags,
+        data: &[T],
+        upload: Option<&mut UploadContext>,
+    ) -> Self {
+        let size = (std::mem::size_of::<T>() * data.len()) as vk::DeviceSize;
+        let size = size.max(32);
+        let mut staging = dev.acquire_staging(size);
+        staging.upload_raw(data.as_ptr(), (std::mem::size_of::<T>() * data.len()) as _);
+
+        let allocator = dev.allocator.clone();
+        let usage = usage | vk::BufferUsageFlags::TRANSFER_DST;
+        let (buffer, allocation) =
+            Self::create_buffer_with_memory(&allocator.deref().borrow(), size, usage, false);
+        let buffer = Self {
+            allocation,
+            buffer,
+            size,
+            usage,
+            host_visible: false,
+            tracker: UploadTracker::default(),
+            allocator,
+        };
+
+        UploadContext::record_or_submit(dev, upload, |upload| {
+            upload
+                .get_command_buffer()
+                .copy_buffer(&staging, &buffer, size);
+            // Make the copy visible to whatever command reads the buffer next
+            let barrier = vk::BufferMemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(vk::AccessFlags::MEMORY_READ)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .buffer(buffer.buffer)
+                .offset(0)
+                .size(vk::WHOLE_SIZE)
+                .build();
+            upload.release_buffer(
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                &barrier,
+            );
+            // The staging buffer can only be dropped after the copy
+            upload.keep(staging);
+        });
+
+        buffer
+    }
+
+    pub fn is_host_visible(&self) -> bool {
+        self.host_visible
+    }
+
     pub fn new<T>(
         allocator: &Rc<RefCell<vk_mem::Allocator>>,
         usage: ash::vk::BufferUsageFlags,
@@ -949,14 +2991,24 @@ impl Buffer {
         buffer
     }
 
-    pub fn upload<T>(&mut self, data: &T) {
-        self.upload_raw(
-            data as *const T,
-            std::mem::size_of::<T>() as ash::vk::DeviceSize,
-        );
+    /// Uploads `data` unless it is the same as the last one uploaded. Buffers which can be
+    /// written by shaders are always uploaded, as their content could have changed.
+    pub fn upload<T: Pod>(&mut self, data: &T) {
+        let size = std::mem::size_of::<T>();
+        let bytes = as_bytes(data);
+        if self.usage.contains(vk::BufferUsageFlags::STORAGE_BUFFER) {
+            self.tracker.invalidate();
+        }
+
+        let mut tracker = self.tracker;
+        tracker.upload_if_changed(bytes, |bytes| {
+            self.write_raw(bytes.as_ptr(), size as vk::DeviceSize)
+        });
+        self.tracker = tracker;
     }
 
     pub fn map<T>(&mut self) -> &[T] {
+        assert!(self.host_visible, "Failed to map device-local buffer");
         let alloc = self.allocator.deref().borrow();
         let data = alloc
             .map_memory(&self.allocation)
@@ -971,6 +3023,12 @@ impl Buffer {
     }
 
     pub fn upload_raw<T>(&mut self, src: *const T, size: ash::vk::DeviceSize) {
+        self.tracker.invalidate();
+        self.write_raw(src, size);
+    }
+
+    fn write_raw<T>(&mut self, src: *const T, size: ash::vk::DeviceSize) {
+        assert!(self.host_visible, "Failed to map device-local buffer");
         let alloc = self.allocator.deref().borrow();
         let data = alloc
             .map_memory(&self.allocation)
@@ -988,13 +3046,115 @@ impl Buffer {
             alloc.destroy_buffer(self.buffer, &self.allocation);
 
             self.size = size;
-            let (buffer, allocation) = Self::create_buffer(&alloc, size, self.usage);
+            let (buffer, allocation) =
+                Self::create_buffer_with_memory(&alloc, size, self.usage, self.host_visible);
             self.buffer = buffer;
             self.allocation = allocation;
         }
 
         self.upload_raw(arr.as_ptr(), size);
     }
+
+    /// Makes compute shader writes to this buffer available to transfer reads
+    fn get_read_back_barrier(buffer: vk::Buffer) -> vk::BufferMemoryBarrier {
+        vk::BufferMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .buffer(buffer)
+            .offset(0)
+            .size(vk::WHOLE_SIZE)
+            .build()
+    }
+
+    /// Makes transfer writes to a staging buffer visible to the host
+    pub(crate) fn get_host_read_barrier(buffer: vk::Buffer) -> vk::BufferMemoryBarrier {
+        vk::BufferMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::HOST_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .buffer(buffer)
+            .offset(0)
+            .size(vk::WHOLE_SIZE)
+            .build()
+    }
+
+    /// Returns the first `len` elements of this buffer after the device has written to it.
+    /// Memory which is not host-coherent is invalidated first, so that the host sees the writes.
+    pub(crate) fn read_mapped<T: Copy>(&mut self, len: usize) -> Vec<T> {
+        assert!(
+            len * std::mem::size_of::<T>() <= self.size as usize,
+            "Failed to read {} elements from a buffer of {} bytes",
+            len,
+            self.size
+        );
+        self.allocator.deref().borrow().invalidate_allocation(
+            &self.allocation,
+            0,
+            vk::WHOLE_SIZE as usize,
+        );
+        let data = self.map::<T>()[..len].to_vec();
+        self.unmap();
+        data
+    }
+
+    /// Copies the content of this buffer, usually written by a compute shader, into a staging buffer.
+    /// It waits for the copy to finish and returns the first `len` elements as a vector of `T`.
+    pub fn read_back<T: Copy>(&self, dev: &Dev, len: usize) -> Vec<T> {
+        assert!(self.usage.contains(vk::BufferUsageFlags::TRANSFER_SRC));
+
+        let mut staging = Buffer::new_with_size(
+            &dev.allocator,
+            vk::BufferUsageFlags::TRANSFER_DST,
+            self.size,
+        );
+
+        let command_buffer = CommandBuffer::new(&dev.transient_command_pool);
+        command_buffer.begin(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        command_buffer.buffer_barriers(
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::PipelineStageFlags::TRANSFER,
+            &[Self::get_read_back_barrier(self.buffer)],
+        );
+        command_buffer.copy_buffer(self, &staging, self.size);
+        command_buffer.buffer_barriers(
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::HOST,
+            &[Self::get_host_read_barrier(staging.buffer)],
+        );
+        command_buffer.end();
+
+        let mut fence = Fence::unsignaled(&dev.device);
+        let submits = [vk::SubmitInfo::builder()
+            .command_buffers(&[command_buffer.command_buffer])
+            .build()];
+        dev.graphics_queue.submit(&submits, Some(&mut fence));
+        fence.wait();
+
+        staging.read_mapped(len)
+    }
+}
+
+impl MemoryMap for Buffer {
+    /// Whatever is written through the returned pointer is unknown to `upload`
+    fn map_memory(&mut self) -> *mut u8 {
+        assert!(self.host_visible, "Failed to map device-local buffer");
+        self.tracker.invalidate();
+        let alloc = self.allocator.deref().borrow();
+        alloc
+            .map_memory(&self.allocation)
+            .expect("Failed to map Vulkan memory")
+    }
+
+    fn unmap_memory(&mut self) {
+        self.unmap();
+    }
+
+    fn get_size(&self) -> vk::DeviceSize {
+        self.size
+    }
 }
 
 impl Drop for Buffer {
@@ -1005,3 +3165,805 @@ impl Drop for Buffer {
             .destroy_buffer(self.buffer, &self.allocation);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn upload_only_changes() {
+        let mut tracker = UploadTracker::default();
+        let mut writes = 0;
+        let mut upload = |matrix: &na::Matrix4<f32>| {
+            let bytes = unsafe {
+                std::slice::from_raw_parts(
+                    matrix.as_ptr() as *const u8,
+                    std::mem::size_of::<na::Matrix4<f32>>(),
+                )
+            };
+            tracker.upload_if_changed(bytes, |_| writes += 1);
+        };
+
+        let matrix = na::Matrix4::new_translation(&na::Vector3::new(1.0, 2.0, 3.0));
+        upload(&matrix);
+        upload(&matrix);
+        upload(&na::Matrix4::identity());
+        upload(&na::Matrix4::identity());
+        upload(&matrix);
+        assert_eq!(writes, 3);
+
+        let mut writes = 0;
+        let bytes = [1u8, 2, 3, 4];
+        tracker.upload_if_changed(&bytes, |_| writes += 1);
+        tracker.invalidate();
+        tracker.upload_if_changed(&bytes, |_| writes += 1);
+        assert_eq!(writes, 2);
+    }
+
+    #[test]
+    fn buffer_upload() {
+        let ctx = Ctx::headless();
+        let dev = Dev::headless(&ctx);
+        let mut buffer =
+            Buffer::new::<[u32; 4]>(&dev.allocator, vk::BufferUsageFlags::UNIFORM_BUFFER);
+        let read = |buffer: &mut Buffer| {
+            let data = buffer.map::<u32>()[..4].to_vec();
+            buffer.unmap();
+            data
+        };
+
+        let data = [1u32, 2, 3, 4];
+        buffer.upload(&data);
+        assert_eq!(read(&mut buffer), data);
+
+        // Overwritten through a mapping, then the same data is uploaded again
+        let dst = buffer.map_memory();
+        unsafe { dst.write_bytes(0, 16) };
+        buffer.unmap_memory();
+        buffer.upload(&data);
+        assert_eq!(read(&mut buffer), data);
+
+        buffer.upload(&[5u32, 6, 7, 8]);
+        assert_eq!(read(&mut buffer), vec![5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn batch_submit_copies() {
+        let ctx = Ctx::headless();
+        let dev = Dev::headless(&ctx);
+        let format = vk::Format::R8G8B8A8_UNORM;
+        let usage = vk::ImageUsageFlags::TRANSFER_DST
+            | vk::ImageUsageFlags::TRANSFER_SRC
+            | vk::ImageUsageFlags::SAMPLED;
+        let mut red = Image::new(&dev.allocator, 2, 2, format, usage);
+        let mut blue = Image::new(&dev.allocator, 2, 2, format, usage);
+        let fill = |texel: [u8; 4]| {
+            let texels: Vec<u8> = texel.iter().copied().cycle().take(16).collect();
+            let mut staging = dev.acquire_staging(texels.len() as vk::DeviceSize);
+            staging.upload_raw(texels.as_ptr(), texels.len() as vk::DeviceSize);
+            staging
+        };
+        let red_staging = fill([255, 0, 0, 255]);
+        let blue_staging = fill([0, 0, 255, 255]);
+
+        // Both copies are recorded in one command buffer and finished when it returns
+        dev.batch_submit(|upload| {
+            upload.copy_image(red_staging, &mut red);
+            upload.copy_image(blue_staging, &mut blue);
+        });
+
+        assert_eq!(red.read_pixel(&dev, 0, 0), [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(red.read_pixel(&dev, 1, 1), [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(blue.read_pixel(&dev, 0, 0), [0.0, 0.0, 1.0, 1.0]);
+        assert_eq!(blue.read_pixel(&dev, 1, 1), [0.0, 0.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn oit_resolve() {
+        let ctx = Ctx::headless();
+        let mut dev = Dev::headless(&ctx);
+        let format = dev.surface_format.format;
+        let formats = AttachmentFormats::new(format);
+        let color_layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+        // Offscreen, preserving depth for the OIT pass
+        let pass = Pass::create(
+            &mut dev,
+            true,
+            None,
+            color_layout,
+            &AttachmentLoadOps::default(),
+            &formats,
+        );
+        let oit_pass = Pass::new_oit(&mut dev, format, color_layout);
+
+        let usage = Image::get_attachment_usage(format) | vk::ImageUsageFlags::TRANSFER_SRC;
+        let mut image = Image::new(&dev.allocator, 4, 4, format, usage);
+        let mut frame = Frame::new(&mut dev, &image, &pass);
+        frame.set_clear_values(ClearValues {
+            color: [0.0, 0.0, 0.0, 1.0],
+            ..Default::default()
+        });
+        let wboit = Wboit::new(&dev, &oit_pass, &frame.buffer);
+        let shader = ShaderModule::main(&dev.device);
+        let resolve = Pipeline::wboit_resolve(&dev, &shader, &oit_pass, 4, 4);
+
+        frame.begin(&pass, 4, 4);
+        frame.res.command_buffer.next_subpass();
+        frame.res.command_buffer.next_subpass();
+        frame.end_pass();
+
+        // As if a red fragment with alpha 0.75 and weight 2 was accumulated
+        frame.begin_oit_pass(&oit_pass, &wboit);
+        let area = [wboit.get_area()];
+        let command_buffer = &frame.res.command_buffer;
+        command_buffer.clear_color_attachment(0, [1.5, 0.0, 0.0, 1.5], &area);
+        command_buffer.clear_color_attachment(1, [0.25, 0.0, 0.0, 0.0], &area);
+        frame.resolve_oit(&resolve, &wboit);
+        frame.end_command_buffer();
+        frame.res.submit(&dev.graphics_queue, None, None, true);
+        frame.res.wait();
+
+        // Composited over black with a coverage of 1 - revealage
+        image.layout = color_layout;
+        let [r, g, b, _] = image.read_pixel(&dev, 1, 1);
+        assert!((r - 0.75).abs() < 1e-2, "{}", r);
+        assert!(g.abs() < 1e-2 && b.abs() < 1e-2);
+    }
+
+    #[test]
+    fn soft_particles() {
+        let ctx = Ctx::headless();
+        let mut dev = Dev::headless(&ctx);
+        let format = dev.surface_format.format;
+        let formats = AttachmentFormats::new(format);
+        let color_layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+        // Offscreen, preserving depth for the particle pass
+        let pass = Pass::create(
+            &mut dev,
+            true,
+            None,
+            color_layout,
+            &AttachmentLoadOps::default(),
+            &formats,
+        );
+        let particle_pass = Pass::new_particles(&mut dev, format, color_layout);
+
+        let usage = Image::get_attachment_usage(format) | vk::ImageUsageFlags::TRANSFER_SRC;
+        let mut image = Image::new(&dev.allocator, 4, 4, format, usage);
+        let mut frame = Frame::new(&mut dev, &image, &pass);
+        frame.set_clear_values(ClearValues {
+            color: [0.0, 0.0, 0.0, 1.0],
+            ..Default::default()
+        });
+        let framebuffer = ParticleFramebuffer::new(&dev, &particle_pass, &frame.buffer);
+        let shader = ShaderModule::main(&dev.device);
+        // Without a fade distance, particles are opaque in front of the cleared depth
+        let particles = SoftParticles::new(0.0);
+        let pipeline = Pipeline::soft_particles(&dev, &shader, &particle_pass, 4, 4, &particles);
+
+        let mut model = Model::new();
+        let mut camera_node = Node::new();
+        camera_node.camera = model.cameras.push(Camera::perspective(1.0));
+        let camera_node = model.nodes.push(camera_node);
+        let emitter = model.nodes.push(Node::new());
+        let red = Color::new(1.0, 0.0, 0.0, 1.0);
+        let quad: Vec<Point> = [
+            (-4.0, -4.0),
+            (4.0, -4.0),
+            (4.0, 4.0),
+            (-4.0, -4.0),
+            (4.0, 4.0),
+            (-4.0, 4.0),
+        ]
+        .iter()
+        .map(|&(x, y)| Point::new(na::Vector3::new(x, y, -1.0), red))
+        .collect();
+
+        frame.begin(&pass, 4, 4);
+        frame.res.command_buffer.next_subpass();
+        frame.res.command_buffer.next_subpass();
+        frame.end_pass();
+
+        frame.begin_particle_pass(&particle_pass, &framebuffer);
+        frame.draw_particles(&pipeline, &particles, &model, camera_node, emitter, &quad);
+        frame.end_pass();
+        frame.end_command_buffer();
+        frame.res.submit(&dev.graphics_queue, None, None, true);
+        frame.res.wait();
+
+        image.layout = color_layout;
+        let [r, g, b, _] = image.read_pixel(&dev, 1, 1);
+        assert!((r - 1.0).abs() < 1e-2, "{}", r);
+        assert!(g.abs() < 1e-2 && b.abs() < 1e-2);
+    }
+
+    #[test]
+    fn pipelines_depth_config() {
+        let ctx = Ctx::headless();
+        let mut dev = Dev::headless(&ctx);
+        let pass = Pass::new_offscreen(&mut dev);
+        let depth = DepthConfig::reverse_z();
+        let mut pipelines = DefaultPipelines::new(&dev, &pass, 64, 64, depth);
+        assert_eq!(pipelines.get_depth_config(), depth);
+
+        // The convention is kept when pipelines are created again for another pass
+        let formats = AttachmentFormats::new(vk::Format::R16G16B16A16_SFLOAT);
+        let other = Pass::create(
+            &mut dev,
+            false,
+            None,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            &AttachmentLoadOps::default(),
+            &formats,
+        );
+        pipelines.recreate(&dev, &other, 64, 64);
+        assert!(pipelines.get(Pipelines::MAIN).is_compatible_with(&other));
+        assert_eq!(pipelines.get_depth_config(), depth);
+    }
+
+    #[test]
+    fn texture_target_clear() {
+        let ctx = Ctx::headless();
+        let mut dev = Dev::headless(&ctx);
+        let extent = vk::Extent2D::builder().width(4).height(4).build();
+        let mut texture_target = TextureTarget::new(&mut dev, extent);
+
+        // Rendered twice, as the frame is kept for the following renders
+        for &color in [[1.0, 0.0, 1.0, 1.0], [0.0, 1.0, 0.0, 1.0]].iter() {
+            let frame = &mut texture_target.frame;
+            frame.set_clear_color(color);
+            frame.begin(&texture_target.pass, extent.width, extent.height);
+            frame.res.command_buffer.next_subpass();
+            frame.res.command_buffer.next_subpass();
+            frame.end();
+            frame.res.submit(&dev.graphics_queue, None, None, true);
+            frame.res.wait();
+
+            let mut target = Model::new();
+            let texture = texture_target.new_texture(&dev, &mut target);
+            assert!(target.textures.get(texture).is_some());
+            let (_, image) = target.images.iter_mut().next().unwrap();
+            assert_eq!(image.layout, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+            assert_eq!(image.read_pixel(&dev, 1, 1), color);
+        }
+    }
+
+    #[test]
+    fn queue_families() {
+        let graphics = vk::QueueFlags::GRAPHICS | vk::QueueFlags::TRANSFER;
+        let compute = vk::QueueFlags::COMPUTE;
+
+        // A family supporting both is preferred
+        let families = [(graphics, false), (compute, true), (graphics, true)];
+        assert_eq!(Dev::select_queue_families(&families, true), Some((2, 2)));
+
+        let families = [(graphics, false), (compute, true)];
+        assert_eq!(Dev::select_queue_families(&families, true), Some((0, 1)));
+
+        let families = [(compute, true)];
+        assert_eq!(Dev::select_queue_families(&families, true), None);
+
+        // Without a surface, the graphics family presents as well
+        let families = [(compute, false), (graphics, false)];
+        assert_eq!(Dev::select_queue_families(&families, false), Some((1, 1)));
+    }
+
+    #[test]
+    fn shared_depth_format() {
+        let ctx = Ctx::headless();
+        let mut dev = Dev::headless(&ctx);
+        assert!(Dev::DEPTH_FORMATS.contains(&dev.depth_format));
+
+        let pass = Pass::new(&mut dev);
+        assert_eq!(pass.depth_format, dev.depth_format);
+
+        let extent = vk::Extent2D::builder().width(64).height(64).build();
+        let image = Image::attachment(
+            &dev.allocator,
+            extent.width,
+            extent.height,
+            dev.surface_format.format,
+        );
+        let framebuffer = Framebuffer::new(&dev, &image, &pass);
+        assert_eq!(framebuffer.depth_image.format, dev.depth_format);
+
+        let shadow_pass = Pass::new_shadow(&mut dev);
+        assert_eq!(shadow_pass.depth_format, dev.depth_format);
+        let shadow = ShadowFramebuffer::new(&dev, &shadow_pass, &ShadowConfig::default());
+        assert_eq!(shadow.depth_image.format, dev.depth_format);
+    }
+
+    #[test]
+    fn transfer_family() {
+        let graphics =
+            vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE | vk::QueueFlags::TRANSFER;
+        let compute = vk::QueueFlags::COMPUTE | vk::QueueFlags::TRANSFER;
+        let transfer = vk::QueueFlags::TRANSFER;
+
+        // Dedicated transfer family is preferred to an async compute one
+        let families = [(graphics, true), (compute, false), (transfer, false)];
+        assert_eq!(Dev::select_transfer_family(&families), Some(2));
+
+        let families = [(graphics, true), (compute, false)];
+        assert_eq!(Dev::select_transfer_family(&families), Some(1));
+
+        // Falls back to graphics
+        let families = [(graphics, true)];
+        assert_eq!(Dev::select_transfer_family(&families), None);
+    }
+
+    #[test]
+    fn physical_device_info() {
+        let mut properties = vk::PhysicalDeviceProperties::default();
+        for (dst, src) in properties.device_name.iter_mut().zip(b"Test GPU".iter()) {
+            *dst = *src as _;
+        }
+        properties.device_type = vk::PhysicalDeviceType::DISCRETE_GPU;
+        properties.vendor_id = 0x10DE;
+
+        let info = PhysicalDeviceInfo::new(1, &properties, true);
+        assert_eq!(info.index, 1);
+        assert_eq!(info.name, "Test GPU");
+        assert!(info.is_discrete());
+        assert_eq!(info.get_vendor_name(), "NVIDIA");
+        assert!(info.supports_surface);
+
+        properties.device_type = vk::PhysicalDeviceType::INTEGRATED_GPU;
+        properties.vendor_id = 0x1234;
+        let info = PhysicalDeviceInfo::new(0, &properties, false);
+        assert!(!info.is_discrete());
+        assert_eq!(info.get_vendor_name(), "Unknown");
+    }
+
+    #[test]
+    fn device_selection() {
+        let device = |index, name: &str, device_type, supports_surface| PhysicalDeviceInfo {
+            index,
+            name: name.to_string(),
+            device_type,
+            vendor_id: 0,
+            supports_surface,
+        };
+        let infos = vec![
+            device(0, "llvmpipe", vk::PhysicalDeviceType::CPU, true),
+            device(1, "Intel UHD", vk::PhysicalDeviceType::INTEGRATED_GPU, true),
+            device(
+                2,
+                "Headless GPU",
+                vk::PhysicalDeviceType::DISCRETE_GPU,
+                false,
+            ),
+            device(3, "GeForce RTX", vk::PhysicalDeviceType::DISCRETE_GPU, true),
+        ];
+
+        // Discrete wins over integrated, devices without present support are skipped
+        assert_eq!(infos[2].get_score(), None);
+        assert_eq!(DeviceSelection::Best.select(&infos), Some(3));
+        assert_eq!(DeviceSelection::Best.select(&infos[..3]), Some(1));
+        assert_eq!(DeviceSelection::Best.select(&infos[2..3]), None);
+
+        // Overrides
+        assert_eq!(DeviceSelection::Index(0).select(&infos), Some(0));
+        assert_eq!(DeviceSelection::Index(4).select(&infos), None);
+        let intel = DeviceSelection::Name("intel".to_string());
+        assert_eq!(intel.select(&infos), Some(1));
+
+        // Forced devices which can not render to the surface are ignored
+        assert_eq!(DeviceSelection::Index(2).select(&infos), None);
+        let headless = DeviceSelection::Name("headless".to_string());
+        assert_eq!(headless.select(&infos), None);
+        let gpu = DeviceSelection::Name("gpu".to_string());
+        assert_eq!(gpu.select(&infos), Some(3));
+
+        assert_eq!(DeviceSelection::parse(" 2 "), DeviceSelection::Index(2));
+        assert_eq!(
+            DeviceSelection::parse("RTX"),
+            DeviceSelection::Name("RTX".into())
+        );
+        assert_eq!(DeviceSelection::parse(""), DeviceSelection::Best);
+    }
+
+    #[test]
+    fn buffer_memory() {
+        let host = Buffer::get_allocation_info(true);
+        assert!(host
+            .required_flags
+            .contains(vk::MemoryPropertyFlags::HOST_VISIBLE));
+
+        let device = Buffer::get_allocation_info(false);
+        assert!(device
+            .required_flags
+            .contains(vk::MemoryPropertyFlags::DEVICE_LOCAL));
+        assert!(!device
+            .required_flags
+            .contains(vk::MemoryPropertyFlags::HOST_VISIBLE));
+    }
+
+    fn depth_properties(format: vk::Format) -> vk::FormatProperties {
+        let optimal_tiling_features = match format {
+            vk::Format::D24_UNORM_S8_UINT | vk::Format::D16_UNORM => {
+                vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT
+                    | vk::FormatFeatureFlags::SAMPLED_IMAGE
+            }
+            _ => vk::FormatFeatureFlags::SAMPLED_IMAGE,
+        };
+        vk::FormatProperties::builder()
+            .optimal_tiling_features(optimal_tiling_features)
+            .build()
+    }
+
+    #[test]
+    fn required_features_missing() {
+        let supported = vk::PhysicalDeviceFeatures::builder()
+            .sampler_anisotropy(true)
+            .build();
+
+        let required = RequiredFeatures {
+            sampler_anisotropy: true,
+            ..Default::default()
+        };
+        assert!(required.get_missing(&supported, false).is_empty());
+
+        let required = RequiredFeatures {
+            sampler_anisotropy: true,
+            wide_lines: true,
+            multiview: true,
+            ..Default::default()
+        };
+        let missing = MissingFeatures {
+            names: required.get_missing(&supported, false),
+        };
+        assert_eq!(missing.names, vec!["wideLines", "multiview"]);
+        assert_eq!(
+            missing.to_string(),
+            "Missing required device features: wideLines, multiview"
+        );
+
+        // Optional features are never missing
+        assert!(RequiredFeatures::default()
+            .get_missing(&vk::PhysicalDeviceFeatures::default(), false)
+            .is_empty());
+    }
+
+    #[test]
+    fn device_selection_errors() {
+        let info = |index, supports_surface| PhysicalDeviceInfo {
+            index,
+            name: String::from("Test"),
+            device_type: vk::PhysicalDeviceType::DISCRETE_GPU,
+            vendor_id: 0,
+            supports_surface,
+        };
+        let infos = [info(0, true), info(1, false)];
+
+        assert_eq!(DeviceError::check_selection(&infos, 0), Ok(()));
+        assert_eq!(
+            DeviceError::check_selection(&infos, 1),
+            Err(DeviceError::UnsupportedSurface(1))
+        );
+        assert_eq!(
+            DeviceError::check_selection(&infos, 2),
+            Err(DeviceError::InvalidIndex(2))
+        );
+        assert_eq!(
+            DeviceError::InvalidIndex(2).to_string(),
+            "Invalid physical device index 2"
+        );
+        assert_eq!(
+            DeviceError::NoMatchingDevice(DeviceSelection::Index(2)).to_string(),
+            "No physical device matches Index(2)"
+        );
+
+        let missing = MissingFeatures {
+            names: vec!["multiview"],
+        };
+        assert_eq!(
+            DeviceError::from(missing.clone()).to_string(),
+            missing.to_string()
+        );
+    }
+
+    #[test]
+    fn depth_format_preferred() {
+        let format = Dev::select_depth_format(&Dev::DEPTH_FORMATS, |_| {
+            vk::FormatProperties::builder()
+                .optimal_tiling_features(vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT)
+                .build()
+        });
+        assert_eq!(format, Some(vk::Format::D32_SFLOAT));
+    }
+
+    #[test]
+    fn surface_format_selection() {
+        let format = |format, color_space| vk::SurfaceFormatKHR {
+            format,
+            color_space,
+        };
+        let linear = format(
+            vk::Format::B8G8R8A8_UNORM,
+            vk::ColorSpaceKHR::SRGB_NONLINEAR,
+        );
+        let srgb = Dev::PREFERRED_SURFACE_FORMAT;
+
+        let selected = Dev::select_surface_format(&[linear, srgb]);
+        assert_eq!(selected.format, vk::Format::B8G8R8A8_SRGB);
+        assert_eq!(selected.color_space, vk::ColorSpaceKHR::SRGB_NONLINEAR);
+
+        // A single format does not panic
+        let selected = Dev::select_surface_format(&[linear]);
+        assert_eq!(selected.format, vk::Format::B8G8R8A8_UNORM);
+
+        // Preferred format with another color space is not the preferred one
+        let extended = format(
+            vk::Format::B8G8R8A8_SRGB,
+            vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT,
+        );
+        let selected = Dev::select_surface_format(&[extended, linear]);
+        assert_eq!(
+            selected.color_space,
+            vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT
+        );
+
+        // No preference
+        let undefined = format(vk::Format::UNDEFINED, vk::ColorSpaceKHR::SRGB_NONLINEAR);
+        let selected = Dev::select_surface_format(&[undefined]);
+        assert_eq!(selected.format, vk::Format::B8G8R8A8_SRGB);
+    }
+
+    #[test]
+    fn srgb_surface_format() {
+        let format = |format| vk::SurfaceFormatKHR {
+            format,
+            color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+        };
+        assert!(Swapchain::is_srgb_surface_format(format(
+            vk::Format::B8G8R8A8_SRGB
+        )));
+        assert!(!Swapchain::is_srgb_surface_format(format(
+            vk::Format::B8G8R8A8_UNORM
+        )));
+    }
+
+    #[test]
+    fn depth_format_fallback() {
+        let format = Dev::select_depth_format(&Dev::DEPTH_FORMATS, depth_properties);
+        assert_eq!(format, Some(vk::Format::D24_UNORM_S8_UINT));
+
+        let format = Dev::select_depth_format(&[vk::Format::D32_SFLOAT], depth_properties);
+        assert_eq!(format, None);
+    }
+
+    #[test]
+    fn swapchain_usage_blit() {
+        let supported = vk::ImageUsageFlags::COLOR_ATTACHMENT
+            | vk::ImageUsageFlags::TRANSFER_DST
+            | vk::ImageUsageFlags::TRANSFER_SRC;
+        let usage = Swapchain::select_usage(
+            Swapchain::REQUIRED_USAGE,
+            Swapchain::OPTIONAL_USAGE,
+            supported,
+        )
+        .unwrap();
+        assert!(usage.contains(vk::ImageUsageFlags::TRANSFER_DST));
+        assert!(usage.contains(vk::ImageUsageFlags::TRANSFER_SRC));
+    }
+
+    #[test]
+    fn swapchain_usage_fallback() {
+        let supported = vk::ImageUsageFlags::COLOR_ATTACHMENT;
+        let usage = Swapchain::select_usage(
+            Swapchain::REQUIRED_USAGE,
+            Swapchain::OPTIONAL_USAGE,
+            supported,
+        );
+        assert_eq!(usage, Some(vk::ImageUsageFlags::COLOR_ATTACHMENT));
+
+        let usage = Swapchain::select_usage(
+            Swapchain::REQUIRED_USAGE,
+            Swapchain::OPTIONAL_USAGE,
+            vk::ImageUsageFlags::TRANSFER_DST,
+        );
+        assert_eq!(usage, None);
+    }
+
+    #[test]
+    fn swapchain_image_count() {
+        assert_eq!(Swapchain::get_image_count(3, 2, 8), 3);
+        assert_eq!(Swapchain::get_image_count(3, 4, 8), 4);
+        assert_eq!(Swapchain::get_image_count(3, 1, 2), 2);
+        // No maximum
+        assert_eq!(Swapchain::get_image_count(3, 1, 0), 3);
+    }
+
+    #[test]
+    fn present_mode_fallback() {
+        let supported = [vk::PresentModeKHR::FIFO, vk::PresentModeKHR::MAILBOX];
+        let mode = Swapchain::select_present_mode(vk::PresentModeKHR::MAILBOX, &supported);
+        assert_eq!(mode, vk::PresentModeKHR::MAILBOX);
+
+        let mode = Swapchain::select_present_mode(vk::PresentModeKHR::IMMEDIATE, &supported);
+        assert_eq!(mode, vk::PresentModeKHR::FIFO);
+    }
+
+    #[test]
+    fn albedo_dont_care() {
+        let load_ops = AttachmentLoadOps {
+            albedo: vk::AttachmentLoadOp::DONT_CARE,
+            ..Default::default()
+        };
+        let attachments = Pass::get_attachments(
+            vk::Format::B8G8R8A8_SRGB,
+            vk::Format::D32_SFLOAT,
+            false,
+            vk::ImageLayout::PRESENT_SRC_KHR,
+            &load_ops,
+            &AttachmentFormats::new(vk::Format::B8G8R8A8_SRGB),
+        );
+        let [present, depth, albedo, normal, light] = attachments;
+        assert_eq!(albedo.load_op, vk::AttachmentLoadOp::DONT_CARE);
+        assert_eq!(depth.load_op, vk::AttachmentLoadOp::CLEAR);
+        assert_eq!(present.load_op, vk::AttachmentLoadOp::CLEAR);
+        assert_eq!(normal.load_op, vk::AttachmentLoadOp::CLEAR);
+        assert_eq!(light.load_op, vk::AttachmentLoadOp::CLEAR);
+        assert_eq!(depth.format, vk::Format::D32_SFLOAT);
+
+        // The G-buffer is left ready to be sampled
+        for attachment in [albedo, normal].iter() {
+            assert_eq!(attachment.store_op, vk::AttachmentStoreOp::STORE);
+            assert_eq!(
+                attachment.final_layout,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+            );
+        }
+    }
+
+    #[test]
+    fn hdr_albedo() {
+        let surface_format = vk::Format::B8G8R8A8_SRGB;
+        let get_formats = |formats: &AttachmentFormats| {
+            let attachments = Pass::get_attachments(
+                surface_format,
+                vk::Format::D32_SFLOAT,
+                false,
+                vk::ImageLayout::PRESENT_SRC_KHR,
+                &AttachmentLoadOps::default(),
+                formats,
+            );
+            let formats: Vec<vk::Format> = attachments.iter().map(|a| a.format).collect();
+            formats
+        };
+
+        let formats = get_formats(&AttachmentFormats::new(surface_format));
+        assert_eq!(formats[0], surface_format);
+        assert_eq!(formats[2], surface_format);
+        assert_eq!(formats[3], Framebuffer::NORMAL_FORMAT);
+        assert_eq!(formats[4], Framebuffer::LIGHT_FORMAT);
+
+        let formats = get_formats(&AttachmentFormats::hdr(surface_format));
+        assert_eq!(formats[0], surface_format);
+        assert_eq!(formats[2], vk::Format::R16G16B16A16_SFLOAT);
+    }
+
+    #[test]
+    fn depth_store() {
+        let (store_op, final_layout) = Pass::get_depth_store(true);
+        assert_eq!(store_op, vk::AttachmentStoreOp::STORE);
+        assert_eq!(
+            final_layout,
+            vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL
+        );
+
+        let (store_op, final_layout) = Pass::get_depth_store(false);
+        assert_eq!(store_op, vk::AttachmentStoreOp::DONT_CARE);
+        assert_eq!(
+            final_layout,
+            vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+        );
+    }
+
+    #[test]
+    fn format_support() {
+        // Half float is fine for rendering and sampling, but not for storage
+        let properties = vk::FormatProperties::builder()
+            .optimal_tiling_features(
+                vk::FormatFeatureFlags::COLOR_ATTACHMENT
+                    | vk::FormatFeatureFlags::SAMPLED_IMAGE
+                    | vk::FormatFeatureFlags::TRANSFER_SRC,
+            )
+            .linear_tiling_features(vk::FormatFeatureFlags::TRANSFER_SRC)
+            .build();
+
+        let optimal = vk::ImageTiling::OPTIMAL;
+        let render_sample = vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED;
+        assert!(Dev::is_format_supported(
+            &properties,
+            render_sample,
+            optimal
+        ));
+        assert!(!Dev::is_format_supported(
+            &properties,
+            render_sample | vk::ImageUsageFlags::STORAGE,
+            optimal
+        ));
+
+        let linear = vk::ImageTiling::LINEAR;
+        assert!(Dev::is_format_supported(
+            &properties,
+            vk::ImageUsageFlags::TRANSFER_SRC,
+            linear
+        ));
+        assert!(!Dev::is_format_supported(
+            &properties,
+            render_sample,
+            linear
+        ));
+    }
+
+    #[test]
+    fn read_back_barrier() {
+        let barrier = Buffer::get_read_back_barrier(vk::Buffer::null());
+        assert_eq!(barrier.src_access_mask, vk::AccessFlags::SHADER_WRITE);
+        assert_eq!(barrier.dst_access_mask, vk::AccessFlags::TRANSFER_READ);
+        assert_eq!(barrier.size, vk::WHOLE_SIZE);
+    }
+
+    #[test]
+    fn read_back_data_length() {
+        let ctx = Ctx::headless();
+        let dev = Dev::headless(&ctx);
+
+        // Three values are padded to the minimum buffer size
+        let values = [1u32, 2, 3];
+        let buffer = Buffer::new_arr(
+            &dev.allocator,
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_SRC,
+            &values,
+        );
+        assert!(buffer.size as usize > std::mem::size_of_val(&values));
+        assert_eq!(buffer.read_back::<u32>(&dev, values.len()), values);
+
+        let barrier = Buffer::get_host_read_barrier(buffer.buffer);
+        assert_eq!(barrier.src_access_mask, vk::AccessFlags::TRANSFER_WRITE);
+        assert_eq!(barrier.dst_access_mask, vk::AccessFlags::HOST_READ);
+    }
+
+    #[test]
+    fn pass_compatibility() {
+        let attachment = |format: vk::Format, store_op: vk::AttachmentStoreOp| {
+            vk::AttachmentDescription::builder()
+                .format(format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .store_op(store_op)
+                .build()
+        };
+        let color = vk::Format::B8G8R8A8_SRGB;
+        let store = vk::AttachmentStoreOp::STORE;
+        let dont_care = vk::AttachmentStoreOp::DONT_CARE;
+
+        // Pipeline built for the old pass
+        let old = Pass::get_compatibility(&[attachment(color, store)], 2, 0);
+
+        // Store ops do not affect compatibility
+        let rebuilt = Pass::get_compatibility(&[attachment(color, dont_care)], 2, 0);
+        assert_eq!(old, rebuilt);
+
+        // Different attachments do
+        let rebuilt =
+            Pass::get_compatibility(&[attachment(vk::Format::R8G8B8A8_UNORM, store)], 2, 0);
+        assert_ne!(old, rebuilt);
+        let rebuilt = Pass::get_compatibility(
+            &[
+                attachment(color, store),
+                attachment(vk::Format::D32_SFLOAT, store),
+            ],
+            2,
+            0,
+        );
+        assert_ne!(old, rebuilt);
+
+        // And so does multiview
+        let stereo = MultiviewConfig::stereo().get_view_mask();
+        let rebuilt = Pass::get_compatibility(&[attachment(color, store)], 2, stereo);
+        assert_ne!(old, rebuilt);
+    }
+}
@@ -0,0 +1,267 @@
+// Copyright © 2021
+// Author: Antonio Caggiano <info@antoniocaggiano.eu>
+// SPDX-License-Identifier: MIT
+
+use std::ffi::CString;
+
+use ash::*;
+
+use super::*;
+
+/// Push constants of the grid fragment shader
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct GridConstants {
+    /// Used to reconstruct world-space positions from depth
+    pub inv_view_proj: na::Matrix4<f32>,
+    /// World-space camera position
+    pub eye: na::Vector4<f32>,
+    pub color: na::Vector4<f32>,
+    /// Size of the scene viewport
+    pub extent: na::Vector2<f32>,
+    pub spacing: f32,
+    pub fade_distance: f32,
+    /// Position of the scene viewport within the framebuffer
+    pub offset: na::Vector2<f32>,
+    /// Whether depth values follow the reverse-Z convention, see `DepthConfig`
+    pub reverse_z: u32,
+}
+
+// Safe as it is made of 30 contiguous floats followed by a 4-byte flag
+unsafe impl Pod for GridConstants {}
+
+/// Returns the matrix transforming normalized device coordinates back to world space
+pub fn get_inv_view_proj(view: &na::Matrix4<f32>, proj: &na::Matrix4<f32>) -> na::Matrix4<f32> {
+    (proj * view)
+        .try_inverse()
+        .unwrap_or_else(na::Matrix4::identity)
+}
+
+/// Returns the world-space position of a fragment at `ndc` whose depth attachment value is
+/// `depth`, like the shaders do. Reverse-Z is undone by the inverse of its projection.
+pub fn reconstruct_world_position(
+    ndc: &na::Vector2<f32>,
+    depth: f32,
+    inv_view_proj: &na::Matrix4<f32>,
+) -> na::Point3<f32> {
+    let world = inv_view_proj * na::Vector4::new(ndc.x, ndc.y, depth, 1.0);
+    na::Point3::from(world.xyz() / world.w)
+}
+
+/// Returns where the view ray from `eye` through `far`, a point on the far plane,
+/// hits the ground plane y = 0, like the grid fragment shader does.
+/// Rays parallel to the plane or pointing away from it do not hit it.
+pub fn intersect_ground(eye: &na::Point3<f32>, far: &na::Point3<f32>) -> Option<na::Point3<f32>> {
+    let dir = far - eye;
+    if dir.y == 0.0 {
+        return None;
+    }
+    let t = -eye.y / dir.y;
+    if t <= 0.0 {
+        return None;
+    }
+    Some(eye + dir * t)
+}
+
+/// Analytic ground grid on the plane y = 0, drawn as a fullscreen triangle during the lighting
+/// subpass. Lines are computed per fragment from the reconstructed view ray, are hidden by the
+/// scene, and fade out with distance from the camera.
+pub struct GridRenderer {
+    /// Distance between two grid lines
+    pub spacing: f32,
+    /// Color in rgb and opacity in a
+    pub color: Color,
+    /// Distance from the camera where lines have faded out completely
+    pub fade_distance: f32,
+    pipeline: Pipeline,
+}
+
+impl GridRenderer {
+    pub fn new(dev: &Dev, pass: &Pass) -> Self {
+        let (width, height) = (1, 1);
+        Self {
+            spacing: 1.0,
+            color: Color::new(0.5, 0.5, 0.5, 0.5),
+            fade_distance: 64.0,
+            pipeline: Pipeline::grid(dev, pass, width, height),
+        }
+    }
+
+    /// Returns the world-space camera position, the translation of the inverse view
+    pub fn get_eye(view: &na::Matrix4<f32>) -> na::Vector3<f32> {
+        view.try_inverse()
+            .unwrap_or_else(na::Matrix4::identity)
+            .column(3)
+            .xyz()
+    }
+
+    pub fn get_constants(
+        &self,
+        view: &na::Matrix4<f32>,
+        proj: &na::Matrix4<f32>,
+        viewport: &vk::Rect2D,
+        depth_config: DepthConfig,
+    ) -> GridConstants {
+        let extent = viewport.extent;
+        let offset = viewport.offset;
+        GridConstants {
+            inv_view_proj: get_inv_view_proj(view, proj),
+            eye: Self::get_eye(view).push(1.0),
+            color: self.color.get_rgba(),
+            extent: na::Vector2::new(extent.width as f32, extent.height as f32),
+            spacing: self.spacing,
+            fade_distance: self.fade_distance,
+            offset: na::Vector2::new(offset.x as f32, offset.y as f32),
+            reverse_z: depth_config.reverse_z as u32,
+        }
+    }
+
+    /// Draws the grid with the view and projection of the current camera.
+    /// Must be called during the lighting subpass, after the light sets of the frame are ready.
+    pub fn draw(&self, frame: &mut Frame) {
+        frame.res.command_buffer.bind_pipeline(&self.pipeline);
+        // Light sets layouts are identically defined, hence compatible
+        frame.res.command_buffer.bind_descriptor_sets(
+            &self.pipeline,
+            &frame.res.descriptors.light_sets,
+            0,
+        );
+        frame
+            .res
+            .command_buffer
+            .bind_vertex_buffer(&frame.res.fallback.present_buffer, 0);
+
+        let viewport = frame.get_scene_rect();
+        let constants = self.get_constants(
+            &frame.current_view,
+            &frame.current_proj,
+            &viewport,
+            frame.get_depth_config(),
+        );
+        frame.res.command_buffer.push_constants_typed(
+            &self.pipeline,
+            vk::ShaderStageFlags::FRAGMENT,
+            0,
+            &constants,
+        );
+        frame.res.command_buffer.draw(3);
+    }
+}
+
+impl VertexInput for GridRenderer {
+    fn get_pipeline() -> Pipelines {
+        Pipelines::LIGHT
+    }
+
+    fn get_bindings() -> Vec<vk::VertexInputBindingDescription> {
+        PresentVertex::get_bindings()
+    }
+
+    fn get_attributes() -> Vec<vk::VertexInputAttributeDescription> {
+        PresentVertex::get_attributes()
+    }
+
+    fn get_set_layouts(device: &Device) -> Vec<vk::DescriptorSetLayout> {
+        Light::get_set_layouts(device)
+    }
+
+    fn get_constants() -> Vec<vk::PushConstantRange> {
+        vec![vk::PushConstantRange::builder()
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            .offset(0)
+            .size(std::mem::size_of::<GridConstants>() as u32)
+            .build()]
+    }
+
+    fn get_depth_state() -> vk::PipelineDepthStencilStateCreateInfo {
+        Light::get_depth_state()
+    }
+
+    fn get_color_blend(_subpass: u32) -> Vec<vk::PipelineColorBlendAttachmentState> {
+        // Lines are weighted by their opacity and added to the accumulated light
+        let mut blend = Light::get_additive_blend();
+        blend.src_color_blend_factor = vk::BlendFactor::SRC_ALPHA;
+        vec![blend]
+    }
+}
+
+impl Pipeline {
+    fn grid(dev: &Dev, pass: &Pass, width: u32, height: u32) -> Self {
+        let shader = ShaderModule::main(&dev.device);
+        let vs = CString::new("present_vs").expect("Failed to create entrypoint");
+        let fs = CString::new("grid_fs").expect("Failed to create entrypoint");
+
+        let dynamic_state = Pipeline::get_dynamic_state();
+
+        Self::new::<GridRenderer>(
+            dev,
+            shader.get_vert(&vs),
+            shader.get_frag(&fs),
+            vk::PrimitiveTopology::TRIANGLE_LIST,
+            &dynamic_state,
+            pass,
+            width,
+            height,
+            Pass::LIGHTING_SUBPASS,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn world_position_reconstruction() {
+        let eye = na::Point3::new(0.0, 4.0, 8.0);
+        let view = na::Matrix4::look_at_rh(&eye, &na::Point3::origin(), &na::Vector3::y());
+        let camera =
+            Camera::perspective_with_range(16.0 / 9.0, std::f32::consts::FRAC_PI_3, 0.1, 100.0);
+
+        // Project a known point as the pipeline would, with either convention
+        let point = na::Point3::new(1.0, 0.5, -2.0);
+        for depth_config in [DepthConfig::standard(), DepthConfig::reverse_z()].iter() {
+            let proj = camera.get_proj_for(*depth_config);
+            let clip = proj * view * point.to_homogeneous();
+            let ndc = clip.xyz() / clip.w;
+            let inv_view_proj = get_inv_view_proj(&view, &proj);
+            let world = reconstruct_world_position(&ndc.xy(), ndc.z, &inv_view_proj);
+            assert!((world - point).norm() < 1e-3);
+        }
+
+        // The camera position is the translation of the inverse view
+        let grid_eye = GridRenderer::get_eye(&view);
+        assert!((grid_eye - eye.coords).norm() < 1e-5);
+    }
+
+    #[test]
+    fn ground_intersection() {
+        // Looking down at the origin from above
+        let eye = na::Point3::new(0.0, 4.0, 8.0);
+        let hit = intersect_ground(&eye, &na::Point3::new(0.0, -96.0, -192.0)).unwrap();
+        assert!((hit - na::Point3::origin()).norm() < 1e-4);
+
+        // Below the camera, the ray hits the plane a quarter of the way to the far point
+        let eye = na::Point3::new(1.0, 2.0, 0.0);
+        let hit = intersect_ground(&eye, &na::Point3::new(5.0, -6.0, -8.0)).unwrap();
+        assert!((hit - na::Point3::new(2.0, 0.0, -2.0)).norm() < 1e-5);
+
+        // Rays looking up or parallel to the ground never hit it
+        assert!(intersect_ground(&eye, &na::Point3::new(0.0, 10.0, -8.0)).is_none());
+        assert!(intersect_ground(&eye, &na::Point3::new(0.0, 2.0, -8.0)).is_none());
+
+        // Full pipeline: the center of the screen looks at the origin
+        let eye = na::Point3::new(0.0, 4.0, 8.0);
+        let view = na::Matrix4::look_at_rh(&eye, &na::Point3::origin(), &na::Vector3::y());
+        let camera = Camera::perspective(16.0 / 9.0);
+        for depth_config in [DepthConfig::standard(), DepthConfig::reverse_z()].iter() {
+            let proj = camera.get_proj_for(*depth_config);
+            let inv_view_proj = get_inv_view_proj(&view, &proj);
+            // The far plane is where depth is cleared to
+            let far_depth = depth_config.get_clear_depth();
+            let far = reconstruct_world_position(&na::Vector2::zeros(), far_depth, &inv_view_proj);
+            let hit = intersect_ground(&eye, &far).unwrap();
+            assert!((hit - na::Point3::origin()).norm() < 1e-3);
+        }
+    }
+}
@@ -11,6 +11,45 @@ use im::internal::RawWrapper;
 use imgui as im;
 use memoffset::offset_of;
 
+/// Push constants of the GUI vertex shader
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GuiConstants {
+    transform: na::Matrix4<f32>,
+    /// Whether the target is sRGB, in which case imgui colors need to be linearized
+    srgb: u32,
+}
+
+// Safe as the matrix and the flag are 4-byte aligned, hence there is no padding between them
+unsafe impl Pod for GuiConstants {}
+
+// Safe as imgui vertices are two vectors and a packed color, without padding
+unsafe impl Pod for im::DrawVert {}
+
+/// How the texels of the font atlas are interpreted when sampled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FontAtlasSpace {
+    /// Texels are coverage values sampled as they are, which is what imgui expects
+    Linear,
+    /// Texels are decoded from sRGB when sampled, which darkens antialiased edges
+    Srgb,
+}
+
+impl Default for FontAtlasSpace {
+    fn default() -> Self {
+        FontAtlasSpace::Linear
+    }
+}
+
+impl FontAtlasSpace {
+    pub fn get_format(&self) -> vk::Format {
+        match self {
+            FontAtlasSpace::Linear => vk::Format::R8G8B8A8_UNORM,
+            FontAtlasSpace::Srgb => vk::Format::R8G8B8A8_SRGB,
+        }
+    }
+}
+
 pub struct Gui {
     /// Not common as camera and model, therefore we store it here
     set_layouts: Vec<vk::DescriptorSetLayout>,
@@ -21,23 +60,31 @@ pub struct Gui {
     view: ImageView,
     /// This is the font bitmap image, no need to cache it
     _image: Image,
+    font_space: FontAtlasSpace,
 
     width: f32,
     height: f32,
     scale: [f32; 2],
 
+    /// Imgui colors are sRGB-authored, so they should be converted to linear
+    /// when the hardware performs sRGB encoding on the render target
+    srgb: bool,
+
     pub mouse_down: [bool; 5],
 
+    /// Recent frame times recorded by the debug window
+    pub frame_times: FrameTimes,
+
     pub ctx: im::Context,
 
     device: Rc<Device>,
 }
 
 impl VertexInput for im::DrawVert {
-    fn get_bindings() -> vk::VertexInputBindingDescription {
-        vk::VertexInputBindingDescription::builder()
+    fn get_bindings() -> Vec<vk::VertexInputBindingDescription> {
+        vec![vk::VertexInputBindingDescription::builder()
             .stride(std::mem::size_of::<Self>() as u32)
-            .build()
+            .build()]
     }
 
     fn get_attributes() -> Vec<vk::VertexInputAttributeDescription> {
@@ -77,34 +124,17 @@ impl VertexInput for im::DrawVert {
         vec![vk::PushConstantRange::builder()
             .offset(0)
             .stage_flags(vk::ShaderStageFlags::VERTEX)
-            .size(std::mem::size_of::<na::Matrix4<f32>>() as u32)
+            .size(std::mem::size_of::<GuiConstants>() as u32)
             .build()]
     }
 
     fn write_set_image(
-        device: &Device,
+        writer: &mut DescriptorWriter,
         set: vk::DescriptorSet,
         view: &ImageView,
         sampler: &Sampler,
     ) {
-        let image_info = vk::DescriptorImageInfo::builder()
-            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
-            .sampler(sampler.sampler)
-            .image_view(view.view)
-            .build();
-
-        let image_write = vk::WriteDescriptorSet::builder()
-            .dst_set(set)
-            .dst_binding(0)
-            .dst_array_element(0)
-            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-            .image_info(&[image_info])
-            .build();
-
-        let writes = vec![image_write];
-        unsafe {
-            device.update_descriptor_sets(&writes, &[]);
-        }
+        writer.write_sampled_image(set, 0, view, sampler);
     }
 
     fn get_depth_state() -> vk::PipelineDepthStencilStateCreateInfo {
@@ -138,10 +168,7 @@ impl Pipeline {
         let vs = CString::new("gui_vs").expect("Failed to create entrypoint");
         let fs = CString::new("gui_fs").expect("Failed to create entrypoint");
 
-        let states = vec![vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
-        let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder()
-            .dynamic_states(&states)
-            .build();
+        let dynamic_state = Pipeline::get_dynamic_state();
 
         Self::new::<im::DrawVert>(
             dev,
@@ -152,13 +179,13 @@ impl Pipeline {
             pass,
             width,
             height,
-            1,
+            Pass::PRESENT_SUBPASS,
         )
     }
 }
 
 impl Gui {
-    fn build_font(dev: &Dev, ctx: &mut im::Context) -> Image {
+    fn build_font(dev: &Dev, ctx: &mut im::Context, space: FontAtlasSpace) -> Image {
         let mut fonts = ctx.fonts();
 
         // @todo Use Roboto
@@ -172,11 +199,27 @@ impl Gui {
         //}]);
 
         let font = fonts.build_rgba32_texture();
-        let format = vk::Format::R8G8B8A8_SRGB;
-        Image::from_data(dev, font.data, font.width, font.height, format)
+        let format = space.get_format();
+        Image::from_data(dev, font.data, font.width, font.height, format, None)
+    }
+
+    /// Whether vertex colors need to be linearized before blending into `target_format`.
+    /// An sRGB target blends in linear space and encodes the result, while a UNORM target
+    /// blends the sRGB-authored imgui colors as they are.
+    pub fn needs_linear_colors(target_format: vk::Format) -> bool {
+        Image::is_srgb_format(target_format)
     }
 
     pub fn new(win: &Win, dev: &Dev, pass: &Pass) -> Self {
+        Self::new_with_font_space(win, dev, pass, FontAtlasSpace::default())
+    }
+
+    pub fn new_with_font_space(
+        win: &Win,
+        dev: &Dev,
+        pass: &Pass,
+        font_space: FontAtlasSpace,
+    ) -> Self {
         let mut ctx = im::Context::create();
 
         let framebuffer_size = win.window.drawable_size();
@@ -213,7 +256,7 @@ impl Gui {
         io.key_map[im::Key::Y as usize] = sdl::keyboard::Scancode::Y as u32;
         io.key_map[im::Key::Z as usize] = sdl::keyboard::Scancode::Z as u32;
 
-        let image = Self::build_font(dev, &mut ctx);
+        let image = Self::build_font(dev, &mut ctx, font_space);
         let view = ImageView::new(&dev.device, &image);
         let sampler = Sampler::new(&dev.device);
 
@@ -221,21 +264,30 @@ impl Gui {
 
         let set_layouts = im::DrawVert::get_set_layouts(&dev.device);
 
+        let srgb = Self::needs_linear_colors(dev.surface_format.format);
+
         Self {
             set_layouts,
             pipeline,
             sampler,
             view,
             _image: image,
+            font_space,
             width,
             height,
             scale,
+            srgb,
             mouse_down: [false; 5],
+            frame_times: FrameTimes::new(120),
             ctx,
             device: dev.device.clone(),
         }
     }
 
+    pub fn get_font_space(&self) -> FontAtlasSpace {
+        self.font_space
+    }
+
     pub fn set_mouse_state(&mut self, mouse_state: &sdl::mouse::MouseState) -> bool {
         let io = self.ctx.io_mut();
         io.mouse_pos[0] = mouse_state.x() as f32 * self.scale[0];
@@ -311,41 +363,32 @@ impl Gui {
         let shift = na::Vector3::new(-1.0, -1.0, 0.0);
         transform.append_translation_mut(&shift);
 
-        let constants = unsafe {
-            std::slice::from_raw_parts(
-                transform.as_ptr() as *const u8,
-                std::mem::size_of::<na::Matrix4<f32>>(),
-            )
+        let constants = GuiConstants {
+            transform,
+            srgb: self.srgb as u32,
         };
-        res.command_buffer.push_constants(
+
+        res.command_buffer.push_constants_typed(
             &self.pipeline,
             vk::ShaderStageFlags::VERTEX,
             0,
-            constants,
+            &constants,
         );
 
-        // Bind descriptors
-        // TODO can we create this descriptor upfront?
-        if res.descriptors.gui_sets.is_empty() {
-            res.descriptors.gui_sets = res.descriptors.allocate(&self.set_layouts);
-
-            im::DrawVert::write_set_image(
-                &self.device,
-                res.descriptors.gui_sets[0],
-                &self.view,
-                &self.sampler,
-            )
-        }
+        // Bind descriptors, which are throwaway as the GUI is drawn from scratch every frame
+        let sets = res.transient.descriptors.allocate(&self.set_layouts);
+        let mut writer = DescriptorWriter::new();
+        im::DrawVert::write_set_image(&mut writer, sets[0], &self.view, &self.sampler);
+        writer.flush(&self.device);
         res.command_buffer
-            .bind_descriptor_sets(&self.pipeline, &res.descriptors.gui_sets, 0);
+            .bind_descriptor_sets(&self.pipeline, &sets, 0);
 
-        // Upload vertex and index buffers
-        res.gui_vertex_buffer.upload_arr(&vertex_data);
-        res.gui_index_buffer.upload_arr(&index_data);
-        // Bind vertex and index buffers
+        // Write vertices and indices to the transient buffer and bind them where they are
+        let (buffer, offset) = res.transient.buffer.push(&vertex_data, 4);
+        res.command_buffer.bind_vertex_buffer(buffer, offset);
+        let (buffer, offset) = res.transient.buffer.push(&index_data, 2);
         res.command_buffer
-            .bind_vertex_buffer(&res.gui_vertex_buffer);
-        res.command_buffer.bind_index_buffer(&res.gui_index_buffer);
+            .bind_index_buffer(buffer, offset, vk::IndexType::UINT16);
 
         let mut vertex_offset = 0;
         let mut index_offset = 0;
@@ -402,6 +445,17 @@ impl Gui {
         model: &Model,
         camera: Handle<Node>,
     ) {
+        self.frame_times.push(delta * 1000.0);
+        let samples = self.frame_times.get_samples();
+        let (min, avg, max) = (
+            self.frame_times.get_min(),
+            self.frame_times.get_avg(),
+            self.frame_times.get_max(),
+        );
+
+        // Captured before the GUI binds its own state
+        let bound = frame.debug_state();
+
         self.update(delta, &mut frame.res, |ui| {
             im::Window::new(im::im_str!("Debug"))
                 .no_decoration()
@@ -440,6 +494,18 @@ impl Gui {
                         }
                     }
 
+                    // Frame time
+                    ui.plot_lines(im::im_str!("##frame_time"), &samples)
+                        .overlay_text(&im::im_str!("{:.2} ms", avg))
+                        .scale_min(0.0)
+                        .scale_max(max.max(1.0))
+                        .graph_size([240.0, 48.0])
+                        .build();
+                    ui.text(format!(
+                        "Frame time\n · min {:.2} ms\n · avg {:.2} ms\n · max {:.2} ms",
+                        min, avg, max
+                    ));
+
                     // Camera
                     let camera_node = model.nodes.get(camera).unwrap();
                     let translation = camera_node.trs.get_translation();
@@ -454,6 +520,9 @@ impl Gui {
                         rotation.k,
                         rotation.w
                     ));
+
+                    // Bound state
+                    ui.text(bound.to_string());
                 });
         });
     }
@@ -468,3 +537,27 @@ impl Drop for Gui {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn font_atlas_format() {
+        assert_eq!(FontAtlasSpace::default(), FontAtlasSpace::Linear);
+        assert_eq!(
+            FontAtlasSpace::Linear.get_format(),
+            vk::Format::R8G8B8A8_UNORM
+        );
+        assert_eq!(FontAtlasSpace::Srgb.get_format(), vk::Format::R8G8B8A8_SRGB);
+
+        // Coverage is never decoded, whatever the target
+        assert!(!Image::is_srgb_format(
+            FontAtlasSpace::default().get_format()
+        ));
+
+        // Colors are blended in linear space only when the target encodes them back to sRGB
+        assert!(Gui::needs_linear_colors(vk::Format::B8G8R8A8_SRGB));
+        assert!(!Gui::needs_linear_colors(vk::Format::B8G8R8A8_UNORM));
+    }
+}
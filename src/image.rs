@@ -2,7 +2,7 @@
 // Author: Antonio Caggiano <info@antoniocaggiano.eu>
 // SPDX-License-Identifier: MIT
 
-use std::{cell::RefCell, fs::File, path::Path, rc::Rc};
+use std::{cell::RefCell, fs::File, ops::Range, path::Path, rc::Rc};
 
 use ash::*;
 
@@ -34,6 +34,8 @@ pub struct Image {
     pub extent: ash::vk::Extent3D,
     pub format: ash::vk::Format,
     pub color_space: ash::vk::ColorSpaceKHR,
+    /// Six for cubemaps, one otherwise
+    pub array_layers: u32,
     allocation: Option<vk_mem::Allocation>,
     allocator: Option<Rc<RefCell<vk_mem::Allocator>>>,
 }
@@ -47,6 +49,18 @@ impl Image {
             || format == vk::Format::D32_SFLOAT_S8_UINT
     }
 
+    /// Returns whether the hardware performs sRGB encoding when writing to this format,
+    /// and sRGB decoding when sampling from it
+    pub fn is_srgb_format(format: vk::Format) -> bool {
+        format == vk::Format::R8_SRGB
+            || format == vk::Format::R8G8_SRGB
+            || format == vk::Format::R8G8B8_SRGB
+            || format == vk::Format::B8G8R8_SRGB
+            || format == vk::Format::R8G8B8A8_SRGB
+            || format == vk::Format::B8G8R8A8_SRGB
+            || format == vk::Format::A8B8G8R8_SRGB_PACK32
+    }
+
     pub fn get_aspect_from_format(format: vk::Format) -> vk::ImageAspectFlags {
         if Self::is_depth_format(format) {
             vk::ImageAspectFlags::DEPTH
@@ -75,6 +89,7 @@ impl Image {
             extent,
             format,
             color_space,
+            array_layers: 1,
             allocation: None,
             allocator: None,
         }
@@ -88,8 +103,18 @@ impl Image {
         format: vk::Format,
         usage: vk::ImageUsageFlags,
     ) -> Self {
-        let allocator = allocator.clone();
+        Self::new_layered(allocator, width, height, format, usage, 1)
+    }
 
+    /// Creates a new empty image with `layers` array layers
+    pub fn new_layered(
+        allocator: &Rc<RefCell<vk_mem::Allocator>>,
+        width: u32,
+        height: u32,
+        format: vk::Format,
+        usage: vk::ImageUsageFlags,
+        layers: u32,
+    ) -> Self {
         let extent = ash::vk::Extent3D::builder()
             .width(width)
             .height(height)
@@ -100,7 +125,7 @@ impl Image {
             .image_type(ash::vk::ImageType::TYPE_2D)
             .extent(extent)
             .mip_levels(1)
-            .array_layers(1)
+            .array_layers(layers)
             .tiling(ash::vk::ImageTiling::OPTIMAL)
             .format(format)
             .initial_layout(ash::vk::ImageLayout::UNDEFINED)
@@ -109,26 +134,192 @@ impl Image {
             .samples(ash::vk::SampleCountFlags::TYPE_1)
             .build();
 
+        Self::from_create_info(allocator, &image_info)
+    }
+
+    /// Allocates device memory for an image described by `image_info`
+    fn from_create_info(
+        allocator: &Rc<RefCell<vk_mem::Allocator>>,
+        image_info: &vk::ImageCreateInfo,
+    ) -> Self {
+        let allocator = allocator.clone();
+
         let mut alloc_info = vk_mem::AllocationCreateInfo::default();
         alloc_info.usage = vk_mem::MemoryUsage::GpuOnly;
 
         let (image, allocation, _) = allocator
             .borrow_mut()
-            .create_image(&image_info, &alloc_info)
+            .create_image(image_info, &alloc_info)
             .expect("Failed to create Vulkan image");
 
         Self {
             managed: true,
             image,
             layout: ash::vk::ImageLayout::UNDEFINED,
-            extent,
-            format,
+            extent: image_info.extent,
+            format: image_info.format,
             color_space: vk::ColorSpaceKHR::default(),
+            array_layers: image_info.array_layers,
             allocation: Some(allocation),
             allocator: Some(allocator),
         }
     }
 
+    /// Number of faces of a cubemap, in order +X, -X, +Y, -Y, +Z, -Z
+    pub const CUBE_FACES: u32 = 6;
+
+    /// Returns the create info of a square cubemap with `size` pixels per side
+    fn get_cubemap_create_info(
+        size: u32,
+        format: vk::Format,
+        usage: vk::ImageUsageFlags,
+    ) -> vk::ImageCreateInfo {
+        let extent = vk::Extent3D::builder()
+            .width(size)
+            .height(size)
+            .depth(1)
+            .build();
+
+        vk::ImageCreateInfo::builder()
+            .flags(vk::ImageCreateFlags::CUBE_COMPATIBLE)
+            .image_type(vk::ImageType::TYPE_2D)
+            .extent(extent)
+            .mip_levels(1)
+            .array_layers(Self::CUBE_FACES)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .format(format)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .build()
+    }
+
+    /// Creates an empty cubemap which can be uploaded and sampled, for example for skyboxes
+    pub fn cubemap(
+        allocator: &Rc<RefCell<vk_mem::Allocator>>,
+        size: u32,
+        format: vk::Format,
+    ) -> Self {
+        let usage = vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED;
+        let image_info = Self::get_cubemap_create_info(size, format, usage);
+        Self::from_create_info(allocator, &image_info)
+    }
+
+    /// Returns copy regions from a buffer where faces are tightly packed one after the other
+    fn get_cubemap_regions(size: u32, face_size: vk::DeviceSize) -> Vec<vk::BufferImageCopy> {
+        let extent = vk::Extent3D::builder()
+            .width(size)
+            .height(size)
+            .depth(1)
+            .build();
+        (0..Self::CUBE_FACES)
+            .map(|face| {
+                vk::BufferImageCopy::builder()
+                    .buffer_offset(face as vk::DeviceSize * face_size)
+                    .image_subresource(
+                        vk::ImageSubresourceLayers::builder()
+                            .aspect_mask(vk::ImageAspectFlags::COLOR)
+                            .base_array_layer(face)
+                            .layer_count(1)
+                            .build(),
+                    )
+                    .image_extent(extent)
+                    .build()
+            })
+            .collect()
+    }
+
+    /// Loads six square PNG images of the same size, one for each face in `CUBE_FACES` order,
+    /// and uploads them into a cubemap ready to be sampled
+    pub fn load_cubemap(dev: &Dev, paths: [&str; 6]) -> Self {
+        let mut pngs: Vec<Png> = paths.iter().map(|path| Png::open(path)).collect();
+        let size = pngs[0].info.width;
+        let face_size = pngs[0].info.buffer_size();
+        for png in &pngs {
+            assert!(
+                png.info.width == size && png.info.height == size,
+                "Cubemap faces should be squares of the same size"
+            );
+            assert_eq!(png.info.buffer_size(), face_size);
+        }
+
+        // All faces go into a single staging buffer
+        let mut staging = dev.acquire_staging((face_size * pngs.len()) as vk::DeviceSize);
+        let data = staging.map_memory();
+        for (face, png) in pngs.iter_mut().enumerate() {
+            let face_data =
+                unsafe { std::slice::from_raw_parts_mut(data.add(face * face_size), face_size) };
+            png.reader
+                .next_frame(face_data)
+                .expect("Failed to read cubemap face");
+        }
+        staging.unmap_memory();
+
+        let mut image = Self::cubemap(&dev.allocator, size, vk::Format::R8G8B8A8_SRGB);
+        let regions = Self::get_cubemap_regions(size, face_size as vk::DeviceSize);
+        image.copy_regions_from(&staging, &regions, dev);
+        dev.release_staging(staging);
+        image
+    }
+
+    /// Like `copy_from`, but for all the array layers and with custom regions
+    fn copy_regions_from(&mut self, staging: &Buffer, regions: &[vk::BufferImageCopy], dev: &Dev) {
+        let mut upload = UploadContext::new(dev);
+        self.record_copy_regions_from(staging, regions, &mut upload);
+        upload.finish(dev);
+    }
+
+    /// Records the copy of `regions` of `staging` into all the array layers of this image,
+    /// which is then ready to be sampled. `staging` should be alive until `upload` is finished.
+    fn record_copy_regions_from(
+        &mut self,
+        staging: &Buffer,
+        regions: &[vk::BufferImageCopy],
+        upload: &mut UploadContext,
+    ) {
+        let range = vk::ImageSubresourceRange::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .level_count(1)
+            .layer_count(self.array_layers)
+            .build();
+
+        // Undefined -> Transfer dst optimal
+        let to_transfer = vk::ImageMemoryBarrier::builder()
+            .old_layout(self.layout)
+            .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .image(self.image)
+            .subresource_range(range)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .build();
+        let command_buffer = upload.get_command_buffer();
+        command_buffer.pipeline_barriers(
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::default(),
+            &[to_transfer],
+        );
+        self.layout = vk::ImageLayout::TRANSFER_DST_OPTIMAL;
+
+        command_buffer.copy_buffer_to_image_regions(staging, self, regions);
+
+        // Transfer dst optimal -> Shader read only optimal
+        let to_shader = vk::ImageMemoryBarrier::builder()
+            .old_layout(self.layout)
+            .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .image(self.image)
+            .subresource_range(range)
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .build();
+        upload.release_image(
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::FRAGMENT_SHADER,
+            &to_shader,
+        );
+        self.layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+    }
+
     /// Create an image that can be used as an input or output attachment
     pub fn attachment(
         allocator: &Rc<RefCell<vk_mem::Allocator>>,
@@ -136,12 +327,30 @@ impl Image {
         height: u32,
         format: vk::Format,
     ) -> Self {
-        let usage = if Image::is_depth_format(format) {
-            vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::INPUT_ATTACHMENT
+        Self::layered_attachment(allocator, width, height, format, 1)
+    }
+
+    /// Create an attachment with an array layer for each view of a multiview render pass
+    pub fn layered_attachment(
+        allocator: &Rc<RefCell<vk_mem::Allocator>>,
+        width: u32,
+        height: u32,
+        format: vk::Format,
+        layers: u32,
+    ) -> Self {
+        let usage = Self::get_attachment_usage(format);
+        Self::new_layered(allocator, width, height, format, usage, layers)
+    }
+
+    /// Attachments can be read as input attachments in a following subpass,
+    /// or sampled by a custom post-process pass
+    pub fn get_attachment_usage(format: vk::Format) -> vk::ImageUsageFlags {
+        let usage = vk::ImageUsageFlags::INPUT_ATTACHMENT | vk::ImageUsageFlags::SAMPLED;
+        if Image::is_depth_format(format) {
+            usage | vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT
         } else {
-            vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::INPUT_ATTACHMENT
-        };
-        Self::new(allocator, width, height, format, usage)
+            usage | vk::ImageUsageFlags::COLOR_ATTACHMENT
+        }
     }
 
     /// Create an image that can be used to upload data from disk and sampled from a fragment shader
@@ -160,40 +369,65 @@ impl Image {
         )
     }
 
-    pub fn from_data(dev: &Dev, data: &[u8], width: u32, height: u32, format: vk::Format) -> Self {
+    /// Creates a sampled image filled with `data`. The copy is recorded into `upload` when
+    /// available, so that the image can only be used after it is finished, otherwise it is
+    /// submitted and waited straight away.
+    pub fn from_data(
+        dev: &Dev,
+        data: &[u8],
+        width: u32,
+        height: u32,
+        format: vk::Format,
+        upload: Option<&mut UploadContext>,
+    ) -> Self {
         let mut image = Self::sampled(&dev.allocator, width, height, format);
 
-        let usage = ash::vk::BufferUsageFlags::TRANSFER_SRC;
-        let staging = Buffer::from_data(&dev.allocator, data, usage);
-        image.copy_from(&staging, dev);
+        let mut staging = dev.acquire_staging(data.len() as vk::DeviceSize);
+        staging.upload_raw(data.as_ptr(), data.len() as vk::DeviceSize);
+        image.upload_from(staging, dev, upload);
         image
     }
 
-    /// Loads a PNG image from file and uploads it into a sampled image
-    pub fn load(dev: &Dev, path: &str) -> Self {
+    /// Loads a PNG image from file and uploads it into a sampled image, like `from_data`
+    pub fn load(dev: &Dev, path: &str, upload: Option<&mut UploadContext>) -> Self {
         let mut png = Png::open(path);
-        let staging = Buffer::load(&dev.allocator, &mut png);
+        let size = png.info.buffer_size();
+        let mut staging = dev.acquire_staging(size as vk::DeviceSize);
+        let data = unsafe { std::slice::from_raw_parts_mut(staging.map_memory(), size) };
+        png.reader.next_frame(data).expect("Failed to read image");
+        staging.unmap_memory();
+
         let mut image = Image::sampled(
             &dev.allocator,
             png.info.width,
             png.info.height,
             vk::Format::R8G8B8A8_SRGB,
         );
-        image.copy_from(&staging, dev);
+        image.upload_from(staging, dev, upload);
         image
     }
 
-    pub fn transition(&mut self, dev: &Dev, new_layout: vk::ImageLayout) {
-        // @todo Use TRANSFER pool and transfer queue?
-        let command_buffer = CommandBuffer::new(&dev.graphics_command_pool);
+    /// Copies the whole `staging` buffer into this image, keeping it alive until the copy is finished
+    fn upload_from(&mut self, staging: Buffer, dev: &Dev, upload: Option<&mut UploadContext>) {
+        let regions = [self.get_copy_region()];
+        UploadContext::record_or_submit(dev, upload, |upload| {
+            self.record_copy_regions_from(&staging, &regions, upload);
+            upload.keep(staging);
+        });
+    }
 
-        command_buffer.begin(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+    pub fn transition(&mut self, dev: &Dev, new_layout: vk::ImageLayout) {
+        let mut upload = UploadContext::new(dev);
+        self.record_transition(new_layout, &mut upload);
+        upload.finish(dev);
+    }
 
+    /// Records the transition of this image to `new_layout` into `upload`
+    pub fn record_transition(&mut self, new_layout: vk::ImageLayout, upload: &mut UploadContext) {
         // Old layout -> New layout
         let src_stage_mask = ash::vk::PipelineStageFlags::TOP_OF_PIPE;
         let dst_stage_mask = ash::vk::PipelineStageFlags::TRANSFER;
-        let dependency_flags = ash::vk::DependencyFlags::default();
-        let image_memory_barriers = vec![ash::vk::ImageMemoryBarrier::builder()
+        let image_memory_barrier = ash::vk::ImageMemoryBarrier::builder()
             .old_layout(self.layout)
             .new_layout(new_layout)
             .image(self.image)
@@ -203,122 +437,449 @@ impl Image {
                     .base_mip_level(0)
                     .level_count(1)
                     .base_array_layer(0)
-                    .layer_count(1)
+                    .layer_count(self.array_layers)
                     .build(),
             )
             .dst_access_mask(ash::vk::AccessFlags::TRANSFER_WRITE)
-            .build()];
-        command_buffer.pipeline_barriers(
-            src_stage_mask,
-            dst_stage_mask,
-            dependency_flags,
-            &image_memory_barriers,
-        );
+            .build();
+        upload.release_image(src_stage_mask, dst_stage_mask, &image_memory_barrier);
 
         self.layout = new_layout;
+    }
 
-        command_buffer.end();
+    /// Returns the region covering the first layer of this image
+    fn get_copy_region(&self) -> vk::BufferImageCopy {
+        ash::vk::BufferImageCopy::builder()
+            .image_subresource(
+                ash::vk::ImageSubresourceLayers::builder()
+                    .aspect_mask(ash::vk::ImageAspectFlags::COLOR)
+                    .layer_count(1)
+                    .build(),
+            )
+            .image_extent(self.extent)
+            .build()
+    }
 
-        let mut fence = Fence::unsignaled(&dev.device);
+    pub fn copy_from(&mut self, staging: &Buffer, dev: &Dev) {
+        let mut upload = UploadContext::new(dev);
+        self.record_copy_from(staging, &mut upload);
+        upload.finish(dev);
+    }
 
-        let submits = [ash::vk::SubmitInfo::builder()
-            .command_buffers(&[command_buffer.command_buffer])
-            .build()];
-        dev.graphics_queue.submit(&submits, Some(&mut fence));
+    /// Records the copy of `staging` into this image, which is then ready to be sampled.
+    /// `staging` should be alive until `upload` is finished.
+    pub fn record_copy_from(&mut self, staging: &Buffer, upload: &mut UploadContext) {
+        self.record_copy_regions_from(staging, &[self.get_copy_region()], upload);
+    }
 
-        fence.wait();
+    /// Returns the size in bytes of a texel of `format`, or `None` when it is not known
+    pub fn get_texel_size(format: vk::Format) -> Option<u32> {
+        let size = match format {
+            vk::Format::R8_UNORM | vk::Format::R8_SRGB => 1,
+            vk::Format::R8G8_UNORM | vk::Format::R8G8_SRGB | vk::Format::D16_UNORM => 2,
+            vk::Format::R8G8B8A8_UNORM
+            | vk::Format::R8G8B8A8_SRGB
+            | vk::Format::B8G8R8A8_UNORM
+            | vk::Format::B8G8R8A8_SRGB
+            | vk::Format::A8B8G8R8_SRGB_PACK32
+            | vk::Format::A2B10G10R10_UNORM_PACK32
+            | vk::Format::R32_SFLOAT
+            | vk::Format::D32_SFLOAT => 4,
+            vk::Format::R16G16B16A16_SFLOAT | vk::Format::R32G32_SFLOAT => 8,
+            vk::Format::R32G32B32A32_SFLOAT => 16,
+            _ => return None,
+        };
+        Some(size)
     }
 
-    pub fn copy_from(&mut self, staging: &Buffer, dev: &Dev) {
-        // @todo Use TRANSFER pool and transfer queue
-        let command_buffer = CommandBuffer::new(&dev.graphics_command_pool);
+    /// Returns whether `cmd_copy_image` can copy between these formats. Color formats only need
+    /// texels of the same size, as bytes are copied without conversion, while depth ones must match.
+    pub fn can_copy(src_format: vk::Format, dst_format: vk::Format) -> bool {
+        if src_format == dst_format {
+            return true;
+        }
+        if Self::is_depth_format(src_format) || Self::is_depth_format(dst_format) {
+            return false;
+        }
+        match (
+            Self::get_texel_size(src_format),
+            Self::get_texel_size(dst_format),
+        ) {
+            (Some(src), Some(dst)) => src == dst,
+            _ => false,
+        }
+    }
 
-        command_buffer.begin(ash::vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+    /// Returns whether `cmd_blit_image` can blit between formats with these optimal tiling
+    /// features. Depth formats can only be blitted into the same format without filtering.
+    pub fn can_blit(
+        src_format: vk::Format,
+        src_features: vk::FormatFeatureFlags,
+        dst_format: vk::Format,
+        dst_features: vk::FormatFeatureFlags,
+        filter: vk::Filter,
+    ) -> bool {
+        if !src_features.contains(vk::FormatFeatureFlags::BLIT_SRC)
+            || !dst_features.contains(vk::FormatFeatureFlags::BLIT_DST)
+        {
+            return false;
+        }
+        if Self::is_depth_format(src_format) || Self::is_depth_format(dst_format) {
+            return src_format == dst_format && filter == vk::Filter::NEAREST;
+        }
+        filter != vk::Filter::LINEAR
+            || src_features.contains(vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR)
+    }
 
-        // Undefined -> Transfer dst optimal
-        let new_layout = ash::vk::ImageLayout::TRANSFER_DST_OPTIMAL;
+    /// Returns the subresource of the first mip level of the first `layer_count` layers
+    fn get_subresource_layers(&self, layer_count: u32) -> vk::ImageSubresourceLayers {
+        vk::ImageSubresourceLayers::builder()
+            .aspect_mask(Image::get_aspect_from_format(self.format))
+            .mip_level(0)
+            .base_array_layer(0)
+            .layer_count(layer_count)
+            .build()
+    }
 
-        let src_stage_mask = ash::vk::PipelineStageFlags::TOP_OF_PIPE;
-        let dst_stage_mask = ash::vk::PipelineStageFlags::TRANSFER;
-        let dependency_flags = ash::vk::DependencyFlags::default();
-        let image_memory_barriers = vec![ash::vk::ImageMemoryBarrier::builder()
-            .old_layout(self.layout)
+    /// Returns the region scaling the whole of `src_extent` onto the whole of `dst_extent`
+    fn get_blit_region(
+        src: vk::ImageSubresourceLayers,
+        src_extent: vk::Extent3D,
+        dst: vk::ImageSubresourceLayers,
+        dst_extent: vk::Extent3D,
+    ) -> vk::ImageBlit {
+        let corner = |extent: vk::Extent3D| {
+            vk::Offset3D::builder()
+                .x(extent.width as i32)
+                .y(extent.height as i32)
+                .z(extent.depth as i32)
+                .build()
+        };
+        vk::ImageBlit::builder()
+            .src_subresource(src)
+            .src_offsets([vk::Offset3D::default(), corner(src_extent)])
+            .dst_subresource(dst)
+            .dst_offsets([vk::Offset3D::default(), corner(dst_extent)])
+            .build()
+    }
+
+    /// Returns a barrier between previous accesses to this image and a transfer in `new_layout`,
+    /// or between the transfer in `old_layout` and following accesses
+    fn get_transfer_barrier(
+        &self,
+        old_layout: vk::ImageLayout,
+        new_layout: vk::ImageLayout,
+    ) -> vk::ImageMemoryBarrier {
+        let transfer_access = |layout| match layout {
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL => Some(vk::AccessFlags::TRANSFER_READ),
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL => Some(vk::AccessFlags::TRANSFER_WRITE),
+            _ => None,
+        };
+        let any_access = vk::AccessFlags::MEMORY_READ | vk::AccessFlags::MEMORY_WRITE;
+        let src_access_mask = transfer_access(old_layout).unwrap_or(vk::AccessFlags::MEMORY_WRITE);
+        let dst_access_mask = transfer_access(new_layout).unwrap_or(any_access);
+
+        vk::ImageMemoryBarrier::builder()
+            .old_layout(old_layout)
             .new_layout(new_layout)
             .image(self.image)
             .subresource_range(
-                ash::vk::ImageSubresourceRange::builder()
-                    .aspect_mask(ash::vk::ImageAspectFlags::COLOR)
+                vk::ImageSubresourceRange::builder()
+                    .aspect_mask(Image::get_aspect_from_format(self.format))
                     .base_mip_level(0)
                     .level_count(1)
                     .base_array_layer(0)
-                    .layer_count(1)
+                    .layer_count(self.array_layers)
                     .build(),
             )
-            .dst_access_mask(ash::vk::AccessFlags::TRANSFER_WRITE)
-            .build()];
+            .src_access_mask(src_access_mask)
+            .dst_access_mask(dst_access_mask)
+            .build()
+    }
 
+    /// Records `record` between the transitions of this image to a transfer source and of `dst`
+    /// to a transfer destination, then submits it and waits for it. Both images are transitioned
+    /// back to their previous layouts, except undefined ones which stay in transfer layouts.
+    fn transfer_to<F>(&mut self, dst: &mut Image, dev: &Dev, record: F)
+    where
+        F: FnOnce(&CommandBuffer, &Image, &Image),
+    {
+        let command_buffer = CommandBuffer::new(&dev.transient_command_pool);
+        command_buffer.begin(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+
+        // Wait for anything writing to these images, like a render pass
+        let (src_layout, dst_layout) = (self.layout, dst.layout);
         command_buffer.pipeline_barriers(
-            src_stage_mask,
-            dst_stage_mask,
-            dependency_flags,
-            &image_memory_barriers,
+            vk::PipelineStageFlags::ALL_COMMANDS,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::default(),
+            &[
+                self.get_transfer_barrier(src_layout, vk::ImageLayout::TRANSFER_SRC_OPTIMAL),
+                dst.get_transfer_barrier(dst_layout, vk::ImageLayout::TRANSFER_DST_OPTIMAL),
+            ],
         );
+        self.layout = vk::ImageLayout::TRANSFER_SRC_OPTIMAL;
+        dst.layout = vk::ImageLayout::TRANSFER_DST_OPTIMAL;
 
-        self.layout = new_layout;
+        record(&command_buffer, self, dst);
 
-        // Copy
-        let region = ash::vk::BufferImageCopy::builder()
-            .image_subresource(
-                ash::vk::ImageSubresourceLayers::builder()
-                    .aspect_mask(ash::vk::ImageAspectFlags::COLOR)
-                    .layer_count(1)
-                    .build(),
-            )
-            .image_extent(self.extent)
+        let mut barriers = vec![];
+        if src_layout != vk::ImageLayout::UNDEFINED {
+            barriers.push(self.get_transfer_barrier(self.layout, src_layout));
+            self.layout = src_layout;
+        }
+        if dst_layout != vk::ImageLayout::UNDEFINED {
+            barriers.push(dst.get_transfer_barrier(dst.layout, dst_layout));
+            dst.layout = dst_layout;
+        }
+        if !barriers.is_empty() {
+            command_buffer.pipeline_barriers(
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::DependencyFlags::default(),
+                &barriers,
+            );
+        }
+
+        command_buffer.end();
+
+        let mut fence = Fence::unsignaled(&dev.device);
+        let submits = [vk::SubmitInfo::builder()
+            .command_buffers(&[command_buffer.command_buffer])
+            .build()];
+        dev.graphics_queue.submit(&submits, Some(&mut fence));
+        fence.wait();
+    }
+
+    /// Copies the first mip level of this image into `dst`, within the extent they have in common.
+    /// Formats must be compatible, see `can_copy`. This image needs the `TRANSFER_SRC` usage and
+    /// `dst` the `TRANSFER_DST` one.
+    pub fn copy_to(&mut self, dst: &mut Image, dev: &Dev) {
+        assert!(
+            Self::can_copy(self.format, dst.format),
+            "Failed to copy image: {:?} is not compatible with {:?}",
+            self.format,
+            dst.format
+        );
+
+        let layer_count = self.array_layers.min(dst.array_layers);
+        let extent = vk::Extent3D::builder()
+            .width(self.extent.width.min(dst.extent.width))
+            .height(self.extent.height.min(dst.extent.height))
+            .depth(self.extent.depth.min(dst.extent.depth))
+            .build();
+        let region = vk::ImageCopy::builder()
+            .src_subresource(self.get_subresource_layers(layer_count))
+            .dst_subresource(dst.get_subresource_layers(layer_count))
+            .extent(extent)
             .build();
-        command_buffer.copy_buffer_to_image(&staging, self, &region);
 
-        // Transfer dst optimal -> Shader read only optimal
-        let new_layout = ash::vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+        self.transfer_to(dst, dev, |command_buffer, src, dst| {
+            command_buffer.copy_image(src, dst, &region)
+        });
+    }
 
-        let src_stage_mask = ash::vk::PipelineStageFlags::TRANSFER;
-        let dst_stage_mask = ash::vk::PipelineStageFlags::FRAGMENT_SHADER;
-        let dependency_flags = ash::vk::DependencyFlags::default();
-        let image_memory_barriers = vec![ash::vk::ImageMemoryBarrier::builder()
-            .old_layout(self.layout)
+    /// Blits the whole first mip level of this image onto the whole of `dst`, scaling it with
+    /// `filter` and converting its format. Formats must support it, see `can_blit`.
+    /// This image needs the `TRANSFER_SRC` usage and `dst` the `TRANSFER_DST` one.
+    pub fn blit_to(&mut self, dst: &mut Image, dev: &Dev, filter: vk::Filter) {
+        let src_features = dev
+            .get_format_properties(self.format)
+            .optimal_tiling_features;
+        let dst_features = dev
+            .get_format_properties(dst.format)
+            .optimal_tiling_features;
+        assert!(
+            Self::can_blit(self.format, src_features, dst.format, dst_features, filter),
+            "Failed to blit image: {:?} can not be blitted into {:?} with {:?}",
+            self.format,
+            dst.format,
+            filter
+        );
+
+        let layer_count = self.array_layers.min(dst.array_layers);
+        let region = Self::get_blit_region(
+            self.get_subresource_layers(layer_count),
+            self.extent,
+            dst.get_subresource_layers(layer_count),
+            dst.extent,
+        );
+
+        self.transfer_to(dst, dev, |command_buffer, src, dst| {
+            command_buffer.blit_image(src, dst, &region, filter)
+        });
+    }
+
+    /// Returns a barrier making previous writes to this image available to a transfer
+    /// in `new_layout`, or making the transfer available to following commands
+    fn get_read_back_barrier(
+        &self,
+        old_layout: vk::ImageLayout,
+        new_layout: vk::ImageLayout,
+    ) -> vk::ImageMemoryBarrier {
+        let (src_access_mask, dst_access_mask) =
+            if new_layout == vk::ImageLayout::TRANSFER_SRC_OPTIMAL {
+                (
+                    vk::AccessFlags::MEMORY_WRITE,
+                    vk::AccessFlags::TRANSFER_READ,
+                )
+            } else {
+                (vk::AccessFlags::TRANSFER_READ, vk::AccessFlags::MEMORY_READ)
+            };
+        vk::ImageMemoryBarrier::builder()
+            .old_layout(old_layout)
             .new_layout(new_layout)
             .image(self.image)
             .subresource_range(
-                ash::vk::ImageSubresourceRange::builder()
-                    .aspect_mask(ash::vk::ImageAspectFlags::COLOR)
+                vk::ImageSubresourceRange::builder()
+                    .aspect_mask(Image::get_aspect_from_format(self.format))
                     .base_mip_level(0)
                     .level_count(1)
                     .base_array_layer(0)
                     .layer_count(1)
                     .build(),
             )
-            .src_access_mask(ash::vk::AccessFlags::TRANSFER_WRITE)
-            .dst_access_mask(ash::vk::AccessFlags::SHADER_READ)
-            .build()];
+            .src_access_mask(src_access_mask)
+            .dst_access_mask(dst_access_mask)
+            .build()
+    }
+
+    /// Copies the pixel at `x` and `y` into a staging buffer and returns its color as RGBA,
+    /// with sRGB values converted to linear. The image needs the `TRANSFER_SRC` usage and
+    /// is transitioned back to its current layout afterwards.
+    pub fn read_pixel(&mut self, dev: &Dev, x: u32, y: u32) -> [f32; 4] {
+        assert!(x < self.extent.width && y < self.extent.height);
+
+        let mut staging =
+            Buffer::new_with_size(&dev.allocator, vk::BufferUsageFlags::TRANSFER_DST, 32);
+
+        let command_buffer = CommandBuffer::new(&dev.transient_command_pool);
+        command_buffer.begin(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+
+        // Wait for anything writing to this image, like a render pass
+        let old_layout = self.layout;
         command_buffer.pipeline_barriers(
-            src_stage_mask,
-            dst_stage_mask,
-            dependency_flags,
-            &image_memory_barriers,
+            vk::PipelineStageFlags::ALL_COMMANDS,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::default(),
+            &[self.get_read_back_barrier(old_layout, vk::ImageLayout::TRANSFER_SRC_OPTIMAL)],
         );
+        self.layout = vk::ImageLayout::TRANSFER_SRC_OPTIMAL;
 
-        self.layout = new_layout;
+        let region = vk::BufferImageCopy::builder()
+            .image_subresource(
+                vk::ImageSubresourceLayers::builder()
+                    .aspect_mask(Image::get_aspect_from_format(self.format))
+                    .layer_count(1)
+                    .build(),
+            )
+            .image_offset(vk::Offset3D::builder().x(x as i32).y(y as i32).build())
+            .image_extent(vk::Extent3D::builder().width(1).height(1).depth(1).build())
+            .build();
+        command_buffer.copy_image_to_buffer(self, &staging, &region);
+        command_buffer.buffer_barriers(
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::HOST,
+            &[Buffer::get_host_read_barrier(staging.buffer)],
+        );
+
+        // An undefined layout can not be transitioned to, so the image stays a transfer source
+        if old_layout != vk::ImageLayout::UNDEFINED {
+            command_buffer.pipeline_barriers(
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::DependencyFlags::default(),
+                &[self.get_read_back_barrier(self.layout, old_layout)],
+            );
+            self.layout = old_layout;
+        }
 
         command_buffer.end();
 
         let mut fence = Fence::unsignaled(&dev.device);
-
-        let submits = [ash::vk::SubmitInfo::builder()
+        let submits = [vk::SubmitInfo::builder()
             .command_buffers(&[command_buffer.command_buffer])
             .build()];
         dev.graphics_queue.submit(&submits, Some(&mut fence));
-
         fence.wait();
+
+        let bytes = staging.read_mapped::<u8>(staging.size as usize);
+        Self::decode_pixel(self.format, &bytes)
+    }
+
+    /// Converts an sRGB encoded value to linear
+    fn srgb_to_linear(value: f32) -> f32 {
+        if value <= 0.04045 {
+            value / 12.92
+        } else {
+            ((value + 0.055) / 1.055).powf(2.4)
+        }
+    }
+
+    /// Converts a 32-bit float to the nearest 16-bit one
+    pub(crate) fn f32_to_half(value: f32) -> u16 {
+        let bits = value.to_bits();
+        let sign = ((bits >> 16) & 0x8000) as u16;
+        let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+        let mantissa = bits & 0x7f_ffff;
+        if value.is_nan() {
+            return sign | 0x7e00;
+        }
+        if exponent >= 0x1f {
+            return sign | 0x7c00;
+        }
+        if exponent <= 0 {
+            // Subnormal, or too small
+            if exponent < -10 {
+                return sign;
+            }
+            let mantissa = mantissa | 0x80_0000;
+            let shift = (14 - exponent) as u32;
+            let half = (mantissa >> shift) + ((mantissa >> (shift - 1)) & 1);
+            return sign | half as u16;
+        }
+        // Rounding may carry into the exponent, up to infinity
+        let half = ((exponent as u32) << 10 | (mantissa >> 13)) + ((mantissa >> 12) & 1);
+        sign | half as u16
+    }
+
+    /// Converts a 16-bit float to a 32-bit one
+    pub(crate) fn half_to_f32(half: u16) -> f32 {
+        let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+        let exponent = ((half >> 10) & 0x1f) as i32;
+        let mantissa = (half & 0x3ff) as f32;
+        match exponent {
+            // Subnormal
+            0 => sign * mantissa * 2f32.powi(-24),
+            0x1f if mantissa == 0.0 => sign * f32::INFINITY,
+            0x1f => f32::NAN,
+            _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+        }
+    }
+
+    /// Decodes the bytes of a pixel with `format` into linear RGBA.
+    /// Missing channels are 0, missing alpha is 1.
+    pub fn decode_pixel(format: vk::Format, bytes: &[u8]) -> [f32; 4] {
+        let unorm = |i: usize| bytes[i] as f32 / 255.0;
+        let srgb = |i: usize| Self::srgb_to_linear(unorm(i));
+        let half =
+            |i: usize| Self::half_to_f32(u16::from_le_bytes([bytes[i * 2], bytes[i * 2 + 1]]));
+        let float = |i: usize| {
+            let mut b = [0; 4];
+            b.copy_from_slice(&bytes[i * 4..i * 4 + 4]);
+            f32::from_le_bytes(b)
+        };
+
+        match format {
+            vk::Format::R8G8B8A8_UNORM => [unorm(0), unorm(1), unorm(2), unorm(3)],
+            vk::Format::B8G8R8A8_UNORM => [unorm(2), unorm(1), unorm(0), unorm(3)],
+            vk::Format::R8G8B8A8_SRGB => [srgb(0), srgb(1), srgb(2), unorm(3)],
+            vk::Format::B8G8R8A8_SRGB => [srgb(2), srgb(1), srgb(0), unorm(3)],
+            vk::Format::R16G16B16A16_SFLOAT => [half(0), half(1), half(2), half(3)],
+            vk::Format::R32G32B32A32_SFLOAT => [float(0), float(1), float(2), float(3)],
+            vk::Format::R32_SFLOAT | vk::Format::D32_SFLOAT => [float(0), 0.0, 0.0, 1.0],
+            _ => panic!("Failed to decode pixel of unsupported format {:?}", format),
+        }
     }
 }
 
@@ -336,34 +897,79 @@ impl Drop for Image {
 
 pub struct ImageView {
     pub view: vk::ImageView,
+    /// Format of the image this view looks into
+    pub format: vk::Format,
+    pub view_type: vk::ImageViewType,
+    /// Mip levels and array layers of the image this view looks into
+    pub range: vk::ImageSubresourceRange,
     device: Rc<Device>,
 }
 
 impl ImageView {
     pub fn new(device: &Rc<Device>, image: &Image) -> Self {
-        let device = device.clone();
+        let range = Self::get_layers_range(image.format, 0..1);
+        Self::new_with_range(device, image, vk::ImageViewType::TYPE_2D, range)
+    }
 
-        let aspect = Image::get_aspect_from_format(image.format);
+    /// Creates a view of `image` with `view_type`, looking into its `range` only
+    pub fn new_with_range(
+        device: &Rc<Device>,
+        image: &Image,
+        view_type: vk::ImageViewType,
+        range: vk::ImageSubresourceRange,
+    ) -> Self {
+        assert!(
+            range.base_array_layer + range.layer_count <= image.array_layers,
+            "Image view layers out of the image"
+        );
+        let device = device.clone();
 
         let create_info = vk::ImageViewCreateInfo::builder()
             .image(image.image)
-            .view_type(vk::ImageViewType::TYPE_2D)
+            .view_type(view_type)
             .format(image.format)
-            .subresource_range(
-                vk::ImageSubresourceRange::builder()
-                    .aspect_mask(aspect)
-                    .base_mip_level(0)
-                    .level_count(1)
-                    .base_array_layer(0)
-                    .layer_count(1)
-                    .build(),
-            )
+            .subresource_range(range)
             .build();
 
         let view = unsafe { device.create_image_view(&create_info, None) }
             .expect("Failed to create Vulkan image view");
 
-        Self { view, device }
+        Self {
+            view,
+            format: image.format,
+            view_type,
+            range,
+            device,
+        }
+    }
+
+    /// Returns the range of the first mip level of `layers` of an image with `format`
+    pub fn get_layers_range(format: vk::Format, layers: Range<u32>) -> vk::ImageSubresourceRange {
+        assert!(!layers.is_empty(), "Image view with no layers");
+        vk::ImageSubresourceRange::builder()
+            .aspect_mask(Image::get_aspect_from_format(format))
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(layers.start)
+            .layer_count(layers.end - layers.start)
+            .build()
+    }
+
+    /// Creates an array view of `layers` of `image`, as sampled by materials
+    pub fn array(device: &Rc<Device>, image: &Image, layers: Range<u32>) -> Self {
+        let range = Self::get_layers_range(image.format, layers);
+        Self::new_with_range(device, image, vk::ImageViewType::TYPE_2D_ARRAY, range)
+    }
+
+    /// Creates a view of all the faces of a cubemap
+    pub fn cube(device: &Rc<Device>, image: &Image) -> Self {
+        assert_eq!(
+            image.array_layers,
+            Image::CUBE_FACES,
+            "Image is not a cubemap"
+        );
+        let range = Self::get_layers_range(image.format, 0..Image::CUBE_FACES);
+        Self::new_with_range(device, image, vk::ImageViewType::CUBE, range)
     }
 }
 
@@ -375,6 +981,66 @@ impl Drop for ImageView {
     }
 }
 
+/// Image which can be rendered into by offscreen passes, such as thumbnails or shadows
+pub struct OffscreenTarget {
+    pub view: ImageView,
+    pub image: Image,
+}
+
+impl OffscreenTarget {
+    /// Formats tried in order when the preferred one is not supported
+    const FALLBACK_FORMATS: [vk::Format; 4] = [
+        vk::Format::R16G16B16A16_SFLOAT,
+        vk::Format::R32G32B32A32_SFLOAT,
+        vk::Format::R8G8B8A8_UNORM,
+        vk::Format::B8G8R8A8_UNORM,
+    ];
+
+    /// Creates a target with `preferred_format`, or with the first fallback format supporting `usage`
+    pub fn new(
+        dev: &Dev,
+        extent: vk::Extent2D,
+        preferred_format: vk::Format,
+        usage: vk::ImageUsageFlags,
+    ) -> Self {
+        let format = Self::select_format(preferred_format, usage, |format| {
+            dev.supports_format(format, usage, vk::ImageTiling::OPTIMAL)
+        })
+        .expect("Failed to find a supported format for offscreen target");
+        if format != preferred_format {
+            println!(
+                "Offscreen format {:?} not supported, falling back to {:?}",
+                preferred_format, format
+            );
+        }
+
+        let image = Image::new(&dev.allocator, extent.width, extent.height, format, usage);
+        let view = ImageView::new(&dev.device, &image);
+        Self { view, image }
+    }
+
+    fn select_format<F>(
+        preferred: vk::Format,
+        usage: vk::ImageUsageFlags,
+        supported: F,
+    ) -> Option<vk::Format>
+    where
+        F: Fn(vk::Format) -> bool,
+    {
+        if supported(preferred) {
+            return Some(preferred);
+        }
+        // Depth formats can not fall back to color ones
+        if usage.contains(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT) {
+            return None;
+        }
+        Self::FALLBACK_FORMATS
+            .iter()
+            .copied()
+            .find(|&format| supported(format))
+    }
+}
+
 pub struct Texture {
     pub view: Handle<ImageView>,
     pub sampler: Handle<Sampler>,
@@ -394,6 +1060,10 @@ mod test {
         path::Path,
     };
 
+    use ash::vk;
+
+    use super::*;
+
     #[test]
     fn save_png() {
         let image_dir = Path::new(r"res/image");
@@ -417,8 +1087,290 @@ mod test {
         writer.write_image_data(&data).unwrap();
     }
 
+    #[test]
+    fn srgb_format() {
+        assert!(Image::is_srgb_format(vk::Format::B8G8R8A8_SRGB));
+        assert!(Image::is_srgb_format(vk::Format::R8G8B8A8_SRGB));
+        assert!(!Image::is_srgb_format(vk::Format::B8G8R8A8_UNORM));
+        assert!(!Image::is_srgb_format(vk::Format::R8G8B8A8_UNORM));
+    }
+
+    #[test]
+    fn offscreen_format_fallback() {
+        let usage = vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::STORAGE;
+        let supported = |format: vk::Format| format == vk::Format::R8G8B8A8_UNORM;
+
+        let format = OffscreenTarget::select_format(vk::Format::R8G8B8A8_UNORM, usage, supported);
+        assert_eq!(format, Some(vk::Format::R8G8B8A8_UNORM));
+
+        let format =
+            OffscreenTarget::select_format(vk::Format::R16G16B16A16_SFLOAT, usage, supported);
+        assert_eq!(format, Some(vk::Format::R8G8B8A8_UNORM));
+
+        let format = OffscreenTarget::select_format(
+            vk::Format::D32_SFLOAT,
+            vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+            supported,
+        );
+        assert_eq!(format, None);
+    }
+
+    #[test]
+    fn attachment_usage() {
+        let sampled = vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::INPUT_ATTACHMENT;
+
+        for format in [vk::Format::B8G8R8A8_SRGB, Framebuffer::NORMAL_FORMAT].iter() {
+            let usage = Image::get_attachment_usage(*format);
+            assert!(usage.contains(sampled | vk::ImageUsageFlags::COLOR_ATTACHMENT));
+        }
+
+        let usage = Image::get_attachment_usage(vk::Format::D32_SFLOAT);
+        assert!(usage.contains(sampled | vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT));
+        assert!(!usage.contains(vk::ImageUsageFlags::COLOR_ATTACHMENT));
+    }
+
+    #[test]
+    fn decode_pixel_formats() {
+        let close =
+            |a: [f32; 4], b: [f32; 4]| a.iter().zip(b.iter()).all(|(a, b)| (a - b).abs() < 1e-3);
+
+        let bgra = Image::decode_pixel(vk::Format::B8G8R8A8_UNORM, &[0, 51, 255, 255]);
+        assert!(close(bgra, [1.0, 0.2, 0.0, 1.0]));
+
+        // sRGB mid grey is darker once linear, alpha is always linear
+        let srgb = Image::decode_pixel(vk::Format::R8G8B8A8_SRGB, &[188, 188, 188, 128]);
+        assert!(close(srgb, [0.5029, 0.5029, 0.5029, 0.5020]));
+
+        // 1.0, -2.0, 0.5, 0.0 as half floats
+        let half = Image::decode_pixel(
+            vk::Format::R16G16B16A16_SFLOAT,
+            &[0x00, 0x3c, 0x00, 0xc0, 0x00, 0x38, 0x00, 0x00],
+        );
+        assert!(close(half, [1.0, -2.0, 0.5, 0.0]));
+
+        let bytes: Vec<u8> = [0.25f32, 0.5, 0.75, 1.0]
+            .iter()
+            .flat_map(|f| f.to_le_bytes().to_vec())
+            .collect();
+        let float = Image::decode_pixel(vk::Format::R32G32B32A32_SFLOAT, &bytes);
+        assert_eq!(float, [0.25, 0.5, 0.75, 1.0]);
+    }
+
+    #[test]
+    fn cubemap_layout() {
+        let usage = vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED;
+        let info = Image::get_cubemap_create_info(64, vk::Format::R8G8B8A8_SRGB, usage);
+        assert!(info.flags.contains(vk::ImageCreateFlags::CUBE_COMPATIBLE));
+        assert_eq!(info.array_layers, Image::CUBE_FACES);
+        assert_eq!(info.extent.width, info.extent.height);
+
+        let face_size = 64 * 64 * 4;
+        let regions = Image::get_cubemap_regions(64, face_size);
+        assert_eq!(regions.len(), Image::CUBE_FACES as usize);
+        for (face, region) in regions.iter().enumerate() {
+            assert_eq!(region.buffer_offset, face as vk::DeviceSize * face_size);
+            assert_eq!(region.image_subresource.base_array_layer, face as u32);
+            assert_eq!(region.image_subresource.layer_count, 1);
+        }
+    }
+
+    /// Images of these tests are filled, copied, and read back by transfers
+    fn get_transfer_usage() -> vk::ImageUsageFlags {
+        vk::ImageUsageFlags::TRANSFER_DST
+            | vk::ImageUsageFlags::TRANSFER_SRC
+            | vk::ImageUsageFlags::SAMPLED
+    }
+
+    /// Red, green, blue, and transparent white texels of a 2x2 image
+    const TEXELS: [u8; 16] = [
+        255, 0, 0, 255, 0, 255, 0, 255, //
+        0, 0, 255, 255, 255, 255, 255, 0,
+    ];
+
+    /// Returns a 2x2 `R8G8B8A8_UNORM` image filled with `TEXELS`
+    fn texel_image(dev: &Dev) -> Image {
+        let format = vk::Format::R8G8B8A8_UNORM;
+        let mut image = Image::new(&dev.allocator, 2, 2, format, get_transfer_usage());
+        let mut staging = dev.acquire_staging(TEXELS.len() as vk::DeviceSize);
+        staging.upload_raw(TEXELS.as_ptr(), TEXELS.len() as vk::DeviceSize);
+        image.copy_from(&staging, &dev);
+        image
+    }
+
+    #[test]
+    fn read_pixel_back() {
+        let ctx = Ctx::headless();
+        let dev = Dev::headless(&ctx);
+        let mut image = texel_image(&dev);
+
+        assert_eq!(image.read_pixel(&dev, 0, 0), [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(image.read_pixel(&dev, 1, 1), [1.0, 1.0, 1.0, 0.0]);
+        // The image goes back to being sampled
+        assert_eq!(image.layout, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+    }
+
     #[test]
     fn test_copy_image() {
-        // TODO a CTX without any window
+        let ctx = Ctx::headless();
+        let dev = Dev::headless(&ctx);
+        let mut src = texel_image(&dev);
+
+        // Only the extent in common is copied, into the top-left corner
+        let format = vk::Format::R8G8B8A8_UNORM;
+        let mut dst = Image::new(&dev.allocator, 4, 4, format, get_transfer_usage());
+        src.copy_to(&mut dst, &dev);
+
+        assert_eq!(dst.read_pixel(&dev, 0, 0), [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(dst.read_pixel(&dev, 1, 0), [0.0, 1.0, 0.0, 1.0]);
+        assert_eq!(dst.read_pixel(&dev, 0, 1), [0.0, 0.0, 1.0, 1.0]);
+        assert_eq!(dst.read_pixel(&dev, 1, 1), [1.0, 1.0, 1.0, 0.0]);
+        // The source goes back to its previous layout
+        assert_eq!(src.layout, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+    }
+
+    #[test]
+    fn blit_read_back() {
+        let ctx = Ctx::headless();
+        let dev = Dev::headless(&ctx);
+        let mut src = texel_image(&dev);
+
+        // Each texel becomes a 2x2 block with nearest filtering
+        let format = vk::Format::R8G8B8A8_UNORM;
+        let mut dst = Image::new(&dev.allocator, 4, 4, format, get_transfer_usage());
+        src.blit_to(&mut dst, &dev, vk::Filter::NEAREST);
+
+        for (x, y, texel) in [
+            (0, 0, [1.0, 0.0, 0.0, 1.0]),
+            (1, 1, [1.0, 0.0, 0.0, 1.0]),
+            (3, 0, [0.0, 1.0, 0.0, 1.0]),
+            (2, 1, [0.0, 1.0, 0.0, 1.0]),
+            (0, 3, [0.0, 0.0, 1.0, 1.0]),
+            (3, 3, [1.0, 1.0, 1.0, 0.0]),
+            (2, 2, [1.0, 1.0, 1.0, 0.0]),
+        ]
+        .iter()
+        {
+            assert_eq!(dst.read_pixel(&dev, *x, *y), *texel, "{} {}", x, y);
+        }
+    }
+
+    #[test]
+    fn copy_and_blit_compatibility() {
+        // Copies only need texels of the same size
+        assert!(Image::can_copy(
+            vk::Format::R8G8B8A8_UNORM,
+            vk::Format::B8G8R8A8_SRGB
+        ));
+        assert!(Image::can_copy(
+            vk::Format::R32_SFLOAT,
+            vk::Format::R8G8B8A8_UNORM
+        ));
+        assert!(!Image::can_copy(
+            vk::Format::R8G8B8A8_UNORM,
+            vk::Format::R16G16B16A16_SFLOAT
+        ));
+        assert!(!Image::can_copy(
+            vk::Format::D32_SFLOAT,
+            vk::Format::R32_SFLOAT
+        ));
+        assert!(Image::can_copy(
+            vk::Format::D32_SFLOAT,
+            vk::Format::D32_SFLOAT
+        ));
+
+        // Blits convert formats, but need format features
+        let color = vk::FormatFeatureFlags::BLIT_SRC
+            | vk::FormatFeatureFlags::BLIT_DST
+            | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR;
+        let unorm = vk::Format::R8G8B8A8_UNORM;
+        let float = vk::Format::R16G16B16A16_SFLOAT;
+        assert!(Image::can_blit(
+            unorm,
+            color,
+            float,
+            color,
+            vk::Filter::LINEAR
+        ));
+        let unfiltered = vk::FormatFeatureFlags::BLIT_SRC;
+        assert!(!Image::can_blit(
+            unorm,
+            unfiltered,
+            float,
+            color,
+            vk::Filter::LINEAR
+        ));
+        assert!(Image::can_blit(
+            unorm,
+            unfiltered,
+            float,
+            color,
+            vk::Filter::NEAREST
+        ));
+        assert!(!Image::can_blit(
+            unorm,
+            color,
+            float,
+            unfiltered,
+            vk::Filter::NEAREST
+        ));
+
+        let depth = vk::Format::D32_SFLOAT;
+        assert!(Image::can_blit(
+            depth,
+            color,
+            depth,
+            color,
+            vk::Filter::NEAREST
+        ));
+        assert!(!Image::can_blit(
+            depth,
+            color,
+            depth,
+            color,
+            vk::Filter::LINEAR
+        ));
+        assert!(!Image::can_blit(
+            depth,
+            color,
+            unorm,
+            color,
+            vk::Filter::NEAREST
+        ));
+    }
+
+    #[test]
+    fn blit_region() {
+        let layers = vk::ImageSubresourceLayers::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .layer_count(1)
+            .build();
+        let extent = |width, height| {
+            vk::Extent3D::builder()
+                .width(width)
+                .height(height)
+                .depth(1)
+                .build()
+        };
+
+        // A smaller source is stretched over the whole destination
+        let region = Image::get_blit_region(layers, extent(1, 1), layers, extent(64, 32));
+        assert_eq!(region.src_offsets[0], vk::Offset3D::default());
+        assert_eq!(region.src_offsets[1], vk::Offset3D { x: 1, y: 1, z: 1 });
+        assert_eq!(region.dst_offsets[0], vk::Offset3D::default());
+        assert_eq!(region.dst_offsets[1], vk::Offset3D { x: 64, y: 32, z: 1 });
+    }
+
+    #[test]
+    fn array_view_range() {
+        let range = ImageView::get_layers_range(vk::Format::R8G8B8A8_SRGB, 2..4);
+        assert_eq!(range.aspect_mask, vk::ImageAspectFlags::COLOR);
+        assert_eq!(range.base_array_layer, 2);
+        assert_eq!(range.layer_count, 2);
+        assert_eq!(range.base_mip_level, 0);
+        assert_eq!(range.level_count, 1);
+
+        let depth = ImageView::get_layers_range(vk::Format::D32_SFLOAT, 0..1);
+        assert_eq!(depth.aspect_mask, vk::ImageAspectFlags::DEPTH);
+        assert_eq!(depth.layer_count, 1);
     }
 }
@@ -21,6 +21,12 @@ pub use image::*;
 pub mod queue;
 pub use queue::*;
 
+pub mod upload;
+pub use upload::*;
+
+pub mod staging;
+pub use staging::*;
+
 pub mod shader;
 pub use shader::*;
 
@@ -30,6 +36,9 @@ pub use sampler::*;
 pub mod pipeline;
 pub use pipeline::*;
 
+pub mod oit;
+pub use oit::*;
+
 pub mod gfx;
 pub use gfx::*;
 
@@ -42,6 +51,30 @@ pub use mesh::*;
 pub mod sync;
 pub use sync::*;
 
+pub mod animation;
+pub use animation::*;
+
+pub mod frustum;
+pub use frustum::*;
+
+pub mod particle;
+pub use particle::*;
+
+pub mod multiview;
+pub use multiview::*;
+
+pub mod arena;
+pub use arena::*;
+
+pub mod light;
+pub use light::*;
+
+pub mod loader;
+pub use loader::*;
+
+pub mod grid;
+pub use grid::*;
+
 mod gui;
 use gui::*;
 
@@ -0,0 +1,287 @@
+// Copyright © 2021
+// Author: Antonio Caggiano <info@antoniocaggiano.eu>
+// SPDX-License-Identifier: MIT
+
+use ash::*;
+
+use super::*;
+
+/// Point light whose contribution is accumulated additively by the lighting subpass
+#[derive(Debug, Clone, Copy)]
+pub struct Light {
+    pub color: na::Vector3<f32>,
+    pub intensity: f32,
+}
+
+/// Push constants of the lighting fragment shader
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct LightConstants {
+    /// View-space position in xyz and radius of the light volume in w
+    pub position: na::Vector4<f32>,
+    /// Color in rgb and intensity in a
+    pub color: na::Vector4<f32>,
+    /// Used to reconstruct view-space positions from depth, with either depth convention
+    pub inv_proj: na::Matrix4<f32>,
+    /// Size of the scene viewport
+    pub extent: na::Vector2<f32>,
+    /// Position of the scene viewport within the framebuffer
+    pub offset: na::Vector2<f32>,
+}
+
+// Safe as it is made of 28 contiguous floats
+unsafe impl Pod for LightConstants {}
+
+impl Light {
+    /// Intensity below which a light does not contribute visibly to an 8-bit target
+    pub const CUTOFF: f32 = 1.0 / 256.0;
+
+    pub fn new(color: na::Vector3<f32>, intensity: f32) -> Self {
+        Self { color, intensity }
+    }
+
+    /// Returns the distance where the inverse-square attenuated intensity falls below `CUTOFF`
+    pub fn get_radius(&self) -> f32 {
+        (self.intensity.max(0.0) / Self::CUTOFF).sqrt()
+    }
+
+    /// Returns the bounds of the volume lit by this light at `position`
+    pub fn get_volume(&self, position: &na::Vector3<f32>) -> Aabb {
+        let radius = na::Vector3::repeat(self.get_radius());
+        Aabb::new(position - radius, position + radius)
+    }
+
+    /// Returns the framebuffer rectangle covered by the volume of this light at `view_position`,
+    /// or `None` when the volume is outside the framebuffer. The whole framebuffer is returned
+    /// when the volume crosses the camera plane, as its projection is not bounded.
+    pub fn get_screen_rect(
+        &self,
+        view_position: &na::Vector3<f32>,
+        proj: &na::Matrix4<f32>,
+        extent: vk::Extent2D,
+    ) -> Option<vk::Rect2D> {
+        let full = vk::Rect2D::builder().extent(extent).build();
+
+        let volume = self.get_volume(view_position);
+        // Camera looks towards -z
+        if volume.max.z >= 0.0 {
+            return Some(full);
+        }
+
+        let mut min = na::Vector2::repeat(f32::INFINITY);
+        let mut max = na::Vector2::repeat(f32::NEG_INFINITY);
+        for corner in volume.get_corners().iter() {
+            let clip = proj * na::Vector4::new(corner.x, corner.y, corner.z, 1.0);
+            let ndc = na::Vector2::new(clip.x, clip.y) / clip.w;
+            min = min.inf(&ndc);
+            max = max.sup(&ndc);
+        }
+
+        // From [-1, 1] to pixels, viewports are not flipped
+        let to_pixel = |ndc: f32, size: u32| ((ndc * 0.5 + 0.5) * size as f32).max(0.0);
+        let x0 = to_pixel(min.x, extent.width)
+            .floor()
+            .min(extent.width as f32) as u32;
+        let y0 = to_pixel(min.y, extent.height)
+            .floor()
+            .min(extent.height as f32) as u32;
+        let x1 = to_pixel(max.x, extent.width)
+            .ceil()
+            .min(extent.width as f32) as u32;
+        let y1 = to_pixel(max.y, extent.height)
+            .ceil()
+            .min(extent.height as f32) as u32;
+        if x1 <= x0 || y1 <= y0 {
+            return None;
+        }
+
+        Some(
+            vk::Rect2D::builder()
+                .offset(vk::Offset2D::builder().x(x0 as i32).y(y0 as i32).build())
+                .extent(
+                    vk::Extent2D::builder()
+                        .width(x1 - x0)
+                        .height(y1 - y0)
+                        .build(),
+                )
+                .build(),
+        )
+    }
+
+    pub fn get_constants(
+        &self,
+        view_position: &na::Vector3<f32>,
+        proj: &na::Matrix4<f32>,
+        viewport: &vk::Rect2D,
+    ) -> LightConstants {
+        let extent = viewport.extent;
+        let offset = viewport.offset;
+        LightConstants {
+            position: view_position.push(self.get_radius()),
+            color: self.color.push(self.intensity),
+            inv_proj: proj.try_inverse().unwrap_or_else(na::Matrix4::identity),
+            extent: na::Vector2::new(extent.width as f32, extent.height as f32),
+            offset: na::Vector2::new(offset.x as f32, offset.y as f32),
+        }
+    }
+
+    /// Light contributions are summed, hence the blend state is ADD with factors ONE and ONE
+    pub fn get_additive_blend() -> vk::PipelineColorBlendAttachmentState {
+        vk::PipelineColorBlendAttachmentState::builder()
+            .blend_enable(true)
+            .color_write_mask(
+                vk::ColorComponentFlags::R
+                    | vk::ColorComponentFlags::G
+                    | vk::ColorComponentFlags::B,
+            )
+            .src_color_blend_factor(vk::BlendFactor::ONE)
+            .dst_color_blend_factor(vk::BlendFactor::ONE)
+            .color_blend_op(vk::BlendOp::ADD)
+            .src_alpha_blend_factor(vk::BlendFactor::ONE)
+            .dst_alpha_blend_factor(vk::BlendFactor::ONE)
+            .alpha_blend_op(vk::BlendOp::ADD)
+            .build()
+    }
+
+    pub fn write_set(
+        writer: &mut DescriptorWriter,
+        set: vk::DescriptorSet,
+        albedo: &ImageView,
+        normal: &ImageView,
+        depth: &ImageView,
+    ) {
+        let color_layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+        writer
+            .write_input_attachment(set, 0, albedo, color_layout)
+            .write_input_attachment(set, 1, normal, color_layout)
+            .write_input_attachment(
+                set,
+                2,
+                depth,
+                vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+            );
+    }
+}
+
+/// Light volumes are drawn as the fullscreen triangle scissored to their screen rectangle
+impl VertexInput for Light {
+    fn get_pipeline() -> Pipelines {
+        Pipelines::LIGHT
+    }
+
+    fn get_bindings() -> Vec<vk::VertexInputBindingDescription> {
+        PresentVertex::get_bindings()
+    }
+
+    fn get_attributes() -> Vec<vk::VertexInputAttributeDescription> {
+        PresentVertex::get_attributes()
+    }
+
+    fn get_set_layouts(device: &Device) -> Vec<vk::DescriptorSetLayout> {
+        // Albedo, normal, and depth of the G-buffer
+        let bindings: Vec<vk::DescriptorSetLayoutBinding> = (0..3)
+            .map(|binding| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding)
+                    .descriptor_type(vk::DescriptorType::INPUT_ATTACHMENT)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                    .build()
+            })
+            .collect();
+        let set_layout = create_set_layout(device, &bindings);
+        vec![set_layout]
+    }
+
+    fn get_constants() -> Vec<vk::PushConstantRange> {
+        vec![vk::PushConstantRange::builder()
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            .offset(0)
+            .size(std::mem::size_of::<LightConstants>() as u32)
+            .build()]
+    }
+
+    fn get_depth_state() -> vk::PipelineDepthStencilStateCreateInfo {
+        // Depth is read as an input attachment
+        vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(false)
+            .depth_write_enable(false)
+            .build()
+    }
+
+    fn get_color_blend(_subpass: u32) -> Vec<vk::PipelineColorBlendAttachmentState> {
+        vec![Light::get_additive_blend()]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn additive_accumulation() {
+        let blend = Light::get_additive_blend();
+        assert_eq!(blend.blend_enable, vk::TRUE);
+        assert_eq!(blend.color_blend_op, vk::BlendOp::ADD);
+        assert_eq!(blend.src_color_blend_factor, vk::BlendFactor::ONE);
+        assert_eq!(blend.dst_color_blend_factor, vk::BlendFactor::ONE);
+
+        // What the blend unit computes for two lights over a cleared light buffer
+        let blend = |src: f32, dst: f32| src * 1.0 + dst * 1.0;
+        let lights = [0.25, 0.5];
+        let accumulated = lights.iter().fold(0.0, |dst, &src| blend(src, dst));
+        assert_eq!(accumulated, 0.75);
+    }
+
+    #[test]
+    fn constants_depth_convention() {
+        let light = Light::new(na::Vector3::new(1.0, 1.0, 1.0), 1.0);
+        let position = na::Vector3::new(0.0, 0.0, -5.0);
+        let camera = Camera::perspective(1.0);
+        let viewport = vk::Rect2D::builder()
+            .extent(vk::Extent2D::builder().width(64).height(64).build())
+            .build();
+
+        // The inverse projection brings depth back to view space with either convention
+        let point = na::Vector4::new(0.5, 0.25, -3.0, 1.0);
+        for depth_config in [DepthConfig::standard(), DepthConfig::reverse_z()].iter() {
+            let proj = camera.get_proj_for(*depth_config);
+            let constants = light.get_constants(&position, &proj, &viewport);
+            let clip = proj * point;
+            let view = constants.inv_proj * (clip / clip.w);
+            assert!((view / view.w - point).norm() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn light_volume() {
+        let light = Light::new(na::Vector3::new(1.0, 1.0, 1.0), 4.0 * Light::CUTOFF);
+        assert_eq!(light.get_radius(), 2.0);
+
+        let volume = light.get_volume(&na::Vector3::new(1.0, 0.0, -10.0));
+        assert_eq!(volume.min, na::Vector3::new(-1.0, -2.0, -12.0));
+        assert_eq!(volume.max, na::Vector3::new(3.0, 2.0, -8.0));
+
+        let extent = vk::Extent2D::builder().width(800).height(600).build();
+        let proj = na::Perspective3::new(800.0 / 600.0, std::f32::consts::FRAC_PI_2, 0.1, 100.0)
+            .to_homogeneous();
+
+        // Light in front of the camera covers a sub-rectangle around the center
+        let rect = light
+            .get_screen_rect(&na::Vector3::new(0.0, 0.0, -10.0), &proj, extent)
+            .unwrap();
+        assert!(rect.offset.x > 0 && rect.offset.y > 0);
+        assert!(rect.extent.width < 800 && rect.extent.height < 600);
+        assert_eq!(rect.offset.x as u32 * 2 + rect.extent.width, 800);
+
+        // Camera inside the light volume
+        let rect = light
+            .get_screen_rect(&na::Vector3::new(0.0, 0.0, -1.0), &proj, extent)
+            .unwrap();
+        assert_eq!(rect.extent, extent);
+
+        // Light far to the side is not visible
+        let rect = light.get_screen_rect(&na::Vector3::new(100.0, 0.0, -10.0), &proj, extent);
+        assert!(rect.is_none());
+    }
+}
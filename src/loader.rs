@@ -0,0 +1,455 @@
+// Copyright © 2021
+// Author: Antonio Caggiano <info@antoniocaggiano.eu>
+// SPDX-License-Identifier: MIT
+
+use ash::*;
+
+use super::*;
+
+/// Converts the pixels of a glTF image to tightly packed RGBA8 texels
+fn get_rgba8(format: gltf::image::Format, pixels: &[u8]) -> Vec<u8> {
+    use gltf::image::Format;
+    match format {
+        Format::R8 => pixels.iter().flat_map(|&r| vec![r, r, r, 255]).collect(),
+        Format::R8G8 => pixels
+            .chunks_exact(2)
+            .flat_map(|p| vec![p[0], p[1], 0, 255])
+            .collect(),
+        Format::R8G8B8 => pixels
+            .chunks_exact(3)
+            .flat_map(|p| vec![p[0], p[1], p[2], 255])
+            .collect(),
+        Format::B8G8R8 => pixels
+            .chunks_exact(3)
+            .flat_map(|p| vec![p[2], p[1], p[0], 255])
+            .collect(),
+        Format::R8G8B8A8 => pixels.to_vec(),
+        Format::B8G8R8A8 => pixels
+            .chunks_exact(4)
+            .flat_map(|p| vec![p[2], p[1], p[0], p[3]])
+            .collect(),
+        _ => panic!("Unsupported glTF image format {:?}", format),
+    }
+}
+
+/// Returns the sampler parameters of a glTF sampler, filters default to linear
+fn get_sampler_info(sampler: &gltf::texture::Sampler) -> SamplerInfo {
+    use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+
+    let mag_filter = match sampler.mag_filter() {
+        Some(MagFilter::Nearest) => vk::Filter::NEAREST,
+        _ => vk::Filter::LINEAR,
+    };
+    let (min_filter, mipmap_mode) = match sampler.min_filter() {
+        Some(MinFilter::Nearest) | Some(MinFilter::NearestMipmapNearest) => {
+            (vk::Filter::NEAREST, vk::SamplerMipmapMode::NEAREST)
+        }
+        Some(MinFilter::NearestMipmapLinear) => {
+            (vk::Filter::NEAREST, vk::SamplerMipmapMode::LINEAR)
+        }
+        Some(MinFilter::LinearMipmapNearest) => {
+            (vk::Filter::LINEAR, vk::SamplerMipmapMode::NEAREST)
+        }
+        _ => (vk::Filter::LINEAR, vk::SamplerMipmapMode::LINEAR),
+    };
+    // Samplers use the same address mode for all coordinates
+    let address_mode = match sampler.wrap_s() {
+        WrappingMode::ClampToEdge => vk::SamplerAddressMode::CLAMP_TO_EDGE,
+        WrappingMode::MirroredRepeat => vk::SamplerAddressMode::MIRRORED_REPEAT,
+        WrappingMode::Repeat => vk::SamplerAddressMode::REPEAT,
+    };
+
+    SamplerInfo {
+        mag_filter,
+        min_filter,
+        mipmap_mode,
+        address_mode,
+        ..Default::default()
+    }
+}
+
+/// Reads the vertices of a glTF primitive. Missing attributes fall back to the defaults
+/// of `Vertex::new`, which are a white color and a normal towards +Z, while missing tangents
+/// are perpendicular to normals. Texture coordinates are converted from `uv_origin`
+/// to the Vulkan convention.
+fn get_vertices(
+    primitive: &gltf::Primitive,
+    buffers: &[gltf::buffer::Data],
+    uv_origin: UvOrigin,
+) -> Vec<Vertex> {
+    let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+
+    let mut vertices: Vec<Vertex> = reader
+        .read_positions()
+        .expect("Failed to read primitive positions")
+        .map(|p| Vertex::new(p[0], p[1], p[2]))
+        .collect();
+
+    if let Some(normals) = reader.read_normals() {
+        for (vertex, n) in vertices.iter_mut().zip(normals) {
+            vertex.normal = na::Vector3::new(n[0], n[1], n[2]);
+        }
+    }
+    match reader.read_tangents() {
+        Some(tangents) => {
+            for (vertex, t) in vertices.iter_mut().zip(tangents) {
+                vertex.tangent = na::Vector4::new(t[0], t[1], t[2], t[3]);
+            }
+        }
+        None => {
+            for vertex in &mut vertices {
+                vertex.tangent = Vertex::get_default_tangent(&vertex.normal);
+            }
+        }
+    }
+    if let Some(colors) = reader.read_colors(0) {
+        for (vertex, c) in vertices.iter_mut().zip(colors.into_rgba_f32()) {
+            vertex.color = Color::new(c[0], c[1], c[2], c[3]);
+        }
+    }
+    if let Some(uvs) = reader.read_tex_coords(0) {
+        for (vertex, uv) in vertices.iter_mut().zip(uvs.into_f32()) {
+            vertex.uv = uv_origin.to_top_left(na::Vector2::new(uv[0], uv[1]));
+        }
+    }
+
+    vertices
+}
+
+/// Returns the second texture coordinate set of a primitive, which is not part of `Vertex`
+fn get_uv1(
+    primitive: &gltf::Primitive,
+    buffers: &[gltf::buffer::Data],
+    uv_origin: UvOrigin,
+) -> Option<Vec<na::Vector2<f32>>> {
+    let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+    reader.read_tex_coords(1).map(|uvs| {
+        uvs.into_f32()
+            .map(|uv| uv_origin.to_top_left(na::Vector2::new(uv[0], uv[1])))
+            .collect()
+    })
+}
+
+/// Indices are read as `u32`, as large meshes may have more than 65535 vertices
+fn get_indices(primitive: &gltf::Primitive, buffers: &[gltf::buffer::Data]) -> Option<Vec<u32>> {
+    let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+    reader
+        .read_indices()
+        .map(|indices| indices.into_u32().collect())
+}
+
+/// Returns the texture coordinate set of a material, falling back to the first one
+/// for sets which are not loaded
+fn get_uv_set(tex_coord: u32) -> u32 {
+    if tex_coord < Material::UV_SET_COUNT {
+        tex_coord
+    } else {
+        eprintln!("Unsupported glTF texture coordinate set {}", tex_coord);
+        0
+    }
+}
+
+/// Infinite perspective projections are approximated with a far plane far away from the near one
+fn get_zfar(znear: f32, zfar: Option<f32>) -> f32 {
+    zfar.unwrap_or(znear * 100_000.0)
+}
+
+fn get_trs(node: &gltf::Node) -> Trs {
+    let (translation, rotation, scale) = node.transform().decomposed();
+    let mut trs = Trs::new();
+    trs.set_translation(&na::Vector3::from(translation));
+    // glTF quaternions are stored as xyzw
+    let rotation = na::Quaternion::new(rotation[3], rotation[0], rotation[1], rotation[2]);
+    trs.set_rotation(&na::UnitQuaternion::from_quaternion(rotation));
+    trs.scale(&na::Vector3::from(scale));
+    trs
+}
+
+impl Model {
+    /// Loads a glTF 2.0 file, uploading its buffers and images to the GPU.
+    /// Only indexed or non-indexed triangle lists are supported, other primitives are skipped.
+    pub fn load_gltf(dev: &Dev, path: &str) -> Self {
+        // As specified by glTF
+        Self::load_gltf_with_uv_origin(dev, path, UvOrigin::TopLeft)
+    }
+
+    /// Like `load_gltf`, for files exported by tools which do not follow the glTF convention
+    /// of texture coordinates, which are flipped once when `uv_origin` is `BottomLeft`
+    pub fn load_gltf_with_uv_origin(dev: &Dev, path: &str, uv_origin: UvOrigin) -> Self {
+        let (document, buffers, images) = gltf::import(path).expect("Failed to import glTF");
+
+        let mut model = Model::new();
+        // Images and vertices are uploaded all at once
+        let mut upload = UploadContext::new(dev);
+
+        for image in &images {
+            let data = get_rgba8(image.format, &image.pixels);
+            // Base color textures are sRGB encoded
+            let image = Image::from_data(
+                dev,
+                &data,
+                image.width,
+                image.height,
+                vk::Format::R8G8B8A8_SRGB,
+                Some(&mut upload),
+            );
+            let view = ImageView::array(&dev.device, &image, 0..image.array_layers);
+            model.images.push(image);
+            model.views.push(view);
+        }
+        let views = model.views.get_handles();
+
+        let samplers: Vec<Handle<Sampler>> = document
+            .samplers()
+            .map(|sampler| {
+                let info = get_sampler_info(&sampler);
+                model.samplers.push(Sampler::material(dev, &info))
+            })
+            .collect();
+        let mut default_sampler = Handle::none();
+
+        let textures: Vec<Handle<Texture>> = document
+            .textures()
+            .map(|texture| {
+                let sampler = match texture.sampler().index() {
+                    Some(index) => samplers[index],
+                    None => {
+                        if !default_sampler.valid() {
+                            let info = get_sampler_info(&texture.sampler());
+                            default_sampler = model.samplers.push(Sampler::material(dev, &info));
+                        }
+                        default_sampler
+                    }
+                };
+                let view = views[texture.source().index()];
+                model.textures.push(Texture::new(view, sampler))
+            })
+            .collect();
+
+        let materials: Vec<Handle<Material>> = document
+            .materials()
+            .map(|material| {
+                let pbr = material.pbr_metallic_roughness();
+                let mut ret = match pbr.base_color_texture() {
+                    Some(info) => {
+                        let mut ret = Material::textured(textures[info.texture().index()]);
+                        ret.albedo_uv = get_uv_set(info.tex_coord());
+                        ret
+                    }
+                    None => Material::new(Color::white()),
+                };
+                let [r, g, b, a] = pbr.base_color_factor();
+                ret.color = Color::new(r, g, b, a);
+                model.materials.push(ret)
+            })
+            .collect();
+
+        let meshes: Vec<Handle<Mesh>> = document
+            .meshes()
+            .map(|mesh| {
+                let primitives = mesh
+                    .primitives()
+                    .filter(|primitive| {
+                        let triangles = primitive.mode() == gltf::mesh::Mode::Triangles;
+                        if !triangles {
+                            eprintln!("Skipping glTF primitive mode {:?}", primitive.mode());
+                        }
+                        triangles
+                    })
+                    .map(|primitive| {
+                        let mut vertices = get_vertices(&primitive, &buffers, uv_origin);
+                        let indices = get_indices(&primitive, &buffers);
+                        // Tangents not provided are derived from texture coordinates
+                        if primitive.get(&gltf::Semantic::Tangents).is_none()
+                            && primitive.get(&gltf::Semantic::TexCoords(0)).is_some()
+                        {
+                            Primitive::compute_tangents(&mut vertices, indices.as_deref());
+                        }
+                        let mut ret =
+                            Primitive::new_device_local(dev, &vertices, Some(&mut upload));
+                        if let Some(indices) = &indices {
+                            ret.set_indices(indices);
+                        }
+                        if let Some(uv1) = get_uv1(&primitive, &buffers, uv_origin) {
+                            ret.set_uv1(&uv1);
+                        }
+                        if let Some(index) = primitive.material().index() {
+                            ret.material = materials[index];
+                        }
+                        model.primitives.push(ret)
+                    })
+                    .collect();
+                model.meshes.push(Mesh::new(primitives))
+            })
+            .collect();
+        upload.finish(dev);
+
+        let cameras: Vec<Handle<Camera>> = document
+            .cameras()
+            .map(|camera| {
+                let camera = match camera.projection() {
+                    gltf::camera::Projection::Perspective(perspective) => {
+                        Camera::perspective_with_range(
+                            perspective.aspect_ratio().unwrap_or(1.0),
+                            perspective.yfov(),
+                            perspective.znear(),
+                            get_zfar(perspective.znear(), perspective.zfar()),
+                        )
+                    }
+                    gltf::camera::Projection::Orthographic(orthographic) => {
+                        let (x, y) = (orthographic.xmag(), orthographic.ymag());
+                        Camera::orthographic(
+                            -x,
+                            x,
+                            -y,
+                            y,
+                            orthographic.znear(),
+                            orthographic.zfar(),
+                        )
+                    }
+                };
+                model.cameras.push(camera)
+            })
+            .collect();
+
+        // Nodes are pushed first, so that children can refer to them by index
+        let nodes: Vec<Handle<Node>> = document
+            .nodes()
+            .map(|node| {
+                let mut ret = Node::new();
+                ret.trs = get_trs(&node);
+                if let Some(mesh) = node.mesh() {
+                    ret.mesh = meshes[mesh.index()];
+                }
+                if let Some(camera) = node.camera() {
+                    ret.camera = cameras[camera.index()];
+                }
+                model.nodes.push(ret)
+            })
+            .collect();
+        for node in document.nodes() {
+            let children = node.children().map(|child| nodes[child.index()]).collect();
+            model.nodes.get_mut(nodes[node.index()]).unwrap().children = children;
+        }
+
+        model
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A triangle with positions and indices only
+    const TRIANGLE: &str = r#"{
+        "asset": { "version": "2.0" },
+        "buffers": [{
+            "byteLength": 44,
+            "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAAAAABAAIAAAA="
+        }],
+        "bufferViews": [
+            { "buffer": 0, "byteOffset": 0, "byteLength": 36 },
+            { "buffer": 0, "byteOffset": 36, "byteLength": 6 }
+        ],
+        "accessors": [
+            { "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+              "min": [0, 0, 0], "max": [1, 1, 0] },
+            { "bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR" }
+        ],
+        "meshes": [{ "primitives": [{ "attributes": { "POSITION": 0 }, "indices": 1 }] }],
+        "nodes": [{ "mesh": 0, "translation": [1, 2, 3] }]
+    }"#;
+
+    /// A triangle with the same texture coordinates in both sets
+    const UV_TRIANGLE: &str = r#"{
+        "asset": { "version": "2.0" },
+        "buffers": [{
+            "byteLength": 60,
+            "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAAAAAAAAAAAAAAAIA/AACAPgAAAD8AAIA/"
+        }],
+        "bufferViews": [
+            { "buffer": 0, "byteOffset": 0, "byteLength": 36 },
+            { "buffer": 0, "byteOffset": 36, "byteLength": 24 }
+        ],
+        "accessors": [
+            { "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+              "min": [0, 0, 0], "max": [1, 1, 0] },
+            { "bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC2" }
+        ],
+        "meshes": [{ "primitives": [{ "attributes": { "POSITION": 0, "TEXCOORD_0": 1, "TEXCOORD_1": 1 } }] }],
+        "nodes": [{ "mesh": 0 }]
+    }"#;
+
+    #[test]
+    fn gltf_uv_origin() {
+        let (document, buffers, _) =
+            gltf::import_slice(UV_TRIANGLE.as_bytes()).expect("Failed to import glTF");
+        let primitive = document
+            .meshes()
+            .next()
+            .unwrap()
+            .primitives()
+            .next()
+            .unwrap();
+        let uv = |u, v| na::Vector2::new(u, v);
+
+        // As stored in the file
+        let vertices = get_vertices(&primitive, &buffers, UvOrigin::TopLeft);
+        let uvs: Vec<na::Vector2<f32>> = vertices.iter().map(|vertex| vertex.uv).collect();
+        assert_eq!(uvs, vec![uv(0.0, 0.0), uv(1.0, 0.25), uv(0.5, 1.0)]);
+        let uv1 = get_uv1(&primitive, &buffers, UvOrigin::TopLeft).unwrap();
+        assert_eq!(uv1, uvs);
+
+        // Flipped once, for both sets
+        let expected = vec![uv(0.0, 1.0), uv(1.0, 0.75), uv(0.5, 0.0)];
+        let vertices = get_vertices(&primitive, &buffers, UvOrigin::BottomLeft);
+        let uvs: Vec<na::Vector2<f32>> = vertices.iter().map(|vertex| vertex.uv).collect();
+        assert_eq!(uvs, expected);
+        let uv1 = get_uv1(&primitive, &buffers, UvOrigin::BottomLeft).unwrap();
+        assert_eq!(uv1, expected);
+    }
+
+    #[test]
+    fn gltf_default_attributes() {
+        let (document, buffers, _) =
+            gltf::import_slice(TRIANGLE.as_bytes()).expect("Failed to import glTF");
+        let primitive = document
+            .meshes()
+            .next()
+            .unwrap()
+            .primitives()
+            .next()
+            .unwrap();
+
+        let vertices = get_vertices(&primitive, &buffers, UvOrigin::TopLeft);
+        assert_eq!(vertices.len(), 3);
+        assert_eq!(vertices[1].pos, na::Vector3::new(1.0, 0.0, 0.0));
+        for vertex in &vertices {
+            assert_eq!(vertex.color, Color::white());
+            assert_eq!(vertex.normal, na::Vector3::new(0.0, 0.0, 1.0));
+            assert_eq!(vertex.tangent, na::Vector4::new(1.0, 0.0, 0.0, 1.0));
+        }
+
+        assert_eq!(get_indices(&primitive, &buffers), Some(vec![0, 1, 2]));
+
+        let node = document.nodes().next().unwrap();
+        let trs = get_trs(&node);
+        assert_eq!(trs.get_translation(), na::Vector3::new(1.0, 2.0, 3.0));
+    }
+
+    #[test]
+    fn gltf_rgba8() {
+        let rgb = get_rgba8(gltf::image::Format::R8G8B8, &[1, 2, 3, 4, 5, 6]);
+        assert_eq!(rgb, vec![1, 2, 3, 255, 4, 5, 6, 255]);
+        let bgra = get_rgba8(gltf::image::Format::B8G8R8A8, &[1, 2, 3, 4]);
+        assert_eq!(bgra, vec![3, 2, 1, 4]);
+    }
+
+    #[test]
+    fn gltf_fallbacks() {
+        assert_eq!(get_uv_set(1), 1);
+        assert_eq!(get_uv_set(2), 0);
+        assert_eq!(get_zfar(0.1, Some(50.0)), 50.0);
+        assert!(get_zfar(0.1, None) > 1000.0);
+    }
+}
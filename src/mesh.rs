@@ -3,100 +3,397 @@
 // SPDX-License-Identifier: MIT
 
 use ash::*;
+use memoffset::offset_of;
 use std::{cell::RefCell, rc::Rc};
 
 use super::*;
 
+/// Maximum number of morph targets of a primitive.
+/// The main vertex shader reads the weights as a single vec4.
+pub const MAX_MORPH_TARGETS: usize = 4;
+
+/// A morph target, or blend shape, is a set of per-vertex offsets
+/// which are added to the base primitive according to a weight
+pub struct MorphTarget {
+    pub positions: Vec<na::Vector3<f32>>,
+    pub normals: Vec<na::Vector3<f32>>,
+}
+
+impl MorphTarget {
+    pub fn new(positions: Vec<na::Vector3<f32>>, normals: Vec<na::Vector3<f32>>) -> Self {
+        assert!(positions.len() == normals.len());
+        Self { positions, normals }
+    }
+}
+
+/// Per-vertex deltas of all the morph targets of a primitive, as read by the vertex shader
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct MorphDeltas {
+    pub positions: [na::Vector3<f32>; MAX_MORPH_TARGETS],
+    pub normals: [na::Vector3<f32>; MAX_MORPH_TARGETS],
+}
+
+impl MorphDeltas {
+    pub fn zero() -> Self {
+        Self {
+            positions: [na::Vector3::zeros(); MAX_MORPH_TARGETS],
+            normals: [na::Vector3::zeros(); MAX_MORPH_TARGETS],
+        }
+    }
+
+    /// Returns the attributes of the deltas starting from `location`
+    pub fn get_attributes(binding: u32, location: u32) -> Vec<vk::VertexInputAttributeDescription> {
+        let delta_size = std::mem::size_of::<na::Vector3<f32>>();
+        let mut attributes = vec![];
+        for i in 0..MAX_MORPH_TARGETS {
+            attributes.push(
+                vk::VertexInputAttributeDescription::builder()
+                    .binding(binding)
+                    .location(location + i as u32)
+                    .format(vk::Format::R32G32B32_SFLOAT)
+                    .offset((offset_of!(MorphDeltas, positions) + i * delta_size) as u32)
+                    .build(),
+            );
+        }
+        for i in 0..MAX_MORPH_TARGETS {
+            attributes.push(
+                vk::VertexInputAttributeDescription::builder()
+                    .binding(binding)
+                    .location(location + (MAX_MORPH_TARGETS + i) as u32)
+                    .format(vk::Format::R32G32B32_SFLOAT)
+                    .offset((offset_of!(MorphDeltas, normals) + i * delta_size) as u32)
+                    .build(),
+            );
+        }
+        attributes
+    }
+
+    /// Adds the weighted deltas to a base value, as done by the main vertex shader
+    pub fn accumulate(
+        base: &na::Vector3<f32>,
+        deltas: &[na::Vector3<f32>],
+        weights: &MorphWeights,
+    ) -> na::Vector3<f32> {
+        deltas
+            .iter()
+            .zip(weights.weights.iter())
+            .fold(*base, |acc, (delta, weight)| acc + delta * *weight)
+    }
+}
+
+/// Weights of the morph targets, usually stored per node and animated
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MorphWeights {
+    pub weights: [f32; MAX_MORPH_TARGETS],
+}
+
+// Safe as it is an array of floats
+unsafe impl Pod for MorphWeights {}
+
+impl MorphWeights {
+    pub fn new(weights: [f32; MAX_MORPH_TARGETS]) -> Self {
+        Self { weights }
+    }
+
+    pub fn lerp(&self, other: &MorphWeights, t: f32) -> Self {
+        let mut weights = self.weights;
+        for (weight, other) in weights.iter_mut().zip(other.weights.iter()) {
+            *weight += (*other - *weight) * t;
+        }
+        Self { weights }
+    }
+}
+
+/// Element type of an index buffer
+pub trait IndexElement: Copy {
+    fn get_index_type() -> vk::IndexType;
+}
+
+impl IndexElement for u16 {
+    fn get_index_type() -> vk::IndexType {
+        vk::IndexType::UINT16
+    }
+}
+
+/// Needed by primitives with more than 65535 vertices
+impl IndexElement for u32 {
+    fn get_index_type() -> vk::IndexType {
+        vk::IndexType::UINT32
+    }
+}
+
 pub struct Primitive {
     pub vertex_count: u32,
     pub vertices: Buffer,
     pub indices: Option<Buffer>,
+    /// Type of the elements of the index buffer
+    pub index_type: vk::IndexType,
+    /// Number of indices, as the index buffer may be larger than needed
+    pub index_count: u32,
     pub material: Handle<Material>,
+    pub morph_targets: Vec<MorphTarget>,
+    /// Interleaved morph target deltas, one `MorphDeltas` for each vertex
+    pub morph_deltas: Option<Buffer>,
+    /// Second texture coordinate set, one for each vertex, in its own vertex buffer
+    /// so that primitives without it do not pay for it, see `Vertex::UV1_BINDING`
+    pub uv1: Option<Buffer>,
+    /// Bounds of the vertex positions in model space
+    pub aabb: Aabb,
+    /// How vertices are assembled into triangles, `TRIANGLE_STRIP` primitives are drawn
+    /// with primitive restart enabled
+    pub topology: vk::PrimitiveTopology,
+    /// Pipeline of the vertex type of this primitive
+    pub pipeline: Pipelines,
 }
 
 impl Primitive {
-    pub fn new<T>(allocator: &Rc<RefCell<vk_mem::Allocator>>, vv: &[T]) -> Self {
-        let vertex_count = vv.len() as u32;
+    /// Index value which starts a new strip when primitive restart is enabled
+    pub const RESTART_INDEX: u16 = u16::MAX;
 
+    pub fn new<T: VertexInput>(allocator: &Rc<RefCell<vk_mem::Allocator>>, vv: &[T]) -> Self {
         let mut vertices = Buffer::new::<T>(allocator, vk::BufferUsageFlags::VERTEX_BUFFER);
         vertices.upload_arr(vv);
+        Self::with_vertices(vertices, vv)
+    }
+
+    /// Creates a primitive whose vertices are in device-local memory, which is faster to read
+    /// for static data. The vertex buffer can not be updated from the host afterwards.
+    /// See `Buffer::device_local` for `upload`.
+    pub fn new_device_local<T: VertexInput>(
+        dev: &Dev,
+        vv: &[T],
+        upload: Option<&mut UploadContext>,
+    ) -> Self {
+        let vertices = Buffer::device_local(dev, vk::BufferUsageFlags::VERTEX_BUFFER, vv, upload);
+        Self::with_vertices(vertices, vv)
+    }
+
+    fn with_vertices<T: VertexInput>(vertices: Buffer, vv: &[T]) -> Self {
+        let vertex_count = vv.len() as u32;
+        let aabb = Aabb::from_points(vv.iter().filter_map(|v| v.get_position()));
 
         Self {
             vertex_count,
             vertices,
             indices: None,
+            index_type: vk::IndexType::UINT16,
+            index_count: 0,
             material: Handle::none(), // default material
+            morph_targets: vec![],
+            morph_deltas: None,
+            uv1: None,
+            aabb,
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            pipeline: T::get_pipeline(),
         }
     }
 
+    /// Whether this primitive can be drawn into shadow maps,
+    /// whose pipeline only takes triangle lists of `Vertex`
+    pub fn casts_shadow(&self) -> bool {
+        self.topology == vk::PrimitiveTopology::TRIANGLE_LIST
+            && self.pipeline == Vertex::get_pipeline()
+    }
+
+    /// Sets the morph targets of this primitive uploading their deltas to a vertex buffer
+    pub fn set_morph_targets(&mut self, targets: Vec<MorphTarget>) {
+        assert!(targets.len() <= MAX_MORPH_TARGETS);
+
+        let mut deltas = vec![MorphDeltas::zero(); self.vertex_count as usize];
+        for (i, target) in targets.iter().enumerate() {
+            assert!(target.positions.len() == self.vertex_count as usize);
+            for (vertex, delta) in deltas.iter_mut().enumerate() {
+                delta.positions[i] = target.positions[vertex];
+                delta.normals[i] = target.normals[vertex];
+            }
+        }
+
+        let mut buffer = Buffer::new::<MorphDeltas>(
+            &self.vertices.allocator,
+            vk::BufferUsageFlags::VERTEX_BUFFER,
+        );
+        buffer.upload_arr(&deltas);
+        self.morph_deltas = Some(buffer);
+        self.morph_targets = targets;
+    }
+
+    /// Sets the second texture coordinate set of this primitive uploading it to a vertex buffer
+    pub fn set_uv1(&mut self, uvs: &[na::Vector2<f32>]) {
+        assert!(uvs.len() == self.vertex_count as usize);
+
+        let mut buffer = Buffer::new::<na::Vector2<f32>>(
+            &self.vertices.allocator,
+            vk::BufferUsageFlags::VERTEX_BUFFER,
+        );
+        buffer.upload_arr(uvs);
+        self.uv1 = Some(buffer);
+    }
+
+    /// Returns the position of a vertex after applying the morph targets with the given weights
+    pub fn get_morphed_position(
+        &self,
+        base: &na::Vector3<f32>,
+        vertex: usize,
+        weights: &MorphWeights,
+    ) -> na::Vector3<f32> {
+        let deltas: Vec<na::Vector3<f32>> = self
+            .morph_targets
+            .iter()
+            .map(|target| target.positions[vertex])
+            .collect();
+        MorphDeltas::accumulate(base, &deltas, weights)
+    }
+
     /// Returns a new primitive quad with side length 1 centered at the origin
     pub fn quad(allocator: &Rc<RefCell<vk_mem::Allocator>>, uv_scale: [f32; 2]) -> Self {
-        let vertices = vec![
+        let mut vertices = vec![
             Vertex {
                 pos: na::Vector3::new(-0.5, -0.5, 0.0),
                 color: Color::white(),
                 normal: na::Vector3::new(0.0, 0.0, 1.0),
                 uv: na::Vector2::new(0.0 * uv_scale[0], 1.0 * uv_scale[1]),
+                tangent: na::Vector4::zeros(),
             },
             Vertex {
                 pos: na::Vector3::new(0.5, -0.5, 0.0),
                 color: Color::white(),
                 normal: na::Vector3::new(0.0, 0.0, 1.0),
                 uv: na::Vector2::new(1.0 * uv_scale[0], 1.0 * uv_scale[1]),
+                tangent: na::Vector4::zeros(),
             },
             Vertex {
                 pos: na::Vector3::new(0.5, 0.5, 0.0),
                 color: Color::white(),
                 normal: na::Vector3::new(0.0, 0.0, 1.0),
                 uv: na::Vector2::new(1.0 * uv_scale[0], 0.0 * uv_scale[1]),
+                tangent: na::Vector4::zeros(),
             },
             Vertex {
                 pos: na::Vector3::new(-0.5, 0.5, 0.0),
                 color: Color::white(),
                 normal: na::Vector3::new(0.0, 0.0, 1.0),
                 uv: na::Vector2::new(0.0 * uv_scale[0], 0.0 * uv_scale[1]),
+                tangent: na::Vector4::zeros(),
             },
         ];
-        let indices = vec![0, 1, 2, 2, 3, 0];
+        let indices: Vec<u16> = vec![0, 1, 2, 2, 3, 0];
+        Self::compute_tangents(&mut vertices, Some(&indices[..]));
 
         let mut ret = Self::new(allocator, &vertices);
         ret.set_indices(&indices);
         ret
     }
 
-    pub fn set_indices(&mut self, ii: &[u16]) {
+    /// Returns a primitive grid of `cols` by `rows` cells with side length 1 centered at the
+    /// origin on the XZ plane, drawn as a triangle strip for each row of cells
+    pub fn grid(allocator: &Rc<RefCell<vk_mem::Allocator>>, cols: u32, rows: u32) -> Self {
+        assert!(cols > 0 && rows > 0);
+        assert!(
+            ((cols + 1) * (rows + 1)) < Self::RESTART_INDEX as u32,
+            "Grid {}x{} has too many vertices for 16-bit indices",
+            cols,
+            rows
+        );
+
+        let mut vertices = vec![];
+        for row in 0..=rows {
+            for col in 0..=cols {
+                let u = col as f32 / cols as f32;
+                let v = row as f32 / rows as f32;
+                vertices.push(Vertex {
+                    pos: na::Vector3::new(u - 0.5, 0.0, v - 0.5),
+                    color: Color::white(),
+                    normal: na::Vector3::new(0.0, 1.0, 0.0),
+                    uv: na::Vector2::new(u, v),
+                    // U grows along X, while V grows along Z, opposite to normal x tangent
+                    tangent: na::Vector4::new(1.0, 0.0, 0.0, -1.0),
+                });
+            }
+        }
+
+        let mut ret = Self::new(allocator, &vertices);
+        ret.set_indices(&Self::get_grid_indices(cols, rows));
+        ret.topology = vk::PrimitiveTopology::TRIANGLE_STRIP;
+        ret
+    }
+
+    /// Returns strip indices for a grid of `cols` by `rows` cells, zig-zagging between
+    /// two rows of vertices and separating each row of cells with `RESTART_INDEX`
+    pub fn get_grid_indices(cols: u32, rows: u32) -> Vec<u16> {
+        let stride = cols + 1;
+        let mut indices = vec![];
+        for row in 0..rows {
+            if row > 0 {
+                indices.push(Self::RESTART_INDEX);
+            }
+            for col in 0..=cols {
+                indices.push((row * stride + col) as u16);
+                indices.push(((row + 1) * stride + col) as u16);
+            }
+        }
+        indices
+    }
+
+    /// Returns the number of triangles drawn by strip indices, where every strip
+    /// delimited by `RESTART_INDEX` draws a triangle for each index after its first two
+    pub fn get_strip_triangle_count(indices: &[u16]) -> usize {
+        indices
+            .split(|&index| index == Self::RESTART_INDEX)
+            .map(|strip| strip.len().saturating_sub(2))
+            .sum()
+    }
+
+    /// Sets the indices of this primitive, which can be either `u16` or `u32`
+    pub fn set_indices<I: IndexElement>(&mut self, ii: &[I]) {
         let mut indices =
-            Buffer::new::<u16>(&self.vertices.allocator, vk::BufferUsageFlags::INDEX_BUFFER);
+            Buffer::new::<I>(&self.vertices.allocator, vk::BufferUsageFlags::INDEX_BUFFER);
         indices.upload_arr(ii);
         self.indices = Some(indices);
+        self.index_type = I::get_index_type();
+        self.index_count = ii.len() as u32;
+    }
+
+    /// Returns a new primitive cube with side length `size` centered at the origin.
+    /// Each face has its own four vertices, so normals are per-face.
+    pub fn cube(allocator: &Rc<RefCell<vk_mem::Allocator>>, size: f32) -> Self {
+        let (vertices, indices) = Self::get_cube_geometry(size);
+        let mut ret = Self::new(allocator, &vertices);
+        ret.set_indices(&indices);
+        ret
     }
 
-    pub fn cube(allocator: &Rc<RefCell<vk_mem::Allocator>>) -> Self {
-        let vertices = vec![
+    fn get_cube_geometry(size: f32) -> (Vec<Vertex>, Vec<u16>) {
+        let mut vertices = vec![
             // Front
             Vertex {
                 pos: na::Vector3::new(-0.5, -0.5, 0.5),
                 color: Color::white(),
                 normal: na::Vector3::new(0.0, 0.0, 1.0),
                 uv: na::Vector2::new(0.0, 0.0),
+                tangent: na::Vector4::zeros(),
             },
             Vertex {
                 pos: na::Vector3::new(0.5, -0.5, 0.5),
                 color: Color::white(),
                 normal: na::Vector3::new(0.0, 0.0, 1.0),
                 uv: na::Vector2::new(1.0, 0.0),
+                tangent: na::Vector4::zeros(),
             },
             Vertex {
                 pos: na::Vector3::new(0.5, 0.5, 0.5),
                 color: Color::white(),
                 normal: na::Vector3::new(0.0, 0.0, 1.0),
                 uv: na::Vector2::new(1.0, 1.0),
+                tangent: na::Vector4::zeros(),
             },
             Vertex {
                 pos: na::Vector3::new(-0.5, 0.5, 0.5),
                 color: Color::white(),
                 normal: na::Vector3::new(0.0, 0.0, 1.0),
                 uv: na::Vector2::new(0.0, 1.0),
+                tangent: na::Vector4::zeros(),
             },
             // Right
             Vertex {
@@ -104,24 +401,28 @@ impl Primitive {
                 color: Color::white(),
                 normal: na::Vector3::new(1.0, 0.0, 0.0),
                 uv: na::Vector2::new(0.0, 0.0),
+                tangent: na::Vector4::zeros(),
             },
             Vertex {
                 pos: na::Vector3::new(0.5, -0.5, -0.5),
                 color: Color::white(),
                 normal: na::Vector3::new(1.0, 0.0, 0.0),
                 uv: na::Vector2::new(1.0, 0.0),
+                tangent: na::Vector4::zeros(),
             },
             Vertex {
                 pos: na::Vector3::new(0.5, 0.5, -0.5),
                 color: Color::white(),
                 normal: na::Vector3::new(1.0, 0.0, 0.0),
                 uv: na::Vector2::new(1.0, 1.0),
+                tangent: na::Vector4::zeros(),
             },
             Vertex {
                 pos: na::Vector3::new(0.5, 0.5, 0.5),
                 color: Color::white(),
                 normal: na::Vector3::new(1.0, 0.0, 0.0),
                 uv: na::Vector2::new(0.0, 1.0),
+                tangent: na::Vector4::zeros(),
             },
             // Back
             Vertex {
@@ -129,24 +430,28 @@ impl Primitive {
                 color: Color::white(),
                 normal: na::Vector3::new(0.0, 0.0, -1.0),
                 uv: na::Vector2::new(0.0, 0.0),
+                tangent: na::Vector4::zeros(),
             },
             Vertex {
                 pos: na::Vector3::new(-0.5, -0.5, -0.5),
                 color: Color::white(),
                 normal: na::Vector3::new(0.0, 0.0, -1.0),
                 uv: na::Vector2::new(1.0, 0.0),
+                tangent: na::Vector4::zeros(),
             },
             Vertex {
                 pos: na::Vector3::new(-0.5, 0.5, -0.5),
                 color: Color::white(),
                 normal: na::Vector3::new(0.0, 0.0, -1.0),
                 uv: na::Vector2::new(1.0, 1.0),
+                tangent: na::Vector4::zeros(),
             },
             Vertex {
                 pos: na::Vector3::new(0.5, 0.5, -0.5),
                 color: Color::white(),
                 normal: na::Vector3::new(0.0, 0.0, -1.0),
                 uv: na::Vector2::new(0.0, 1.0),
+                tangent: na::Vector4::zeros(),
             },
             // Left
             Vertex {
@@ -154,24 +459,28 @@ impl Primitive {
                 color: Color::white(),
                 normal: na::Vector3::new(-1.0, 0.0, 0.0),
                 uv: na::Vector2::new(0.0, 0.0),
+                tangent: na::Vector4::zeros(),
             },
             Vertex {
                 pos: na::Vector3::new(-0.5, -0.5, 0.5),
                 color: Color::white(),
                 normal: na::Vector3::new(-1.0, 0.0, 0.0),
                 uv: na::Vector2::new(1.0, 0.0),
+                tangent: na::Vector4::zeros(),
             },
             Vertex {
                 pos: na::Vector3::new(-0.5, 0.5, 0.5),
                 color: Color::white(),
                 normal: na::Vector3::new(-1.0, 0.0, 0.0),
                 uv: na::Vector2::new(1.0, 1.0),
+                tangent: na::Vector4::zeros(),
             },
             Vertex {
                 pos: na::Vector3::new(-0.5, 0.5, -0.5),
                 color: Color::white(),
                 normal: na::Vector3::new(-1.0, 0.0, 0.0),
                 uv: na::Vector2::new(0.0, 1.0),
+                tangent: na::Vector4::zeros(),
             },
             // Top
             Vertex {
@@ -179,24 +488,28 @@ impl Primitive {
                 color: Color::white(),
                 normal: na::Vector3::new(0.0, 1.0, 0.0),
                 uv: na::Vector2::new(0.0, 0.0),
+                tangent: na::Vector4::zeros(),
             },
             Vertex {
                 pos: na::Vector3::new(0.5, 0.5, 0.5),
                 color: Color::white(),
                 normal: na::Vector3::new(0.0, 1.0, 0.0),
                 uv: na::Vector2::new(1.0, 0.0),
+                tangent: na::Vector4::zeros(),
             },
             Vertex {
                 pos: na::Vector3::new(0.5, 0.5, -0.5),
                 color: Color::white(),
                 normal: na::Vector3::new(0.0, 1.0, 0.0),
                 uv: na::Vector2::new(1.0, 1.0),
+                tangent: na::Vector4::zeros(),
             },
             Vertex {
                 pos: na::Vector3::new(-0.5, 0.5, -0.5),
                 color: Color::white(),
                 normal: na::Vector3::new(0.0, 1.0, 0.0),
                 uv: na::Vector2::new(0.0, 1.0),
+                tangent: na::Vector4::zeros(),
             },
             // Bottom
             Vertex {
@@ -204,24 +517,28 @@ impl Primitive {
                 color: Color::white(),
                 normal: na::Vector3::new(0.0, -1.0, 0.0),
                 uv: na::Vector2::new(0.0, 0.0),
+                tangent: na::Vector4::zeros(),
             },
             Vertex {
                 pos: na::Vector3::new(0.5, -0.5, -0.5),
                 color: Color::white(),
                 normal: na::Vector3::new(0.0, -1.0, 0.0),
                 uv: na::Vector2::new(1.0, 0.0),
+                tangent: na::Vector4::zeros(),
             },
             Vertex {
                 pos: na::Vector3::new(0.5, -0.5, 0.5),
                 color: Color::white(),
                 normal: na::Vector3::new(0.0, -1.0, 0.0),
                 uv: na::Vector2::new(1.0, 1.0),
+                tangent: na::Vector4::zeros(),
             },
             Vertex {
                 pos: na::Vector3::new(-0.5, -0.5, 0.5),
                 color: Color::white(),
                 normal: na::Vector3::new(0.0, -1.0, 0.0),
                 uv: na::Vector2::new(0.0, 1.0),
+                tangent: na::Vector4::zeros(),
             },
         ];
 
@@ -234,10 +551,133 @@ impl Primitive {
             20, 21, 22, 20, 22, 23, // bottom
         ];
 
+        for vertex in &mut vertices {
+            vertex.pos *= size;
+        }
+        Self::compute_tangents(&mut vertices, Some(&indices[..]));
+        (vertices, indices)
+    }
+
+    /// Returns a new UV sphere centered at the origin, with `rings` divisions from pole to pole
+    /// and `sectors` divisions around the Y axis. Texture coordinates wrap around once.
+    pub fn sphere(
+        allocator: &Rc<RefCell<vk_mem::Allocator>>,
+        radius: f32,
+        rings: u32,
+        sectors: u32,
+    ) -> Self {
+        let (vertices, indices) = Self::get_sphere_geometry(radius, rings, sectors);
         let mut ret = Self::new(allocator, &vertices);
         ret.set_indices(&indices);
         ret
     }
+
+    fn get_sphere_geometry(radius: f32, rings: u32, sectors: u32) -> (Vec<Vertex>, Vec<u16>) {
+        assert!(rings > 1 && sectors > 2);
+        assert!(
+            ((rings + 1) * (sectors + 1)) <= u16::MAX as u32,
+            "Sphere {}x{} has too many vertices for 16-bit indices",
+            rings,
+            sectors
+        );
+
+        // Vertices are duplicated along the seam and at the poles for texture coordinates
+        let mut vertices = vec![];
+        for ring in 0..=rings {
+            let v = ring as f32 / rings as f32;
+            // From the north pole down to the south pole
+            let phi = v * std::f32::consts::PI;
+            for sector in 0..=sectors {
+                let u = sector as f32 / sectors as f32;
+                let theta = u * 2.0 * std::f32::consts::PI;
+                // Sectors go counter-clockwise when seen from above
+                let normal =
+                    na::Vector3::new(phi.sin() * theta.cos(), phi.cos(), -phi.sin() * theta.sin());
+                vertices.push(Vertex {
+                    pos: normal * radius,
+                    color: Color::white(),
+                    normal,
+                    uv: na::Vector2::new(u, v),
+                    tangent: na::Vector4::zeros(),
+                });
+            }
+        }
+
+        let stride = sectors + 1;
+        let mut indices = vec![];
+        for ring in 0..rings {
+            for sector in 0..sectors {
+                let top_left = (ring * stride + sector) as u16;
+                let bottom_left = ((ring + 1) * stride + sector) as u16;
+                // Counter-clockwise when seen from outside
+                indices.extend_from_slice(&[
+                    top_left,
+                    bottom_left,
+                    bottom_left + 1,
+                    top_left,
+                    bottom_left + 1,
+                    top_left + 1,
+                ]);
+            }
+        }
+
+        Self::compute_tangents(&mut vertices, Some(&indices[..]));
+        (vertices, indices)
+    }
+
+    /// Computes the tangents of `vertices` from their positions and texture coordinates with
+    /// Lengyel's method, averaging the tangents of the triangles sharing each vertex.
+    /// Triangles are listed by `indices`, or by consecutive vertices when there are none.
+    /// Vertices without texture coordinates to derive a tangent from get a default one.
+    pub fn compute_tangents<I: Copy + Into<u32>>(vertices: &mut [Vertex], indices: Option<&[I]>) {
+        let indices: Vec<usize> = match indices {
+            Some(indices) => indices.iter().map(|&index| index.into() as usize).collect(),
+            None => (0..vertices.len()).collect(),
+        };
+
+        let mut tangents = vec![na::Vector3::zeros(); vertices.len()];
+        let mut bitangents = vec![na::Vector3::zeros(); vertices.len()];
+        for triangle in indices.chunks_exact(3) {
+            let (a, b, c) = (
+                &vertices[triangle[0]],
+                &vertices[triangle[1]],
+                &vertices[triangle[2]],
+            );
+            let e1 = b.pos - a.pos;
+            let e2 = c.pos - a.pos;
+            let d1 = b.uv - a.uv;
+            let d2 = c.uv - a.uv;
+
+            // Degenerate texture coordinates do not define a tangent
+            let det = d1.x * d2.y - d2.x * d1.y;
+            if det.abs() < f32::EPSILON {
+                continue;
+            }
+            let tangent = (e1 * d2.y - e2 * d1.y) / det;
+            let bitangent = (e2 * d1.x - e1 * d2.x) / det;
+            for &index in triangle {
+                tangents[index] += tangent;
+                bitangents[index] += bitangent;
+            }
+        }
+
+        for (i, vertex) in vertices.iter_mut().enumerate() {
+            // Orthogonalized against the normal
+            let normal = vertex.normal;
+            let tangent = tangents[i] - normal * normal.dot(&tangents[i]);
+            if tangent.norm() < 1e-6 {
+                vertex.tangent = Vertex::get_default_tangent(&normal);
+                continue;
+            }
+            let tangent = tangent.normalize();
+            let handedness = if normal.cross(&tangent).dot(&bitangents[i]) < 0.0 {
+                -1.0
+            } else {
+                1.0
+            };
+            vertex.tangent = na::Vector4::new(tangent.x, tangent.y, tangent.z, handedness);
+        }
+    }
 }
 
 pub struct Mesh {
@@ -249,3 +689,154 @@ impl Mesh {
         Self { primitives }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn morph_accumulate() {
+        let base = na::Vector3::new(1.0, 0.0, 0.0);
+        let deltas = [
+            na::Vector3::new(0.0, 2.0, 0.0),
+            na::Vector3::new(0.0, 0.0, 4.0),
+        ];
+        let weights = MorphWeights::new([0.5, 0.25, 1.0, 1.0]);
+
+        // Weights of missing targets are ignored
+        let position = MorphDeltas::accumulate(&base, &deltas, &weights);
+        assert_eq!(position, na::Vector3::new(1.0, 1.0, 1.0));
+
+        let position = MorphDeltas::accumulate(&base, &deltas, &MorphWeights::default());
+        assert_eq!(position, base);
+    }
+
+    #[test]
+    fn shadow_casters() {
+        let ctx = Ctx::headless();
+        let dev = Dev::headless(&ctx);
+        assert!(Primitive::cube(&dev.allocator, 1.0).casts_shadow());
+
+        // Strips and lines are not drawn by the shadow pipeline
+        assert!(!Primitive::grid(&dev.allocator, 2, 2).casts_shadow());
+        let point = Point::new(na::Vector3::zeros(), Color::white());
+        let line = Primitive::new(&dev.allocator, &[Line::new(point, point)]);
+        assert!(!line.casts_shadow());
+    }
+
+    #[test]
+    fn tangents() {
+        // Faces of the cube are mapped with U and V along their edges
+        let (vertices, _) = Primitive::get_cube_geometry(2.0);
+        for vertex in &vertices {
+            let tangent = vertex.tangent.xyz();
+            assert!((tangent.norm() - 1.0).abs() < 1e-5);
+            assert!(tangent.dot(&vertex.normal).abs() < 1e-5);
+            assert!(vertex.tangent.w == 1.0 || vertex.tangent.w == -1.0);
+        }
+        // U grows along X on the front face, V along Y
+        for vertex in &vertices[..4] {
+            assert_eq!(vertex.tangent, na::Vector4::new(1.0, 0.0, 0.0, 1.0));
+        }
+
+        // V growing the other way flips the handedness, without indices
+        let mut triangle = vec![
+            Vertex::new(0.0, 0.0, 0.0),
+            Vertex::new(1.0, 0.0, 0.0),
+            Vertex::new(0.0, 1.0, 0.0),
+        ];
+        triangle[1].uv = na::Vector2::new(1.0, 0.0);
+        triangle[2].uv = na::Vector2::new(0.0, -1.0);
+        Primitive::compute_tangents::<u16>(&mut triangle, None);
+        for vertex in &triangle {
+            assert_eq!(vertex.tangent, na::Vector4::new(1.0, 0.0, 0.0, -1.0));
+        }
+
+        // Without texture coordinates, tangents are only perpendicular to normals
+        let mut triangle = vec![
+            Vertex::new(0.0, 0.0, 0.0),
+            Vertex::new(0.0, 1.0, 0.0),
+            Vertex::new(0.0, 0.0, 1.0),
+        ];
+        for vertex in &mut triangle {
+            vertex.normal = na::Vector3::new(1.0, 0.0, 0.0);
+        }
+        Primitive::compute_tangents(&mut triangle, Some(&[0u32, 1, 2][..]));
+        for vertex in &triangle {
+            assert_eq!(vertex.tangent, Vertex::get_default_tangent(&vertex.normal));
+            assert_eq!(vertex.tangent.xyz().dot(&vertex.normal), 0.0);
+        }
+    }
+
+    #[test]
+    fn grid_strip_indices() {
+        let indices = Primitive::get_grid_indices(3, 3);
+        let restart = Primitive::RESTART_INDEX;
+        #[rustfmt::skip]
+        let expected = vec![
+            0, 4, 1, 5, 2, 6, 3, 7, restart,
+            4, 8, 5, 9, 6, 10, 7, 11, restart,
+            8, 12, 9, 13, 10, 14, 11, 15,
+        ];
+        assert_eq!(indices, expected);
+
+        // Two triangles for each cell, none across rows
+        assert_eq!(Primitive::get_strip_triangle_count(&indices), 18);
+    }
+
+    #[test]
+    fn cube_and_sphere_geometry() {
+        let (vertices, indices) = Primitive::get_cube_geometry(2.0);
+        assert_eq!(vertices.len(), 24);
+        assert_eq!(indices.len(), 36);
+        assert!(vertices.iter().all(|v| v.pos.amax() == 1.0));
+
+        let (rings, sectors) = (8, 16);
+        let (vertices, indices) = Primitive::get_sphere_geometry(2.0, rings, sectors);
+        assert_eq!(vertices.len() as u32, (rings + 1) * (sectors + 1));
+        assert_eq!(indices.len() as u32, rings * sectors * 6);
+        for vertex in &vertices {
+            assert!((vertex.pos.norm() - 2.0).abs() < 1e-5);
+            assert!((vertex.normal - vertex.pos / 2.0).norm() < 1e-5);
+        }
+        assert_eq!(vertices[0].uv, na::Vector2::new(0.0, 0.0));
+        assert_eq!(vertices.last().unwrap().uv, na::Vector2::new(1.0, 1.0));
+
+        // Triangles face outwards
+        for triangle in indices.chunks_exact(3) {
+            let [a, b, c] = [
+                &vertices[triangle[0] as usize],
+                &vertices[triangle[1] as usize],
+                &vertices[triangle[2] as usize],
+            ];
+            let normal = (b.pos - a.pos).cross(&(c.pos - a.pos));
+            // Triangles touching the poles are degenerate
+            if normal.norm() > 1e-6 {
+                assert!(normal.dot(&(a.pos + b.pos + c.pos)) > 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn index_element_type() {
+        assert_eq!(u16::get_index_type(), vk::IndexType::UINT16);
+        assert_eq!(u32::get_index_type(), vk::IndexType::UINT32);
+        for &index_type in [vk::IndexType::UINT16, vk::IndexType::UINT32].iter() {
+            let size = match index_type {
+                vk::IndexType::UINT16 => std::mem::size_of::<u16>(),
+                _ => std::mem::size_of::<u32>(),
+            };
+            assert_eq!(
+                CommandBuffer::get_index_size(index_type),
+                size as vk::DeviceSize
+            );
+        }
+    }
+
+    #[test]
+    fn morph_weights_lerp() {
+        let a = MorphWeights::new([0.0, 1.0, 0.0, 0.0]);
+        let b = MorphWeights::new([1.0, 0.0, 0.0, 0.5]);
+        assert_eq!(a.lerp(&b, 0.5), MorphWeights::new([0.5, 0.5, 0.0, 0.25]));
+    }
+}
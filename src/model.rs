@@ -7,6 +7,7 @@ use super::*;
 use ash::*;
 use memoffset::offset_of;
 use nalgebra as na;
+use std::{collections::HashSet, ops::Range};
 
 pub fn create_set_layout(
     device: &Device,
@@ -19,69 +20,116 @@ pub fn create_set_layout(
         .expect("Failed to create Vulkan descriptor set layout")
 }
 
+/// Transforms normals to view space. It is the inverse-transpose of the upper-left 3x3 of the
+/// model-view matrix, hence translation does not affect normals and non-uniform scale keeps
+/// them perpendicular to their surfaces. Columns are padded to 16 bytes as std140 requires.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NormalMatrix {
+    pub columns: [na::Vector4<f32>; 3],
+}
+
+// Safe as it is made of 12 contiguous floats
+unsafe impl Pod for NormalMatrix {}
+
+impl NormalMatrix {
+    pub fn identity() -> Self {
+        Self::from_matrix(&na::Matrix3::identity())
+    }
+
+    /// Returns `None` when the model-view matrix is not invertible, as with a zero scale
+    pub fn try_new(model_view: &na::Matrix4<f32>) -> Option<Self> {
+        let matrix = model_view
+            .fixed_slice::<na::U3, na::U3>(0, 0)
+            .into_owned()
+            .try_inverse()?
+            .transpose();
+        Some(Self::from_matrix(&matrix))
+    }
+
+    pub fn from_matrix(matrix: &na::Matrix3<f32>) -> Self {
+        let column = |i: usize| matrix.column(i).into_owned().push(0.0);
+        Self {
+            columns: [column(0), column(1), column(2)],
+        }
+    }
+
+    pub fn get_matrix(&self) -> na::Matrix3<f32> {
+        na::Matrix3::from_columns(&[
+            self.columns[0].xyz(),
+            self.columns[1].xyz(),
+            self.columns[2].xyz(),
+        ])
+    }
+}
+
+/// What is needed to select the pipeline a primitive is drawn with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PrimitiveInfo {
+    /// Pipeline of the vertex type of the primitive
+    pub pipeline: Pipelines,
+    pub topology: vk::PrimitiveTopology,
+    pub blend_mode: BlendMode,
+}
+
+/// A primitive of a node drawn by `Frame::draw_model`
+#[derive(Clone, Copy, PartialEq)]
+pub struct ModelDraw {
+    pub node: Handle<Node>,
+    pub primitive: Handle<Primitive>,
+    pub pipeline: Pipelines,
+}
+
 pub trait VertexInput {
     fn get_pipeline() -> Pipelines {
         Pipelines::MAIN
     }
 
-    fn get_bindings() -> vk::VertexInputBindingDescription;
+    fn get_bindings() -> Vec<vk::VertexInputBindingDescription>;
 
     fn get_attributes() -> Vec<vk::VertexInputAttributeDescription>;
 
-    /// @TODO Would it be useful to follow a convention where we know exactly which set layout is at a certain index?
-    /// The answer is definitely yes: 0 model, 1 camera, 2 material
+    /// Set layouts are at the index of their `DescriptorFrequency`
     fn get_set_layouts(device: &Device) -> Vec<vk::DescriptorSetLayout>;
 
     fn get_constants() -> Vec<vk::PushConstantRange> {
         vec![]
     }
 
-    fn write_set_model(device: &Device, set: vk::DescriptorSet, ubo: &Buffer) {
-        // Update immediately the descriptor sets
-        let buffer_info = vk::DescriptorBufferInfo::builder()
-            .range(std::mem::size_of::<na::Matrix4<f32>>() as vk::DeviceSize)
-            .buffer(ubo.buffer)
-            .build();
-
-        let buffer_write = vk::WriteDescriptorSet::builder()
-            .dst_set(set)
-            .dst_binding(0)
-            .dst_array_element(0)
-            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-            .buffer_info(&[buffer_info])
-            .build();
-
-        let writes = vec![buffer_write];
-
-        unsafe {
-            device.update_descriptor_sets(&writes, &[]);
-        }
+    /// Returns the position of this vertex, if it has one, used to compute bounding boxes
+    fn get_position(&self) -> Option<na::Vector3<f32>> {
+        None
     }
 
-    fn write_set_model_view(device: &Device, set: vk::DescriptorSet, model_view: &Buffer) {
-        // Update immediately the descriptor sets
-        let buffer_info = vk::DescriptorBufferInfo::builder()
-            .range(std::mem::size_of::<na::Matrix4<f32>>() as vk::DeviceSize)
-            .buffer(model_view.buffer)
-            .build();
-
-        let buffer_write = vk::WriteDescriptorSet::builder()
-            .dst_set(set)
-            .dst_binding(1)
-            .dst_array_element(0)
-            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-            .buffer_info(&[buffer_info])
-            .build();
+    fn write_set_model(
+        writer: &mut DescriptorWriter,
+        set: vk::DescriptorSet,
+        ubo: &Buffer,
+        offset: vk::DeviceSize,
+    ) {
+        writer.write_uniform_at::<na::Matrix4<f32>>(set, 0, ubo, offset);
+    }
 
-        let writes = vec![buffer_write];
+    fn write_set_model_view(
+        writer: &mut DescriptorWriter,
+        set: vk::DescriptorSet,
+        model_view: &Buffer,
+        offset: vk::DeviceSize,
+    ) {
+        writer.write_uniform_at::<NormalMatrix>(set, 1, model_view, offset);
+    }
 
-        unsafe {
-            device.update_descriptor_sets(&writes, &[]);
-        }
+    /// Only vertex inputs supporting morph targets need to write their weights
+    fn write_set_morph_weights(
+        _writer: &mut DescriptorWriter,
+        _set: vk::DescriptorSet,
+        _weights: &Buffer,
+        _offset: vk::DeviceSize,
+    ) {
     }
 
     fn write_set_image(
-        _device: &Device,
+        _writer: &mut DescriptorWriter,
         _set: vk::DescriptorSet,
         _view: &ImageView,
         _sampler: &Sampler,
@@ -122,6 +170,7 @@ pub trait VertexInput {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(C)]
 pub struct Color {
     r: f32,
@@ -130,6 +179,9 @@ pub struct Color {
     a: f32,
 }
 
+// Safe as it is made of 4 contiguous floats
+unsafe impl Pod for Color {}
+
 impl Color {
     pub fn new(r: f32, g: f32, b: f32, a: f32) -> Self {
         Color { r, g, b, a }
@@ -138,23 +190,151 @@ impl Color {
     pub fn white() -> Self {
         Self::new(1.0, 1.0, 1.0, 1.0)
     }
+
+    pub fn get_rgba(&self) -> na::Vector4<f32> {
+        na::Vector4::new(self.r, self.g, self.b, self.a)
+    }
+}
+
+/// How the fragments of a material are combined with what is already in the framebuffer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlendMode {
+    /// Fragments replace what is behind them and write depth
+    Opaque,
+    /// Fragments are blended over what is behind them. They are depth tested, but do not
+    /// write depth, otherwise they would occlude other blended surfaces behind them.
+    Blend,
+}
+
+impl Default for BlendMode {
+    fn default() -> Self {
+        BlendMode::Opaque
+    }
+}
+
+impl BlendMode {
+    pub fn get_depth_write(&self) -> bool {
+        match self {
+            BlendMode::Opaque => true,
+            BlendMode::Blend => false,
+        }
+    }
+
+    /// Returns `state` with alpha blending enabled for blended materials,
+    /// while opaque ones keep the color blending of their vertex input
+    pub fn get_color_blend(
+        &self,
+        state: vk::PipelineColorBlendAttachmentState,
+    ) -> vk::PipelineColorBlendAttachmentState {
+        match self {
+            BlendMode::Opaque => state,
+            BlendMode::Blend => vk::PipelineColorBlendAttachmentState {
+                blend_enable: vk::TRUE,
+                src_color_blend_factor: vk::BlendFactor::SRC_ALPHA,
+                dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+                color_blend_op: vk::BlendOp::ADD,
+                ..state
+            },
+        }
+    }
 }
 
-#[repr(C)]
 pub struct Material {
     pub color: Color,
     pub albedo: Handle<Texture>,
+    /// When valid, it is used instead of the albedo texture sampler, for example to bias its LOD
+    pub sampler: Handle<Sampler>,
+    /// Texture coordinate set sampled by the albedo texture, either 0 for `uv` or 1 for `Primitive::uv1`
+    pub albedo_uv: u32,
+    /// Blended materials are drawn with a pipeline which does not write depth
+    pub blend_mode: BlendMode,
+    /// First array layer of the albedo image sampled by this material
+    pub base_array_layer: u32,
+    /// Number of array layers of the albedo image this material may sample
+    pub layer_count: u32,
 }
 
+/// Material parameters read by the fragment shader
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct MaterialUniform {
+    pub color: Color,
+    pub albedo_uv: u32,
+    /// Layer of the albedo array view sampled by the fragment shader
+    pub albedo_layer: u32,
+}
+
+// Safe as the color and the indices are all 4-byte aligned, hence there is no padding
+unsafe impl Pod for MaterialUniform {}
+
 impl Material {
+    /// Number of texture coordinate sets of a `Vertex`
+    pub const UV_SET_COUNT: u32 = 2;
+
     pub fn new(color: Color) -> Self {
         let albedo = Handle::none();
-        Self { color, albedo }
+        let sampler = Handle::none();
+        Self {
+            color,
+            albedo,
+            sampler,
+            albedo_uv: 0,
+            blend_mode: BlendMode::default(),
+            base_array_layer: 0,
+            layer_count: 1,
+        }
     }
 
     pub fn textured(albedo: Handle<Texture>) -> Self {
         let color = Color::white();
-        Self { color, albedo }
+        let sampler = Handle::none();
+        Self {
+            color,
+            albedo,
+            sampler,
+            albedo_uv: 0,
+            blend_mode: BlendMode::default(),
+            base_array_layer: 0,
+            layer_count: 1,
+        }
+    }
+
+    /// Returns the array layers of the albedo image sampled by this material,
+    /// which an albedo view should look into
+    pub fn get_albedo_layers(&self) -> Range<u32> {
+        assert!(self.layer_count > 0, "Material with no albedo layers");
+        self.base_array_layer..self.base_array_layer + self.layer_count
+    }
+
+    /// Returns the content of the uniform buffer of this material,
+    /// for an albedo view starting at the first layer of the image
+    pub fn get_uniform(&self) -> MaterialUniform {
+        let range = vk::ImageSubresourceRange::builder()
+            .layer_count(self.get_albedo_layers().end)
+            .build();
+        self.get_uniform_for(&range)
+    }
+
+    /// Returns the content of the uniform buffer of this material, for an albedo view
+    /// looking into `range`. The layer passed to the shader is relative to the view.
+    pub fn get_uniform_for(&self, range: &vk::ImageSubresourceRange) -> MaterialUniform {
+        assert!(
+            self.albedo_uv < Self::UV_SET_COUNT,
+            "Invalid albedo texture coordinate set {}",
+            self.albedo_uv
+        );
+        let layers = self.get_albedo_layers();
+        assert!(
+            layers.start >= range.base_array_layer
+                && layers.end <= range.base_array_layer + range.layer_count,
+            "Material layers {:?} out of the albedo view",
+            layers
+        );
+        MaterialUniform {
+            color: self.color,
+            albedo_uv: self.albedo_uv,
+            albedo_layer: layers.start - range.base_array_layer,
+        }
     }
 
     pub fn get_set_layout_bindings() -> Vec<vk::DescriptorSetLayoutBinding> {
@@ -176,47 +356,19 @@ impl Material {
     }
 
     pub fn write_set(
-        device: &Device,
+        writer: &mut DescriptorWriter,
         set: vk::DescriptorSet,
         material: &Buffer,
         albedo: &ImageView,
         sampler: &Sampler,
     ) {
-        let buffer_info = vk::DescriptorBufferInfo::builder()
-            .range(std::mem::size_of::<Color>() as vk::DeviceSize)
-            .buffer(material.buffer)
-            .build();
-
-        let buffer_write = vk::WriteDescriptorSet::builder()
-            .dst_set(set)
-            .dst_binding(0)
-            .dst_array_element(0)
-            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-            .buffer_info(&[buffer_info])
-            .build();
-
-        let image_info = vk::DescriptorImageInfo::builder()
-            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
-            .image_view(albedo.view)
-            .sampler(sampler.sampler)
-            .build();
-
-        let image_write = vk::WriteDescriptorSet::builder()
-            .dst_set(set)
-            .dst_binding(1)
-            .dst_array_element(0)
-            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-            .image_info(&[image_info])
-            .build();
-
-        let writes = vec![buffer_write, image_write];
-
-        unsafe {
-            device.update_descriptor_sets(&writes, &[]);
-        }
+        writer
+            .write_uniform::<MaterialUniform>(set, 0, material)
+            .write_sampled_image(set, 1, albedo, sampler);
     }
 }
 
+#[derive(Clone, Copy)]
 #[repr(C)]
 pub struct Point {
     pos: na::Vector3<f32>,
@@ -224,6 +376,9 @@ pub struct Point {
     normal: na::Vector3<f32>,
 }
 
+// Safe as it is made of 10 contiguous floats
+unsafe impl Pod for Point {}
+
 impl Point {
     pub fn new(pos: na::Vector3<f32>, color: Color) -> Self {
         Self {
@@ -235,12 +390,16 @@ impl Point {
 }
 
 impl VertexInput for Point {
-    fn get_bindings() -> vk::VertexInputBindingDescription {
-        vk::VertexInputBindingDescription::builder()
+    fn get_position(&self) -> Option<na::Vector3<f32>> {
+        Some(self.pos)
+    }
+
+    fn get_bindings() -> Vec<vk::VertexInputBindingDescription> {
+        vec![vk::VertexInputBindingDescription::builder()
             .binding(0)
             .stride(std::mem::size_of::<Point>() as u32)
             .input_rate(vk::VertexInputRate::VERTEX)
-            .build()
+            .build()]
     }
 
     fn get_attributes() -> Vec<vk::VertexInputAttributeDescription> {
@@ -286,7 +445,10 @@ impl VertexInput for Point {
         let camera_bindings = Camera::get_set_layout_bindings();
         let camera = create_set_layout(device, &camera_bindings);
 
-        vec![model, camera]
+        DescriptorFrequency::sort_set_layouts(vec![
+            (DescriptorFrequency::PerObject, model),
+            (DescriptorFrequency::PerView, camera),
+        ])
     }
 }
 
@@ -307,7 +469,7 @@ impl VertexInput for Line {
         Pipelines::LINE
     }
 
-    fn get_bindings() -> vk::VertexInputBindingDescription {
+    fn get_bindings() -> Vec<vk::VertexInputBindingDescription> {
         Point::get_bindings()
     }
 
@@ -320,70 +482,118 @@ impl VertexInput for Line {
     }
 }
 
+/// Direction of the Y axis in clip space, which depends on the coordinate convention of the renderer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClipY {
+    /// Vulkan convention, where -1 is the top of the viewport
+    Down,
+    /// OpenGL convention, obtained in Vulkan by flipping the viewport
+    Up,
+}
+
+/// Where the texture coordinates (0, 0) of some content are on their image. Vulkan samples
+/// images with V growing downwards, hence content with a bottom-left origin is flipped
+/// once, when it is loaded, and shaders never flip V.
+///
+/// Flipping the viewport, see `ClipY::Up`, does not change how textures are sampled,
+/// only the screen-space coordinates of `PresentVertex`, which take it into account.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UvOrigin {
+    /// Vulkan, Direct3D, and glTF convention
+    TopLeft,
+    /// OpenGL convention
+    BottomLeft,
+}
+
+impl Default for UvOrigin {
+    fn default() -> Self {
+        UvOrigin::TopLeft
+    }
+}
+
+impl UvOrigin {
+    /// Returns whether V should be flipped to follow the Vulkan convention
+    pub fn get_flip_v(&self) -> bool {
+        *self == UvOrigin::BottomLeft
+    }
+
+    /// Returns `uv` in the Vulkan convention
+    pub fn to_top_left(&self, uv: na::Vector2<f32>) -> na::Vector2<f32> {
+        if self.get_flip_v() {
+            na::Vector2::new(uv.x, 1.0 - uv.y)
+        } else {
+            uv
+        }
+    }
+}
+
 /// Very simple vertex used for the presentation pass
 #[repr(C)]
 pub struct PresentVertex {
     /// The shader just needs x and y
     pub pos: na::Vector2<f32>,
+    /// Texture coordinates with origin at the top-left corner of the screen
+    pub uv: na::Vector2<f32>,
 }
 
 impl PresentVertex {
+    /// Creates a vertex with texture coordinates following the Vulkan convention
     pub fn new(x: f32, y: f32) -> Self {
+        Self::with_convention(x, y, ClipY::Down)
+    }
+
+    pub fn with_convention(x: f32, y: f32, clip_y: ClipY) -> Self {
+        let v = match clip_y {
+            ClipY::Down => (y + 1.0) / 2.0,
+            ClipY::Up => (1.0 - y) / 2.0,
+        };
         Self {
             pos: na::Vector2::new(x, y),
+            uv: na::Vector2::new((x + 1.0) / 2.0, v),
         }
     }
 
+    /// Returns a counter-clockwise triangle covering the whole screen
+    pub fn fullscreen_triangle(clip_y: ClipY) -> [PresentVertex; 3] {
+        match clip_y {
+            ClipY::Down => [
+                Self::with_convention(-1.0, -1.0, clip_y),
+                Self::with_convention(-1.0, 3.0, clip_y),
+                Self::with_convention(3.0, -1.0, clip_y),
+            ],
+            // Flipping the Y axis flips the winding as well
+            ClipY::Up => [
+                Self::with_convention(-1.0, -1.0, clip_y),
+                Self::with_convention(3.0, -1.0, clip_y),
+                Self::with_convention(-1.0, 3.0, clip_y),
+            ],
+        }
+    }
+
+    /// The sampler is not used, as the G-buffer is read through input attachments
     pub fn write_set(
-        device: &ash::Device,
+        writer: &mut DescriptorWriter,
         set: vk::DescriptorSet,
         albedo: &ImageView,
         normal: &ImageView,
-        sampler: &Sampler,
+        light: &ImageView,
+        _sampler: &Sampler,
     ) {
-        let image_info = vk::DescriptorImageInfo::builder()
-            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
-            .image_view(albedo.view)
-            .sampler(sampler.sampler)
-            .build();
-
-        let image_write = vk::WriteDescriptorSet::builder()
-            .dst_set(set)
-            .dst_binding(0)
-            .dst_array_element(0)
-            .descriptor_type(vk::DescriptorType::INPUT_ATTACHMENT)
-            .image_info(&[image_info])
-            .build();
-
-        let normal_image_info = vk::DescriptorImageInfo::builder()
-            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
-            .image_view(normal.view)
-            .sampler(sampler.sampler)
-            .build();
-
-        let normal_image_write = vk::WriteDescriptorSet::builder()
-            .dst_set(set)
-            .dst_binding(1)
-            .dst_array_element(0)
-            .descriptor_type(vk::DescriptorType::INPUT_ATTACHMENT)
-            .image_info(&[normal_image_info])
-            .build();
-
-        let writes = vec![image_write, normal_image_write];
-
-        unsafe {
-            device.update_descriptor_sets(&writes, &[]);
-        }
+        let layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+        writer
+            .write_input_attachment(set, 0, albedo, layout)
+            .write_input_attachment(set, 1, normal, layout)
+            .write_input_attachment(set, 2, light, layout);
     }
 }
 
 impl VertexInput for PresentVertex {
-    fn get_bindings() -> vk::VertexInputBindingDescription {
-        vk::VertexInputBindingDescription::builder()
+    fn get_bindings() -> Vec<vk::VertexInputBindingDescription> {
+        vec![vk::VertexInputBindingDescription::builder()
             .binding(0)
             .stride(std::mem::size_of::<Self>() as u32)
             .input_rate(vk::VertexInputRate::VERTEX)
-            .build()
+            .build()]
     }
 
     fn get_attributes() -> Vec<vk::VertexInputAttributeDescription> {
@@ -395,6 +605,13 @@ impl VertexInput for PresentVertex {
                 .format(vk::Format::R32G32_SFLOAT)
                 .offset(offset_of!(Self, pos) as u32)
                 .build(),
+            // uv
+            vk::VertexInputAttributeDescription::builder()
+                .binding(0)
+                .location(1)
+                .format(vk::Format::R32G32_SFLOAT)
+                .offset(offset_of!(Self, uv) as u32)
+                .build(),
         ]
     }
 
@@ -413,7 +630,14 @@ impl VertexInput for PresentVertex {
             .stage_flags(vk::ShaderStageFlags::FRAGMENT)
             .build();
 
-        let bindings = vec![albedo_binding, normal_binding];
+        let light_binding = vk::DescriptorSetLayoutBinding::builder()
+            .binding(2)
+            .descriptor_type(vk::DescriptorType::INPUT_ATTACHMENT)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            .build();
+
+        let bindings = vec![albedo_binding, normal_binding, light_binding];
         let set_layout = create_set_layout(device, &bindings);
         vec![set_layout]
     }
@@ -425,31 +649,85 @@ pub struct Vertex {
     pub color: Color,
     pub normal: na::Vector3<f32>,
     pub uv: na::Vector2<f32>,
+    /// Direction where U grows in xyz, for normal mapping. The bitangent, where V grows,
+    /// is `w * normal.cross(tangent)`, with `w` either 1 or -1. See `Primitive::compute_tangents`.
+    pub tangent: na::Vector4<f32>,
 }
 
 impl Vertex {
     pub fn new(x: f32, y: f32, z: f32) -> Self {
+        let normal = na::Vector3::new(0.0, 0.0, 1.0);
         Self {
             pos: na::Vector3::new(x, y, z),
             color: Color::white(),
             // From the screen towards the viewer
-            normal: na::Vector3::new(0.0, 0.0, 1.0),
+            normal,
             uv: na::Vector2::new(0.0, 0.0),
+            tangent: Self::get_default_tangent(&normal),
         }
     }
+
+    /// Returns a tangent perpendicular to `normal`, for vertices without texture coordinates
+    pub fn get_default_tangent(normal: &na::Vector3<f32>) -> na::Vector4<f32> {
+        // The axis least aligned with the normal avoids a degenerate projection
+        let axis = if normal.x.abs() < 0.9 {
+            na::Vector3::x()
+        } else {
+            na::Vector3::y()
+        };
+        let tangent = (axis - normal * normal.dot(&axis)).normalize();
+        na::Vector4::new(tangent.x, tangent.y, tangent.z, 1.0)
+    }
 }
 
-impl VertexInput for Vertex {
-    fn get_bindings() -> vk::VertexInputBindingDescription {
-        vk::VertexInputBindingDescription::builder()
-            .binding(0)
-            .stride(std::mem::size_of::<Vertex>() as u32)
-            .input_rate(vk::VertexInputRate::VERTEX)
+impl Vertex {
+    /// Location of the tangent attribute, following the morph target deltas
+    pub const TANGENT_LOCATION: u32 = 5 + 2 * MAX_MORPH_TARGETS as u32;
+
+    /// Binding of the second texture coordinate set, which is not part of the vertex as
+    /// most meshes do not have it, for example for lightmaps. See `Primitive::uv1`.
+    pub const UV1_BINDING: u32 = 2;
+
+    /// Returns the attribute of the second texture coordinate set, shared by vertex types
+    fn get_uv1_attribute() -> vk::VertexInputAttributeDescription {
+        vk::VertexInputAttributeDescription::builder()
+            .binding(Self::UV1_BINDING)
+            .location(4)
+            .format(vk::Format::R32G32_SFLOAT)
+            .offset(0)
             .build()
     }
+}
 
-    fn get_attributes() -> Vec<vk::VertexInputAttributeDescription> {
+impl VertexInput for Vertex {
+    fn get_position(&self) -> Option<na::Vector3<f32>> {
+        Some(self.pos)
+    }
+
+    fn get_bindings() -> Vec<vk::VertexInputBindingDescription> {
         vec![
+            vk::VertexInputBindingDescription::builder()
+                .binding(0)
+                .stride(std::mem::size_of::<Vertex>() as u32)
+                .input_rate(vk::VertexInputRate::VERTEX)
+                .build(),
+            // Morph target deltas
+            vk::VertexInputBindingDescription::builder()
+                .binding(1)
+                .stride(std::mem::size_of::<MorphDeltas>() as u32)
+                .input_rate(vk::VertexInputRate::VERTEX)
+                .build(),
+            // Second texture coordinates
+            vk::VertexInputBindingDescription::builder()
+                .binding(Self::UV1_BINDING)
+                .stride(std::mem::size_of::<na::Vector2<f32>>() as u32)
+                .input_rate(vk::VertexInputRate::VERTEX)
+                .build(),
+        ]
+    }
+
+    fn get_attributes() -> Vec<vk::VertexInputAttributeDescription> {
+        let mut attributes = vec![
             // position
             vk::VertexInputAttributeDescription::builder()
                 .binding(0)
@@ -478,7 +756,19 @@ impl VertexInput for Vertex {
                 .format(vk::Format::R32G32_SFLOAT)
                 .offset(offset_of!(Vertex, uv) as u32)
                 .build(),
-        ]
+            Self::get_uv1_attribute(),
+        ];
+        attributes.extend(MorphDeltas::get_attributes(1, 5));
+        // tangent, after the morph target deltas
+        attributes.push(
+            vk::VertexInputAttributeDescription::builder()
+                .binding(0)
+                .location(Self::TANGENT_LOCATION)
+                .format(vk::Format::R32G32B32A32_SFLOAT)
+                .offset(offset_of!(Vertex, tangent) as u32)
+                .build(),
+        );
+        attributes
     }
 
     fn get_set_layouts(device: &Device) -> Vec<vk::DescriptorSetLayout> {
@@ -495,6 +785,13 @@ impl VertexInput for Vertex {
                 .descriptor_count(1)
                 .stage_flags(vk::ShaderStageFlags::VERTEX)
                 .build(),
+            // Morph weights
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(2)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::VERTEX)
+                .build(),
         ];
         let model = create_set_layout(device, &model_bindings);
 
@@ -504,7 +801,181 @@ impl VertexInput for Vertex {
         let material_bindings = Material::get_set_layout_bindings();
         let material = create_set_layout(device, &material_bindings);
 
-        vec![model, camera, material]
+        let shadow_bindings = ShadowMap::get_set_layout_bindings();
+        let shadow = create_set_layout(device, &shadow_bindings);
+
+        DescriptorFrequency::sort_set_layouts(vec![
+            (DescriptorFrequency::PerObject, model),
+            (DescriptorFrequency::PerView, camera),
+            (DescriptorFrequency::PerMaterial, material),
+            (DescriptorFrequency::PerFrame, shadow),
+        ])
+    }
+
+    fn write_set_morph_weights(
+        writer: &mut DescriptorWriter,
+        set: vk::DescriptorSet,
+        weights: &Buffer,
+        offset: vk::DeviceSize,
+    ) {
+        writer.write_uniform_at::<MorphWeights>(set, 2, weights, offset);
+    }
+}
+
+/// A vertex with compressed attributes, which takes less memory and bandwidth than `Vertex`.
+/// Attributes are at the same locations as `Vertex`, hence it is drawn with the same shaders,
+/// as Vulkan converts normalized and half float formats to floats when fetching them.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PackedVertex {
+    pub pos: na::Vector3<f32>,
+    pub color: Color,
+    /// `A2B10G10R10_SNORM_PACK32` normal, see `pack_normal`
+    pub normal: u32,
+    /// `R16G16_SFLOAT` texture coordinates
+    pub uv: [u16; 2],
+    /// `A2B10G10R10_SNORM_PACK32` tangent, with handedness in alpha, see `pack_tangent`
+    pub tangent: u32,
+}
+
+impl PackedVertex {
+    pub const NORMAL_FORMAT: vk::Format = vk::Format::A2B10G10R10_SNORM_PACK32;
+    pub const UV_FORMAT: vk::Format = vk::Format::R16G16_SFLOAT;
+
+    /// Packs the components of `normal` into 10-bit signed normalized values, with x in the
+    /// lowest bits. Alpha is left to 0.
+    pub fn pack_normal(normal: &na::Vector3<f32>) -> u32 {
+        let snorm = |value: f32| ((value.max(-1.0).min(1.0) * 511.0).round() as i32 as u32) & 0x3ff;
+        snorm(normal.x) | snorm(normal.y) << 10 | snorm(normal.z) << 20
+    }
+
+    /// Packs a tangent as a normal, with the handedness in the 2-bit signed normalized alpha
+    pub fn pack_tangent(tangent: &na::Vector4<f32>) -> u32 {
+        let handedness = if tangent.w < 0.0 { 0b11 } else { 0b01 };
+        Self::pack_normal(&tangent.xyz()) | handedness << 30
+    }
+
+    pub fn unpack_tangent(tangent: u32) -> na::Vector4<f32> {
+        let xyz = Self::unpack_normal(tangent);
+        let w = if tangent >> 30 == 0b11 { -1.0 } else { 1.0 };
+        na::Vector4::new(xyz.x, xyz.y, xyz.z, w)
+    }
+
+    pub fn unpack_normal(normal: u32) -> na::Vector3<f32> {
+        let snorm = |shift: u32| {
+            // Sign extends the 10-bit value
+            let value = ((normal >> shift << 22) as i32) >> 22;
+            (value as f32 / 511.0).max(-1.0)
+        };
+        na::Vector3::new(snorm(0), snorm(10), snorm(20))
+    }
+
+    pub fn pack_uv(uv: &na::Vector2<f32>) -> [u16; 2] {
+        [Image::f32_to_half(uv.x), Image::f32_to_half(uv.y)]
+    }
+
+    pub fn unpack_uv(uv: [u16; 2]) -> na::Vector2<f32> {
+        na::Vector2::new(Image::half_to_f32(uv[0]), Image::half_to_f32(uv[1]))
+    }
+
+    /// Returns whether `dev` can fetch the packed attributes. Half float texture coordinates
+    /// are always supported, while the packed normal format is optional.
+    pub fn is_supported(dev: &Dev) -> bool {
+        dev.supports_vertex_format(Self::NORMAL_FORMAT)
+    }
+
+    pub fn get_normal(&self) -> na::Vector3<f32> {
+        Self::unpack_normal(self.normal)
+    }
+
+    pub fn get_uv(&self) -> na::Vector2<f32> {
+        Self::unpack_uv(self.uv)
+    }
+}
+
+impl From<&Vertex> for PackedVertex {
+    fn from(vertex: &Vertex) -> Self {
+        Self {
+            pos: vertex.pos,
+            color: vertex.color,
+            normal: Self::pack_normal(&vertex.normal),
+            uv: Self::pack_uv(&vertex.uv),
+            tangent: Self::pack_tangent(&vertex.tangent),
+        }
+    }
+}
+
+impl VertexInput for PackedVertex {
+    fn get_pipeline() -> Pipelines {
+        Pipelines::PACKED
+    }
+
+    fn get_position(&self) -> Option<na::Vector3<f32>> {
+        Some(self.pos)
+    }
+
+    fn get_bindings() -> Vec<vk::VertexInputBindingDescription> {
+        let mut bindings = Vertex::get_bindings();
+        bindings[0].stride = std::mem::size_of::<PackedVertex>() as u32;
+        bindings
+    }
+
+    fn get_attributes() -> Vec<vk::VertexInputAttributeDescription> {
+        let mut attributes = vec![
+            // position
+            vk::VertexInputAttributeDescription::builder()
+                .binding(0)
+                .location(0)
+                .format(vk::Format::R32G32B32_SFLOAT)
+                .offset(offset_of!(PackedVertex, pos) as u32)
+                .build(),
+            // color
+            vk::VertexInputAttributeDescription::builder()
+                .binding(0)
+                .location(1)
+                .format(vk::Format::R32G32B32A32_SFLOAT)
+                .offset(offset_of!(PackedVertex, color) as u32)
+                .build(),
+            // normal
+            vk::VertexInputAttributeDescription::builder()
+                .binding(0)
+                .location(2)
+                .format(Self::NORMAL_FORMAT)
+                .offset(offset_of!(PackedVertex, normal) as u32)
+                .build(),
+            // texture coordinates
+            vk::VertexInputAttributeDescription::builder()
+                .binding(0)
+                .location(3)
+                .format(Self::UV_FORMAT)
+                .offset(offset_of!(PackedVertex, uv) as u32)
+                .build(),
+            Vertex::get_uv1_attribute(),
+        ];
+        attributes.extend(MorphDeltas::get_attributes(1, 5));
+        // tangent
+        attributes.push(
+            vk::VertexInputAttributeDescription::builder()
+                .binding(0)
+                .location(Vertex::TANGENT_LOCATION)
+                .format(Self::NORMAL_FORMAT)
+                .offset(offset_of!(PackedVertex, tangent) as u32)
+                .build(),
+        );
+        attributes
+    }
+
+    fn get_set_layouts(device: &Device) -> Vec<vk::DescriptorSetLayout> {
+        Vertex::get_set_layouts(device)
+    }
+
+    fn write_set_morph_weights(
+        writer: &mut DescriptorWriter,
+        set: vk::DescriptorSet,
+        weights: &Buffer,
+        offset: vk::DeviceSize,
+    ) {
+        Vertex::write_set_morph_weights(writer, set, weights, offset)
     }
 }
 
@@ -529,21 +1000,37 @@ impl Trs {
             .append_nonuniform_scaling(&self.scale)
     }
 
+    /// Returns the matrix transforming world space into the space of a camera with this transform.
+    /// Scale is ignored, as cameras are not supposed to be scaled.
     pub fn get_view_matrix(&self) -> na::Matrix4<f32> {
-        let mut matrix = self.get_matrix();
+        self.model.inverse().to_homogeneous()
+    }
 
-        // Invert translation
-        matrix.m14 = -matrix.m14;
-        matrix.m24 = -matrix.m24;
-        matrix.m34 = -matrix.m34;
+    /// Places this transform at `eye`, oriented so that its forward direction points at `target`.
+    /// Cameras follow the right-handed convention where they look towards -Z with +Y up,
+    /// hence this is the inverse of the view built by `Isometry3::look_at_rh`.
+    pub fn look_at(
+        &mut self,
+        eye: &na::Point3<f32>,
+        target: &na::Point3<f32>,
+        up: &na::Vector3<f32>,
+    ) {
+        self.model = na::Isometry3::look_at_rh(eye, target, up).inverse();
+    }
 
-        matrix
+    /// Returns the direction a camera with this transform is looking towards
+    pub fn get_forward(&self) -> na::Vector3<f32> {
+        self.model.rotation * -na::Vector3::z()
     }
 
     pub fn get_translation(&self) -> na::Vector3<f32> {
         self.model.translation.vector
     }
 
+    pub fn set_translation(&mut self, trs: &na::Vector3<f32>) {
+        self.model.translation.vector = *trs;
+    }
+
     pub fn get_rotation(&self) -> na::UnitQuaternion<f32> {
         self.model.rotation
     }
@@ -575,24 +1062,68 @@ pub enum CameraType {
 
 pub struct Camera {
     typ: CameraType,
+    /// Vertical field of view in radians, and near and far planes of perspective projections,
+    /// so that they are kept when the aspect changes
+    yfov: f32,
+    znear: f32,
+    zfar: f32,
+    /// Depth convention of the projection, standard by default
+    depth: DepthConfig,
     pub proj: na::Matrix4<f32>,
 }
 
 impl Camera {
-    fn perspective_matrix(aspect: f32) -> na::Matrix4<f32> {
-        let fovy = 3.14 / 4.0;
-        let znear = 0.1;
-        let zfar = 100.0;
-        na::Perspective3::new(aspect, fovy, znear, zfar).to_homogeneous()
+    pub const DEFAULT_YFOV: f32 = 3.14 / 4.0;
+    pub const DEFAULT_ZNEAR: f32 = 0.1;
+    pub const DEFAULT_ZFAR: f32 = 100.0;
+
+    /// Returns a perspective projection mapping the near plane to 0 and the far plane to 1
+    /// with standard `depth`, or the near plane to 1 and the far plane to 0 with reverse-Z
+    fn perspective_matrix_with_range(
+        aspect: f32,
+        yfov: f32,
+        znear: f32,
+        zfar: f32,
+        depth: DepthConfig,
+    ) -> na::Matrix4<f32> {
+        // Nalgebra follows the OpenGL convention, from -1 to 1
+        let to_zero_one = na::Matrix4::new(
+            1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.5, 0.5, 0.0, 0.0, 0.0, 1.0,
+        );
+        depth.get_projection(
+            &(to_zero_one * na::Perspective3::new(aspect, yfov, znear, zfar).to_homogeneous()),
+        )
     }
 
     pub fn perspective(aspect: f32) -> Self {
+        Self::perspective_with_range(
+            aspect,
+            Self::DEFAULT_YFOV,
+            Self::DEFAULT_ZNEAR,
+            Self::DEFAULT_ZFAR,
+        )
+    }
+
+    /// Returns a perspective camera with a vertical field of view of `yfov` radians
+    pub fn perspective_with_range(aspect: f32, yfov: f32, znear: f32, zfar: f32) -> Self {
         Self {
             typ: CameraType::PERSPECTIVE,
-            proj: Camera::perspective_matrix(aspect),
+            yfov,
+            znear,
+            zfar,
+            depth: DepthConfig::default(),
+            proj: Camera::perspective_matrix_with_range(
+                aspect,
+                yfov,
+                znear,
+                zfar,
+                DepthConfig::default(),
+            ),
         }
     }
 
+    /// Returns an orthographic projection mapping the near plane to 0 and the far plane to 1
+    /// with standard `depth`, or the near plane to 1 and the far plane to 0 with reverse-Z
     fn orthographic_matrix(
         left: f32,
         right: f32,
@@ -600,6 +1131,7 @@ impl Camera {
         top: f32,
         near: f32,
         far: f32,
+        depth: DepthConfig,
     ) -> na::Matrix4<f32> {
         let mid = na::Vector3::new(
             (left + right) / (right - left),
@@ -613,28 +1145,137 @@ impl Camera {
             1.0 / (near - far),
         );
 
-        na::Matrix4::new(
+        depth.get_projection(&na::Matrix4::new(
             scale.x, 0.0, 0.0, mid.x, 0.0, -scale.y, 0.0, mid.y, 0.0, 0.0, scale.z, mid.z, 0.0,
             0.0, 0.0, 1.0,
-        )
+        ))
     }
 
     /// Parameters here are referred to the camera, where towards direction is positive.
     pub fn orthographic(left: f32, right: f32, bottom: f32, top: f32, near: f32, far: f32) -> Self {
         Self {
             typ: CameraType::ORTHOGRAPHIC,
-            proj: Camera::orthographic_matrix(left, right, bottom, top, near, far),
+            yfov: Self::DEFAULT_YFOV,
+            znear: near,
+            zfar: far,
+            depth: DepthConfig::default(),
+            proj: Camera::orthographic_matrix(
+                left,
+                right,
+                bottom,
+                top,
+                near,
+                far,
+                DepthConfig::default(),
+            ),
+        }
+    }
+
+    pub fn get_depth_config(&self) -> DepthConfig {
+        self.depth
+    }
+
+    /// Makes the projection of this camera, and the ones computed when it is framed or resized,
+    /// follow the `depth` convention
+    pub fn set_depth_config(&mut self, depth: DepthConfig) {
+        self.proj = self.get_proj_for(depth);
+        self.depth = depth;
+    }
+
+    /// Returns the projection of this camera with the `depth` convention, such as the one
+    /// of the frame drawing with it
+    pub fn get_proj_for(&self, depth: DepthConfig) -> na::Matrix4<f32> {
+        if depth == self.depth {
+            self.proj
+        } else {
+            // Reversing is its own inverse
+            DepthConfig::reverse_z().get_projection(&self.proj)
         }
     }
 
+    /// Returns the distance of the near plane from the camera
+    pub fn get_znear(&self) -> f32 {
+        self.znear
+    }
+
+    /// Returns the distance of the far plane from the camera
+    pub fn get_zfar(&self) -> f32 {
+        self.zfar
+    }
+
+    /// Returns the depth range of the projection matrix of this camera. Both perspective and
+    /// orthographic projections map the near plane to 0 and the far plane to 1 by default,
+    /// or the near plane to 1 and the far plane to 0 with reverse-Z, see `set_depth_config`.
+    pub fn get_depth_range(&self) -> DepthRange {
+        self.depth.get_depth_range()
+    }
+
+    /// Returns the frustum of this camera given its view matrix
+    pub fn get_frustum(&self, view: &na::Matrix4<f32>) -> Frustum {
+        Frustum::from_matrix(&(self.proj * view), self.get_depth_range())
+    }
+
+    /// Sizes the projection of this camera to fit `aabb`, returning the transform
+    /// of a camera node looking at it along the negative z axis
+    pub fn frame_aabb(&mut self, aabb: &Aabb) -> Trs {
+        let center = aabb.get_center();
+        let radius = ((aabb.max - aabb.min).norm() / 2.0).max(std::f32::EPSILON);
+
+        let distance = match self.typ {
+            CameraType::PERSPECTIVE => {
+                // The narrowest field of view decides how far the camera should be
+                let tan_half_x = 1.0 / self.proj.m11;
+                let tan_half_y = 1.0 / self.proj.m22;
+                let aspect = tan_half_x / tan_half_y;
+                let half_fov = tan_half_x.min(tan_half_y).atan();
+                let distance = radius / half_fov.sin();
+                self.znear = (distance - radius).max(0.01);
+                self.zfar = distance + radius;
+                self.proj = Camera::perspective_matrix_with_range(
+                    aspect, self.yfov, self.znear, self.zfar, self.depth,
+                );
+                distance
+            }
+            CameraType::ORTHOGRAPHIC => {
+                let aspect = self.proj.m22.abs() / self.proj.m11;
+                let (half_width, half_height) = if aspect >= 1.0 {
+                    (radius * aspect, radius)
+                } else {
+                    (radius, radius / aspect)
+                };
+                let distance = radius + 0.1;
+                self.proj = Camera::orthographic_matrix(
+                    -half_width,
+                    half_width,
+                    -half_height,
+                    half_height,
+                    0.1,
+                    distance + radius,
+                    self.depth,
+                );
+                distance
+            }
+        };
+
+        let mut trs = Trs::new();
+        trs.set_translation(&(center + na::Vector3::new(0.0, 0.0, distance)));
+        trs
+    }
+
     pub fn update(&mut self, win: &Win) {
         let (width, height) = win.window.drawable_size();
-        let aspect = width as f32 / height as f32;
+        self.update_aspect(width as f32 / height as f32);
+    }
+
+    /// Recomputes the projection for a viewport with `aspect` ratio
+    pub fn update_aspect(&mut self, aspect: f32) {
         self.proj = match self.typ {
             CameraType::ORTHOGRAPHIC => {
-                Camera::orthographic_matrix(-aspect, aspect, -1.0, 1.0, 0.1, 1.0)
+                Camera::orthographic_matrix(-aspect, aspect, -1.0, 1.0, 0.1, 1.0, self.depth)
             }
-            CameraType::PERSPECTIVE => Camera::perspective_matrix(aspect),
+            CameraType::PERSPECTIVE => Camera::perspective_matrix_with_range(
+                aspect, self.yfov, self.znear, self.zfar, self.depth,
+            ),
         };
     }
 
@@ -656,46 +1297,12 @@ impl Camera {
         vec![view, proj]
     }
 
-    pub fn write_set_view(device: &Device, set: vk::DescriptorSet, view: &Buffer) {
-        let buffer_info = vk::DescriptorBufferInfo::builder()
-            .range(std::mem::size_of::<na::Matrix4<f32>>() as vk::DeviceSize)
-            .buffer(view.buffer)
-            .build();
-
-        let buffer_write = vk::WriteDescriptorSet::builder()
-            .dst_set(set)
-            .dst_binding(0)
-            .dst_array_element(0)
-            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-            .buffer_info(&[buffer_info])
-            .build();
-
-        let writes = vec![buffer_write];
-
-        unsafe {
-            device.update_descriptor_sets(&writes, &[]);
-        }
+    pub fn write_set_view(writer: &mut DescriptorWriter, set: vk::DescriptorSet, view: &Buffer) {
+        writer.write_uniform::<na::Matrix4<f32>>(set, 0, view);
     }
 
-    pub fn write_set_proj(device: &Device, set: vk::DescriptorSet, proj: &Buffer) {
-        let buffer_info = vk::DescriptorBufferInfo::builder()
-            .range(std::mem::size_of::<na::Matrix4<f32>>() as vk::DeviceSize)
-            .buffer(proj.buffer)
-            .build();
-
-        let buffer_write = vk::WriteDescriptorSet::builder()
-            .dst_set(set)
-            .dst_binding(1)
-            .dst_array_element(0)
-            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-            .buffer_info(&[buffer_info])
-            .build();
-
-        let writes = vec![buffer_write];
-
-        unsafe {
-            device.update_descriptor_sets(&writes, &[]);
-        }
+    pub fn write_set_proj(writer: &mut DescriptorWriter, set: vk::DescriptorSet, proj: &Buffer) {
+        writer.write_uniform::<na::Matrix4<f32>>(set, 1, proj);
     }
 }
 
@@ -730,6 +1337,11 @@ pub struct Node {
     pub camera: Handle<Camera>,
     pub mesh: Handle<Mesh>,
     pub script: Handle<Script>,
+    pub light: Handle<Light>,
+    /// Weights of the morph targets of this node's mesh primitives
+    pub morph_weights: MorphWeights,
+    /// Invisible nodes are not drawn, neither are their children
+    pub visible: bool,
 }
 
 impl Node {
@@ -740,8 +1352,15 @@ impl Node {
             camera: Handle::none(),
             mesh: Handle::none(),
             script: Handle::none(),
+            light: Handle::none(),
+            morph_weights: MorphWeights::default(),
+            visible: true,
         }
     }
+
+    pub fn set_visible(&mut self, visible: bool) {
+        self.visible = visible;
+    }
 }
 
 pub struct Model {
@@ -755,6 +1374,8 @@ pub struct Model {
     pub primitives: Pack<Primitive>,
     pub meshes: Pack<Mesh>,
     pub scripts: Pack<Script>,
+    pub clips: Pack<Clip>,
+    pub lights: Pack<Light>,
 }
 
 impl Model {
@@ -770,6 +1391,574 @@ impl Model {
             primitives: Pack::new(),
             meshes: Pack::new(),
             scripts: Pack::new(),
+            clips: Pack::new(),
+            lights: Pack::new(),
+        }
+    }
+
+    /// Returns the nodes of the subtree rooted at `node` which should be drawn.
+    /// The subtree of an invisible node is skipped entirely.
+    pub fn get_visible_nodes(&self, node: Handle<Node>) -> Vec<Handle<Node>> {
+        let mut ret = vec![];
+        self.collect_visible_nodes(node, &mut ret);
+        ret
+    }
+
+    /// Returns the world-space bounds of the visible nodes of this model
+    pub fn world_aabb(&self) -> Aabb {
+        self.get_world_aabb_with(|node| match self.meshes.try_get(node.mesh) {
+            Some(mesh) => mesh
+                .primitives
+                .iter()
+                .filter_map(|primitive| self.primitives.try_get(*primitive))
+                .fold(Aabb::empty(), |aabb, primitive| aabb.union(&primitive.aabb)),
+            None => Aabb::empty(),
+        })
+    }
+
+    /// Returns the nodes which are not children of any other node
+    pub fn get_roots(&self) -> Vec<Handle<Node>> {
+        let children: HashSet<Handle<Node>> = self
+            .nodes
+            .iter()
+            .flat_map(|(_, node)| node.children.iter().copied())
+            .collect();
+
+        self.nodes
+            .get_handles()
+            .into_iter()
+            .filter(|node| !children.contains(node))
+            .collect()
+    }
+
+    /// Unions the local bounds of each visible node transformed to world space
+    fn get_world_aabb_with<F: Fn(&Node) -> Aabb>(&self, get_local_aabb: F) -> Aabb {
+        let mut aabb = Aabb::empty();
+        for root in self.get_roots() {
+            self.collect_world_aabb(root, &na::Matrix4::identity(), &get_local_aabb, &mut aabb);
+        }
+        aabb
+    }
+
+    /// Returns what is needed to select the pipeline of `primitive`
+    pub fn get_primitive_info(&self, primitive: Handle<Primitive>) -> Option<PrimitiveInfo> {
+        let primitive = self.primitives.try_get(primitive)?;
+        let blend_mode = self
+            .materials
+            .get(primitive.material)
+            .map(|material| material.blend_mode)
+            .unwrap_or_default();
+        Some(PrimitiveInfo {
+            pipeline: primitive.pipeline,
+            topology: primitive.topology,
+            blend_mode,
+        })
+    }
+
+    /// Returns the draws of the primitives of all the visible nodes, see `get_draws_with`
+    pub fn get_draws(&self) -> Vec<ModelDraw> {
+        self.get_draws_with(|primitive| self.get_primitive_info(primitive))
+    }
+
+    /// Returns the draws of the primitives of all the visible nodes, with the pipeline selected
+    /// from the info returned by `get_info`. Draws are sorted by pipeline, so that blended
+    /// primitives are drawn after opaque ones.
+    pub fn get_draws_with<F>(&self, get_info: F) -> Vec<ModelDraw>
+    where
+        F: Fn(Handle<Primitive>) -> Option<PrimitiveInfo>,
+    {
+        let mut draws = vec![];
+        for root in self.get_roots() {
+            for node in self.get_visible_nodes(root) {
+                let mesh = match self
+                    .nodes
+                    .try_get(node)
+                    .and_then(|n| self.meshes.try_get(n.mesh))
+                {
+                    Some(mesh) => mesh,
+                    None => continue,
+                };
+                for primitive in &mesh.primitives {
+                    if let Some(info) = get_info(*primitive) {
+                        draws.push(ModelDraw {
+                            node,
+                            primitive: *primitive,
+                            pipeline: info.pipeline.select(info.topology, info.blend_mode),
+                        });
+                    }
+                }
+            }
+        }
+        // Stable, so draws of the same pipeline keep the scene order
+        draws.sort_by_key(|draw| draw.pipeline.ordinal());
+        draws
+    }
+
+    fn collect_world_aabb<F: Fn(&Node) -> Aabb>(
+        &self,
+        node: Handle<Node>,
+        parent: &na::Matrix4<f32>,
+        get_local_aabb: &F,
+        aabb: &mut Aabb,
+    ) {
+        let cnode = match self.nodes.try_get(node) {
+            Some(cnode) if cnode.visible => cnode,
+            _ => return,
+        };
+        let world = parent * cnode.trs.get_matrix();
+        *aabb = aabb.union(&get_local_aabb(cnode).transform(&world));
+        for child in &cnode.children {
+            self.collect_world_aabb(*child, &world, get_local_aabb, aabb);
+        }
+    }
+
+    fn collect_visible_nodes(&self, node: Handle<Node>, ret: &mut Vec<Handle<Node>>) {
+        if let Some(cnode) = self.nodes.try_get(node) {
+            if !cnode.visible {
+                return;
+            }
+            for child in &cnode.children {
+                self.collect_visible_nodes(*child, ret);
+            }
+            ret.push(node);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Returns the signed area of a triangle as seen on screen, positive when counter-clockwise
+    fn screen_area(triangle: &[PresentVertex; 3], clip_y: ClipY) -> f32 {
+        let screen = |v: &PresentVertex| match clip_y {
+            ClipY::Down => na::Vector2::new(v.pos.x, -v.pos.y),
+            ClipY::Up => v.pos,
+        };
+        let (a, b, c) = (
+            screen(&triangle[0]),
+            screen(&triangle[1]),
+            screen(&triangle[2]),
+        );
+        (b - a).perp(&(c - a))
+    }
+
+    #[test]
+    fn model_draws() {
+        let mut model = Model::new();
+
+        // Handles of primitives which are not uploaded
+        let triangles = Handle::new(0, 0);
+        let lines = Handle::new(1, 0);
+        let blended = Handle::new(2, 0);
+        let get_info = |primitive: Handle<Primitive>| {
+            let (pipeline, blend_mode) = if primitive == lines {
+                (Pipelines::LINE, BlendMode::Opaque)
+            } else if primitive == blended {
+                (Pipelines::MAIN, BlendMode::Blend)
+            } else {
+                (Pipelines::MAIN, BlendMode::Opaque)
+            };
+            Some(PrimitiveInfo {
+                pipeline,
+                topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+                blend_mode,
+            })
+        };
+
+        let mut first = Node::new();
+        first.mesh = model.meshes.push(Mesh::new(vec![blended, triangles]));
+        let first = model.nodes.push(first);
+        let mut second = Node::new();
+        second.mesh = model.meshes.push(Mesh::new(vec![lines]));
+        let second = model.nodes.push(second);
+
+        let draws = model.get_draws_with(get_info);
+        let pipelines: Vec<Pipelines> = draws.iter().map(|draw| draw.pipeline).collect();
+        assert_eq!(
+            pipelines,
+            vec![Pipelines::LINE, Pipelines::MAIN, Pipelines::BLEND]
+        );
+        assert!(draws[0].node == second);
+        assert!(draws[1].node == first);
+        assert!(draws[1].primitive == triangles);
+
+        // Invisible nodes are not drawn
+        model.nodes.get_mut(first).unwrap().visible = false;
+        assert_eq!(model.get_draws_with(get_info).len(), 1);
+    }
+
+    #[test]
+    fn normal_matrix() {
+        let mut trs = Trs::new();
+        trs.set_translation(&na::Vector3::new(4.0, 5.0, 6.0));
+        trs.scale(&na::Vector3::new(2.0, 1.0, 1.0));
+        let normal_matrix = NormalMatrix::try_new(&trs.get_matrix()).unwrap();
+        assert_eq!(std::mem::size_of::<NormalMatrix>(), 48);
+
+        // Translation does not move normals
+        let normal = normal_matrix.get_matrix() * na::Vector3::new(0.0, 1.0, 0.0);
+        assert_eq!(normal, na::Vector3::new(0.0, 1.0, 0.0));
+
+        // A slope stretched along x gets flatter, so its normal leans towards y
+        let tangent = trs
+            .get_matrix()
+            .transform_vector(&na::Vector3::new(1.0, 1.0, 0.0));
+        let normal = normal_matrix.get_matrix() * na::Vector3::new(1.0, -1.0, 0.0);
+        assert!(normal.dot(&tangent).abs() < 1e-6);
+        assert!(normal.x.abs() < normal.y.abs());
+
+        // Zero scale has no inverse
+        trs.scale(&na::Vector3::new(0.0, 1.0, 1.0));
+        assert!(NormalMatrix::try_new(&trs.get_matrix()).is_none());
+        assert_eq!(
+            NormalMatrix::identity().get_matrix(),
+            na::Matrix3::identity()
+        );
+    }
+
+    #[test]
+    fn second_uv_set() {
+        let mut material = Material::textured(Handle::none());
+        assert_eq!(material.get_uniform().albedo_uv, 0);
+        material.albedo_uv = 1;
+        let uniform = material.get_uniform();
+        assert_eq!(uniform.albedo_uv, 1);
+        assert_eq!(uniform.color, Color::white());
+        // The selector follows the color in the uniform block
+        assert_eq!(offset_of!(MaterialUniform, albedo_uv), 16);
+        assert_eq!(offset_of!(MaterialUniform, albedo_layer), 20);
+
+        let attributes = Vertex::get_attributes();
+        let uv1 = attributes.iter().find(|a| a.location == 4).unwrap();
+        assert_eq!(uv1.binding, Vertex::UV1_BINDING);
+        assert_eq!(uv1.format, vk::Format::R32G32_SFLOAT);
+        assert_eq!(uv1.offset, 0);
+
+        // It comes from its own stream, hence vertices do not grow
+        let uv1_binding = Vertex::get_bindings()[Vertex::UV1_BINDING as usize];
+        assert_eq!(uv1_binding.binding, Vertex::UV1_BINDING);
+        assert_eq!(uv1_binding.stride, 8);
+        assert_eq!(std::mem::size_of::<Vertex>(), 64);
+        let packed_uv1 = PackedVertex::get_attributes()
+            .into_iter()
+            .find(|a| a.location == 4)
+            .unwrap();
+        assert_eq!(packed_uv1.binding, Vertex::UV1_BINDING);
+        assert_eq!(packed_uv1.format, uv1.format);
+
+        // Morph deltas follow the second texture coordinates
+        let first_delta = attributes.iter().find(|a| a.binding == 1).unwrap();
+        assert_eq!(first_delta.location, 5);
+
+        // And the tangent follows the deltas
+        let last_delta = attributes.iter().filter(|a| a.binding == 1).last().unwrap();
+        let tangent = attributes
+            .iter()
+            .find(|a| a.location == Vertex::TANGENT_LOCATION)
+            .unwrap();
+        assert_eq!(tangent.location, last_delta.location + 1);
+        assert_eq!(tangent.binding, 0);
+        assert_eq!(tangent.format, vk::Format::R32G32B32A32_SFLOAT);
+        assert_eq!(tangent.offset, offset_of!(Vertex, tangent) as u32);
+    }
+
+    #[test]
+    fn fullscreen_triangle_uv() {
+        for &clip_y in [ClipY::Down, ClipY::Up].iter() {
+            let triangle = PresentVertex::fullscreen_triangle(clip_y);
+            assert!(screen_area(&triangle, clip_y) > 0.0);
+
+            // Top-left corner of the screen samples the top-left corner of the image
+            let top = match clip_y {
+                ClipY::Down => -1.0,
+                ClipY::Up => 1.0,
+            };
+            let top_left = PresentVertex::with_convention(-1.0, top, clip_y);
+            assert_eq!(top_left.uv, na::Vector2::new(0.0, 0.0));
+            let bottom_right = PresentVertex::with_convention(1.0, -top, clip_y);
+            assert_eq!(bottom_right.uv, na::Vector2::new(1.0, 1.0));
+
+            // UVs are linear in position, so the triangle interpolates them correctly
+            for v in triangle.iter() {
+                let expected = PresentVertex::with_convention(v.pos.x, v.pos.y, clip_y);
+                assert_eq!(v.uv, expected.uv);
+            }
         }
     }
+
+    #[test]
+    fn uv_origin_flip_once() {
+        // Vulkan coordinates are left as they are
+        let uv = na::Vector2::new(0.25, 0.125);
+        assert_eq!(UvOrigin::TopLeft.to_top_left(uv), uv);
+
+        // OpenGL coordinates are flipped vertically, once
+        let bottom_left = UvOrigin::BottomLeft;
+        assert_eq!(bottom_left.to_top_left(uv), na::Vector2::new(0.25, 0.875));
+        assert_eq!(
+            bottom_left.to_top_left(na::Vector2::new(0.0, 1.0)),
+            na::Vector2::new(0.0, 0.0)
+        );
+        assert_eq!(
+            bottom_left.to_top_left(na::Vector2::new(1.0, 0.0)),
+            na::Vector2::new(1.0, 1.0)
+        );
+
+        // Presentation does not flip again, the top of the screen samples the top of the G-buffer
+        let present = PresentVertex::with_convention(-1.0, -1.0, ClipY::Down);
+        assert_eq!(present.uv, na::Vector2::new(0.0, 0.0));
+        let present = PresentVertex::with_convention(-1.0, 1.0, ClipY::Up);
+        assert_eq!(present.uv, na::Vector2::new(0.0, 0.0));
+        let present = PresentVertex::with_convention(1.0, 1.0, ClipY::Down);
+        assert_eq!(present.uv, na::Vector2::new(1.0, 1.0));
+    }
+
+    #[test]
+    fn world_aabb_union() {
+        let mut model = Model::new();
+        let mut left = Node::new();
+        left.trs.translate(&na::Vector3::new(-2.0, 0.0, 0.0));
+        let left = model.nodes.push(left);
+        let mut right = Node::new();
+        right.trs.translate(&na::Vector3::new(3.0, 1.0, 0.0));
+        let right = model.nodes.push(right);
+        let mut root = Node::new();
+        root.children.push(left);
+        root.children.push(right);
+        model.nodes.push(root);
+
+        // Unit box around each node, while the root has no bounds
+        let unit = Aabb::new(na::Vector3::repeat(-0.5), na::Vector3::repeat(0.5));
+        let aabb = model.get_world_aabb_with(|node| match node.children.is_empty() {
+            true => unit,
+            false => Aabb::empty(),
+        });
+        assert_eq!(aabb.min, na::Vector3::new(-2.5, -0.5, -0.5));
+        assert_eq!(aabb.max, na::Vector3::new(3.5, 1.5, 0.5));
+
+        // Invisible nodes do not contribute
+        model.nodes.get_mut(right).unwrap().set_visible(false);
+        let aabb = model.get_world_aabb_with(|_| unit);
+        assert_eq!(aabb.max, na::Vector3::new(-1.5, 0.5, 0.5));
+    }
+
+    #[test]
+    fn frame_aabb_contains_box() {
+        let aabb = Aabb::new(
+            na::Vector3::new(-3.0, 1.0, -2.0),
+            na::Vector3::new(5.0, 4.0, 7.0),
+        );
+        let cameras = vec![
+            Camera::perspective(16.0 / 9.0),
+            Camera::perspective(9.0 / 16.0),
+            Camera::orthographic(-1.0, 1.0, -1.0, 1.0, 0.1, 1.0),
+        ];
+        for mut camera in cameras {
+            let trs = camera.frame_aabb(&aabb);
+            let frustum = camera.get_frustum(&trs.get_view_matrix());
+            for corner in aabb.get_corners().iter() {
+                assert!(frustum.contains_point(&na::Point3::from(*corner)));
+            }
+        }
+    }
+
+    #[test]
+    fn look_at_origin() {
+        let mut trs = Trs::new();
+        let eye = na::Point3::new(0.0, 0.0, 5.0);
+        trs.look_at(&eye, &na::Point3::origin(), &na::Vector3::y());
+
+        assert!((trs.get_forward() - na::Vector3::new(0.0, 0.0, -1.0)).norm() < 1e-5);
+        assert!((trs.get_translation() - eye.coords).norm() < 1e-5);
+
+        // Target ends up in front of the camera, and eye at the origin of view space
+        let view = trs.get_view_matrix();
+        let target = view.transform_point(&na::Point3::origin());
+        assert!((target - na::Point3::new(0.0, 0.0, -5.0)).norm() < 1e-5);
+        assert!(view.transform_point(&eye).coords.norm() < 1e-5);
+
+        // Looking from the side
+        trs.look_at(
+            &na::Point3::new(3.0, 0.0, 0.0),
+            &na::Point3::origin(),
+            &na::Vector3::y(),
+        );
+        assert!((trs.get_forward() - na::Vector3::new(-1.0, 0.0, 0.0)).norm() < 1e-5);
+    }
+
+    #[test]
+    fn invisible_subtree() {
+        let mut model = Model::new();
+        let child = model.nodes.push(Node::new());
+        let mut parent = Node::new();
+        parent.children.push(child);
+        let parent = model.nodes.push(parent);
+        let mut root = Node::new();
+        root.children.push(parent);
+        let root = model.nodes.push(root);
+
+        assert!(model.get_visible_nodes(root) == vec![child, parent, root]);
+
+        model.nodes.get_mut(parent).unwrap().set_visible(false);
+        assert!(model.get_visible_nodes(root) == vec![root]);
+
+        model.nodes.get_mut(parent).unwrap().set_visible(true);
+        assert!(model.get_visible_nodes(root) == vec![child, parent, root]);
+    }
+
+    #[test]
+    fn removed_node_skipped() {
+        let mut model = Model::new();
+        let child = model.nodes.push(Node::new());
+        let mut root = Node::new();
+        root.children.push(child);
+        let root = model.nodes.push(root);
+
+        // Root still refers to its removed child
+        model.nodes.remove(child);
+        assert!(model.get_visible_nodes(root) == vec![root]);
+        assert!(model.get_visible_nodes(child).is_empty());
+    }
+
+    #[test]
+    fn packed_vertex() {
+        let bindings = PackedVertex::get_bindings();
+        assert_eq!(bindings[0].stride, 40);
+        assert!(bindings[0].stride < Vertex::get_bindings()[0].stride);
+
+        let attributes = PackedVertex::get_attributes();
+        let normal = attributes.iter().find(|a| a.location == 2).unwrap();
+        assert_eq!(normal.format, vk::Format::A2B10G10R10_SNORM_PACK32);
+        assert_eq!(normal.offset, 28);
+        let uv = attributes.iter().find(|a| a.location == 3).unwrap();
+        assert_eq!(uv.format, vk::Format::R16G16_SFLOAT);
+        assert_eq!(uv.offset, 32);
+
+        let mut vertex = Vertex::new(1.0, 2.0, 3.0);
+        vertex.normal = na::Vector3::new(1.0, -2.0, 3.0).normalize();
+        vertex.uv = na::Vector2::new(0.25, 0.7);
+        let packed = PackedVertex::from(&vertex);
+
+        assert_eq!(packed.pos, vertex.pos);
+        assert!((packed.get_normal() - vertex.normal).amax() <= 1.0 / 511.0);
+        assert!((packed.get_uv() - vertex.uv).amax() <= 1e-3);
+        let tangent = PackedVertex::unpack_tangent(packed.tangent);
+        assert!((tangent - vertex.tangent).amax() <= 1.0 / 511.0);
+
+        let flipped = na::Vector4::new(0.0, 1.0, 0.0, -1.0);
+        let unpacked = PackedVertex::unpack_tangent(PackedVertex::pack_tangent(&flipped));
+        assert_eq!(unpacked, flipped);
+
+        // Extremes
+        let extremes = na::Vector3::new(-1.0, 1.0, 0.0);
+        let unpacked = PackedVertex::unpack_normal(PackedVertex::pack_normal(&extremes));
+        assert_eq!(unpacked, extremes);
+    }
+
+    #[test]
+    fn material_layers() {
+        let mut material = Material::textured(Handle::none());
+        assert_eq!(material.get_albedo_layers(), 0..1);
+        assert_eq!(material.get_uniform().albedo_layer, 0);
+
+        // Splat layers [2, 4) of a texture array
+        material.base_array_layer = 2;
+        material.layer_count = 2;
+        assert_eq!(material.get_albedo_layers(), 2..4);
+        let view_range = ImageView::get_layers_range(vk::Format::R8G8B8A8_SRGB, 2..4);
+        assert_eq!(view_range.base_array_layer, 2);
+        assert_eq!(view_range.layer_count, 2);
+
+        // The base layer is relative to the view the shader samples
+        assert_eq!(material.get_uniform_for(&view_range).albedo_layer, 0);
+        let whole = ImageView::get_layers_range(vk::Format::R8G8B8A8_SRGB, 0..8);
+        assert_eq!(material.get_uniform_for(&whole).albedo_layer, 2);
+        assert_eq!(material.get_uniform().albedo_layer, 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn material_layers_out_of_view() {
+        let mut material = Material::textured(Handle::none());
+        material.base_array_layer = 3;
+        let view_range = ImageView::get_layers_range(vk::Format::R8G8B8A8_SRGB, 0..2);
+        material.get_uniform_for(&view_range);
+    }
+
+    #[test]
+    fn perspective_depth() {
+        let camera = Camera::perspective(1.0);
+        assert_eq!(camera.get_depth_range(), DepthRange::ZeroToOne);
+
+        // Near plane at 0 and far plane at 1
+        let project = |z: f32| {
+            let clip = camera.proj * na::Vector4::new(0.0, 0.0, z, 1.0);
+            clip.z / clip.w
+        };
+        assert!(project(-0.1).abs() < 1e-5);
+        assert!((project(-100.0) - 1.0).abs() < 1e-5);
+
+        let frustum = camera.get_frustum(&na::Matrix4::identity());
+        assert!(frustum.contains_point(&na::Point3::new(0.0, 0.0, -1.0)));
+        assert!(!frustum.contains_point(&na::Point3::new(0.0, 0.0, -101.0)));
+    }
+
+    #[test]
+    fn reverse_z_projections() {
+        let cameras = vec![
+            Camera::perspective_with_range(1.0, Camera::DEFAULT_YFOV, 0.1, 100.0),
+            Camera::orthographic(-1.0, 1.0, -1.0, 1.0, 0.1, 100.0),
+        ];
+        for mut camera in cameras {
+            let standard = camera.proj;
+            camera.set_depth_config(DepthConfig::reverse_z());
+            assert_eq!(camera.get_depth_range(), DepthRange::ReverseZ);
+
+            // Near plane at 1 and far plane at 0
+            let project = |proj: &na::Matrix4<f32>, z: f32| {
+                let clip = proj * na::Vector4::new(0.0, 0.0, z, 1.0);
+                clip.z / clip.w
+            };
+            assert!((project(&camera.proj, -0.1) - 1.0).abs() < 1e-5);
+            assert!(project(&camera.proj, -100.0).abs() < 1e-5);
+
+            // Frames with another convention get the matching projection
+            assert!((camera.get_proj_for(DepthConfig::standard()) - standard).norm() < 1e-5);
+            assert_eq!(camera.get_proj_for(DepthConfig::reverse_z()), camera.proj);
+
+            // Kept when resized and framed
+            camera.update_aspect(2.0);
+            assert!(project(&camera.proj, -0.1) > project(&camera.proj, -0.5));
+            let aabb = Aabb::new(na::Vector3::repeat(-1.0), na::Vector3::repeat(1.0));
+            let trs = camera.frame_aabb(&aabb);
+            let frustum = camera.get_frustum(&trs.get_view_matrix());
+            for corner in aabb.get_corners().iter() {
+                assert!(frustum.contains_point(&na::Point3::from(*corner)));
+            }
+
+            camera.set_depth_config(DepthConfig::standard());
+            assert_eq!(camera.get_depth_range(), DepthRange::ZeroToOne);
+            assert!(project(&camera.proj, -0.1) < project(&camera.proj, -0.5));
+        }
+    }
+
+    #[test]
+    fn perspective_range_kept() {
+        let yfov = 1.2;
+        let mut camera = Camera::perspective_with_range(1.0, yfov, 0.5, 20.0);
+        let focal = 1.0 / (yfov / 2.0).tan();
+        assert!((camera.proj.m22 - focal).abs() < 1e-5);
+
+        // Field of view and planes do not go back to the defaults with a new aspect
+        camera.update_aspect(2.0);
+        assert!((camera.proj.m22 - focal).abs() < 1e-5);
+        assert!((camera.proj.m11 - focal / 2.0).abs() < 1e-5);
+        let project = |z: f32| {
+            let p = camera.proj * na::Vector4::new(0.0, 0.0, z, 1.0);
+            p.z / p.w
+        };
+        assert!(project(-0.5).abs() < 1e-5);
+        assert!((project(-20.0) - 1.0).abs() < 1e-5);
+    }
 }
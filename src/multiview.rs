@@ -0,0 +1,150 @@
+// Copyright © 2021
+// Author: Antonio Caggiano <info@antoniocaggiano.eu>
+// SPDX-License-Identifier: MIT
+
+use ash::vk;
+
+use super::*;
+
+/// Maximum number of views supported by the multiview shaders
+pub const MAX_VIEWS: usize = 2;
+
+/// Multiview renders the same scene from multiple views in a single pass,
+/// such as the two eyes of a VR headset. It needs the multiview device feature.
+#[derive(Debug, Clone, Copy)]
+pub struct MultiviewConfig {
+    pub view_count: u32,
+    /// Distance between the views along the x axis of the camera, see `MultiviewMatrices::stereo`
+    pub eye_distance: f32,
+}
+
+impl MultiviewConfig {
+    /// Average distance between human eyes, in meters
+    pub const EYE_DISTANCE: f32 = 0.064;
+
+    pub fn new(view_count: u32) -> Self {
+        assert!(view_count > 0 && view_count as usize <= MAX_VIEWS);
+        Self {
+            view_count,
+            eye_distance: 0.0,
+        }
+    }
+
+    /// One view per eye
+    pub fn stereo() -> Self {
+        Self {
+            eye_distance: Self::EYE_DISTANCE,
+            ..Self::new(2)
+        }
+    }
+
+    /// Returns a mask with a bit set for each view, which renders to the array layer with the same index
+    pub fn get_view_mask(&self) -> u32 {
+        (1 << self.view_count) - 1
+    }
+
+    /// Views are spatially correlated, hence implementations can render them concurrently
+    pub fn get_correlation_mask(&self) -> u32 {
+        self.get_view_mask()
+    }
+
+    /// Returns the matrices of each view of a camera with `view` and `proj` matrices
+    pub fn get_matrices(
+        &self,
+        view: &na::Matrix4<f32>,
+        proj: &na::Matrix4<f32>,
+    ) -> MultiviewMatrices {
+        MultiviewMatrices::stereo(view, proj, self.eye_distance)
+    }
+}
+
+/// Camera matrices of each view. The vertex shader picks the ones to use by view index,
+/// therefore views and projections are uploaded as arrays to the camera bindings.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MultiviewMatrices {
+    pub views: [na::Matrix4<f32>; MAX_VIEWS],
+    pub projs: [na::Matrix4<f32>; MAX_VIEWS],
+}
+
+impl MultiviewMatrices {
+    /// Returns the matrices of the left and right eyes, in this order, for a head with `view` matrix.
+    /// Eyes are `eye_distance` apart along the x axis.
+    pub fn stereo(view: &na::Matrix4<f32>, proj: &na::Matrix4<f32>, eye_distance: f32) -> Self {
+        let half = eye_distance / 2.0;
+        // The left eye is on the negative side of the x axis, so the world moves right from its view
+        let left = na::Matrix4::new_translation(&na::Vector3::new(half, 0.0, 0.0)) * view;
+        let right = na::Matrix4::new_translation(&na::Vector3::new(-half, 0.0, 0.0)) * view;
+        Self {
+            views: [left, right],
+            projs: [*proj, *proj],
+        }
+    }
+
+    /// Writes the buffers where views and projections are uploaded to the camera bindings
+    /// of `main_multiview_vs`
+    pub fn write_set(
+        writer: &mut DescriptorWriter,
+        set: vk::DescriptorSet,
+        views: &Buffer,
+        projs: &Buffer,
+    ) {
+        writer
+            .write_uniform::<[na::Matrix4<f32>; MAX_VIEWS]>(set, 0, views)
+            .write_uniform::<[na::Matrix4<f32>; MAX_VIEWS]>(set, 1, projs);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn view_mask() {
+        assert_eq!(MultiviewConfig::new(1).get_view_mask(), 0b1);
+        assert_eq!(MultiviewConfig::stereo().get_view_mask(), 0b11);
+        assert_eq!(MultiviewConfig::stereo().get_correlation_mask(), 0b11);
+    }
+
+    #[test]
+    fn config_matrices() {
+        let view = na::Matrix4::new_translation(&na::Vector3::new(0.0, 1.0, 0.0));
+        let proj = na::Matrix4::new_scaling(2.0);
+
+        // A single view is the one of the camera
+        let matrices = MultiviewConfig::new(1).get_matrices(&view, &proj);
+        assert_eq!(matrices.views[0], view);
+        assert_eq!(matrices.projs[0], proj);
+
+        // Stereo eyes are apart by the eye distance
+        let config = MultiviewConfig::stereo();
+        let matrices = config.get_matrices(&view, &proj);
+        let offset = matrices.views[0].column(3) - matrices.views[1].column(3);
+        assert!((offset.x - config.eye_distance).abs() < 1e-6);
+    }
+
+    #[test]
+    fn stereo_order() {
+        let view = na::Matrix4::identity();
+        let proj = na::Matrix4::new_scaling(2.0);
+        let matrices = MultiviewMatrices::stereo(&view, &proj, 0.064);
+
+        // A point in front of the head appears on the right of the left eye
+        let point = na::Point3::new(0.0, 0.0, -1.0);
+        let left = matrices.views[0].transform_point(&point);
+        let right = matrices.views[1].transform_point(&point);
+        assert!((left.x - 0.032).abs() < 1e-6);
+        assert!((right.x + 0.032).abs() < 1e-6);
+        assert_eq!(matrices.projs[0], proj);
+        assert_eq!(matrices.projs[1], proj);
+
+        // Packed as left view, right view, left projection, right projection
+        assert_eq!(
+            std::mem::size_of::<MultiviewMatrices>(),
+            4 * std::mem::size_of::<na::Matrix4<f32>>()
+        );
+        let base = &matrices as *const MultiviewMatrices as usize;
+        assert_eq!(&matrices.views[1] as *const _ as usize - base, 64);
+        assert_eq!(&matrices.projs[0] as *const _ as usize - base, 128);
+    }
+}
@@ -0,0 +1,342 @@
+// Copyright © 2021
+// Author: Antonio Caggiano <info@antoniocaggiano.eu>
+// SPDX-License-Identifier: MIT
+
+use std::rc::Rc;
+
+use ash::*;
+
+use super::*;
+
+/// Attachments of weighted-blended order-independent transparency. Blended fragments are
+/// accumulated in any order, weighted by depth and alpha, then a resolve subpass composites
+/// their average color over the opaque scene, without sorting blended geometry.
+/// Both subpasses belong to the pass created by `Pass::new_oit`.
+pub struct Wboit {
+    /// Framebuffer of the pass created by `Pass::new_oit`
+    pub framebuffer: vk::Framebuffer,
+    pub accum_view: ImageView,
+    pub accum_image: Image,
+    pub revealage_view: ImageView,
+    pub revealage_image: Image,
+    pub width: u32,
+    pub height: u32,
+    device: Rc<Device>,
+}
+
+impl Wboit {
+    /// Subpass where blended primitives are accumulated with `Pipeline::weighted_blended`
+    pub const ACCUMULATION_SUBPASS: u32 = 0;
+    /// Subpass where accumulated fragments are composited with `Pipeline::wboit_resolve`
+    pub const RESOLVE_SUBPASS: u32 = 1;
+
+    /// Sum of premultiplied weighted colors, and of weighted alphas
+    pub const ACCUM_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+    /// Product of the transmittances of blended fragments
+    pub const REVEALAGE_FORMAT: vk::Format = vk::Format::R16_SFLOAT;
+
+    /// Creates accumulation and revealage attachments with the extent of `buffer`, and the
+    /// framebuffer of `pass` compositing them over the color and depth attachments of `buffer`.
+    /// Its pass should preserve depth, see `Pass::new_with_depth_read`.
+    pub fn new(dev: &Dev, pass: &Pass, buffer: &Framebuffer) -> Self {
+        let (width, height) = (buffer.width, buffer.height);
+
+        // Attachments are read as input attachments by the resolve subpass
+        let accum_image = Image::attachment(&dev.allocator, width, height, Self::ACCUM_FORMAT);
+        let accum_view = ImageView::new(&dev.device, &accum_image);
+
+        let revealage_image =
+            Image::attachment(&dev.allocator, width, height, Self::REVEALAGE_FORMAT);
+        let revealage_view = ImageView::new(&dev.device, &revealage_image);
+
+        let framebuffer = {
+            let attachments = [
+                buffer.swapchain_view,
+                buffer.depth_view.view,
+                accum_view.view,
+                revealage_view.view,
+            ];
+
+            let create_info = vk::FramebufferCreateInfo::builder()
+                .render_pass(pass.render)
+                .attachments(&attachments)
+                .width(width)
+                .height(height)
+                .layers(1)
+                .build();
+
+            unsafe { dev.device.create_framebuffer(&create_info, None) }
+                .expect("Failed to create Vulkan framebuffer")
+        };
+
+        Self {
+            framebuffer,
+            accum_view,
+            accum_image,
+            revealage_view,
+            revealage_image,
+            width,
+            height,
+            device: Rc::clone(&dev.device),
+        }
+    }
+
+    /// Returns the area of the framebuffer, which is rendered by the whole pass
+    pub fn get_area(&self) -> vk::Rect2D {
+        vk::Rect2D::builder()
+            .extent(
+                vk::Extent2D::builder()
+                    .width(self.width)
+                    .height(self.height)
+                    .build(),
+            )
+            .build()
+    }
+
+    /// Writes the attachments read by the resolve subpass into `set`
+    pub fn write_set(&self, writer: &mut DescriptorWriter, set: vk::DescriptorSet) {
+        let layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+        writer
+            .write_input_attachment(set, 0, &self.accum_view, layout)
+            .write_input_attachment(set, 1, &self.revealage_view, layout);
+    }
+
+    /// Returns the weight of a fragment at view-space `distance` from the camera with `alpha`.
+    /// Closer and more opaque fragments contribute more to the average color. This is equation 7
+    /// of McGuire and Bavoil, "Weighted Blended Order-Independent Transparency", which does not
+    /// depend on the depth convention. The fragment shader computes the same.
+    pub fn get_weight(distance: f32, alpha: f32) -> f32 {
+        let distance = distance.abs();
+        let falloff = 1e-5 + (distance / 5.0).powi(2) + (distance / 200.0).powi(6);
+        alpha * (10.0 / falloff).max(1e-2).min(3e3)
+    }
+
+    /// Accumulation starts from zero, while revealage starts from one as nothing is covered
+    pub fn get_clear_values() -> [vk::ClearValue; 2] {
+        let accum = vk::ClearValue {
+            color: vk::ClearColorValue {
+                float32: [0.0, 0.0, 0.0, 0.0],
+            },
+        };
+        let revealage = vk::ClearValue {
+            color: vk::ClearColorValue {
+                float32: [1.0, 0.0, 0.0, 0.0],
+            },
+        };
+        [accum, revealage]
+    }
+
+    /// Returns the blend states of the accumulation subpass. Weighted premultiplied colors
+    /// and weighted alphas are summed, while revealage is multiplied by `1 - alpha`.
+    pub fn get_accumulation_blend() -> Vec<vk::PipelineColorBlendAttachmentState> {
+        let accum = vk::PipelineColorBlendAttachmentState::builder()
+            .blend_enable(true)
+            .color_write_mask(vk::ColorComponentFlags::all())
+            .src_color_blend_factor(vk::BlendFactor::ONE)
+            .dst_color_blend_factor(vk::BlendFactor::ONE)
+            .color_blend_op(vk::BlendOp::ADD)
+            .src_alpha_blend_factor(vk::BlendFactor::ONE)
+            .dst_alpha_blend_factor(vk::BlendFactor::ONE)
+            .alpha_blend_op(vk::BlendOp::ADD)
+            .build();
+
+        let revealage = vk::PipelineColorBlendAttachmentState::builder()
+            .blend_enable(true)
+            .color_write_mask(vk::ColorComponentFlags::R)
+            .src_color_blend_factor(vk::BlendFactor::ZERO)
+            .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_COLOR)
+            .color_blend_op(vk::BlendOp::ADD)
+            .src_alpha_blend_factor(vk::BlendFactor::ZERO)
+            .dst_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+            .alpha_blend_op(vk::BlendOp::ADD)
+            .build();
+
+        vec![accum, revealage]
+    }
+
+    /// Returns the blend state of the resolve subpass, which outputs the average color
+    /// with an alpha of `1 - revealage`, blended over the opaque scene
+    pub fn get_resolve_blend() -> Vec<vk::PipelineColorBlendAttachmentState> {
+        vec![vk::PipelineColorBlendAttachmentState::builder()
+            .blend_enable(true)
+            .color_write_mask(
+                vk::ColorComponentFlags::R
+                    | vk::ColorComponentFlags::G
+                    | vk::ColorComponentFlags::B,
+            )
+            .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+            .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+            .color_blend_op(vk::BlendOp::ADD)
+            .src_alpha_blend_factor(vk::BlendFactor::ONE)
+            .dst_alpha_blend_factor(vk::BlendFactor::ZERO)
+            .alpha_blend_op(vk::BlendOp::ADD)
+            .build()]
+    }
+
+    /// Returns the output of the resolve fragment shader for `accum` and `revealage`
+    pub fn resolve(accum: [f32; 4], revealage: f32) -> [f32; 4] {
+        let weight = accum[3].max(1e-5);
+        [
+            accum[0] / weight,
+            accum[1] / weight,
+            accum[2] / weight,
+            1.0 - revealage,
+        ]
+    }
+}
+
+impl Drop for Wboit {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_framebuffer(self.framebuffer, None);
+        }
+    }
+}
+
+/// The resolve subpass is drawn as the fullscreen triangle, reading the accumulated fragments
+impl VertexInput for Wboit {
+    fn get_bindings() -> Vec<vk::VertexInputBindingDescription> {
+        PresentVertex::get_bindings()
+    }
+
+    fn get_attributes() -> Vec<vk::VertexInputAttributeDescription> {
+        PresentVertex::get_attributes()
+    }
+
+    fn get_set_layouts(device: &Device) -> Vec<vk::DescriptorSetLayout> {
+        // Accumulation and revealage
+        let bindings: Vec<vk::DescriptorSetLayoutBinding> = (0..2)
+            .map(|binding| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding)
+                    .descriptor_type(vk::DescriptorType::INPUT_ATTACHMENT)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                    .build()
+            })
+            .collect();
+        let set_layout = create_set_layout(device, &bindings);
+        vec![set_layout]
+    }
+
+    fn get_depth_state() -> vk::PipelineDepthStencilStateCreateInfo {
+        // The resolve subpass has no depth attachment
+        vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(false)
+            .depth_write_enable(false)
+            .build()
+    }
+
+    fn get_color_blend(_subpass: u32) -> Vec<vk::PipelineColorBlendAttachmentState> {
+        Self::get_resolve_blend()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Applies `factor` as fixed-function blending does, for the factors used by WBOIT
+    fn apply(factor: vk::BlendFactor, src: [f32; 4], value: f32) -> f32 {
+        match factor {
+            vk::BlendFactor::ZERO => 0.0,
+            vk::BlendFactor::ONE => value,
+            vk::BlendFactor::SRC_ALPHA => src[3] * value,
+            vk::BlendFactor::ONE_MINUS_SRC_ALPHA => (1.0 - src[3]) * value,
+            vk::BlendFactor::ONE_MINUS_SRC_COLOR => (1.0 - src[0]) * value,
+            _ => panic!("Blend factor {:?} is not used by WBOIT", factor),
+        }
+    }
+
+    /// Blends `src` over `dst` with `state`, for the channels it writes
+    fn blend(
+        state: &vk::PipelineColorBlendAttachmentState,
+        src: [f32; 4],
+        dst: [f32; 4],
+    ) -> [f32; 4] {
+        let mut ret = dst;
+        let masks = [
+            vk::ColorComponentFlags::R,
+            vk::ColorComponentFlags::G,
+            vk::ColorComponentFlags::B,
+            vk::ColorComponentFlags::A,
+        ];
+        for (i, &mask) in masks.iter().enumerate() {
+            if !state.color_write_mask.contains(mask) {
+                continue;
+            }
+            let (src_factor, dst_factor) = if i < 3 {
+                (state.src_color_blend_factor, state.dst_color_blend_factor)
+            } else {
+                (state.src_alpha_blend_factor, state.dst_alpha_blend_factor)
+            };
+            ret[i] = apply(src_factor, src, src[i]) + apply(dst_factor, src, dst[i]);
+        }
+        ret
+    }
+
+    #[test]
+    fn oit_weight() {
+        // Weights grow with alpha
+        assert_eq!(Wboit::get_weight(1.0, 0.0), 0.0);
+        assert!(Wboit::get_weight(10.0, 0.25) < Wboit::get_weight(10.0, 0.5));
+
+        // And towards the camera
+        let far = Wboit::get_weight(1000.0, 0.5);
+        let mid = Wboit::get_weight(10.0, 0.5);
+        let near = Wboit::get_weight(0.0, 0.5);
+        assert!(far < mid && mid < near);
+
+        // Clamped so that far fragments still contribute
+        assert_eq!(far, 0.5 * 1e-2);
+        assert_eq!(near, 0.5 * 3e3);
+    }
+
+    #[test]
+    fn oit_blend_states() {
+        let [accum_clear, revealage_clear] = Wboit::get_clear_values();
+        let mut accum = unsafe { accum_clear.color.float32 };
+        let mut revealage = unsafe { revealage_clear.color.float32 };
+        assert_eq!(accum, [0.0; 4]);
+        assert_eq!(revealage[0], 1.0);
+
+        let states = Wboit::get_accumulation_blend();
+        assert_eq!(states.len(), 2);
+
+        // Red in front, blue behind, drawn in either order
+        let fragments = [([0.0, 0.0, 1.0], 0.5, 8.0), ([1.0, 0.0, 0.0], 0.5, 2.0)];
+        for &(color, alpha, distance) in fragments.iter().rev() {
+            let weight = Wboit::get_weight(distance, alpha);
+            let src_accum = [
+                color[0] * alpha * weight,
+                color[1] * alpha * weight,
+                color[2] * alpha * weight,
+                alpha * weight,
+            ];
+            accum = blend(&states[0], src_accum, accum);
+            revealage = blend(&states[1], [alpha, 0.0, 0.0, 0.0], revealage);
+        }
+
+        // Sums and product of the formula
+        let weights: Vec<f32> = fragments
+            .iter()
+            .map(|&(_, alpha, distance)| Wboit::get_weight(distance, alpha))
+            .collect();
+        let expected_weight = 0.5 * weights[0] + 0.5 * weights[1];
+        assert!((accum[3] - expected_weight).abs() < 1e-3);
+        assert!((accum[0] - 0.5 * weights[1]).abs() < 1e-3);
+        assert!((accum[2] - 0.5 * weights[0]).abs() < 1e-3);
+        assert!((revealage[0] - 0.25).abs() < 1e-6);
+
+        // The closer red fragment dominates the average color
+        let resolved = Wboit::resolve(accum, revealage[0]);
+        assert!(resolved[0] > resolved[2]);
+        assert!((resolved[3] - 0.75).abs() < 1e-6);
+
+        // Composited over a black background, coverage is 1 - revealage
+        let resolve = Wboit::get_resolve_blend();
+        let composited = blend(&resolve[0], resolved, [0.0, 0.0, 0.0, 1.0]);
+        assert!((composited[0] - resolved[0] * 0.75).abs() < 1e-6);
+        assert_eq!(composited[3], 1.0);
+    }
+}
@@ -0,0 +1,261 @@
+// Copyright © 2021
+// Author: Antonio Caggiano <info@antoniocaggiano.eu>
+// SPDX-License-Identifier: MIT
+
+use std::rc::Rc;
+
+use ash::*;
+
+use super::*;
+
+/// Soft particles fade out where they intersect opaque geometry instead of showing hard billboard edges.
+/// They need to read the depth of the scene, therefore the pass should be created with depth read enabled.
+/// Particles are drawn by `Frame::draw_particles` with `Pipeline::soft_particles`.
+#[derive(Debug, Clone, Copy)]
+pub struct SoftParticles {
+    /// Distance in view space over which a particle fades in front of opaque geometry
+    pub fade_distance: f32,
+    /// Convention of the scene depth, which should be the one of the frame drawing the scene
+    pub depth: DepthConfig,
+}
+
+/// Push constants of the soft particle fragment shader
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct SoftParticleConstants {
+    pub fade_distance: f32,
+    /// Planes of the camera projection, used to linearize the scene depth
+    pub near: f32,
+    pub far: f32,
+    /// Extent of the framebuffer, where the scene depth is sampled
+    pub width: f32,
+    pub height: f32,
+    /// Whether depth values follow the reverse-Z convention, see `DepthConfig`
+    pub reverse_z: u32,
+}
+
+// Safe as it is made of 5 contiguous floats followed by a 32-bit integer
+unsafe impl Pod for SoftParticleConstants {}
+
+impl SoftParticles {
+    pub fn new(fade_distance: f32) -> Self {
+        Self {
+            fade_distance,
+            depth: DepthConfig::default(),
+        }
+    }
+
+    /// Returns the constants of the fragment shader, for a camera with `near` and `far` planes
+    /// drawing into a framebuffer of `width` and `height`
+    pub fn get_constants(
+        &self,
+        near: f32,
+        far: f32,
+        width: u32,
+        height: u32,
+    ) -> SoftParticleConstants {
+        SoftParticleConstants {
+            fade_distance: self.fade_distance,
+            near,
+            far,
+            width: width as f32,
+            height: height as f32,
+            reverse_z: self.depth.reverse_z as u32,
+        }
+    }
+
+    /// Returns the opacity factor of a particle fragment, from 0 where it touches opaque geometry
+    /// to 1 where it is at least `fade_distance` in front of it. Depths are linear distances from the camera.
+    pub fn get_fade(&self, scene_depth: f32, particle_depth: f32) -> f32 {
+        if self.fade_distance <= 0.0 {
+            return 1.0;
+        }
+        ((scene_depth - particle_depth) / self.fade_distance)
+            .max(0.0)
+            .min(1.0)
+    }
+
+    /// Returns the opacity factor of a particle fragment reading the scene depth straight from the depth buffer
+    pub fn get_fade_from_buffer(
+        &self,
+        scene_depth: f32,
+        particle_depth: f32,
+        near: f32,
+        far: f32,
+    ) -> f32 {
+        let scene_depth = self
+            .depth
+            .get_depth_range()
+            .linearize(scene_depth, near, far);
+        self.get_fade(scene_depth, particle_depth)
+    }
+
+    /// Writes the scene depth sampled by the particle fragment shader into `set`.
+    /// Depth is linearized in the shader, hence `sampler` should not filter.
+    pub fn write_set(
+        writer: &mut DescriptorWriter,
+        set: vk::DescriptorSet,
+        depth_view: &ImageView,
+        sampler: &Sampler,
+    ) {
+        writer.write_depth_image(set, 0, depth_view, sampler);
+    }
+}
+
+/// Particles are camera-facing quads made of colored points, whose model matrix is the one
+/// of the node emitting them. The scene depth is sampled from the set of the material slot,
+/// as particles have no material.
+impl VertexInput for SoftParticles {
+    fn get_bindings() -> Vec<vk::VertexInputBindingDescription> {
+        Point::get_bindings()
+    }
+
+    fn get_attributes() -> Vec<vk::VertexInputAttributeDescription> {
+        Point::get_attributes()
+    }
+
+    fn get_set_layouts(device: &Device) -> Vec<vk::DescriptorSetLayout> {
+        let depth_bindings = vec![vk::DescriptorSetLayoutBinding::builder()
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            .build()];
+
+        let mut layouts = Point::get_set_layouts(device);
+        layouts.push(create_set_layout(device, &depth_bindings));
+        layouts
+    }
+
+    fn get_constants() -> Vec<vk::PushConstantRange> {
+        vec![vk::PushConstantRange::builder()
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            .offset(0)
+            .size(std::mem::size_of::<SoftParticleConstants>() as u32)
+            .build()]
+    }
+
+    fn get_color_blend(subpass: u32) -> Vec<vk::PipelineColorBlendAttachmentState> {
+        // The particle pass has a color attachment, but no normal one
+        Point::get_color_blend(subpass)
+            .into_iter()
+            .take(1)
+            .collect()
+    }
+}
+
+/// Framebuffer of the pass created by `Pass::new_particles`, drawing over the color of a frame
+/// while testing and sampling its depth
+pub struct ParticleFramebuffer {
+    pub framebuffer: vk::Framebuffer,
+    pub width: u32,
+    pub height: u32,
+    device: Rc<Device>,
+}
+
+impl ParticleFramebuffer {
+    /// Creates the framebuffer of `pass` over the color and depth attachments of `buffer`,
+    /// whose pass should preserve depth, see `Pass::new_with_depth_read`
+    pub fn new(dev: &Dev, pass: &Pass, buffer: &Framebuffer) -> Self {
+        let (width, height) = (buffer.width, buffer.height);
+
+        let framebuffer = {
+            let attachments = [buffer.swapchain_view, buffer.depth_view.view];
+
+            let create_info = vk::FramebufferCreateInfo::builder()
+                .render_pass(pass.render)
+                .attachments(&attachments)
+                .width(width)
+                .height(height)
+                .layers(1)
+                .build();
+
+            unsafe { dev.device.create_framebuffer(&create_info, None) }
+                .expect("Failed to create Vulkan framebuffer")
+        };
+
+        Self {
+            framebuffer,
+            width,
+            height,
+            device: Rc::clone(&dev.device),
+        }
+    }
+
+    /// Returns the area of the framebuffer, which is rendered by the whole pass
+    pub fn get_area(&self) -> vk::Rect2D {
+        vk::Rect2D::builder()
+            .extent(
+                vk::Extent2D::builder()
+                    .width(self.width)
+                    .height(self.height)
+                    .build(),
+            )
+            .build()
+    }
+}
+
+impl Drop for ParticleFramebuffer {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_framebuffer(self.framebuffer, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn depth_fade() {
+        let particles = SoftParticles::new(0.5);
+
+        // Particle behind geometry
+        assert_eq!(particles.get_fade(4.0, 5.0), 0.0);
+        // Particle touching geometry
+        assert_eq!(particles.get_fade(4.0, 4.0), 0.0);
+        assert_eq!(particles.get_fade(4.0, 3.75), 0.5);
+        // Particle far enough from geometry
+        assert_eq!(particles.get_fade(4.0, 3.5), 1.0);
+        assert_eq!(particles.get_fade(4.0, 1.0), 1.0);
+    }
+
+    #[test]
+    fn depth_fade_from_buffer() {
+        let particles = SoftParticles {
+            depth: DepthConfig::reverse_z(),
+            ..SoftParticles::new(1.0)
+        };
+        let (near, far) = (0.1, 100.0);
+
+        // With reverse-Z the near plane is at 1 and the far plane at 0
+        let depth_range = particles.depth.get_depth_range();
+        let near_depth = depth_range.linearize(1.0, near, far);
+        assert!((near_depth - near).abs() < 1e-5);
+        let far_depth = depth_range.linearize(0.0, near, far);
+        assert!((far_depth - far).abs() < 1e-2);
+
+        assert_eq!(particles.get_fade_from_buffer(0.0, 50.0, near, far), 1.0);
+        assert_eq!(particles.get_fade_from_buffer(1.0, 50.0, near, far), 0.0);
+
+        // Standard depth is the other way around
+        let particles = SoftParticles::new(1.0);
+        assert_eq!(particles.get_fade_from_buffer(1.0, 50.0, near, far), 1.0);
+        assert_eq!(particles.get_fade_from_buffer(0.0, 50.0, near, far), 0.0);
+    }
+
+    #[test]
+    fn constants_depth_convention() {
+        let particles = SoftParticles::new(0.5);
+        let constants = particles.get_constants(0.1, 100.0, 64, 32);
+        assert_eq!(constants.reverse_z, 0);
+        assert_eq!((constants.width, constants.height), (64.0, 32.0));
+
+        let particles = SoftParticles {
+            depth: DepthConfig::reverse_z(),
+            ..particles
+        };
+        assert_eq!(particles.get_constants(0.1, 100.0, 64, 32).reverse_z, 1);
+    }
+}
@@ -2,7 +2,11 @@
 // Author: Antonio Caggiano <info@antoniocaggiano.eu>
 // SPDX-License-Identifier: MIT
 
-use std::{ffi::CString, rc::Rc};
+use std::{
+    ffi::{CStr, CString},
+    path::Path,
+    rc::Rc,
+};
 
 use enum_ordinalize::*;
 use variant_count::*;
@@ -10,38 +14,268 @@ use variant_count::*;
 use ash::vk;
 
 use super::*;
-#[derive(Debug, Clone, Copy, VariantCount, Ordinalize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, VariantCount, Ordinalize)]
 pub enum Pipelines {
     LINE,
     PRESENT,
     NORMAL,
     MAIN,
+    LIGHT,
+    TERRAIN,
+    BLEND,
+    PACKED,
+}
+
+impl Pipelines {
+    /// Returns the variant of this vertex pipeline which draws primitives with `topology`
+    /// and materials with `blend_mode`
+    pub fn select(self, topology: vk::PrimitiveTopology, blend_mode: BlendMode) -> Self {
+        match (self, topology, blend_mode) {
+            (Pipelines::MAIN, vk::PrimitiveTopology::TRIANGLE_STRIP, _) => Pipelines::TERRAIN,
+            (Pipelines::MAIN, _, BlendMode::Blend) => Pipelines::BLEND,
+            (variant, _, _) => variant,
+        }
+    }
 }
 
 /// Collection of built-in pipelines
 pub struct DefaultPipelines {
-    /// When debug is set, it is used instead of the one requested by a mesh
+    /// When set, this presentation pipeline is used instead of `PRESENT` to visualize one of
+    /// the attachments the scene is drawn into, see `get_presentation`. Scene draws are the same.
     pub debug: Option<Pipelines>,
-    pub pipelines: [Pipeline; Pipelines::VARIANT_COUNT],
+    /// Pipelines by variant, without the ones whose vertex formats the device does not support
+    pub pipelines: [Option<Pipeline>; Pipelines::VARIANT_COUNT],
+    /// SPIR-V file the pipelines are reloaded from when it changes
+    watcher: Option<ShaderWatcher>,
+    /// Whether scene pipelines render all the views of a multiview pass
+    multiview: bool,
+    /// Depth convention of the scene pipelines, which should match the one of the frames
+    depth: DepthConfig,
 }
 
 impl DefaultPipelines {
-    pub fn new(dev: &Dev, pass: &Pass, width: u32, height: u32) -> Self {
-        let line = Pipeline::line(dev, pass, width, height);
-        let main = Pipeline::main(dev, pass, width, height);
-        let normal = Pipeline::normal(dev, pass, width, height);
-        let present = Pipeline::present(dev, pass, width, height);
+    /// Creates the pipelines, where the ones drawing the scene test depth with the `depth`
+    /// convention, see `Frame::set_depth_config`
+    pub fn new(dev: &Dev, pass: &Pass, width: u32, height: u32, depth: DepthConfig) -> Self {
+        let shader = ShaderModule::main(&dev.device);
+        Self::with_shader(dev, &shader, pass, width, height, false, depth)
+    }
+
+    /// Creates the pipelines for a pass created by `Pass::new_multiview`, where the pipelines
+    /// drawing the scene render each view with its own camera matrices
+    pub fn new_multiview(
+        dev: &Dev,
+        pass: &Pass,
+        width: u32,
+        height: u32,
+        depth: DepthConfig,
+    ) -> Self {
+        let shader = ShaderModule::main(&dev.device);
+        Self::with_shader(dev, &shader, pass, width, height, true, depth)
+    }
+
+    /// Environment variable with the path of a SPIR-V file to load and watch shaders from
+    pub const ENV: &'static str = "VKR_SHADERS";
+
+    /// Creates the pipelines from the file named by `ENV` if set and valid,
+    /// otherwise from the embedded shaders
+    pub fn from_env(dev: &Dev, pass: &Pass, width: u32, height: u32, depth: DepthConfig) -> Self {
+        if let Ok(path) = std::env::var(Self::ENV) {
+            match Self::from_path(dev, &path, pass, width, height, depth) {
+                Ok(pipelines) => return pipelines,
+                Err(err) => eprintln!("Failed to load shaders from {}: {}", path, err),
+            }
+        }
+        Self::new(dev, pass, width, height, depth)
+    }
+
+    /// Creates the pipelines from the SPIR-V file at `path` instead of the shaders embedded
+    /// at build time, and watches it, so that `reload_if_changed` can pick up changes
+    pub fn from_path<P: AsRef<Path>>(
+        dev: &Dev,
+        path: P,
+        pass: &Pass,
+        width: u32,
+        height: u32,
+        depth: DepthConfig,
+    ) -> std::io::Result<Self> {
+        let watcher = ShaderWatcher::new(path);
+        let shader = ShaderModule::from_path(&dev.device, watcher.get_path())?;
+        let mut pipelines = Self::with_shader(dev, &shader, pass, width, height, false, depth);
+        pipelines.watcher = Some(watcher);
+        Ok(pipelines)
+    }
+
+    fn with_shader(
+        dev: &Dev,
+        shader: &ShaderModule,
+        pass: &Pass,
+        width: u32,
+        height: u32,
+        multiview: bool,
+        depth: DepthConfig,
+    ) -> Self {
+        let line = Pipeline::line(dev, shader, pass, width, height, depth);
+        let normal = Pipeline::normal(dev, shader, pass, width, height);
+        let present = Pipeline::present(dev, shader, pass, width, height);
+        let light = Pipeline::light(dev, shader, pass, width, height);
+        let packed_supported = PackedVertex::is_supported(dev);
+        let (main, terrain, blend, packed) = if multiview {
+            let triangles = vk::PrimitiveTopology::TRIANGLE_LIST;
+            let strips = vk::PrimitiveTopology::TRIANGLE_STRIP;
+            (
+                Pipeline::multiview::<Vertex>(
+                    dev,
+                    shader,
+                    pass,
+                    width,
+                    height,
+                    triangles,
+                    &RasterState {
+                        depth,
+                        ..Default::default()
+                    },
+                ),
+                Pipeline::multiview::<Vertex>(
+                    dev,
+                    shader,
+                    pass,
+                    width,
+                    height,
+                    strips,
+                    &RasterState {
+                        depth,
+                        ..RasterState::terrain()
+                    },
+                ),
+                Pipeline::multiview::<Vertex>(
+                    dev,
+                    shader,
+                    pass,
+                    width,
+                    height,
+                    triangles,
+                    &RasterState {
+                        depth,
+                        ..RasterState::blend()
+                    },
+                ),
+                if packed_supported {
+                    Some(Pipeline::multiview::<PackedVertex>(
+                        dev,
+                        shader,
+                        pass,
+                        width,
+                        height,
+                        triangles,
+                        &RasterState {
+                            depth,
+                            ..Default::default()
+                        },
+                    ))
+                } else {
+                    None
+                },
+            )
+        } else {
+            (
+                Pipeline::main(dev, shader, pass, width, height, depth),
+                Pipeline::terrain(dev, shader, pass, width, height, depth),
+                Pipeline::blend(dev, shader, pass, width, height, depth),
+                if packed_supported {
+                    Some(Pipeline::packed(dev, shader, pass, width, height, depth))
+                } else {
+                    None
+                },
+            )
+        };
         let debug = None;
 
-        let pipelines = [line, present, normal, main];
+        let pipelines = [
+            Some(line),
+            Some(present),
+            Some(normal),
+            Some(main),
+            Some(light),
+            Some(terrain),
+            Some(blend),
+            packed,
+        ];
 
-        Self { debug, pipelines }
+        Self {
+            debug,
+            pipelines,
+            watcher: None,
+            multiview,
+            depth,
+        }
+    }
+
+    /// Returns the depth convention the scene pipelines were created with
+    pub fn get_depth_config(&self) -> DepthConfig {
+        self.depth
+    }
+
+    /// Returns the shader module the pipelines are created from, which is loaded again
+    /// from the watched file if any
+    fn load_shader(&self, dev: &Dev) -> std::io::Result<ShaderModule> {
+        match &self.watcher {
+            Some(watcher) => ShaderModule::from_path(&dev.device, watcher.get_path()),
+            None => Ok(ShaderModule::main(&dev.device)),
+        }
+    }
+
+    /// Recreates the pipelines when the watched SPIR-V file has changed, returning whether
+    /// they have been. It is meant to be called every frame, before recording. The device is
+    /// waited, as pipelines may still be used by frames in flight. A file which can not be
+    /// loaded, for example because it is still being written, keeps the current pipelines.
+    /// Set layouts are created again with the same definitions, hence descriptor sets allocated
+    /// with the previous ones stay compatible as long as the shader interface is unchanged.
+    pub fn reload_if_changed(&mut self, dev: &Dev, pass: &Pass, width: u32, height: u32) -> bool {
+        match &mut self.watcher {
+            Some(watcher) if watcher.poll() => (),
+            _ => return false,
+        }
+        let shader = match self.load_shader(dev) {
+            Ok(shader) => shader,
+            Err(err) => {
+                eprintln!("Failed to reload shaders: {}", err);
+                return false;
+            }
+        };
+
+        dev.wait();
+        let debug = self.debug;
+        let watcher = self.watcher.take();
+        *self = Self::with_shader(
+            dev,
+            &shader,
+            pass,
+            width,
+            height,
+            self.multiview,
+            self.depth,
+        );
+        self.debug = debug;
+        self.watcher = watcher;
+        true
     }
 
     pub fn get_for<T: VertexInput>(&self) -> &Pipeline {
         self.get(T::get_pipeline())
     }
 
+    /// Returns the pipeline to draw `primitive` with, which is the one for `T` unless
+    /// the primitive is a triangle strip that needs primitive restart, or its material
+    /// is blended and should not write depth
+    pub fn get_for_primitive<T: VertexInput>(
+        &self,
+        primitive: &Primitive,
+        blend_mode: BlendMode,
+    ) -> &Pipeline {
+        self.get(T::get_pipeline().select(primitive.topology, blend_mode))
+    }
+
     pub fn get_presentation(&self) -> &Pipeline {
         match self.debug {
             Some(variant) => self.get(variant),
@@ -50,16 +284,259 @@ impl DefaultPipelines {
     }
 
     pub fn get(&self, variant: Pipelines) -> &Pipeline {
-        &self.pipelines[variant as usize]
+        self.try_get(variant).unwrap_or_else(|| {
+            panic!(
+                "Failed to get pipeline: {:?} is not supported by the device",
+                variant
+            )
+        })
+    }
+
+    /// Returns the pipeline of `variant`, or `None` when the device does not support it
+    pub fn try_get(&self, variant: Pipelines) -> Option<&Pipeline> {
+        self.pipelines[variant as usize].as_ref()
+    }
+
+    /// Recreates the pipelines when they are not compatible with `pass` anymore
+    pub fn recreate(&mut self, dev: &Dev, pass: &Pass, width: u32, height: u32) {
+        if self
+            .pipelines
+            .iter()
+            .flatten()
+            .all(|p| p.is_compatible_with(pass))
+        {
+            return;
+        }
+        let shader = self.load_shader(dev).expect("Failed to load shaders");
+        let debug = self.debug;
+        let watcher = self.watcher.take();
+        *self = Self::with_shader(
+            dev,
+            &shader,
+            pass,
+            width,
+            height,
+            self.multiview,
+            self.depth,
+        );
+        self.debug = debug;
+        self.watcher = watcher;
+    }
+}
+
+/// Options for the input assembly and rasterization states of a graphics pipeline
+#[derive(Debug, Clone, Copy)]
+pub struct RasterState {
+    /// Clamps fragment depth to the viewport range instead of clipping primitives against
+    /// near and far planes. It requires the `depthClamp` device feature.
+    pub depth_clamp: bool,
+    /// An index equal to `Primitive::RESTART_INDEX` starts a new strip.
+    /// It is only valid for strip and fan topologies.
+    pub primitive_restart: bool,
+    /// Blended geometry is alpha blended and depth tested without writing depth
+    pub blend_mode: BlendMode,
+    /// Pipelines of passes without color attachments, such as shadow ones, do not blend colors
+    pub depth_only: bool,
+    /// Vertices of each patch, only for pipelines with tessellation stages,
+    /// whose topology should be `PATCH_LIST`
+    pub patch_control_points: u32,
+    /// Faces culled, none by default as some assets are not closed meshes
+    pub cull_mode: vk::CullModeFlags,
+    /// Winding order of front faces, counter-clockwise as glTF requires
+    pub front_face: vk::FrontFace,
+    /// Modes other than `FILL` require the `fillModeNonSolid` device feature
+    pub polygon_mode: vk::PolygonMode,
+    /// Widths other than 1.0 require the `wideLines` device feature
+    pub line_width: f32,
+    /// Blended fragments are accumulated into the attachments of `Wboit`, in any order
+    pub weighted_blended: bool,
+    /// Depth convention of the compare op, standard by default, which should match
+    /// the one of the frame drawing with this pipeline, see `Frame::set_depth_config`
+    pub depth: DepthConfig,
+}
+
+impl Default for RasterState {
+    fn default() -> Self {
+        Self {
+            depth_clamp: false,
+            primitive_restart: false,
+            blend_mode: BlendMode::default(),
+            depth_only: false,
+            patch_control_points: 0,
+            cull_mode: vk::CullModeFlags::NONE,
+            front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+            polygon_mode: vk::PolygonMode::FILL,
+            line_width: 1.0,
+            weighted_blended: false,
+            depth: DepthConfig::default(),
+        }
+    }
+}
+
+impl RasterState {
+    /// Shadow casters outside the light's near and far planes should still cast shadows,
+    /// therefore depth is clamped when the device supports it. Shadow maps always use
+    /// reverse-Z, whatever the convention of the scene, see `Frame::render_shadow_pass`.
+    pub fn shadow(depth_clamp_supported: bool) -> Self {
+        Self {
+            depth_clamp: depth_clamp_supported,
+            depth_only: true,
+            depth: DepthConfig::reverse_z(),
+            ..Default::default()
+        }
+    }
+
+    /// Terrain is drawn as a triangle strip for each row of the grid
+    pub fn terrain() -> Self {
+        Self {
+            primitive_restart: true,
+            ..Default::default()
+        }
+    }
+
+    /// Transparent geometry is blended over opaque geometry drawn before it
+    pub fn blend() -> Self {
+        Self {
+            blend_mode: BlendMode::Blend,
+            ..Default::default()
+        }
+    }
+
+    /// Transparent geometry is accumulated for weighted-blended order-independent
+    /// transparency, hence it does not need to be sorted
+    pub fn weighted_blended() -> Self {
+        Self {
+            blend_mode: BlendMode::Blend,
+            weighted_blended: true,
+            ..Default::default()
+        }
+    }
+
+    /// Closed meshes do not need their back faces, which are culled
+    pub fn back_face_culling(front_face: vk::FrontFace) -> Self {
+        Self {
+            cull_mode: vk::CullModeFlags::BACK,
+            front_face,
+            ..Default::default()
+        }
+    }
+
+    /// Only the edges of polygons are drawn, with lines of `line_width`
+    pub fn wireframe(line_width: f32) -> Self {
+        Self {
+            polygon_mode: vk::PolygonMode::LINE,
+            line_width,
+            ..Default::default()
+        }
+    }
+
+    /// Returns whether a device with `fill_mode_non_solid` and `wide_lines` features
+    /// can rasterize with this state, otherwise the reason why not
+    fn check_features(
+        &self,
+        fill_mode_non_solid: bool,
+        wide_lines: bool,
+    ) -> Result<(), &'static str> {
+        if self.polygon_mode != vk::PolygonMode::FILL && !fill_mode_non_solid {
+            return Err("non-solid polygon modes are not supported by this device");
+        }
+        if self.line_width != 1.0 && !wide_lines {
+            return Err("wide lines are not supported by this device");
+        }
+        Ok(())
+    }
+
+    /// Returns the depth state of a vertex input with depth writes disabled
+    /// when the blend mode does not allow them
+    pub fn get_depth_state(
+        &self,
+        depth_state: vk::PipelineDepthStencilStateCreateInfo,
+    ) -> vk::PipelineDepthStencilStateCreateInfo {
+        let depth_write =
+            depth_state.depth_write_enable == vk::TRUE && self.blend_mode.get_depth_write();
+        let depth_state = vk::PipelineDepthStencilStateCreateInfo {
+            depth_write_enable: depth_write as vk::Bool32,
+            ..depth_state
+        };
+        self.depth.get_depth_state(depth_state)
+    }
+
+    /// Returns the color blend states of `T` for `subpass`, none for depth-only pipelines
+    pub fn get_color_blend<T: VertexInput>(
+        &self,
+        subpass: u32,
+    ) -> Vec<vk::PipelineColorBlendAttachmentState> {
+        if self.depth_only {
+            vec![]
+        } else if self.weighted_blended {
+            Wboit::get_accumulation_blend()
+        } else {
+            T::get_color_blend(subpass)
+                .into_iter()
+                .map(|state| self.blend_mode.get_color_blend(state))
+                .collect()
+        }
+    }
+
+    pub fn get_input_assembly(
+        &self,
+        topology: vk::PrimitiveTopology,
+    ) -> vk::PipelineInputAssemblyStateCreateInfo {
+        let is_strip = matches!(
+            topology,
+            vk::PrimitiveTopology::LINE_STRIP
+                | vk::PrimitiveTopology::TRIANGLE_STRIP
+                | vk::PrimitiveTopology::TRIANGLE_FAN
+        );
+        assert!(
+            !self.primitive_restart || is_strip,
+            "Primitive restart is not supported with {:?}",
+            topology
+        );
+
+        vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(topology)
+            .primitive_restart_enable(self.primitive_restart)
+            .build()
+    }
+
+    /// Returns the tessellation state, when patches have control points
+    pub fn get_tessellation_state(&self) -> Option<vk::PipelineTessellationStateCreateInfo> {
+        if self.patch_control_points == 0 {
+            return None;
+        }
+        Some(
+            vk::PipelineTessellationStateCreateInfo::builder()
+                .patch_control_points(self.patch_control_points)
+                .build(),
+        )
+    }
+
+    pub fn get_create_info(&self) -> vk::PipelineRasterizationStateCreateInfo {
+        vk::PipelineRasterizationStateCreateInfo::builder()
+            .depth_clamp_enable(self.depth_clamp)
+            .rasterizer_discard_enable(false)
+            .polygon_mode(self.polygon_mode)
+            .cull_mode(self.cull_mode)
+            .front_face(self.front_face)
+            .depth_bias_enable(false)
+            .line_width(self.line_width)
+            .build()
     }
 }
 
 pub struct Pipeline {
     pub graphics: vk::Pipeline,
+    /// Entry points of its stages joined by `+`, for example `main_vs+main_fs`
+    pub name: String,
     /// A pipeline layout depends on set layouts, constants, etc, to be created.
     pub layout: vk::PipelineLayout,
     /// Set layouts do not really depend on anything
     pub set_layouts: Vec<vk::DescriptorSetLayout>,
+    /// Compatibility of the render pass this pipeline was created for
+    pass_compatibility: u64,
+    /// Alignment of the offsets of vertex buffers, see `VertexInputLayout::get_binding_alignments`
+    pub vertex_alignments: Vec<vk::DeviceSize>,
     device: Rc<ash::Device>,
 }
 
@@ -75,6 +552,201 @@ impl Pipeline {
         height: u32,
         subpass: u32,
     ) -> Self {
+        Self::new_with_raster::<T>(
+            dev,
+            vert,
+            frag,
+            topology,
+            dynamic_state,
+            pass,
+            width,
+            height,
+            subpass,
+            &RasterState::default(),
+        )
+    }
+
+    pub fn new_with_raster<T: VertexInput>(
+        dev: &Dev,
+        vert: vk::PipelineShaderStageCreateInfo,
+        frag: vk::PipelineShaderStageCreateInfo,
+        topology: vk::PrimitiveTopology,
+        dynamic_state: &vk::PipelineDynamicStateCreateInfo,
+        pass: &Pass,
+        width: u32,
+        height: u32,
+        subpass: u32,
+        raster: &RasterState,
+    ) -> Self {
+        Self::new_with_vertex_input::<T>(
+            dev,
+            vert,
+            frag,
+            topology,
+            dynamic_state,
+            pass,
+            width,
+            height,
+            subpass,
+            raster,
+            &VertexInputLayout::from_vertex_input::<T>(),
+        )
+    }
+
+    /// Like `new_with_raster`, but `vertex_input` replaces the bindings and attributes of `T`,
+    /// such as a layout reflected from the vertex shader with `ShaderModule::get_vertex_input_layout`.
+    /// Set layouts and constants still come from `T`.
+    pub fn new_with_vertex_input<T: VertexInput>(
+        dev: &Dev,
+        vert: vk::PipelineShaderStageCreateInfo,
+        frag: vk::PipelineShaderStageCreateInfo,
+        topology: vk::PrimitiveTopology,
+        dynamic_state: &vk::PipelineDynamicStateCreateInfo,
+        pass: &Pass,
+        width: u32,
+        height: u32,
+        subpass: u32,
+        raster: &RasterState,
+        vertex_input: &VertexInputLayout,
+    ) -> Self {
+        Self::new_with_stages::<T>(
+            dev,
+            &[vert, frag],
+            topology,
+            dynamic_state,
+            pass,
+            width,
+            height,
+            subpass,
+            raster,
+            vertex_input,
+        )
+    }
+
+    /// Creates a pipeline with the stages whose entry points are named `prefix` followed by a stage
+    /// suffix, see `StageEntryPoints`. With tessellation stages, topology is `PATCH_LIST`
+    /// and `raster` should specify the patch control points.
+    pub fn with_prefix<T: VertexInput>(
+        dev: &Dev,
+        shader: &ShaderModule,
+        prefix: &str,
+        topology: vk::PrimitiveTopology,
+        dynamic_state: &vk::PipelineDynamicStateCreateInfo,
+        pass: &Pass,
+        width: u32,
+        height: u32,
+        subpass: u32,
+        raster: &RasterState,
+    ) -> Self {
+        let entry_points = shader.find_stages(prefix);
+        let topology = if entry_points.has_tessellation() {
+            vk::PrimitiveTopology::PATCH_LIST
+        } else {
+            topology
+        };
+        Self::new_with_stages::<T>(
+            dev,
+            &entry_points.get_stages(shader),
+            topology,
+            dynamic_state,
+            pass,
+            width,
+            height,
+            subpass,
+            raster,
+            &VertexInputLayout::from_vertex_input::<T>(),
+        )
+    }
+
+    /// Returns whether a pipeline with `stages` can be created with `topology`
+    /// and `patch_control_points`, otherwise the reason why not
+    fn check_stages(
+        stages: &[vk::ShaderStageFlags],
+        topology: vk::PrimitiveTopology,
+        patch_control_points: u32,
+    ) -> Result<(), &'static str> {
+        let has = |stage| stages.contains(&stage);
+        if !has(vk::ShaderStageFlags::VERTEX) {
+            return Err("a vertex stage is required");
+        }
+        let tesc = has(vk::ShaderStageFlags::TESSELLATION_CONTROL);
+        let tese = has(vk::ShaderStageFlags::TESSELLATION_EVALUATION);
+        if tesc != tese {
+            return Err("tessellation needs both control and evaluation stages");
+        }
+        if tesc != (topology == vk::PrimitiveTopology::PATCH_LIST) {
+            return Err("patch list topology is only valid with tessellation");
+        }
+        if tesc != (patch_control_points > 0) {
+            return Err("patch control points are only valid with tessellation");
+        }
+        Ok(())
+    }
+
+    /// Viewport and scissor are set while recording rather than baked into pipelines
+    const DYNAMIC_STATES: [vk::DynamicState; 2] =
+        [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+
+    /// Returns the dynamic state shared by the pipelines of this crate
+    pub fn get_dynamic_state() -> vk::PipelineDynamicStateCreateInfo {
+        vk::PipelineDynamicStateCreateInfo::builder()
+            .dynamic_states(&Self::DYNAMIC_STATES)
+            .build()
+    }
+
+    /// Returns whether `state` is among the dynamic states of `dynamic_state`
+    fn has_dynamic_state(
+        dynamic_state: &vk::PipelineDynamicStateCreateInfo,
+        state: vk::DynamicState,
+    ) -> bool {
+        if dynamic_state.p_dynamic_states.is_null() {
+            return false;
+        }
+        let states = unsafe {
+            std::slice::from_raw_parts(
+                dynamic_state.p_dynamic_states,
+                dynamic_state.dynamic_state_count as usize,
+            )
+        };
+        states.contains(&state)
+    }
+
+    /// Like `new_with_vertex_input`, with any number of `stages`, which may include
+    /// geometry and tessellation ones
+    pub fn new_with_stages<T: VertexInput>(
+        dev: &Dev,
+        stages: &[vk::PipelineShaderStageCreateInfo],
+        topology: vk::PrimitiveTopology,
+        dynamic_state: &vk::PipelineDynamicStateCreateInfo,
+        pass: &Pass,
+        width: u32,
+        height: u32,
+        subpass: u32,
+        raster: &RasterState,
+        vertex_input: &VertexInputLayout,
+    ) -> Self {
+        assert!(
+            !raster.depth_clamp || dev.depth_clamp,
+            "Depth clamp is not supported by this device"
+        );
+        if let Err(reason) = raster.check_features(dev.fill_mode_non_solid, dev.wide_lines) {
+            panic!("Failed to create pipeline: {}", reason);
+        }
+        let stage_flags: Vec<vk::ShaderStageFlags> =
+            stages.iter().map(|stage| stage.stage).collect();
+        if let Err(reason) = Self::check_stages(&stage_flags, topology, raster.patch_control_points)
+        {
+            panic!("Failed to create pipeline: {}", reason);
+        }
+        assert!(
+            !stage_flags.contains(&vk::ShaderStageFlags::GEOMETRY) || dev.geometry_shader,
+            "Geometry shaders are not supported by this device"
+        );
+        assert!(
+            raster.patch_control_points == 0 || dev.tessellation_shader,
+            "Tessellation shaders are not supported by this device"
+        );
+
         let set_layouts = T::get_set_layouts(&dev.device);
         let constants = T::get_constants();
 
@@ -90,49 +762,36 @@ impl Pipeline {
 
         // Graphics pipeline (shaders, renderpass)
         let graphics = {
-            let vertex_binding = T::get_bindings();
-            let vertex_attributes = T::get_attributes();
-
-            let vertex_binding = [vertex_binding];
-
             let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder()
-                .vertex_attribute_descriptions(&vertex_attributes)
-                .vertex_binding_descriptions(&vertex_binding)
-                .build();
-
-            let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
-                .topology(topology)
-                .primitive_restart_enable(false)
+                .vertex_attribute_descriptions(&vertex_input.attributes)
+                .vertex_binding_descriptions(&vertex_input.bindings)
                 .build();
 
-            let raster_state = vk::PipelineRasterizationStateCreateInfo::builder()
-                .depth_clamp_enable(false)
-                .rasterizer_discard_enable(false)
-                .polygon_mode(vk::PolygonMode::FILL)
-                .cull_mode(vk::CullModeFlags::NONE)
-                .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
-                .depth_bias_enable(false)
-                .line_width(1.0)
-                .build();
+            let input_assembly = raster.get_input_assembly(topology);
 
-            let viewport = [vk::Viewport::builder()
-                .x(0.0)
-                .y(0.0)
-                .width(width as f32)
-                .height(height as f32)
-                .min_depth(1.0)
-                .max_depth(0.0)
-                .build()];
+            let raster_state = raster.get_create_info();
 
             let scissor = [vk::Rect2D::builder()
                 .offset(vk::Offset2D::builder().x(0).y(0).build())
                 .extent(vk::Extent2D::builder().width(width).height(height).build())
                 .build()];
 
-            let view_state = vk::PipelineViewportStateCreateInfo::builder()
-                .viewports(&viewport)
-                .scissors(&scissor)
-                .build();
+            let viewport = [DepthConfig::get_viewport(&scissor[0])];
+
+            // Dynamic viewport and scissor are set while recording, so that the pipeline
+            // does not depend on the extent of the framebuffer
+            let mut view_state = vk::PipelineViewportStateCreateInfo::builder();
+            view_state = if Self::has_dynamic_state(dynamic_state, vk::DynamicState::VIEWPORT) {
+                view_state.viewport_count(1)
+            } else {
+                view_state.viewports(&viewport)
+            };
+            view_state = if Self::has_dynamic_state(dynamic_state, vk::DynamicState::SCISSOR) {
+                view_state.scissor_count(1)
+            } else {
+                view_state.scissors(&scissor)
+            };
+            let view_state = view_state.build();
 
             let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
                 .rasterization_samples(vk::SampleCountFlags::TYPE_1)
@@ -141,19 +800,19 @@ impl Pipeline {
                 .alpha_to_one_enable(false)
                 .build();
 
-            let depth_state = T::get_depth_state();
+            let depth_state = raster.get_depth_state(T::get_depth_state());
 
-            let blend_attachment = T::get_color_blend(subpass);
+            let blend_attachment = raster.get_color_blend::<T>(subpass);
 
             let blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
                 .logic_op_enable(false)
                 .attachments(&blend_attachment)
                 .build();
 
-            let stages = [vert, frag];
+            let tessellation_state = raster.get_tessellation_state();
 
-            let create_info = [vk::GraphicsPipelineCreateInfo::builder()
-                .stages(&stages)
+            let mut create_info = vk::GraphicsPipelineCreateInfo::builder()
+                .stages(stages)
                 .vertex_input_state(&vertex_input)
                 .input_assembly_state(&input_assembly)
                 .viewport_state(&view_state)
@@ -164,8 +823,11 @@ impl Pipeline {
                 .dynamic_state(&dynamic_state)
                 .render_pass(pass.render)
                 .subpass(subpass)
-                .layout(layout)
-                .build()];
+                .layout(layout);
+            if let Some(tessellation_state) = &tessellation_state {
+                create_info = create_info.tessellation_state(tessellation_state);
+            }
+            let create_info = [create_info.build()];
 
             let pipelines = unsafe {
                 dev.device
@@ -177,23 +839,61 @@ impl Pipeline {
 
         Self {
             graphics,
+            name: Self::get_name(stages),
             set_layouts,
             layout,
+            pass_compatibility: pass.compatibility,
+            vertex_alignments: vertex_input.get_binding_alignments(),
             device: Rc::clone(&dev.device),
         }
     }
 
-    pub fn line(dev: &Dev, pass: &Pass, width: u32, height: u32) -> Self {
-        let shader = ShaderModule::main(&dev.device);
+    /// Returns the entry points of `stages` joined by `+`
+    fn get_name(stages: &[vk::PipelineShaderStageCreateInfo]) -> String {
+        stages
+            .iter()
+            .map(|stage| unsafe { CStr::from_ptr(stage.p_name) }.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("+")
+    }
+
+    /// Returns whether the layout of this pipeline has a set for `frequency`
+    pub fn has_set_layout(&self, frequency: DescriptorFrequency) -> bool {
+        (frequency.get_set_index() as usize) < self.set_layouts.len()
+    }
+
+    pub fn get_set_layout(&self, frequency: DescriptorFrequency) -> vk::DescriptorSetLayout {
+        *self
+            .set_layouts
+            .get(frequency.get_set_index() as usize)
+            .unwrap_or_else(|| panic!("Pipeline has no {:?} set layout", frequency))
+    }
+
+    /// Returns whether this pipeline can be used within `pass`
+    pub fn is_compatible_with(&self, pass: &Pass) -> bool {
+        self.pass_compatibility == pass.compatibility
+    }
+
+    /// Returns whether this pipeline can be used with a framebuffer
+    pub fn is_compatible_with_framebuffer(&self, framebuffer: &Framebuffer) -> bool {
+        self.pass_compatibility == framebuffer.pass_compatibility
+    }
+
+    /// Returns a graphics pipeline which draws line strips, depth tested with the `depth` convention
+    pub fn line(
+        dev: &Dev,
+        shader: &ShaderModule,
+        pass: &Pass,
+        width: u32,
+        height: u32,
+        depth: DepthConfig,
+    ) -> Self {
         let vs = CString::new("line_vs").expect("Failed to create entrypoint");
         let fs = CString::new("line_fs").expect("Failed to create entrypoint");
 
-        let states = vec![vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
-        let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder()
-            .dynamic_states(&states)
-            .build();
+        let dynamic_state = Self::get_dynamic_state();
 
-        Self::new::<Line>(
+        Self::new_with_raster::<Line>(
             dev,
             shader.get_vert(&vs),
             shader.get_frag(&fs),
@@ -203,20 +903,44 @@ impl Pipeline {
             width,
             height,
             0,
+            &RasterState {
+                depth,
+                ..Default::default()
+            },
         )
     }
 
-    pub fn main(dev: &Dev, pass: &Pass, width: u32, height: u32) -> Self {
-        let shader = ShaderModule::main(&dev.device);
+    /// Returns a graphics pipeline which draws the scene, depth tested with the `depth` convention
+    pub fn main(
+        dev: &Dev,
+        shader: &ShaderModule,
+        pass: &Pass,
+        width: u32,
+        height: u32,
+        depth: DepthConfig,
+    ) -> Self {
+        let raster = RasterState {
+            depth,
+            ..Default::default()
+        };
+        Self::main_with_raster(dev, shader, pass, width, height, &raster)
+    }
+
+    /// Returns a graphics pipeline like the main one, but for primitives of `PackedVertex`
+    pub fn packed(
+        dev: &Dev,
+        shader: &ShaderModule,
+        pass: &Pass,
+        width: u32,
+        height: u32,
+        depth: DepthConfig,
+    ) -> Self {
         let vs = CString::new("main_vs").expect("Failed to create entrypoint");
         let fs = CString::new("main_fs").expect("Failed to create entrypoint");
 
-        let states = vec![vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
-        let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder()
-            .dynamic_states(&states)
-            .build();
+        let dynamic_state = Self::get_dynamic_state();
 
-        Self::new::<Vertex>(
+        Self::new_with_raster::<PackedVertex>(
             dev,
             shader.get_vert(&vs),
             shader.get_frag(&fs),
@@ -226,16 +950,19 @@ impl Pipeline {
             width,
             height,
             0,
+            &RasterState {
+                depth,
+                ..Default::default()
+            },
         )
     }
 
     /// Returns a graphics pipeline which draws the normals of primitive's surfaces as a color
-    pub fn normal(dev: &Dev, pass: &Pass, width: u32, height: u32) -> Self {
-        let shader = ShaderModule::main(&dev.device);
+    pub fn normal(dev: &Dev, shader: &ShaderModule, pass: &Pass, width: u32, height: u32) -> Self {
         let vs = CString::new("present_vs").expect("Failed to create entrypoint");
         let fs = CString::new("normal_fs").expect("Failed to create entrypoint");
 
-        let dynamic_state = vk::PipelineDynamicStateCreateInfo::default();
+        let dynamic_state = Self::get_dynamic_state();
 
         Self::new::<PresentVertex>(
             dev,
@@ -246,18 +973,19 @@ impl Pipeline {
             pass,
             width,
             height,
-            1,
+            Pass::PRESENT_SUBPASS,
         )
     }
 
-    pub fn present(dev: &Dev, pass: &Pass, width: u32, height: u32) -> Self {
-        let shader = ShaderModule::main(&dev.device);
+    /// Returns a graphics pipeline which additively accumulates light volumes
+    pub fn light(dev: &Dev, shader: &ShaderModule, pass: &Pass, width: u32, height: u32) -> Self {
         let vs = CString::new("present_vs").expect("Failed to create entrypoint");
-        let fs = CString::new("present_fs").expect("Failed to create entry point");
+        let fs = CString::new("light_fs").expect("Failed to create entrypoint");
 
-        let dynamic_state = vk::PipelineDynamicStateCreateInfo::default();
+        // Each light volume is scissored to its screen rectangle
+        let dynamic_state = Self::get_dynamic_state();
 
-        Self::new::<PresentVertex>(
+        Self::new::<Light>(
             dev,
             shader.get_vert(&vs),
             shader.get_frag(&fs),
@@ -266,19 +994,623 @@ impl Pipeline {
             pass,
             width,
             height,
-            1,
+            Pass::LIGHTING_SUBPASS,
         )
     }
-}
 
-impl Drop for Pipeline {
-    fn drop(&mut self) {
-        unsafe {
-            for set_layout in &self.set_layouts {
-                self.device.destroy_descriptor_set_layout(*set_layout, None);
-            }
-            self.device.destroy_pipeline_layout(self.layout, None);
-            self.device.destroy_pipeline(self.graphics, None);
-        }
+    /// Returns a graphics pipeline which draws triangle strips separated by restart indices
+    pub fn terrain(
+        dev: &Dev,
+        shader: &ShaderModule,
+        pass: &Pass,
+        width: u32,
+        height: u32,
+        depth: DepthConfig,
+    ) -> Self {
+        let vs = CString::new("main_vs").expect("Failed to create entrypoint");
+        let fs = CString::new("main_fs").expect("Failed to create entrypoint");
+
+        let dynamic_state = Self::get_dynamic_state();
+
+        Self::new_with_raster::<Vertex>(
+            dev,
+            shader.get_vert(&vs),
+            shader.get_frag(&fs),
+            vk::PrimitiveTopology::TRIANGLE_STRIP,
+            &dynamic_state,
+            pass,
+            width,
+            height,
+            0,
+            &RasterState {
+                depth,
+                ..RasterState::terrain()
+            },
+        )
+    }
+
+    /// Returns a graphics pipeline like the main one, but which does not write depth,
+    /// for primitives whose material is blended
+    pub fn blend(
+        dev: &Dev,
+        shader: &ShaderModule,
+        pass: &Pass,
+        width: u32,
+        height: u32,
+        depth: DepthConfig,
+    ) -> Self {
+        let vs = CString::new("main_vs").expect("Failed to create entrypoint");
+        let fs = CString::new("main_fs").expect("Failed to create entrypoint");
+
+        let dynamic_state = Self::get_dynamic_state();
+
+        Self::new_with_raster::<Vertex>(
+            dev,
+            shader.get_vert(&vs),
+            shader.get_frag(&fs),
+            vk::PrimitiveTopology::TRIANGLE_LIST,
+            &dynamic_state,
+            pass,
+            width,
+            height,
+            0,
+            &RasterState {
+                depth,
+                ..RasterState::blend()
+            },
+        )
+    }
+
+    /// Returns a graphics pipeline like the main one, which draws `raster` options such as
+    /// culling or wireframes
+    pub fn main_with_raster(
+        dev: &Dev,
+        shader: &ShaderModule,
+        pass: &Pass,
+        width: u32,
+        height: u32,
+        raster: &RasterState,
+    ) -> Self {
+        let vs = CString::new("main_vs").expect("Failed to create entrypoint");
+        let fs = CString::new("main_fs").expect("Failed to create entrypoint");
+
+        let dynamic_state = Self::get_dynamic_state();
+
+        Self::new_with_raster::<Vertex>(
+            dev,
+            shader.get_vert(&vs),
+            shader.get_frag(&fs),
+            vk::PrimitiveTopology::TRIANGLE_LIST,
+            &dynamic_state,
+            pass,
+            width,
+            height,
+            0,
+            raster,
+        )
+    }
+
+    /// Returns a graphics pipeline like `main_with_raster`, whose vertex stage renders each view
+    /// of a pass created by `Pass::new_multiview` with its own camera matrices
+    pub fn multiview<T: VertexInput>(
+        dev: &Dev,
+        shader: &ShaderModule,
+        pass: &Pass,
+        width: u32,
+        height: u32,
+        topology: vk::PrimitiveTopology,
+        raster: &RasterState,
+    ) -> Self {
+        assert!(dev.multiview, "Multiview is not supported by this device");
+        let multiview_shader = ShaderModule::multiview(&dev.device);
+        let vs = CString::new("main_multiview_vs").expect("Failed to create entrypoint");
+        let fs = CString::new("main_fs").expect("Failed to create entrypoint");
+
+        let dynamic_state = Self::get_dynamic_state();
+
+        Self::new_with_raster::<T>(
+            dev,
+            multiview_shader.get_vert(&vs),
+            shader.get_frag(&fs),
+            topology,
+            &dynamic_state,
+            pass,
+            width,
+            height,
+            0,
+            raster,
+        )
+    }
+
+    /// Returns a graphics pipeline which accumulates blended primitives into the attachments
+    /// of `Wboit`, bound as the color attachments of `subpass`, such as the accumulation one
+    /// of a pass created by `Pass::new_oit`
+    pub fn weighted_blended(
+        dev: &Dev,
+        shader: &ShaderModule,
+        pass: &Pass,
+        width: u32,
+        height: u32,
+        subpass: u32,
+    ) -> Self {
+        let vs = CString::new("main_vs").expect("Failed to create entrypoint");
+        let fs = CString::new("oit_fs").expect("Failed to create entrypoint");
+
+        let dynamic_state = Self::get_dynamic_state();
+
+        Self::new_with_raster::<Vertex>(
+            dev,
+            shader.get_vert(&vs),
+            shader.get_frag(&fs),
+            vk::PrimitiveTopology::TRIANGLE_LIST,
+            &dynamic_state,
+            pass,
+            width,
+            height,
+            subpass,
+            &RasterState::weighted_blended(),
+        )
+    }
+
+    /// Returns a graphics pipeline which composites the fragments accumulated into `Wboit`
+    /// over the color attachment, in the resolve subpass of a pass created by `Pass::new_oit`
+    pub fn wboit_resolve(
+        dev: &Dev,
+        shader: &ShaderModule,
+        pass: &Pass,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let vs = CString::new("present_vs").expect("Failed to create entrypoint");
+        let fs = CString::new("oit_resolve_fs").expect("Failed to create entrypoint");
+
+        let dynamic_state = Self::get_dynamic_state();
+
+        Self::new::<Wboit>(
+            dev,
+            shader.get_vert(&vs),
+            shader.get_frag(&fs),
+            vk::PrimitiveTopology::TRIANGLE_LIST,
+            &dynamic_state,
+            pass,
+            width,
+            height,
+            Wboit::RESOLVE_SUBPASS,
+        )
+    }
+
+    /// Returns a graphics pipeline which blends soft particles over the color attachment of a pass
+    /// created by `Pass::new_particles`, fading them near the scene depth with the convention
+    /// of `particles`. Vertices are transformed by the line shader, as they are colored points.
+    pub fn soft_particles(
+        dev: &Dev,
+        shader: &ShaderModule,
+        pass: &Pass,
+        width: u32,
+        height: u32,
+        particles: &SoftParticles,
+    ) -> Self {
+        let vs = CString::new("line_vs").expect("Failed to create entrypoint");
+        let fs = CString::new("particle_fs").expect("Failed to create entrypoint");
+
+        let dynamic_state = Self::get_dynamic_state();
+
+        Self::new_with_raster::<SoftParticles>(
+            dev,
+            shader.get_vert(&vs),
+            shader.get_frag(&fs),
+            vk::PrimitiveTopology::TRIANGLE_LIST,
+            &dynamic_state,
+            pass,
+            width,
+            height,
+            0,
+            &RasterState {
+                depth: particles.depth,
+                ..RasterState::blend()
+            },
+        )
+    }
+
+    /// Returns a graphics pipeline which only writes the depth of the scene as seen by a light
+    pub fn shadow(dev: &Dev, pass: &Pass, width: u32, height: u32) -> Self {
+        let shader = ShaderModule::main(&dev.device);
+        let vs = CString::new("shadow_vs").expect("Failed to create entrypoint");
+        let fs = CString::new("shadow_fs").expect("Failed to create entrypoint");
+
+        let dynamic_state = Self::get_dynamic_state();
+
+        Self::new_with_raster::<Vertex>(
+            dev,
+            shader.get_vert(&vs),
+            shader.get_frag(&fs),
+            vk::PrimitiveTopology::TRIANGLE_LIST,
+            &dynamic_state,
+            pass,
+            width,
+            height,
+            0,
+            &RasterState::shadow(dev.depth_clamp),
+        )
+    }
+
+    pub fn present(dev: &Dev, shader: &ShaderModule, pass: &Pass, width: u32, height: u32) -> Self {
+        let vs = CString::new("present_vs").expect("Failed to create entrypoint");
+        let fs = CString::new("present_fs").expect("Failed to create entry point");
+
+        let dynamic_state = Self::get_dynamic_state();
+
+        Self::new::<PresentVertex>(
+            dev,
+            shader.get_vert(&vs),
+            shader.get_frag(&fs),
+            vk::PrimitiveTopology::TRIANGLE_LIST,
+            &dynamic_state,
+            pass,
+            width,
+            height,
+            Pass::PRESENT_SUBPASS,
+        )
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        unsafe {
+            for set_layout in &self.set_layouts {
+                self.device.destroy_descriptor_set_layout(*set_layout, None);
+            }
+            self.device.destroy_pipeline_layout(self.layout, None);
+            self.device.destroy_pipeline(self.graphics, None);
+        }
+    }
+}
+
+/// Pipeline running a compute shader
+pub struct ComputePipeline {
+    pub compute: vk::Pipeline,
+    pub layout: vk::PipelineLayout,
+    pub set_layouts: Vec<vk::DescriptorSetLayout>,
+    /// Workgroup size reflected from the shader
+    local_size: [u32; 3],
+    device: Rc<ash::Device>,
+}
+
+impl ComputePipeline {
+    /// Side of the square grid of values written by `main_cs`
+    pub const MAIN_CS_SIZE: u32 = 32;
+
+    pub fn new(
+        dev: &Dev,
+        shader: &ShaderModule,
+        entrypoint: &str,
+        set_layouts: Vec<vk::DescriptorSetLayout>,
+    ) -> Self {
+        let local_size = shader
+            .get_local_size(entrypoint)
+            .expect("Failed to reflect compute shader local size");
+
+        let layout = {
+            let create_info = vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&set_layouts)
+                .build();
+            unsafe { dev.device.create_pipeline_layout(&create_info, None) }
+                .expect("Failed to create Vulkan pipeline layout")
+        };
+
+        let compute = {
+            let cs = CString::new(entrypoint).expect("Failed to create entrypoint");
+            let create_info = [vk::ComputePipelineCreateInfo::builder()
+                .stage(shader.get_comp(&cs))
+                .layout(layout)
+                .build()];
+
+            let pipelines = unsafe {
+                dev.device
+                    .create_compute_pipelines(vk::PipelineCache::null(), &create_info, None)
+            }
+            .expect("Failed to create Vulkan compute pipeline");
+            pipelines[0]
+        };
+
+        Self {
+            compute,
+            layout,
+            set_layouts,
+            local_size,
+            device: Rc::clone(&dev.device),
+        }
+    }
+
+    /// Returns the bindings of a set of `count` storage buffers accessed by a compute shader
+    pub fn get_storage_set_layout_bindings(count: u32) -> Vec<vk::DescriptorSetLayoutBinding> {
+        (0..count)
+            .map(|binding| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                    .build()
+            })
+            .collect()
+    }
+
+    /// Returns a compute pipeline writing a value for each element of a storage buffer
+    /// of `MAIN_CS_SIZE` × `MAIN_CS_SIZE` `u32`s, bound at set 0 and binding 0
+    pub fn main(dev: &Dev) -> Self {
+        let shader = ShaderModule::main(&dev.device);
+        let bindings = Self::get_storage_set_layout_bindings(1);
+        let set_layouts = vec![create_set_layout(&dev.device, &bindings)];
+        Self::new(dev, &shader, "main_cs", set_layouts)
+    }
+
+    pub fn local_size(&self) -> [u32; 3] {
+        self.local_size
+    }
+
+    /// Returns the number of workgroups needed to cover a problem of `extent` invocations
+    fn get_group_count(local_size: [u32; 3], extent: [u32; 3]) -> [u32; 3] {
+        let mut count = [1; 3];
+        for i in 0..3 {
+            let size = local_size[i].max(1);
+            count[i] = (extent[i] + size - 1) / size;
+        }
+        count
+    }
+
+    /// Dispatches enough workgroups to cover a `width` × `height` × `depth` problem.
+    /// The pipeline and its descriptor sets should already be bound.
+    pub fn dispatch_for_extent(&self, frame: &Frame, width: u32, height: u32, depth: u32) {
+        self.record_dispatch(&frame.res.command_buffer, width, height, depth);
+    }
+
+    /// Like `dispatch_for_extent`, but records into any command buffer, for example
+    /// one submitted outside of a frame
+    pub fn record_dispatch(
+        &self,
+        command_buffer: &CommandBuffer,
+        width: u32,
+        height: u32,
+        depth: u32,
+    ) {
+        let [x, y, z] = Self::get_group_count(self.local_size, [width, height, depth]);
+        command_buffer.dispatch(x, y, z);
+    }
+}
+
+impl Drop for ComputePipeline {
+    fn drop(&mut self) {
+        unsafe {
+            for set_layout in &self.set_layouts {
+                self.device.destroy_descriptor_set_layout(*set_layout, None);
+            }
+            self.device.destroy_pipeline_layout(self.layout, None);
+            self.device.destroy_pipeline(self.compute, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn group_count() {
+        let count = ComputePipeline::get_group_count([32, 32, 1], [64, 64, 1]);
+        assert_eq!(count, [2, 2, 1]);
+
+        // Partial groups are rounded up
+        let count = ComputePipeline::get_group_count([32, 32, 1], [65, 31, 1]);
+        assert_eq!(count, [3, 1, 1]);
+    }
+
+    #[test]
+    fn storage_set_layout() {
+        let bindings = ComputePipeline::get_storage_set_layout_bindings(2);
+        assert_eq!(bindings.len(), 2);
+        for (i, binding) in bindings.iter().enumerate() {
+            assert_eq!(binding.binding, i as u32);
+            assert_eq!(binding.descriptor_type, vk::DescriptorType::STORAGE_BUFFER);
+            assert_eq!(binding.stage_flags, vk::ShaderStageFlags::COMPUTE);
+        }
+    }
+
+    #[test]
+    fn shadow_depth_clamp() {
+        let state = RasterState::shadow(true).get_create_info();
+        assert_eq!(state.depth_clamp_enable, vk::TRUE);
+
+        // Not available on this device
+        let state = RasterState::shadow(false).get_create_info();
+        assert_eq!(state.depth_clamp_enable, vk::FALSE);
+
+        let state = RasterState::default().get_create_info();
+        assert_eq!(state.depth_clamp_enable, vk::FALSE);
+    }
+
+    #[test]
+    fn shadow_depth_only() {
+        // The shadow pass has no color attachments
+        assert!(RasterState::shadow(true)
+            .get_color_blend::<Vertex>(0)
+            .is_empty());
+        assert_eq!(
+            RasterState::default().get_color_blend::<Vertex>(0).len(),
+            Vertex::get_color_blend(0).len()
+        );
+    }
+
+    #[test]
+    fn blend_color_blend() {
+        let blend = RasterState::blend().get_color_blend::<Vertex>(0);
+        assert_eq!(blend.len(), Vertex::get_color_blend(0).len());
+        for state in &blend {
+            assert_eq!(state.blend_enable, vk::TRUE);
+            assert_eq!(state.src_color_blend_factor, vk::BlendFactor::SRC_ALPHA);
+            assert_eq!(
+                state.dst_color_blend_factor,
+                vk::BlendFactor::ONE_MINUS_SRC_ALPHA
+            );
+        }
+
+        // Opaque pipelines keep the blending of their vertex input
+        let disabled = vk::PipelineColorBlendAttachmentState::default();
+        assert_eq!(
+            BlendMode::Opaque.get_color_blend(disabled).blend_enable,
+            vk::FALSE
+        );
+        assert_eq!(
+            BlendMode::Blend.get_color_blend(disabled).blend_enable,
+            vk::TRUE
+        );
+    }
+
+    #[test]
+    fn terrain_primitive_restart() {
+        let state =
+            RasterState::terrain().get_input_assembly(vk::PrimitiveTopology::TRIANGLE_STRIP);
+        assert_eq!(state.primitive_restart_enable, vk::TRUE);
+        assert_eq!(state.topology, vk::PrimitiveTopology::TRIANGLE_STRIP);
+    }
+
+    #[test]
+    fn blend_depth_write() {
+        let state = RasterState::blend().get_depth_state(Vertex::get_depth_state());
+        assert_eq!(state.depth_write_enable, vk::FALSE);
+        assert_eq!(state.depth_test_enable, vk::TRUE);
+        // Standard depth by default
+        assert_eq!(state.depth_compare_op, vk::CompareOp::LESS);
+
+        let state = RasterState::default().get_depth_state(Vertex::get_depth_state());
+        assert_eq!(state.depth_write_enable, vk::TRUE);
+        assert_eq!(state.depth_test_enable, vk::TRUE);
+
+        let reverse = RasterState {
+            depth: DepthConfig::reverse_z(),
+            ..RasterState::blend()
+        };
+        let state = reverse.get_depth_state(Vertex::get_depth_state());
+        assert_eq!(state.depth_compare_op, vk::CompareOp::GREATER);
+
+        // Shadow maps are always reversed
+        let state = RasterState::shadow(false).get_depth_state(Vertex::get_depth_state());
+        assert_eq!(state.depth_compare_op, vk::CompareOp::GREATER);
+    }
+
+    #[test]
+    #[should_panic]
+    fn list_primitive_restart() {
+        RasterState::terrain().get_input_assembly(vk::PrimitiveTopology::TRIANGLE_LIST);
+    }
+
+    #[test]
+    fn pipeline_stages() {
+        let vertex = vk::ShaderStageFlags::VERTEX;
+        let fragment = vk::ShaderStageFlags::FRAGMENT;
+        let geometry = vk::ShaderStageFlags::GEOMETRY;
+        let tesc = vk::ShaderStageFlags::TESSELLATION_CONTROL;
+        let tese = vk::ShaderStageFlags::TESSELLATION_EVALUATION;
+        let triangles = vk::PrimitiveTopology::TRIANGLE_LIST;
+        let patches = vk::PrimitiveTopology::PATCH_LIST;
+
+        assert!(Pipeline::check_stages(&[vertex, fragment], triangles, 0).is_ok());
+        assert!(Pipeline::check_stages(&[vertex, geometry, fragment], triangles, 0).is_ok());
+        assert!(Pipeline::check_stages(&[vertex, tesc, tese, fragment], patches, 3).is_ok());
+
+        assert!(Pipeline::check_stages(&[fragment], triangles, 0).is_err());
+        assert!(Pipeline::check_stages(&[vertex, tesc, fragment], patches, 3).is_err());
+        assert!(Pipeline::check_stages(&[vertex, tesc, tese, fragment], triangles, 3).is_err());
+        assert!(Pipeline::check_stages(&[vertex, tesc, tese, fragment], patches, 0).is_err());
+        assert!(Pipeline::check_stages(&[vertex, fragment], patches, 3).is_err());
+
+        let raster = RasterState {
+            patch_control_points: 3,
+            ..Default::default()
+        };
+        assert_eq!(
+            raster
+                .get_tessellation_state()
+                .unwrap()
+                .patch_control_points,
+            3
+        );
+        assert!(RasterState::default().get_tessellation_state().is_none());
+    }
+
+    #[test]
+    fn dynamic_viewport() {
+        let dynamic_state = Pipeline::get_dynamic_state();
+        assert!(Pipeline::has_dynamic_state(
+            &dynamic_state,
+            vk::DynamicState::VIEWPORT
+        ));
+        assert!(Pipeline::has_dynamic_state(
+            &dynamic_state,
+            vk::DynamicState::SCISSOR
+        ));
+        assert!(!Pipeline::has_dynamic_state(
+            &dynamic_state,
+            vk::DynamicState::LINE_WIDTH
+        ));
+
+        let baked = vk::PipelineDynamicStateCreateInfo::default();
+        assert!(!Pipeline::has_dynamic_state(
+            &baked,
+            vk::DynamicState::VIEWPORT
+        ));
+    }
+
+    #[test]
+    fn raster_modes() {
+        let info = RasterState::default().get_create_info();
+        assert_eq!(info.cull_mode, vk::CullModeFlags::NONE);
+        assert_eq!(info.front_face, vk::FrontFace::COUNTER_CLOCKWISE);
+        assert_eq!(info.polygon_mode, vk::PolygonMode::FILL);
+        assert_eq!(info.line_width, 1.0);
+        assert!(RasterState::default().check_features(false, false).is_ok());
+
+        let culled = RasterState::back_face_culling(vk::FrontFace::CLOCKWISE).get_create_info();
+        assert_eq!(culled.cull_mode, vk::CullModeFlags::BACK);
+        assert_eq!(culled.front_face, vk::FrontFace::CLOCKWISE);
+
+        let wireframe = RasterState::wireframe(1.0);
+        assert_eq!(
+            wireframe.get_create_info().polygon_mode,
+            vk::PolygonMode::LINE
+        );
+        assert!(wireframe.check_features(false, false).is_err());
+        assert!(wireframe.check_features(true, false).is_ok());
+        assert!(RasterState::wireframe(2.0)
+            .check_features(true, false)
+            .is_err());
+        assert!(RasterState::wireframe(2.0)
+            .check_features(true, true)
+            .is_ok());
+
+        let oit = RasterState::weighted_blended();
+        assert_eq!(
+            oit.get_color_blend::<Vertex>(0).len(),
+            Wboit::get_accumulation_blend().len()
+        );
+        let depth_state = oit.get_depth_state(Vertex::get_depth_state());
+        assert_eq!(depth_state.depth_write_enable, vk::FALSE);
+    }
+
+    #[test]
+    fn pipeline_name() {
+        let vs = CString::new("main_vs").unwrap();
+        let fs = CString::new("main_fs").unwrap();
+        let stages = [
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::VERTEX)
+                .name(&vs)
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::FRAGMENT)
+                .name(&fs)
+                .build(),
+        ];
+        assert_eq!(Pipeline::get_name(&stages), "main_vs+main_fs");
     }
 }
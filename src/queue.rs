@@ -7,8 +7,136 @@ use std::rc::Rc;
 use super::*;
 use ash::*;
 
+/// Ownership transfer of swapchain images from the graphics queue family,
+/// where they are rendered, to the present queue family, where they are presented.
+/// Images are only released by the graphics queue as the render pass discards their content,
+/// hence the ownership never needs to go back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueueTransfer {
+    pub graphics_family: u32,
+    pub present_family: u32,
+}
+
+impl QueueTransfer {
+    /// Returns a transfer only when the families differ,
+    /// as images with exclusive sharing mode need it in that case only
+    pub fn new(graphics_family: u32, present_family: u32) -> Option<Self> {
+        if graphics_family == present_family {
+            None
+        } else {
+            Some(Self {
+                graphics_family,
+                present_family,
+            })
+        }
+    }
+
+    fn get_barrier(&self, image: vk::Image) -> vk::ImageMemoryBarrier {
+        // The render pass already left the image in present layout
+        vk::ImageMemoryBarrier::builder()
+            .old_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+            .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+            .src_queue_family_index(self.graphics_family)
+            .dst_queue_family_index(self.present_family)
+            .image(image)
+            .subresource_range(
+                vk::ImageSubresourceRange::builder()
+                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                    .level_count(1)
+                    .layer_count(1)
+                    .build(),
+            )
+            .build()
+    }
+
+    /// Barrier to record on the graphics queue after rendering to `image`
+    pub fn get_release_barrier(&self, image: vk::Image) -> vk::ImageMemoryBarrier {
+        let mut barrier = self.get_barrier(image);
+        barrier.src_access_mask = vk::AccessFlags::COLOR_ATTACHMENT_WRITE;
+        barrier
+    }
+
+    /// Matching barrier to record on the present queue before presenting `image`
+    pub fn get_acquire_barrier(&self, image: vk::Image) -> vk::ImageMemoryBarrier {
+        // Presentation does not need any access mask
+        self.get_barrier(image)
+    }
+}
+
+/// Ownership transfer of uploaded images from the transfer queue family, where they are written,
+/// to the graphics queue family, where they are used. The transfer queue releases an image
+/// with a barrier, which the graphics queue repeats to acquire it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UploadTransfer {
+    pub transfer_family: u32,
+    pub graphics_family: u32,
+}
+
+impl UploadTransfer {
+    /// Returns a transfer only when the families differ,
+    /// as images with exclusive sharing mode need it in that case only
+    pub fn new(transfer_family: u32, graphics_family: u32) -> Option<Self> {
+        if transfer_family == graphics_family {
+            None
+        } else {
+            Some(Self {
+                transfer_family,
+                graphics_family,
+            })
+        }
+    }
+
+    fn get_barrier(&self, barrier: &vk::ImageMemoryBarrier) -> vk::ImageMemoryBarrier {
+        let mut barrier = *barrier;
+        barrier.src_queue_family_index = self.transfer_family;
+        barrier.dst_queue_family_index = self.graphics_family;
+        barrier
+    }
+
+    /// Barrier to record on the transfer queue in place of `barrier`, which makes an upload
+    /// available to the graphics queue. Its destination access is ignored by the release.
+    pub fn get_release_barrier(&self, barrier: &vk::ImageMemoryBarrier) -> vk::ImageMemoryBarrier {
+        let mut barrier = self.get_barrier(barrier);
+        barrier.dst_access_mask = vk::AccessFlags::empty();
+        barrier
+    }
+
+    /// Matching barrier to record on the graphics queue. Its source access is ignored by the acquire.
+    pub fn get_acquire_barrier(&self, barrier: &vk::ImageMemoryBarrier) -> vk::ImageMemoryBarrier {
+        let mut barrier = self.get_barrier(barrier);
+        barrier.src_access_mask = vk::AccessFlags::empty();
+        barrier
+    }
+
+    /// Like `get_release_barrier`, but for a buffer
+    pub fn get_release_buffer_barrier(
+        &self,
+        barrier: &vk::BufferMemoryBarrier,
+    ) -> vk::BufferMemoryBarrier {
+        let mut barrier = *barrier;
+        barrier.src_queue_family_index = self.transfer_family;
+        barrier.dst_queue_family_index = self.graphics_family;
+        barrier.dst_access_mask = vk::AccessFlags::empty();
+        barrier
+    }
+
+    /// Like `get_acquire_barrier`, but for a buffer
+    pub fn get_acquire_buffer_barrier(
+        &self,
+        barrier: &vk::BufferMemoryBarrier,
+    ) -> vk::BufferMemoryBarrier {
+        let mut barrier = *barrier;
+        barrier.src_queue_family_index = self.transfer_family;
+        barrier.dst_queue_family_index = self.graphics_family;
+        barrier.src_access_mask = vk::AccessFlags::empty();
+        barrier
+    }
+}
+
 pub struct Queue {
     queue: vk::Queue,
+    /// Family this queue belongs to
+    pub family_index: u32,
     device: Rc<Device>,
 }
 
@@ -18,7 +146,11 @@ impl Queue {
 
         let queue = unsafe { device.get_device_queue(queue_family_index, 0) };
 
-        Queue { queue, device }
+        Queue {
+            queue,
+            family_index: queue_family_index,
+            device,
+        }
     }
 
     pub fn submit(&self, submits: &[vk::SubmitInfo], fence: Option<&mut Fence>) {
@@ -42,37 +174,55 @@ impl Queue {
         signal: vk::Semaphore,
         fence: Option<&mut Fence>,
     ) {
-        // Wait for the image to be available ..
-        let waits = [wait];
-        // .. at color attachment output stage
-        let wait_dst_stage_mask = [ash::vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
-        let command_buffers = [command_buffer.command_buffer];
-        let signals = [signal];
-
-        let submits = [ash::vk::SubmitInfo::builder()
-            .wait_semaphores(&waits)
-            .wait_dst_stage_mask(&wait_dst_stage_mask)
-            .command_buffers(&command_buffers)
-            .signal_semaphores(&signals)
-            .build()];
+        let chain = ChainedSubmit::chain(
+            &[command_buffer.command_buffer],
+            &[],
+            Some(wait),
+            Some(signal),
+        );
+        self.submit_chain(&chain, fence);
+    }
+
+    /// Submits `chain` with a single call, so that its submissions start in order
+    pub fn submit_chain(&self, chain: &[ChainedSubmit], fence: Option<&mut Fence>) {
+        let submits: Vec<vk::SubmitInfo> = chain
+            .iter()
+            .map(|submit| {
+                vk::SubmitInfo::builder()
+                    .wait_semaphores(&submit.waits)
+                    .wait_dst_stage_mask(&submit.wait_stages)
+                    .command_buffers(std::slice::from_ref(&submit.command_buffer))
+                    .signal_semaphores(&submit.signals)
+                    .build()
+            })
+            .collect();
 
         self.submit(&submits, fence);
     }
 
+    /// Presents `image_index`, tagging the request with `present_id` when VK_KHR_present_id
+    /// is enabled, so that it can be waited with VK_KHR_present_wait
     pub fn present(
         &self,
         image_index: u32,
         swapchain: &Swapchain,
         wait: vk::Semaphore,
+        present_id: Option<u64>,
     ) -> Result<(), ash::vk::Result> {
         let pres_image_indices = [image_index];
         let pres_swapchains = [swapchain.swapchain];
         let pres_semaphores = [wait];
-        let present_info = ash::vk::PresentInfoKHR::builder()
+        let mut present_info = ash::vk::PresentInfoKHR::builder()
             .image_indices(&pres_image_indices)
             .swapchains(&pres_swapchains)
             .wait_semaphores(&pres_semaphores);
 
+        let present_ids = [present_id.unwrap_or_default()];
+        let mut present_id_info = vk::PresentIdKHR::builder().present_ids(&present_ids);
+        if present_id.is_some() {
+            present_info = present_info.push_next(&mut present_id_info);
+        }
+
         let ret = unsafe { swapchain.ext.queue_present(self.queue, &present_info) };
 
         match ret {
@@ -83,3 +233,193 @@ impl Queue {
         }
     }
 }
+
+/// Submission of a command buffer which is part of a chain, where each submission waits
+/// for the previous one to complete through a semaphore
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainedSubmit {
+    pub command_buffer: vk::CommandBuffer,
+    pub waits: Vec<vk::Semaphore>,
+    pub wait_stages: Vec<vk::PipelineStageFlags>,
+    pub signals: Vec<vk::Semaphore>,
+}
+
+impl ChainedSubmit {
+    /// Returns the submissions of `command_buffers` in order, where `links[i]` is signaled by the
+    /// submission of `command_buffers[i]` and waited by the next one. Only the last submission,
+    /// which renders to the image, waits for `wait` at color attachment output stage, and it is
+    /// the one signaling `signal`.
+    pub fn chain(
+        command_buffers: &[vk::CommandBuffer],
+        links: &[vk::Semaphore],
+        wait: Option<vk::Semaphore>,
+        signal: Option<vk::Semaphore>,
+    ) -> Vec<Self> {
+        assert!(
+            !command_buffers.is_empty(),
+            "Failed to chain no command buffers"
+        );
+        assert_eq!(
+            links.len(),
+            command_buffers.len() - 1,
+            "Failed to chain command buffers: one link is needed between each of them"
+        );
+
+        let last = command_buffers.len() - 1;
+        let mut ret = Vec::with_capacity(command_buffers.len());
+        for (i, &command_buffer) in command_buffers.iter().enumerate() {
+            let mut submit = Self {
+                command_buffer,
+                waits: vec![],
+                wait_stages: vec![],
+                signals: vec![],
+            };
+
+            if i > 0 {
+                submit.waits.push(links[i - 1]);
+                submit
+                    .wait_stages
+                    .push(vk::PipelineStageFlags::ALL_COMMANDS);
+            }
+
+            if i == last {
+                if let Some(wait) = wait {
+                    submit.waits.push(wait);
+                    submit
+                        .wait_stages
+                        .push(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT);
+                }
+                submit.signals.extend(signal);
+            } else {
+                submit.signals.push(links[i]);
+            }
+
+            ret.push(submit);
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ash::vk::Handle;
+
+    #[test]
+    fn chained_submits() {
+        let command_buffers = [
+            vk::CommandBuffer::from_raw(1),
+            vk::CommandBuffer::from_raw(2),
+            vk::CommandBuffer::from_raw(3),
+        ];
+        let links = [vk::Semaphore::from_raw(10), vk::Semaphore::from_raw(11)];
+        let ready = vk::Semaphore::from_raw(20);
+        let drawn = vk::Semaphore::from_raw(21);
+
+        let chain = ChainedSubmit::chain(&command_buffers, &links, Some(ready), Some(drawn));
+        assert_eq!(chain.len(), 3);
+
+        // Submitted in the order they were recorded
+        for (submit, &command_buffer) in chain.iter().zip(command_buffers.iter()) {
+            assert_eq!(submit.command_buffer, command_buffer);
+            assert_eq!(submit.waits.len(), submit.wait_stages.len());
+        }
+
+        // Each waits for the previous one
+        assert!(chain[0].waits.is_empty());
+        assert_eq!(chain[0].signals, vec![links[0]]);
+        assert_eq!(chain[1].waits, vec![links[0]]);
+        assert_eq!(chain[1].signals, vec![links[1]]);
+
+        // The last renders to the image, and signals its completion
+        assert_eq!(chain[2].waits, vec![links[1], ready]);
+        assert_eq!(
+            chain[2].wait_stages[1],
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+        );
+        assert_eq!(chain[2].signals, vec![drawn]);
+
+        // A single command buffer without semaphores, as offscreen frames submit
+        let single = ChainedSubmit::chain(&command_buffers[..1], &[], None, None);
+        assert_eq!(single.len(), 1);
+        assert!(single[0].waits.is_empty());
+        assert!(single[0].signals.is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn chained_submits_missing_link() {
+        let command_buffers = [vk::CommandBuffer::null(), vk::CommandBuffer::null()];
+        ChainedSubmit::chain(&command_buffers, &[], None, None);
+    }
+
+    #[test]
+    fn ownership_transfer() {
+        assert!(QueueTransfer::new(0, 0).is_none());
+
+        let transfer = QueueTransfer::new(0, 2).unwrap();
+        let image = vk::Image::null();
+
+        let release = transfer.get_release_barrier(image);
+        let acquire = transfer.get_acquire_barrier(image);
+        for barrier in [release, acquire].iter() {
+            assert_eq!(barrier.src_queue_family_index, 0);
+            assert_eq!(barrier.dst_queue_family_index, 2);
+            assert_eq!(barrier.old_layout, vk::ImageLayout::PRESENT_SRC_KHR);
+            assert_eq!(barrier.new_layout, vk::ImageLayout::PRESENT_SRC_KHR);
+        }
+        assert_eq!(
+            release.src_access_mask,
+            vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+        );
+        assert_eq!(acquire.dst_access_mask, vk::AccessFlags::empty());
+    }
+
+    #[test]
+    fn upload_ownership_transfer() {
+        assert!(UploadTransfer::new(1, 1).is_none());
+
+        let transfer = UploadTransfer::new(2, 0).unwrap();
+        let barrier = vk::ImageMemoryBarrier::builder()
+            .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .build();
+
+        // Both halves perform the same layout transition
+        let release = transfer.get_release_barrier(&barrier);
+        let acquire = transfer.get_acquire_barrier(&barrier);
+        for barrier in [release, acquire].iter() {
+            assert_eq!(barrier.src_queue_family_index, 2);
+            assert_eq!(barrier.dst_queue_family_index, 0);
+            assert_eq!(barrier.old_layout, vk::ImageLayout::TRANSFER_DST_OPTIMAL);
+            assert_eq!(
+                barrier.new_layout,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+            );
+        }
+        assert_eq!(release.src_access_mask, vk::AccessFlags::TRANSFER_WRITE);
+        assert_eq!(release.dst_access_mask, vk::AccessFlags::empty());
+        assert_eq!(acquire.src_access_mask, vk::AccessFlags::empty());
+        assert_eq!(acquire.dst_access_mask, vk::AccessFlags::SHADER_READ);
+
+        let barrier = vk::BufferMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::MEMORY_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .size(vk::WHOLE_SIZE)
+            .build();
+        let release = transfer.get_release_buffer_barrier(&barrier);
+        let acquire = transfer.get_acquire_buffer_barrier(&barrier);
+        for barrier in [release, acquire].iter() {
+            assert_eq!(barrier.src_queue_family_index, 2);
+            assert_eq!(barrier.dst_queue_family_index, 0);
+            assert_eq!(barrier.size, vk::WHOLE_SIZE);
+        }
+        assert_eq!(release.dst_access_mask, vk::AccessFlags::empty());
+        assert_eq!(acquire.src_access_mask, vk::AccessFlags::empty());
+        assert_eq!(acquire.dst_access_mask, vk::AccessFlags::MEMORY_READ);
+    }
+}
@@ -6,37 +6,120 @@ use std::rc::Rc;
 
 use ash::*;
 
+use super::*;
+
+/// Parameters of a sampler
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplerInfo {
+    pub mag_filter: vk::Filter,
+    pub min_filter: vk::Filter,
+    /// How to blend between mip levels
+    pub mipmap_mode: vk::SamplerMipmapMode,
+    /// Used for all the texture coordinates, `REPEAT` tiles while `CLAMP_TO_EDGE` stretches borders
+    pub address_mode: vk::SamplerAddressMode,
+    /// Anisotropic filtering is enabled for values greater than 1
+    pub max_anisotropy: f32,
+    /// Lowest mip level which can be sampled
+    pub min_lod: f32,
+    /// Highest mip level which can be sampled
+    pub max_lod: f32,
+    /// Offset added to the computed mip level, positive values select lower resolution mips
+    pub mip_lod_bias: f32,
+}
+
+impl Default for SamplerInfo {
+    fn default() -> Self {
+        Self {
+            mag_filter: vk::Filter::NEAREST,
+            min_filter: vk::Filter::NEAREST,
+            mipmap_mode: vk::SamplerMipmapMode::LINEAR,
+            address_mode: vk::SamplerAddressMode::REPEAT,
+            max_anisotropy: 1.0,
+            min_lod: 0.0,
+            max_lod: 0.0,
+            mip_lod_bias: 0.0,
+        }
+    }
+}
+
+impl SamplerInfo {
+    /// Returns the create info of a sampler, with the LOD bias clamped to `max_lod_bias`
+    /// and anisotropy clamped to `max_anisotropy`, which should be 1 when it is not supported
+    pub fn get_create_info(&self, max_lod_bias: f32, max_anisotropy: f32) -> vk::SamplerCreateInfo {
+        assert!(self.min_lod <= self.max_lod);
+        let mip_lod_bias = self.mip_lod_bias.max(-max_lod_bias).min(max_lod_bias);
+        let anisotropy = self.max_anisotropy.min(max_anisotropy).max(1.0);
+
+        vk::SamplerCreateInfo::builder()
+            .mag_filter(self.mag_filter)
+            .min_filter(self.min_filter)
+            .address_mode_u(self.address_mode)
+            .address_mode_v(self.address_mode)
+            .address_mode_w(self.address_mode)
+            .anisotropy_enable(anisotropy > 1.0)
+            .max_anisotropy(anisotropy)
+            .border_color(vk::BorderColor::INT_OPAQUE_BLACK)
+            .unnormalized_coordinates(false)
+            .compare_enable(false)
+            .compare_op(vk::CompareOp::ALWAYS)
+            .mipmap_mode(self.mipmap_mode)
+            .mip_lod_bias(mip_lod_bias)
+            .min_lod(self.min_lod)
+            .max_lod(self.max_lod)
+            .build()
+    }
+
+    /// Returns these parameters with anisotropy set to `max_anisotropy`, trilinear filtering,
+    /// and no limit on the mip levels which can be sampled
+    pub fn with_quality_defaults(&self, max_anisotropy: f32) -> Self {
+        Self {
+            min_filter: vk::Filter::LINEAR,
+            mipmap_mode: vk::SamplerMipmapMode::LINEAR,
+            max_anisotropy,
+            max_lod: vk::LOD_CLAMP_NONE,
+            ..*self
+        }
+    }
+}
+
 pub struct Sampler {
     pub sampler: vk::Sampler,
+    pub info: SamplerInfo,
     device: Rc<Device>,
 }
 
 impl Sampler {
     pub fn new(device: &Rc<Device>) -> Self {
-        let device = device.clone();
+        let info = SamplerInfo::default();
+        // Default info has no bias nor anisotropy
+        Self::create(device, &info.get_create_info(0.0, 1.0), info)
+    }
 
-        let create_info = vk::SamplerCreateInfo::builder()
-            .mag_filter(vk::Filter::NEAREST)
-            .min_filter(vk::Filter::NEAREST)
-            .address_mode_u(vk::SamplerAddressMode::REPEAT)
-            .address_mode_v(vk::SamplerAddressMode::REPEAT)
-            .address_mode_w(vk::SamplerAddressMode::REPEAT)
-            .anisotropy_enable(false)
-            .max_anisotropy(1.0)
-            .border_color(vk::BorderColor::INT_OPAQUE_BLACK)
-            .unnormalized_coordinates(false)
-            .compare_enable(false)
-            .compare_op(vk::CompareOp::ALWAYS)
-            .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
-            .mip_lod_bias(0.0)
-            .min_lod(0.0)
-            .max_lod(0.0)
-            .build();
+    /// Creates a sampler with the parameters of `info`, where bias is limited by `maxSamplerLodBias`
+    /// and anisotropy by `maxSamplerAnisotropy`, or disabled when the device does not support it
+    pub fn with_info(dev: &Dev, info: &SamplerInfo) -> Self {
+        let limits = dev.get_properties().limits;
+        let create_info =
+            info.get_create_info(limits.max_sampler_lod_bias, dev.get_max_anisotropy());
+        Self::create(&dev.device, &create_info, *info)
+    }
+
+    /// Creates a sampler for material textures, see `Vkr::enable_quality_defaults`
+    pub fn material(dev: &Dev, info: &SamplerInfo) -> Self {
+        Self::with_info(dev, &dev.get_material_sampler_info(info))
+    }
+
+    fn create(device: &Rc<Device>, create_info: &vk::SamplerCreateInfo, info: SamplerInfo) -> Self {
+        let device = device.clone();
 
-        let sampler = unsafe { device.create_sampler(&create_info, None) }
+        let sampler = unsafe { device.create_sampler(create_info, None) }
             .expect("Failed to create Vulkan sampler");
 
-        Self { sampler, device }
+        Self {
+            sampler,
+            info,
+            device,
+        }
     }
 }
 
@@ -47,3 +130,92 @@ impl Drop for Sampler {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lod_clamp_and_bias() {
+        let info = SamplerInfo {
+            min_lod: 1.0,
+            max_lod: 4.0,
+            mip_lod_bias: 2.5,
+            ..Default::default()
+        };
+
+        let create_info = info.get_create_info(16.0, 1.0);
+        assert_eq!(create_info.min_lod, 1.0);
+        assert_eq!(create_info.max_lod, 4.0);
+        assert_eq!(create_info.mip_lod_bias, 2.5);
+
+        // Bias exceeding the device limit is clamped
+        let create_info = info.get_create_info(2.0, 1.0);
+        assert_eq!(create_info.mip_lod_bias, 2.0);
+        let negative = SamplerInfo {
+            mip_lod_bias: -8.0,
+            ..info
+        };
+        assert_eq!(negative.get_create_info(2.0, 1.0).mip_lod_bias, -2.0);
+    }
+
+    #[test]
+    fn anisotropy_clamp() {
+        let info = SamplerInfo {
+            min_filter: vk::Filter::LINEAR,
+            mipmap_mode: vk::SamplerMipmapMode::NEAREST,
+            address_mode: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            max_anisotropy: 16.0,
+            ..Default::default()
+        };
+
+        let create_info = info.get_create_info(0.0, 8.0);
+        assert_eq!(create_info.anisotropy_enable, vk::TRUE);
+        assert_eq!(create_info.max_anisotropy, 8.0);
+        assert_eq!(create_info.mipmap_mode, vk::SamplerMipmapMode::NEAREST);
+        assert_eq!(
+            create_info.address_mode_v,
+            vk::SamplerAddressMode::CLAMP_TO_EDGE
+        );
+
+        // Not supported
+        let create_info = info.get_create_info(0.0, 1.0);
+        assert_eq!(create_info.anisotropy_enable, vk::FALSE);
+        assert_eq!(create_info.max_anisotropy, 1.0);
+
+        // Default is disabled
+        let create_info = SamplerInfo::default().get_create_info(0.0, 16.0);
+        assert_eq!(create_info.anisotropy_enable, vk::FALSE);
+    }
+
+    #[test]
+    fn quality_defaults() {
+        let info = SamplerInfo {
+            mag_filter: vk::Filter::NEAREST,
+            mipmap_mode: vk::SamplerMipmapMode::NEAREST,
+            address_mode: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            ..Default::default()
+        };
+
+        // Device max anisotropy is 16
+        let quality = info.with_quality_defaults(16.0);
+        let create_info = quality.get_create_info(0.0, 16.0);
+        assert_eq!(create_info.anisotropy_enable, vk::TRUE);
+        assert_eq!(create_info.max_anisotropy, 16.0);
+        assert_eq!(create_info.min_filter, vk::Filter::LINEAR);
+        assert_eq!(create_info.mipmap_mode, vk::SamplerMipmapMode::LINEAR);
+        assert_eq!(create_info.max_lod, vk::LOD_CLAMP_NONE);
+
+        // Content choices are kept
+        assert_eq!(create_info.mag_filter, vk::Filter::NEAREST);
+        assert_eq!(
+            create_info.address_mode_u,
+            vk::SamplerAddressMode::CLAMP_TO_EDGE
+        );
+
+        // Anisotropy not supported
+        let create_info = info.with_quality_defaults(1.0).get_create_info(0.0, 1.0);
+        assert_eq!(create_info.anisotropy_enable, vk::FALSE);
+        assert_eq!(create_info.mipmap_mode, vk::SamplerMipmapMode::LINEAR);
+    }
+}
@@ -2,13 +2,324 @@
 // Author: Antonio Caggiano <info@antoniocaggiano.eu>
 // SPDX-License-Identifier: MIT
 
-use std::{ffi::CString, rc::Rc};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::CString,
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::SystemTime,
+};
 
 use ash::*;
 use byteorder::{ByteOrder, NativeEndian};
 
+use super::*;
+
+/// Numeric type of the components of a shader input
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ScalarKind {
+    Float,
+    Sint,
+    Uint,
+}
+
+/// Input variable of a vertex shader found through reflection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReflectedInput {
+    pub location: u32,
+    pub format: vk::Format,
+    /// Size in bytes
+    pub size: u32,
+}
+
+impl ReflectedInput {
+    /// Returns the format of a scalar or vector of `count` components of `kind` and `width`
+    fn get_format(kind: ScalarKind, width: u32, count: u32) -> Option<vk::Format> {
+        if width != 32 {
+            return None;
+        }
+        let formats = match kind {
+            ScalarKind::Float => [
+                vk::Format::R32_SFLOAT,
+                vk::Format::R32G32_SFLOAT,
+                vk::Format::R32G32B32_SFLOAT,
+                vk::Format::R32G32B32A32_SFLOAT,
+            ],
+            ScalarKind::Sint => [
+                vk::Format::R32_SINT,
+                vk::Format::R32G32_SINT,
+                vk::Format::R32G32B32_SINT,
+                vk::Format::R32G32B32A32_SINT,
+            ],
+            ScalarKind::Uint => [
+                vk::Format::R32_UINT,
+                vk::Format::R32G32_UINT,
+                vk::Format::R32G32B32_UINT,
+                vk::Format::R32G32B32A32_UINT,
+            ],
+        };
+        formats.get(count.checked_sub(1)? as usize).copied()
+    }
+}
+
+/// Vertex input bindings and attributes of a graphics pipeline
+#[derive(Debug, Clone, Default)]
+pub struct VertexInputLayout {
+    pub bindings: Vec<vk::VertexInputBindingDescription>,
+    pub attributes: Vec<vk::VertexInputAttributeDescription>,
+}
+
+impl VertexInputLayout {
+    /// Returns the layout declared by `T`, which overrides reflection
+    /// for interleaved, packed, or multiple vertex buffers
+    pub fn from_vertex_input<T: VertexInput>() -> Self {
+        Self {
+            bindings: T::get_bindings(),
+            attributes: T::get_attributes(),
+        }
+    }
+
+    /// Returns a layout with a single per-vertex binding, where `inputs` are tightly packed
+    /// in order of location
+    pub fn packed(inputs: &[ReflectedInput]) -> Self {
+        let mut offset = 0;
+        let attributes = inputs
+            .iter()
+            .map(|input| {
+                let attribute = vk::VertexInputAttributeDescription::builder()
+                    .binding(0)
+                    .location(input.location)
+                    .format(input.format)
+                    .offset(offset)
+                    .build();
+                offset += input.size;
+                attribute
+            })
+            .collect();
+
+        let bindings = if inputs.is_empty() {
+            vec![]
+        } else {
+            vec![vk::VertexInputBindingDescription::builder()
+                .binding(0)
+                .stride(offset)
+                .input_rate(vk::VertexInputRate::VERTEX)
+                .build()]
+        };
+
+        Self {
+            bindings,
+            attributes,
+        }
+    }
+
+    /// Returns the alignment of vertex attributes with `format`, which is the size of its
+    /// components, or the size of the whole format when components are packed together
+    pub fn get_format_alignment(format: vk::Format) -> vk::DeviceSize {
+        match format {
+            vk::Format::R8_UNORM
+            | vk::Format::R8_SNORM
+            | vk::Format::R8_UINT
+            | vk::Format::R8_SINT
+            | vk::Format::R8G8_UNORM
+            | vk::Format::R8G8_SNORM
+            | vk::Format::R8G8_UINT
+            | vk::Format::R8G8_SINT
+            | vk::Format::R8G8B8A8_UNORM
+            | vk::Format::R8G8B8A8_SNORM
+            | vk::Format::R8G8B8A8_UINT
+            | vk::Format::R8G8B8A8_SINT
+            | vk::Format::B8G8R8A8_UNORM => 1,
+            vk::Format::R16_UNORM
+            | vk::Format::R16_SNORM
+            | vk::Format::R16_UINT
+            | vk::Format::R16_SINT
+            | vk::Format::R16_SFLOAT
+            | vk::Format::R16G16_UNORM
+            | vk::Format::R16G16_SNORM
+            | vk::Format::R16G16_UINT
+            | vk::Format::R16G16_SINT
+            | vk::Format::R16G16_SFLOAT
+            | vk::Format::R16G16B16A16_UNORM
+            | vk::Format::R16G16B16A16_SNORM
+            | vk::Format::R16G16B16A16_UINT
+            | vk::Format::R16G16B16A16_SINT
+            | vk::Format::R16G16B16A16_SFLOAT => 2,
+            vk::Format::R64_SFLOAT
+            | vk::Format::R64G64_SFLOAT
+            | vk::Format::R64G64B64_SFLOAT
+            | vk::Format::R64G64B64A64_SFLOAT => 8,
+            // 32-bit components and packed formats
+            _ => 4,
+        }
+    }
+
+    /// Returns the alignment of the offset a vertex buffer should be bound at, indexed by binding.
+    /// Attributes are fetched at the offset of the buffer plus their own offset, hence it is
+    /// the largest alignment among the formats of the attributes of a binding.
+    pub fn get_binding_alignments(&self) -> Vec<vk::DeviceSize> {
+        let count = self
+            .bindings
+            .iter()
+            .map(|binding| binding.binding as usize + 1)
+            .max()
+            .unwrap_or(0);
+        let mut alignments = vec![1; count];
+        for attribute in &self.attributes {
+            let alignment = &mut alignments[attribute.binding as usize];
+            *alignment = (*alignment).max(Self::get_format_alignment(attribute.format));
+        }
+        alignments
+    }
+}
+
+/// Descriptor used by a shader entry point found through reflection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReflectedBinding {
+    pub set: u32,
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    pub count: u32,
+    pub stage_flags: vk::ShaderStageFlags,
+}
+
+impl ReflectedBinding {
+    /// Combines the bindings of different stages, so that a binding used by more stages
+    /// is visible to all of them. The result is ordered by set and binding.
+    pub fn merge<I: IntoIterator<Item = ReflectedBinding>>(bindings: I) -> Vec<Self> {
+        let mut merged: Vec<ReflectedBinding> = vec![];
+        for binding in bindings {
+            match merged
+                .iter_mut()
+                .find(|b| b.set == binding.set && b.binding == binding.binding)
+            {
+                Some(existing) => {
+                    assert_eq!(
+                        existing.descriptor_type, binding.descriptor_type,
+                        "Descriptor type mismatch at set {} binding {}",
+                        binding.set, binding.binding
+                    );
+                    existing.stage_flags |= binding.stage_flags;
+                    existing.count = existing.count.max(binding.count);
+                }
+                None => merged.push(binding),
+            }
+        }
+        merged.sort_by_key(|binding| (binding.set, binding.binding));
+        merged
+    }
+
+    /// Returns the layout bindings of `set` among `bindings`
+    pub fn get_set_layout_bindings(
+        bindings: &[ReflectedBinding],
+        set: u32,
+    ) -> Vec<vk::DescriptorSetLayoutBinding> {
+        bindings
+            .iter()
+            .filter(|binding| binding.set == set)
+            .map(|binding| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding.binding)
+                    .descriptor_type(binding.descriptor_type)
+                    .descriptor_count(binding.count)
+                    .stage_flags(binding.stage_flags)
+                    .build()
+            })
+            .collect()
+    }
+}
+
+/// Entry points of the stages of a graphics pipeline, named after a common prefix followed by
+/// `_vs`, `_tcs`, `_tes`, `_gs`, or `_fs`. The strings should be alive until the pipeline
+/// has been created.
+pub struct StageEntryPoints {
+    names: Vec<(vk::ShaderStageFlags, CString)>,
+}
+
+impl StageEntryPoints {
+    /// Suffixes of the entry points of each stage, in pipeline order
+    const SUFFIXES: [(&'static str, vk::ShaderStageFlags); 5] = [
+        ("_vs", vk::ShaderStageFlags::VERTEX),
+        ("_tcs", vk::ShaderStageFlags::TESSELLATION_CONTROL),
+        ("_tes", vk::ShaderStageFlags::TESSELLATION_EVALUATION),
+        ("_gs", vk::ShaderStageFlags::GEOMETRY),
+        ("_fs", vk::ShaderStageFlags::FRAGMENT),
+    ];
+
+    /// Returns the stages of `prefix` whose entry points are among `entry_points`
+    pub fn find(prefix: &str, entry_points: &[String]) -> Self {
+        let names = Self::SUFFIXES
+            .iter()
+            .map(|(suffix, stage)| (*stage, format!("{}{}", prefix, suffix)))
+            .filter(|(_, name)| entry_points.contains(name))
+            .map(|(stage, name)| {
+                (
+                    stage,
+                    CString::new(name).expect("Failed to create entrypoint"),
+                )
+            })
+            .collect();
+        Self { names }
+    }
+
+    pub fn get_stage_flags(&self) -> Vec<vk::ShaderStageFlags> {
+        self.names.iter().map(|(stage, _)| *stage).collect()
+    }
+
+    pub fn has_tessellation(&self) -> bool {
+        self.get_stage_flags()
+            .contains(&vk::ShaderStageFlags::TESSELLATION_CONTROL)
+    }
+
+    /// Returns the create infos of the stages of `module`, which should be the one
+    /// the entry points were found in
+    pub fn get_stages(&self, module: &ShaderModule) -> Vec<vk::PipelineShaderStageCreateInfo> {
+        self.names
+            .iter()
+            .map(|(stage, name)| module.get_stage(name, *stage))
+            .collect()
+    }
+}
+
+/// Watches a SPIR-V file by polling its modification time, so that shaders can be reloaded
+/// when it is rebuilt
+pub struct ShaderWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl ShaderWatcher {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let modified = Self::get_modified(&path);
+        Self { path, modified }
+    }
+
+    fn get_modified(path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns whether the file has been modified since the previous poll, or creation
+    pub fn poll(&mut self) -> bool {
+        let modified = Self::get_modified(&self.path);
+        if modified.is_none() || modified == self.modified {
+            return false;
+        }
+        self.modified = modified;
+        true
+    }
+}
+
 pub struct ShaderModule {
     shader: vk::ShaderModule,
+    /// SPIR-V words, kept around for reflection
+    code: Vec<u32>,
     device: Rc<Device>,
 }
 
@@ -25,7 +336,31 @@ impl ShaderModule {
         let shader = unsafe { device.create_shader_module(&create_info, None) }
             .expect("Failed to create Vulkan shader module");
 
-        Self { shader, device }
+        Self {
+            shader,
+            code,
+            device,
+        }
+    }
+
+    /// Loads a SPIR-V module at runtime, for example to iterate on shaders without rebuilding
+    pub fn from_path<P: AsRef<Path>>(device: &Rc<Device>, path: P) -> std::io::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        if !Self::is_spirv(&bytes) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Not a SPIR-V module",
+            ));
+        }
+        Ok(Self::new(device, &bytes))
+    }
+
+    /// Returns whether `bytes` are words starting with the SPIR-V magic number
+    fn is_spirv(bytes: &[u8]) -> bool {
+        const MAGIC: u32 = 0x07230203;
+        bytes.len() >= 20
+            && bytes.len() % std::mem::size_of::<u32>() == 0
+            && NativeEndian::read_u32(bytes) == MAGIC
     }
 
     pub fn main(device: &Rc<Device>) -> Self {
@@ -38,6 +373,12 @@ impl ShaderModule {
         Self::new(device, SHADERS)
     }
 
+    /// Shaders needing the multiview device feature
+    pub fn multiview(device: &Rc<Device>) -> Self {
+        const SHADERS: &[u8] = include_bytes!(env!("vkr_multiview_shaders.spv"));
+        Self::new(device, SHADERS)
+    }
+
     /// The entrypoint c string should be alive until the pipeline has been created
     pub fn get_stage(
         &self,
@@ -58,6 +399,377 @@ impl ShaderModule {
     pub fn get_frag(&self, entrypoint: &CString) -> vk::PipelineShaderStageCreateInfo {
         self.get_stage(entrypoint, vk::ShaderStageFlags::FRAGMENT)
     }
+
+    pub fn get_geom(&self, entrypoint: &CString) -> vk::PipelineShaderStageCreateInfo {
+        self.get_stage(entrypoint, vk::ShaderStageFlags::GEOMETRY)
+    }
+
+    pub fn get_tesc(&self, entrypoint: &CString) -> vk::PipelineShaderStageCreateInfo {
+        self.get_stage(entrypoint, vk::ShaderStageFlags::TESSELLATION_CONTROL)
+    }
+
+    pub fn get_tese(&self, entrypoint: &CString) -> vk::PipelineShaderStageCreateInfo {
+        self.get_stage(entrypoint, vk::ShaderStageFlags::TESSELLATION_EVALUATION)
+    }
+
+    /// Returns the names of the entry points of this module
+    pub fn get_entry_points(&self) -> Vec<String> {
+        Self::reflect_entry_points(&self.code)
+    }
+
+    /// Returns the entry points of the stages named `prefix` followed by a stage suffix
+    pub fn find_stages(&self, prefix: &str) -> StageEntryPoints {
+        StageEntryPoints::find(prefix, &self.get_entry_points())
+    }
+
+    pub fn get_comp(&self, entrypoint: &CString) -> vk::PipelineShaderStageCreateInfo {
+        self.get_stage(entrypoint, vk::ShaderStageFlags::COMPUTE)
+    }
+
+    /// Returns the workgroup size declared by a compute `entrypoint`
+    pub fn get_local_size(&self, entrypoint: &str) -> Option<[u32; 3]> {
+        Self::reflect_local_size(&self.code, entrypoint)
+    }
+
+    /// Returns the vertex inputs of a vertex `entrypoint`, ordered by location, see `reflect_inputs`
+    pub fn get_vertex_inputs(&self, entrypoint: &str) -> Vec<ReflectedInput> {
+        Self::reflect_inputs(&self.code, entrypoint)
+    }
+
+    /// Returns a vertex input layout generated from the inputs of a vertex `entrypoint`,
+    /// with a single binding and tightly packed attributes, see `VertexInputLayout::packed`
+    pub fn get_vertex_input_layout(&self, entrypoint: &str) -> VertexInputLayout {
+        VertexInputLayout::packed(&self.get_vertex_inputs(entrypoint))
+    }
+
+    /// Returns the descriptors used by `entrypoint`, ordered by set and binding, with the stage
+    /// of its execution model. Bindings of the stages of a pipeline can be combined with
+    /// `ReflectedBinding::merge`.
+    pub fn get_bindings(&self, entrypoint: &str) -> Vec<ReflectedBinding> {
+        Self::reflect_bindings(&self.code, entrypoint)
+    }
+
+    /// Returns opcode and operands of each instruction following the SPIR-V header
+    fn get_instructions(code: &[u32]) -> Vec<(u32, &[u32])> {
+        const HEADER_LEN: usize = 5;
+
+        let mut instructions = vec![];
+        let mut i = HEADER_LEN;
+        while i < code.len() {
+            let word_count = (code[i] >> 16) as usize;
+            let opcode = code[i] & 0xffff;
+            if word_count == 0 || i + word_count > code.len() {
+                break;
+            }
+            instructions.push((opcode, &code[i + 1..i + word_count]));
+            i += word_count;
+        }
+        instructions
+    }
+
+    /// Returns the nul-terminated name starting at the first of `words`
+    fn decode_name(words: &[u32]) -> String {
+        let bytes: Vec<u8> = words
+            .iter()
+            .flat_map(|word| word.to_le_bytes().to_vec())
+            .take_while(|&byte| byte != 0)
+            .collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    /// Looks for the names of all the `OpEntryPoint`s
+    fn reflect_entry_points(code: &[u32]) -> Vec<String> {
+        const OP_ENTRY_POINT: u32 = 15;
+
+        Self::get_instructions(code)
+            .into_iter()
+            .filter(|(opcode, operands)| *opcode == OP_ENTRY_POINT && operands.len() > 2)
+            .map(|(_, operands)| Self::decode_name(&operands[2..]))
+            .collect()
+    }
+
+    /// Returns the interface variable ids from the operands of an `OpEntryPoint`,
+    /// when it names `entrypoint`
+    fn parse_entry_point<'a>(operands: &'a [u32], entrypoint: &str) -> Option<&'a [u32]> {
+        if operands.len() <= 2 {
+            return None;
+        }
+        // Execution model, function id, nul-terminated name, interface ids
+        let name = Self::decode_name(&operands[2..]);
+        if name != entrypoint {
+            return None;
+        }
+        let name_words = (name.len() + 1 + 3) / 4;
+        operands.get(2 + name_words..)
+    }
+
+    /// Looks for the `OpExecutionMode LocalSize` of the entry point named `entrypoint`
+    fn reflect_local_size(code: &[u32], entrypoint: &str) -> Option<[u32; 3]> {
+        const OP_ENTRY_POINT: u32 = 15;
+        const OP_EXECUTION_MODE: u32 = 16;
+        const EXECUTION_MODE_LOCAL_SIZE: u32 = 17;
+
+        let mut function_id = None;
+
+        for (opcode, operands) in Self::get_instructions(code) {
+            if opcode == OP_ENTRY_POINT && Self::parse_entry_point(operands, entrypoint).is_some() {
+                function_id = Some(operands[1]);
+            } else if opcode == OP_EXECUTION_MODE && operands.len() == 5 {
+                // Function id, mode, x, y, z
+                if function_id == Some(operands[0]) && operands[1] == EXECUTION_MODE_LOCAL_SIZE {
+                    return Some([operands[2], operands[3], operands[4]]);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Looks for the input variables of the entry point named `entrypoint` decorated with
+    /// a location, skipping built-ins. Only 32-bit scalars and vectors are supported.
+    fn reflect_inputs(code: &[u32], entrypoint: &str) -> Vec<ReflectedInput> {
+        const OP_ENTRY_POINT: u32 = 15;
+        const OP_TYPE_INT: u32 = 21;
+        const OP_TYPE_FLOAT: u32 = 22;
+        const OP_TYPE_VECTOR: u32 = 23;
+        const OP_TYPE_POINTER: u32 = 32;
+        const OP_VARIABLE: u32 = 59;
+        const OP_DECORATE: u32 = 71;
+        const DECORATION_LOCATION: u32 = 30;
+        const STORAGE_CLASS_INPUT: u32 = 1;
+
+        let mut interface: &[u32] = &[];
+        let mut locations = HashMap::new();
+        // Scalar types by id, with their kind and width
+        let mut scalars = HashMap::new();
+        // Vector types by id, with their component type and count
+        let mut vectors = HashMap::new();
+        // Pointee types by pointer type id
+        let mut pointers = HashMap::new();
+        // Pointer types by input variable id
+        let mut variables = HashMap::new();
+
+        for (opcode, operands) in Self::get_instructions(code) {
+            match (opcode, operands) {
+                (OP_ENTRY_POINT, _) => {
+                    if let Some(ids) = Self::parse_entry_point(operands, entrypoint) {
+                        interface = ids;
+                    }
+                }
+                (OP_DECORATE, [target, DECORATION_LOCATION, location]) => {
+                    locations.insert(*target, *location);
+                }
+                (OP_TYPE_INT, [id, width, signedness]) => {
+                    let kind = if *signedness == 0 {
+                        ScalarKind::Uint
+                    } else {
+                        ScalarKind::Sint
+                    };
+                    scalars.insert(*id, (kind, *width));
+                }
+                (OP_TYPE_FLOAT, [id, width]) => {
+                    scalars.insert(*id, (ScalarKind::Float, *width));
+                }
+                (OP_TYPE_VECTOR, [id, component, count]) => {
+                    vectors.insert(*id, (*component, *count));
+                }
+                (OP_TYPE_POINTER, [id, _storage, pointee]) => {
+                    pointers.insert(*id, *pointee);
+                }
+                (OP_VARIABLE, [pointer, id, STORAGE_CLASS_INPUT, ..]) => {
+                    variables.insert(*id, *pointer);
+                }
+                _ => (),
+            }
+        }
+
+        let mut inputs: Vec<ReflectedInput> = interface
+            .iter()
+            .filter_map(|id| {
+                // Built-ins have no location
+                let location = *locations.get(id)?;
+                let pointee = *pointers.get(variables.get(id)?)?;
+                let (component, count) = vectors.get(&pointee).copied().unwrap_or((pointee, 1));
+                let (kind, width) = *scalars.get(&component)?;
+                let format = ReflectedInput::get_format(kind, width, count)?;
+                Some(ReflectedInput {
+                    location,
+                    format,
+                    size: width / 8 * count,
+                })
+            })
+            .collect();
+        inputs.sort_by_key(|input| input.location);
+        inputs
+    }
+
+    /// Looks for the descriptors used by the entry point named `entrypoint`, either directly
+    /// or through the functions it calls, with the stage of its execution model
+    fn reflect_bindings(code: &[u32], entrypoint: &str) -> Vec<ReflectedBinding> {
+        const OP_ENTRY_POINT: u32 = 15;
+        const OP_TYPE_IMAGE: u32 = 25;
+        const OP_TYPE_SAMPLER: u32 = 26;
+        const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+        const OP_TYPE_ARRAY: u32 = 28;
+        const OP_TYPE_STRUCT: u32 = 30;
+        const OP_TYPE_POINTER: u32 = 32;
+        const OP_CONSTANT: u32 = 43;
+        const OP_FUNCTION: u32 = 54;
+        const OP_FUNCTION_END: u32 = 56;
+        const OP_FUNCTION_CALL: u32 = 57;
+        const OP_VARIABLE: u32 = 59;
+        const OP_DECORATE: u32 = 71;
+        const DECORATION_BUFFER_BLOCK: u32 = 3;
+        const DECORATION_BINDING: u32 = 33;
+        const DECORATION_DESCRIPTOR_SET: u32 = 34;
+        const STORAGE_CLASS_UNIFORM_CONSTANT: u32 = 0;
+        const STORAGE_CLASS_UNIFORM: u32 = 2;
+        const STORAGE_CLASS_STORAGE_BUFFER: u32 = 12;
+        const DIM_BUFFER: u32 = 5;
+        const DIM_SUBPASS_DATA: u32 = 6;
+
+        let mut entry = None;
+        let mut sets = HashMap::new();
+        let mut bindings = HashMap::new();
+        let mut buffer_blocks = HashSet::new();
+        let mut constants = HashMap::new();
+        // Descriptor types of images, samplers, and structs by type id
+        let mut types = HashMap::new();
+        let mut structs = HashSet::new();
+        // Element types and length ids of arrays
+        let mut arrays = HashMap::new();
+        let mut pointers = HashMap::new();
+        // Pointer types and storage classes of global variables
+        let mut variables = HashMap::new();
+        // Ids referenced by each function, and the functions it calls
+        let mut function = None;
+        let mut used: HashMap<u32, HashSet<u32>> = HashMap::new();
+        let mut calls: HashMap<u32, Vec<u32>> = HashMap::new();
+
+        for (opcode, operands) in Self::get_instructions(code) {
+            if let Some(function) = function {
+                if let (OP_FUNCTION_CALL, [_, _, callee, ..]) = (opcode, operands) {
+                    calls.entry(function).or_default().push(*callee);
+                }
+                used.entry(function).or_default().extend(operands);
+            }
+            match (opcode, operands) {
+                (OP_ENTRY_POINT, [model, id, ..]) => {
+                    if Self::parse_entry_point(operands, entrypoint).is_some() {
+                        entry = Some((*model, *id));
+                    }
+                }
+                (OP_DECORATE, [target, DECORATION_DESCRIPTOR_SET, set]) => {
+                    sets.insert(*target, *set);
+                }
+                (OP_DECORATE, [target, DECORATION_BINDING, binding]) => {
+                    bindings.insert(*target, *binding);
+                }
+                (OP_DECORATE, [target, DECORATION_BUFFER_BLOCK]) => {
+                    buffer_blocks.insert(*target);
+                }
+                (OP_CONSTANT, [_, id, value, ..]) => {
+                    constants.insert(*id, *value);
+                }
+                (OP_TYPE_IMAGE, [id, _, dim, _, _, _, sampled, ..]) => {
+                    let descriptor_type = match (*dim, *sampled) {
+                        (DIM_SUBPASS_DATA, _) => vk::DescriptorType::INPUT_ATTACHMENT,
+                        (DIM_BUFFER, 2) => vk::DescriptorType::STORAGE_TEXEL_BUFFER,
+                        (DIM_BUFFER, _) => vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+                        (_, 2) => vk::DescriptorType::STORAGE_IMAGE,
+                        _ => vk::DescriptorType::SAMPLED_IMAGE,
+                    };
+                    types.insert(*id, descriptor_type);
+                }
+                (OP_TYPE_SAMPLER, [id]) => {
+                    types.insert(*id, vk::DescriptorType::SAMPLER);
+                }
+                (OP_TYPE_SAMPLED_IMAGE, [id, _]) => {
+                    types.insert(*id, vk::DescriptorType::COMBINED_IMAGE_SAMPLER);
+                }
+                (OP_TYPE_ARRAY, [id, element, length]) => {
+                    arrays.insert(*id, (*element, *length));
+                }
+                (OP_TYPE_STRUCT, [id, ..]) => {
+                    structs.insert(*id);
+                }
+                (OP_TYPE_POINTER, [id, _storage, pointee]) => {
+                    pointers.insert(*id, *pointee);
+                }
+                (OP_VARIABLE, [pointer, id, storage, ..]) if function.is_none() => {
+                    variables.insert(*id, (*pointer, *storage));
+                }
+                (OP_FUNCTION, [_, id, ..]) => function = Some(*id),
+                (OP_FUNCTION_END, _) => function = None,
+                _ => (),
+            }
+        }
+
+        let (model, entry_function) = match entry {
+            Some(entry) => entry,
+            None => return vec![],
+        };
+        let stage_flags = match model {
+            0 => vk::ShaderStageFlags::VERTEX,
+            1 => vk::ShaderStageFlags::TESSELLATION_CONTROL,
+            2 => vk::ShaderStageFlags::TESSELLATION_EVALUATION,
+            3 => vk::ShaderStageFlags::GEOMETRY,
+            4 => vk::ShaderStageFlags::FRAGMENT,
+            5 => vk::ShaderStageFlags::COMPUTE,
+            _ => return vec![],
+        };
+
+        // Ids referenced by the entry point and the functions it reaches
+        let mut reached = HashSet::new();
+        let mut ids = HashSet::new();
+        let mut pending = vec![entry_function];
+        while let Some(function) = pending.pop() {
+            if !reached.insert(function) {
+                continue;
+            }
+            if let Some(used) = used.get(&function) {
+                ids.extend(used);
+            }
+            if let Some(callees) = calls.get(&function) {
+                pending.extend(callees);
+            }
+        }
+
+        let mut reflected: Vec<ReflectedBinding> = variables
+            .iter()
+            .filter(|(id, _)| ids.contains(id))
+            .filter_map(|(id, (pointer, storage))| {
+                let set = *sets.get(id)?;
+                let binding = *bindings.get(id)?;
+                let mut pointee = *pointers.get(pointer)?;
+                let mut count = 1;
+                if let Some((element, length)) = arrays.get(&pointee) {
+                    count = *constants.get(length)?;
+                    pointee = *element;
+                }
+                let descriptor_type = match *storage {
+                    STORAGE_CLASS_UNIFORM_CONSTANT => *types.get(&pointee)?,
+                    STORAGE_CLASS_UNIFORM if buffer_blocks.contains(&pointee) => {
+                        vk::DescriptorType::STORAGE_BUFFER
+                    }
+                    STORAGE_CLASS_UNIFORM if structs.contains(&pointee) => {
+                        vk::DescriptorType::UNIFORM_BUFFER
+                    }
+                    STORAGE_CLASS_STORAGE_BUFFER => vk::DescriptorType::STORAGE_BUFFER,
+                    _ => return None,
+                };
+                Some(ReflectedBinding {
+                    set,
+                    binding,
+                    descriptor_type,
+                    count,
+                    stage_flags,
+                })
+            })
+            .collect();
+        reflected.sort_by_key(|binding| (binding.set, binding.binding));
+        reflected
+    }
 }
 
 impl Drop for ShaderModule {
@@ -67,3 +779,301 @@ impl Drop for ShaderModule {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode_name(name: &str) -> Vec<u32> {
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.push(0);
+        while bytes.len() % 4 != 0 {
+            bytes.push(0);
+        }
+        bytes
+            .chunks(4)
+            .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect()
+    }
+
+    fn entry_point(id: u32, name: &str) -> Vec<u32> {
+        let name = encode_name(name);
+        // GLCompute execution model
+        let mut words = vec![((3 + name.len() as u32) << 16) | 15, 5, id];
+        words.extend(name);
+        words
+    }
+
+    #[test]
+    fn reflect_local_size() {
+        let mut code = vec![0x07230203, 0x00010000, 0, 16, 0];
+        code.extend(entry_point(4, "other_cs"));
+        code.extend(entry_point(8, "main_cs"));
+        code.extend(&[(6 << 16) | 16, 4, 17, 8, 8, 1]);
+        code.extend(&[(6 << 16) | 16, 8, 17, 32, 32, 1]);
+
+        assert_eq!(
+            ShaderModule::reflect_local_size(&code, "main_cs"),
+            Some([32, 32, 1])
+        );
+        assert_eq!(
+            ShaderModule::reflect_local_size(&code, "other_cs"),
+            Some([8, 8, 1])
+        );
+        assert_eq!(ShaderModule::reflect_local_size(&code, "missing"), None);
+    }
+
+    fn instruction(opcode: u32, operands: &[u32]) -> Vec<u32> {
+        let mut words = vec![((1 + operands.len() as u32) << 16) | opcode];
+        words.extend(operands);
+        words
+    }
+
+    #[test]
+    fn reflect_vertex_inputs() {
+        let mut code = vec![0x07230203, 0x00010000, 0, 32, 0];
+        // Vertex execution model, with interface variables 10, 11, 12, 13
+        let mut entry = vec![0, 5];
+        entry.extend(encode_name("main_vs"));
+        entry.extend(&[10, 11, 12, 13]);
+        code.extend(instruction(15, &entry));
+
+        // Decorations: locations, and a built-in
+        code.extend(instruction(71, &[10, 30, 0]));
+        code.extend(instruction(71, &[11, 30, 1]));
+        code.extend(instruction(71, &[12, 11, 42]));
+        code.extend(instruction(71, &[13, 30, 0]));
+
+        // float, vec3, vec2, uint and their pointers
+        code.extend(instruction(22, &[1, 32]));
+        code.extend(instruction(23, &[2, 1, 3]));
+        code.extend(instruction(23, &[4, 1, 2]));
+        code.extend(instruction(21, &[6, 32, 0]));
+        code.extend(instruction(32, &[3, 1, 2]));
+        code.extend(instruction(32, &[5, 1, 4]));
+        code.extend(instruction(32, &[7, 1, 6]));
+        code.extend(instruction(32, &[8, 3, 2]));
+
+        // Inputs, declared out of location order, and an output
+        code.extend(instruction(59, &[5, 11, 1]));
+        code.extend(instruction(59, &[3, 10, 1]));
+        code.extend(instruction(59, &[7, 12, 1]));
+        code.extend(instruction(59, &[8, 13, 3]));
+
+        let inputs = ShaderModule::reflect_inputs(&code, "main_vs");
+        assert_eq!(
+            inputs,
+            vec![
+                ReflectedInput {
+                    location: 0,
+                    format: vk::Format::R32G32B32_SFLOAT,
+                    size: 12,
+                },
+                ReflectedInput {
+                    location: 1,
+                    format: vk::Format::R32G32_SFLOAT,
+                    size: 8,
+                },
+            ]
+        );
+        assert!(ShaderModule::reflect_inputs(&code, "other_vs").is_empty());
+
+        let layout = VertexInputLayout::packed(&inputs);
+        assert_eq!(layout.bindings.len(), 1);
+        assert_eq!(layout.bindings[0].stride, 20);
+        let offsets: Vec<u32> = layout.attributes.iter().map(|a| a.offset).collect();
+        assert_eq!(offsets, vec![0, 12]);
+    }
+
+    #[test]
+    fn binding_alignments() {
+        // Half float texture coordinates are fetched along 32-bit positions,
+        // while morph deltas and second texture coordinates have their own streams
+        let layout = VertexInputLayout::from_vertex_input::<PackedVertex>();
+        assert_eq!(layout.get_binding_alignments(), vec![4, 4, 4]);
+
+        let attribute = |binding, format| {
+            vk::VertexInputAttributeDescription::builder()
+                .binding(binding)
+                .format(format)
+                .build()
+        };
+        let binding = |binding| {
+            vk::VertexInputBindingDescription::builder()
+                .binding(binding)
+                .build()
+        };
+        let layout = VertexInputLayout {
+            bindings: vec![binding(0), binding(1)],
+            attributes: vec![
+                attribute(0, vk::Format::R16G16_SFLOAT),
+                attribute(0, vk::Format::R8G8B8A8_UNORM),
+                attribute(1, vk::Format::A2B10G10R10_SNORM_PACK32),
+            ],
+        };
+        assert_eq!(layout.get_binding_alignments(), vec![2, 4]);
+    }
+
+    #[test]
+    fn reflect_bindings() {
+        let mut code = vec![0x07230203, 0x00010000, 0, 64, 0];
+        // Vertex and fragment entry points
+        let mut vertex = vec![0, 40];
+        vertex.extend(encode_name("main_vs"));
+        code.extend(instruction(15, &vertex));
+        let mut fragment = vec![4, 41];
+        fragment.extend(encode_name("main_fs"));
+        code.extend(instruction(15, &fragment));
+
+        // Uniform at set 0 binding 0, combined image sampler at set 0 binding 1,
+        // input attachment at set 1 binding 0, unused uniform at set 2 binding 0
+        for (variable, set, binding) in &[(10, 0, 0), (11, 0, 1), (12, 1, 0), (13, 2, 0)] {
+            code.extend(instruction(71, &[*variable, 34, *set]));
+            code.extend(instruction(71, &[*variable, 33, *binding]));
+        }
+
+        // float, struct, image, sampled image, subpass image, and their pointers
+        code.extend(instruction(22, &[1, 32]));
+        code.extend(instruction(30, &[2, 1]));
+        code.extend(instruction(25, &[3, 1, 1, 0, 0, 0, 1, 0]));
+        code.extend(instruction(27, &[4, 3]));
+        code.extend(instruction(25, &[5, 1, 6, 0, 0, 0, 2, 0]));
+        code.extend(instruction(32, &[6, 2, 2]));
+        code.extend(instruction(32, &[7, 0, 4]));
+        code.extend(instruction(32, &[8, 0, 5]));
+
+        code.extend(instruction(59, &[6, 10, 2]));
+        code.extend(instruction(59, &[7, 11, 0]));
+        code.extend(instruction(59, &[8, 12, 0]));
+        code.extend(instruction(59, &[6, 13, 2]));
+
+        // Vertex function loads the uniform
+        code.extend(instruction(54, &[1, 40, 0, 9]));
+        code.extend(instruction(248, &[50]));
+        code.extend(instruction(61, &[2, 51, 10]));
+        code.extend(instruction(253, &[]));
+        code.extend(instruction(56, &[]));
+
+        // Fragment function loads the image, and calls a function loading the others
+        code.extend(instruction(54, &[1, 41, 0, 9]));
+        code.extend(instruction(248, &[52]));
+        code.extend(instruction(61, &[4, 53, 11]));
+        code.extend(instruction(57, &[1, 54, 42]));
+        code.extend(instruction(253, &[]));
+        code.extend(instruction(56, &[]));
+        code.extend(instruction(54, &[1, 42, 0, 9]));
+        code.extend(instruction(248, &[55]));
+        code.extend(instruction(61, &[2, 56, 10]));
+        code.extend(instruction(61, &[5, 57, 12]));
+        code.extend(instruction(253, &[]));
+        code.extend(instruction(56, &[]));
+
+        let vertex = ShaderModule::reflect_bindings(&code, "main_vs");
+        assert_eq!(vertex.len(), 1);
+        assert_eq!(
+            vertex[0].descriptor_type,
+            vk::DescriptorType::UNIFORM_BUFFER
+        );
+        assert_eq!(vertex[0].stage_flags, vk::ShaderStageFlags::VERTEX);
+
+        let fragment = ShaderModule::reflect_bindings(&code, "main_fs");
+        let types: Vec<vk::DescriptorType> = fragment.iter().map(|b| b.descriptor_type).collect();
+        assert_eq!(
+            types,
+            vec![
+                vk::DescriptorType::UNIFORM_BUFFER,
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                vk::DescriptorType::INPUT_ATTACHMENT,
+            ]
+        );
+        assert!(fragment
+            .iter()
+            .all(|b| b.stage_flags == vk::ShaderStageFlags::FRAGMENT));
+
+        let merged = ReflectedBinding::merge(vertex.into_iter().chain(fragment));
+        assert_eq!(merged.len(), 3);
+        assert_eq!(
+            merged[0].stage_flags,
+            vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT
+        );
+
+        let set_0 = ReflectedBinding::get_set_layout_bindings(&merged, 0);
+        assert_eq!(set_0.len(), 2);
+        assert_eq!(set_0[1].stage_flags, vk::ShaderStageFlags::FRAGMENT);
+        assert_eq!(
+            ReflectedBinding::get_set_layout_bindings(&merged, 2).len(),
+            0
+        );
+    }
+
+    #[test]
+    fn shader_watcher() {
+        let path = std::env::temp_dir().join(format!("vkr-watcher-{}.spv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // A missing file is not a change
+        let mut watcher = ShaderWatcher::new(&path);
+        assert!(!watcher.poll());
+
+        let mut bytes = vec![];
+        for word in &[0x07230203u32, 0x00010000, 0, 1, 0] {
+            bytes.extend(&word.to_ne_bytes());
+        }
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(ShaderModule::is_spirv(&std::fs::read(&path).unwrap()));
+        assert!(watcher.poll());
+        // Only once for each modification
+        assert!(!watcher.poll());
+
+        assert!(!ShaderModule::is_spirv(&bytes[..18]));
+        assert!(!ShaderModule::is_spirv(&[0; 20]));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(!watcher.poll());
+    }
+
+    #[test]
+    fn stage_entry_points() {
+        let mut code = vec![0x07230203, 0x00010000, 0, 16, 0];
+        for (id, name) in &[
+            (1, "wire_vs"),
+            (2, "wire_gs"),
+            (3, "wire_fs"),
+            (4, "main_vs"),
+        ] {
+            code.extend(entry_point(*id, name));
+        }
+        let entry_points = ShaderModule::reflect_entry_points(&code);
+        assert_eq!(
+            entry_points,
+            vec!["wire_vs", "wire_gs", "wire_fs", "main_vs"]
+        );
+
+        let wire = StageEntryPoints::find("wire", &entry_points);
+        assert_eq!(
+            wire.get_stage_flags(),
+            vec![
+                vk::ShaderStageFlags::VERTEX,
+                vk::ShaderStageFlags::GEOMETRY,
+                vk::ShaderStageFlags::FRAGMENT,
+            ]
+        );
+        assert!(!wire.has_tessellation());
+
+        let names: Vec<String> = ["terrain_tes", "terrain_fs", "terrain_vs", "terrain_tcs"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        let terrain = StageEntryPoints::find("terrain", &names);
+        assert_eq!(
+            terrain.get_stage_flags(),
+            vec![
+                vk::ShaderStageFlags::VERTEX,
+                vk::ShaderStageFlags::TESSELLATION_CONTROL,
+                vk::ShaderStageFlags::TESSELLATION_EVALUATION,
+                vk::ShaderStageFlags::FRAGMENT,
+            ]
+        );
+        assert!(terrain.has_tessellation());
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright © 2021
+// Author: Antonio Caggiano <info@antoniocaggiano.eu>
+// SPDX-License-Identifier: MIT
+
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use ash::*;
+
+use super::*;
+
+/// Recycles staging buffers by size class, so that streaming uploads do not allocate a new
+/// buffer every time. Buffers should be released only once the copies reading them are finished,
+/// as `UploadContext::finish` does.
+pub struct StagingPool<B = Buffer> {
+    /// Sizes are rounded up to a power of two no smaller than this
+    min_size: vk::DeviceSize,
+    /// Free buffers kept for each size class, further ones are dropped on release
+    max_free: usize,
+    free: HashMap<vk::DeviceSize, Vec<B>>,
+}
+
+impl<B> Default for StagingPool<B> {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_MIN_SIZE, Self::DEFAULT_MAX_FREE)
+    }
+}
+
+impl<B> StagingPool<B> {
+    pub const DEFAULT_MIN_SIZE: vk::DeviceSize = 256;
+    pub const DEFAULT_MAX_FREE: usize = 4;
+
+    pub fn new(min_size: vk::DeviceSize, max_free: usize) -> Self {
+        assert!(
+            min_size >= 32,
+            "Staging buffers should be at least 32 bytes"
+        );
+        Self {
+            min_size,
+            max_free,
+            free: HashMap::new(),
+        }
+    }
+
+    pub fn get_size_class(&self, size: vk::DeviceSize) -> vk::DeviceSize {
+        size.max(self.min_size).next_power_of_two()
+    }
+
+    /// Returns a free buffer of the size class of `size`, otherwise one created by `create`
+    /// with the size of the class
+    pub fn acquire_with<F>(&mut self, size: vk::DeviceSize, create: F) -> B
+    where
+        F: FnOnce(vk::DeviceSize) -> B,
+    {
+        let class = self.get_size_class(size);
+        match self.free.get_mut(&class).and_then(|free| free.pop()) {
+            Some(buffer) => buffer,
+            None => create(class),
+        }
+    }
+
+    /// Returns `buffer` of `size` to the pool. It is dropped when its size is not a size class,
+    /// or when enough buffers of its class are free already.
+    pub fn release_with_size(&mut self, buffer: B, size: vk::DeviceSize) {
+        if self.get_size_class(size) != size {
+            return;
+        }
+        let free = self.free.entry(size).or_default();
+        if free.len() < self.max_free {
+            free.push(buffer);
+        }
+    }
+
+    /// Returns the number of free buffers across all size classes
+    pub fn get_free_count(&self) -> usize {
+        self.free.values().map(Vec::len).sum()
+    }
+
+    /// Drops all the free buffers
+    pub fn clear(&mut self) {
+        self.free.clear();
+    }
+}
+
+impl StagingPool<Buffer> {
+    /// Returns a host-visible buffer of at least `size` bytes to copy from
+    pub fn acquire(
+        &mut self,
+        allocator: &Rc<RefCell<vk_mem::Allocator>>,
+        size: vk::DeviceSize,
+    ) -> Buffer {
+        self.acquire_with(size, |class| {
+            Buffer::new_with_size(allocator, vk::BufferUsageFlags::TRANSFER_SRC, class)
+        })
+    }
+
+    /// Returns a staging buffer to the pool, once the copies reading it are finished
+    pub fn release(&mut self, buffer: Buffer) {
+        if buffer.is_host_visible() {
+            let size = buffer.size;
+            self.release_with_size(buffer, size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn staging_reuse() {
+        let mut pool = StagingPool::<u32>::default();
+        assert_eq!(pool.get_size_class(1), 256);
+        assert_eq!(pool.get_size_class(1000), 1024);
+        assert_eq!(pool.get_size_class(1024), 1024);
+
+        let mut created = 0;
+        let mut create = |_: vk::DeviceSize| {
+            created += 1;
+            created
+        };
+
+        // Upload of 1000 bytes, released once its copy is complete
+        let first = pool.acquire_with(1000, &mut create);
+        pool.release_with_size(first, 1024);
+        assert_eq!(pool.get_free_count(), 1);
+
+        // A second upload of the same size reuses it
+        let second = pool.acquire_with(1000, &mut create);
+        assert_eq!(second, first);
+        assert_eq!(pool.get_free_count(), 0);
+
+        // While it is in use, another one is created
+        let third = pool.acquire_with(900, &mut create);
+        assert_ne!(third, second);
+
+        // Buffers which are not of a size class are not kept
+        pool.release_with_size(4, 1000);
+        assert_eq!(pool.get_free_count(), 0);
+    }
+
+    #[test]
+    fn staging_max_free() {
+        let mut pool = StagingPool::<u32>::new(32, 1);
+        pool.release_with_size(1, 32);
+        pool.release_with_size(2, 32);
+        assert_eq!(pool.get_free_count(), 1);
+        pool.clear();
+        assert_eq!(pool.get_free_count(), 0);
+    }
+}
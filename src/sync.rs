@@ -63,14 +63,17 @@ impl Fence {
     }
 
     pub fn wait(&mut self) {
-        if self.can_wait {
-            unsafe {
-                self.device
-                    .wait_for_fences(&[self.fence], true, std::u64::MAX)
-            }
+        self.wait_timeout(std::u64::MAX)
             .expect("Failed waiting for Vulkan fence");
+    }
+
+    /// Waits up to `timeout` nanoseconds, returning `TIMEOUT` if the fence is not signaled yet
+    pub fn wait_timeout(&mut self, timeout: u64) -> Result<(), vk::Result> {
+        if self.can_wait {
+            unsafe { self.device.wait_for_fences(&[self.fence], true, timeout) }?;
             self.can_wait = false;
         }
+        Ok(())
     }
 
     pub fn reset(&mut self) {
@@ -87,3 +90,77 @@ impl Drop for Fence {
         }
     }
 }
+
+/// Callbacks to run once the GPU work they were registered with is complete,
+/// such as freeing a streamed resource or resolving a readback
+#[derive(Default)]
+pub struct CompletionCallbacks {
+    callbacks: Vec<Box<dyn FnOnce()>>,
+}
+
+impl CompletionCallbacks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, callback: Box<dyn FnOnce()>) {
+        self.callbacks.push(callback);
+    }
+
+    pub fn len(&self) -> usize {
+        self.callbacks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.callbacks.is_empty()
+    }
+
+    /// Runs the callbacks in order of registration. It should be called only after waiting
+    /// for the fence of the submission they were registered with.
+    pub fn run(&mut self) {
+        for callback in self.callbacks.drain(..) {
+            callback();
+        }
+    }
+}
+
+impl Drop for CompletionCallbacks {
+    /// Callbacks still pending run on drop, which should follow the wait for their fence
+    fn drop(&mut self) {
+        self.run();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    #[test]
+    fn completion_callbacks() {
+        let completed = Rc::new(RefCell::new(vec![]));
+        let mut callbacks = CompletionCallbacks::new();
+
+        for frame in 0..2 {
+            let completed = completed.clone();
+            callbacks.push(Box::new(move || completed.borrow_mut().push(frame)));
+        }
+        // Nothing runs before the fence is waited
+        assert_eq!(callbacks.len(), 2);
+        assert!(completed.borrow().is_empty());
+
+        callbacks.run();
+        assert_eq!(*completed.borrow(), vec![0, 1]);
+        assert!(callbacks.is_empty());
+
+        // Callbacks run only once
+        callbacks.run();
+        assert_eq!(completed.borrow().len(), 2);
+
+        let dropped = completed.clone();
+        callbacks.push(Box::new(move || dropped.borrow_mut().push(2)));
+        drop(callbacks);
+        assert_eq!(*completed.borrow(), vec![0, 1, 2]);
+    }
+}
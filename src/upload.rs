@@ -0,0 +1,179 @@
+// Copyright © 2021
+// Author: Antonio Caggiano <info@antoniocaggiano.eu>
+// SPDX-License-Identifier: MIT
+
+use ash::*;
+
+use super::*;
+
+/// Records many uploads, such as the images and buffers of a whole scene, into a single command
+/// buffer of the transfer queue, which is submitted and waited once by `finish`. Uploaded
+/// resources should not be used before that. See also `Dev::batch_submit`.
+pub struct UploadContext {
+    command_buffer: CommandBuffer,
+    /// Ownership transfer to the graphics queue, when its family differs from the transfer one
+    transfer: Option<UploadTransfer>,
+    /// Stages of the graphics queue acquiring the uploaded resources
+    dst_stage_mask: vk::PipelineStageFlags,
+    image_acquires: Vec<vk::ImageMemoryBarrier>,
+    buffer_acquires: Vec<vk::BufferMemoryBarrier>,
+    /// Staging buffers kept alive until the copies are finished
+    staging: Vec<Buffer>,
+}
+
+impl UploadContext {
+    pub fn new(dev: &Dev) -> Self {
+        let command_buffer = CommandBuffer::new(dev.get_transfer_command_pool());
+        command_buffer.begin(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+
+        Self {
+            command_buffer,
+            transfer: dev.get_upload_transfer(),
+            dst_stage_mask: vk::PipelineStageFlags::empty(),
+            image_acquires: vec![],
+            buffer_acquires: vec![],
+            staging: vec![],
+        }
+    }
+
+    /// Calls `record` with `upload` when available, otherwise with a new context
+    /// which is finished straight away
+    pub fn record_or_submit<F>(dev: &Dev, upload: Option<&mut UploadContext>, record: F)
+    where
+        F: FnOnce(&mut UploadContext),
+    {
+        match upload {
+            Some(upload) => record(upload),
+            None => {
+                let mut upload = UploadContext::new(dev);
+                record(&mut upload);
+                upload.finish(dev);
+            }
+        }
+    }
+
+    pub fn get_command_buffer(&self) -> &CommandBuffer {
+        &self.command_buffer
+    }
+
+    /// Keeps `staging` alive until the copies reading from it are finished
+    pub fn keep(&mut self, staging: Buffer) {
+        self.staging.push(staging);
+    }
+
+    /// Records the copy of the whole `staging` buffer into `image`, keeping it alive until
+    /// the copy is finished
+    pub fn copy_image(&mut self, staging: Buffer, image: &mut Image) {
+        image.record_copy_from(&staging, self);
+        self.keep(staging);
+    }
+
+    /// Records the transition of `image` to `new_layout`
+    pub fn transition(&mut self, image: &mut Image, new_layout: vk::ImageLayout) {
+        image.record_transition(new_layout, self);
+    }
+
+    /// Records `barrier`, which makes an image upload available to the graphics queue
+    /// at `dst_stage_mask`. When the queue families differ, it releases the image instead,
+    /// and the graphics queue acquires it on `finish`.
+    pub fn release_image(
+        &mut self,
+        src_stage_mask: vk::PipelineStageFlags,
+        dst_stage_mask: vk::PipelineStageFlags,
+        barrier: &vk::ImageMemoryBarrier,
+    ) {
+        match &self.transfer {
+            // Graphics stages may not be supported by the transfer queue
+            Some(transfer) => {
+                self.command_buffer.pipeline_barriers(
+                    src_stage_mask,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    vk::DependencyFlags::default(),
+                    &[transfer.get_release_barrier(barrier)],
+                );
+                self.image_acquires
+                    .push(transfer.get_acquire_barrier(barrier));
+                self.dst_stage_mask |= dst_stage_mask;
+            }
+            None => self.command_buffer.pipeline_barriers(
+                src_stage_mask,
+                dst_stage_mask,
+                vk::DependencyFlags::default(),
+                &[*barrier],
+            ),
+        }
+    }
+
+    /// Like `release_image`, but for a buffer upload
+    pub fn release_buffer(
+        &mut self,
+        src_stage_mask: vk::PipelineStageFlags,
+        dst_stage_mask: vk::PipelineStageFlags,
+        barrier: &vk::BufferMemoryBarrier,
+    ) {
+        match &self.transfer {
+            Some(transfer) => {
+                self.command_buffer.buffer_barriers(
+                    src_stage_mask,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    &[transfer.get_release_buffer_barrier(barrier)],
+                );
+                self.buffer_acquires
+                    .push(transfer.get_acquire_buffer_barrier(barrier));
+                self.dst_stage_mask |= dst_stage_mask;
+            }
+            None => {
+                self.command_buffer
+                    .buffer_barriers(src_stage_mask, dst_stage_mask, &[*barrier])
+            }
+        }
+    }
+
+    /// Submits all the recorded uploads and waits for them, then releases the staging buffers
+    /// to the staging pool of `dev`
+    pub fn finish(self, dev: &Dev) {
+        self.command_buffer.end();
+
+        let mut fence = Fence::unsignaled(&dev.device);
+        let submits = [vk::SubmitInfo::builder()
+            .command_buffers(&[self.command_buffer.command_buffer])
+            .build()];
+        dev.transfer_queue.submit(&submits, Some(&mut fence));
+        fence.wait();
+
+        // Copies are finished, hence staging buffers can be reused
+        for staging in self.staging {
+            dev.release_staging(staging);
+        }
+
+        if self.image_acquires.is_empty() && self.buffer_acquires.is_empty() {
+            return;
+        }
+
+        // Release is complete, hence no semaphore is needed
+        let command_buffer = CommandBuffer::new(&dev.transient_command_pool);
+        command_buffer.begin(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        if !self.image_acquires.is_empty() {
+            command_buffer.pipeline_barriers(
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                self.dst_stage_mask,
+                vk::DependencyFlags::default(),
+                &self.image_acquires,
+            );
+        }
+        if !self.buffer_acquires.is_empty() {
+            command_buffer.buffer_barriers(
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                self.dst_stage_mask,
+                &self.buffer_acquires,
+            );
+        }
+        command_buffer.end();
+
+        let submits = [vk::SubmitInfo::builder()
+            .command_buffers(&[command_buffer.command_buffer])
+            .build()];
+        dev.graphics_queue.submit(&submits, Some(&mut fence));
+        fence.wait();
+    }
+}
@@ -2,24 +2,46 @@
 // Author: Antonio Caggiano <info@antoniocaggiano.eu>
 // SPDX-License-Identifier: MIT
 
+use std::collections::{HashMap, VecDeque};
 use std::ops::Deref;
 use std::time::{Duration, Instant};
 use std::{
+    cell::Cell,
     hash::{Hash, Hasher},
     marker::PhantomData,
 };
 
+use nalgebra as na;
+
 /// Useful timer to get delta time, and previous time
 pub struct Timer {
     prev: Instant,
     curr: Instant,
+    /// Most recent deltas, used to smooth the instantaneous value
+    deltas: VecDeque<Duration>,
+    window: usize,
 }
 
 impl Timer {
+    /// Number of deltas averaged by default
+    pub const DEFAULT_WINDOW: usize = 60;
+
     pub fn new() -> Self {
+        Self::with_window(Self::DEFAULT_WINDOW)
+    }
+
+    /// Creates a timer averaging the last `window` deltas, where a window of 1
+    /// makes the average equal to the instantaneous delta
+    pub fn with_window(window: usize) -> Self {
+        assert!(window > 0);
         let prev = Instant::now();
         let curr = Instant::now();
-        Self { prev, curr }
+        Self {
+            prev,
+            curr,
+            deltas: VecDeque::with_capacity(window),
+            window,
+        }
     }
 
     /// Returns delta time in seconds
@@ -27,28 +49,147 @@ impl Timer {
         self.curr = Instant::now();
         let delta = self.curr - self.prev;
         self.prev = self.curr;
+        self.push_delta(delta);
         delta
     }
 
+    fn push_delta(&mut self, delta: Duration) {
+        if self.deltas.len() == self.window {
+            self.deltas.pop_front();
+        }
+        self.deltas.push_back(delta);
+    }
+
+    /// Returns the average of the last deltas, or zero before the first `get_delta()`
+    pub fn get_average_delta(&self) -> Duration {
+        if self.deltas.is_empty() {
+            return Duration::default();
+        }
+        self.deltas.iter().sum::<Duration>() / self.deltas.len() as u32
+    }
+
+    /// Returns frames per second according to the average delta
+    pub fn get_fps(&self) -> f32 {
+        let delta = self.get_average_delta().as_secs_f32();
+        if delta > 0.0 {
+            1.0 / delta
+        } else {
+            0.0
+        }
+    }
+
     /// Returns the time of last `get_delta()`
     pub fn _get_prev(&self) -> Instant {
         self.prev
     }
 }
 
+/// Rolling window of the most recent frame times, in milliseconds
+pub struct FrameTimes {
+    /// Ring buffer of samples
+    samples: Vec<f32>,
+    /// Position where next sample is written
+    next: usize,
+    capacity: usize,
+}
+
+impl FrameTimes {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0);
+        Self {
+            samples: Vec::with_capacity(capacity),
+            next: 0,
+            capacity,
+        }
+    }
+
+    /// Records a new sample, replacing the oldest one when the window is full
+    pub fn push(&mut self, millis: f32) {
+        if self.samples.len() < self.capacity {
+            self.samples.push(millis);
+        } else {
+            self.samples[self.next] = millis;
+        }
+        self.next = (self.next + 1) % self.capacity;
+    }
+
+    /// Returns the samples from the oldest to the most recent, ready to be plotted
+    pub fn get_samples(&self) -> Vec<f32> {
+        if self.samples.len() < self.capacity {
+            return self.samples.clone();
+        }
+        let (newest, oldest) = self.samples.split_at(self.next);
+        [oldest, newest].concat()
+    }
+
+    pub fn get_min(&self) -> f32 {
+        self.samples.iter().copied().fold(f32::INFINITY, f32::min)
+    }
+
+    pub fn get_max(&self) -> f32 {
+        self.samples
+            .iter()
+            .copied()
+            .fold(f32::NEG_INFINITY, f32::max)
+    }
+
+    /// Returns the average of the samples in the window
+    pub fn get_avg(&self) -> f32 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+        self.samples.iter().sum::<f32>() / self.samples.len() as f32
+    }
+}
+
+/// Plain old data, which can be viewed as bytes, for example to be pushed as constants.
+///
+/// # Safety
+///
+/// Implementors should have no padding bytes and no invalid bit patterns, which holds for
+/// `#[repr(C)]` structs made only of `Pod` fields with no gaps between them.
+pub unsafe trait Pod: Copy {}
+
+unsafe impl Pod for u8 {}
+unsafe impl Pod for u16 {}
+unsafe impl Pod for u32 {}
+unsafe impl Pod for u64 {}
+unsafe impl Pod for i8 {}
+unsafe impl Pod for i16 {}
+unsafe impl Pod for i32 {}
+unsafe impl Pod for i64 {}
+unsafe impl Pod for f32 {}
+unsafe impl Pod for f64 {}
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+unsafe impl Pod for na::Vector2<f32> {}
+unsafe impl Pod for na::Vector3<f32> {}
+unsafe impl Pod for na::Vector4<f32> {}
+unsafe impl Pod for na::Matrix3<f32> {}
+unsafe impl Pod for na::Matrix4<f32> {}
+
+/// Returns the bytes of `data`
+pub fn as_bytes<T: Pod>(data: &T) -> &[u8] {
+    // Safe as Pod types have no padding, and the slice borrows data
+    unsafe { std::slice::from_raw_parts(data as *const T as *const u8, std::mem::size_of::<T>()) }
+}
+
 /// A handle is a sort of index into a vector of elements of a specific kind.
 /// It is useful when we do not want to keep a reference to an element,
 /// while taking advantage of strong typing to avoid using integers.
 #[derive(Debug)]
 pub struct Handle<T> {
     pub id: usize,
+    /// Generation of the pack slot when this handle was issued, so a handle
+    /// to a removed element does not resolve to the element reusing its id
+    pub generation: u32,
     phantom: PhantomData<T>,
 }
 
 impl<T> Handle<T> {
-    pub fn new(id: usize) -> Self {
+    pub fn new(id: usize, generation: u32) -> Self {
         Self {
             id,
+            generation,
             phantom: PhantomData,
         }
     }
@@ -56,6 +197,7 @@ impl<T> Handle<T> {
     pub fn none() -> Self {
         Self {
             id: std::usize::MAX,
+            generation: 0,
             phantom: PhantomData,
         }
     }
@@ -67,7 +209,7 @@ impl<T> Handle<T> {
 
 impl<'a, T> Handle<T> {
     pub fn get(&self, pack: &'a Pack<T>) -> Option<&'a T> {
-        pack.vec.get(self.id)
+        pack.get(*self)
     }
 }
 
@@ -81,11 +223,7 @@ impl<T> Copy for Handle<T> {}
 
 impl<T> PartialEq for Handle<T> {
     fn eq(&self, other: &Self) -> bool {
-        self.id == other.id
-    }
-
-    fn ne(&self, other: &Self) -> bool {
-        self.id != other.id
+        self.id == other.id && self.generation == other.generation
     }
 }
 
@@ -94,6 +232,7 @@ impl<T> Eq for Handle<T> {}
 impl<T> Hash for Handle<T> {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.id.hash(state);
+        self.generation.hash(state);
     }
 }
 
@@ -104,8 +243,14 @@ pub struct Pack<T> {
     vec: Vec<T>,
     /// List of indices to elements
     indices: Vec<usize>,
+    /// For each element, the position of the index pointing to it
+    rev: Vec<usize>,
     /// List of positions to free indices
     free: Vec<usize>,
+    /// Generation of each index, incremented when its element is removed
+    generations: Vec<u32>,
+    /// Whether a dangling access has already been logged
+    dangling_logged: Cell<bool>,
 }
 
 impl<T> Pack<T> {
@@ -113,7 +258,10 @@ impl<T> Pack<T> {
         Self {
             vec: vec![],
             indices: vec![],
+            rev: vec![],
             free: vec![],
+            generations: vec![],
+            dangling_logged: Cell::new(false),
         }
     }
 
@@ -121,15 +269,26 @@ impl<T> Pack<T> {
         let index = self.vec.len();
         self.vec.push(elem);
 
-        if !self.free.is_empty() {
+        let id = if !self.free.is_empty() {
             let id = self.free.pop().unwrap();
             self.indices[id] = index;
-            Handle::new(id)
+            id
         } else {
             let id = self.indices.len();
             self.indices.push(index);
-            Handle::new(id)
-        }
+            // Generations survive `clear`, so ids issued again are still distinguishable
+            if id == self.generations.len() {
+                self.generations.push(0);
+            }
+            id
+        };
+        self.rev.push(id);
+        Handle::new(id, self.generations[id])
+    }
+
+    /// Returns whether the element referred by this handle has been removed
+    fn is_stale(&self, handle: Handle<T>) -> bool {
+        self.generations[handle.id] != handle.generation
     }
 
     fn get_vec_index(&self, handle: Handle<T>) -> usize {
@@ -143,6 +302,9 @@ impl<T> Pack<T> {
         if !handle.valid() {
             return None;
         }
+        if self.is_stale(handle) {
+            return None;
+        }
         self.vec.get(self.get_vec_index(handle))
     }
 
@@ -150,25 +312,130 @@ impl<T> Pack<T> {
         if !handle.valid() {
             return None;
         }
+        if self.is_stale(handle) {
+            return None;
+        }
         let vec_index = self.get_vec_index(handle);
         self.vec.get_mut(vec_index)
     }
 
+    /// Returns the index of the element referred by a handle which is out of range
+    /// or refers to a removed element, logging the first dangling access
+    fn try_get_vec_index(&self, handle: Handle<T>) -> Option<usize> {
+        if !handle.valid() {
+            return None;
+        }
+        if handle.id >= self.indices.len() || self.is_stale(handle) {
+            if !self.dangling_logged.replace(true) {
+                eprintln!(
+                    "Dangling handle {} into pack of {}",
+                    handle.id,
+                    std::any::type_name::<T>()
+                );
+            }
+            return None;
+        }
+        Some(self.indices[handle.id])
+    }
+
+    /// Like `get`, but does not panic when the handle is dangling
+    pub fn try_get(&self, handle: Handle<T>) -> Option<&T> {
+        let vec_index = self.try_get_vec_index(handle)?;
+        self.vec.get(vec_index)
+    }
+
+    /// Like `get_mut`, but does not panic when the handle is dangling
+    pub fn try_get_mut(&mut self, handle: Handle<T>) -> Option<&mut T> {
+        let vec_index = self.try_get_vec_index(handle)?;
+        self.vec.get_mut(vec_index)
+    }
+
+    /// Returns mutable references to the elements referred by two different handles,
+    /// or `None` when the handles are equal or any of them is invalid
+    pub fn get_mut2(&mut self, a: Handle<T>, b: Handle<T>) -> Option<(&mut T, &mut T)> {
+        if a == b {
+            return None;
+        }
+        let a_index = self.try_get_vec_index(a)?;
+        let b_index = self.try_get_vec_index(b)?;
+
+        if a_index < b_index {
+            let (left, right) = self.vec.split_at_mut(b_index);
+            Some((&mut left[a_index], &mut right[0]))
+        } else {
+            let (left, right) = self.vec.split_at_mut(a_index);
+            Some((&mut right[0], &mut left[b_index]))
+        }
+    }
+
+    /// Returns handles to all the elements of this pack
+    pub fn get_handles(&self) -> Vec<Handle<T>> {
+        self.iter().map(|(handle, _)| handle).collect()
+    }
+
+    /// Returns the ids of the handles in use with the positions of their elements,
+    /// sorted by id. Only elements have an entry in `rev`, so free slots are skipped in linear time.
+    fn get_live_indices(&self) -> Vec<(usize, usize)> {
+        let mut live = vec![None; self.indices.len()];
+        for (vec_index, &id) in self.rev.iter().enumerate() {
+            live[id] = Some(vec_index);
+        }
+        live.into_iter()
+            .enumerate()
+            .filter_map(|(id, vec_index)| Some((id, vec_index?)))
+            .collect()
+    }
+
+    /// Iterates over the elements of this pack together with their handles.
+    /// Removed slots are skipped.
+    pub fn iter(&self) -> impl Iterator<Item = (Handle<T>, &T)> {
+        self.get_live_indices()
+            .into_iter()
+            .map(move |(id, vec_index)| {
+                (Handle::new(id, self.generations[id]), &self.vec[vec_index])
+            })
+    }
+
+    /// Like `iter`, but elements can be mutated
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Handle<T>, &mut T)> {
+        let handles: Vec<(Handle<T>, usize)> = self
+            .get_live_indices()
+            .into_iter()
+            .map(|(id, vec_index)| (Handle::new(id, self.generations[id]), vec_index))
+            .collect();
+        // Each element is referred by one index only, so it is taken exactly once
+        let mut elems: Vec<Option<&mut T>> = self.vec.iter_mut().map(Some).collect();
+        handles
+            .into_iter()
+            .map(move |(handle, vec_index)| (handle, elems[vec_index].take().unwrap()))
+    }
+
+    /// Removes all the elements while keeping allocated memory for following pushes.
+    /// Handles issued before clearing are dangling, although their ids may be reissued.
+    pub fn clear(&mut self) {
+        self.vec.clear();
+        self.indices.clear();
+        self.rev.clear();
+        self.free.clear();
+        for generation in &mut self.generations {
+            *generation = generation.wrapping_add(1);
+        }
+    }
+
     pub fn remove(&mut self, handle: Handle<T>) {
+        assert!(!self.is_stale(handle), "Failed to remove stale handle");
         let vec_index = self.get_vec_index(handle);
-        let last_vec_index = self.vec.len() - 1;
-        self.vec.swap(vec_index, last_vec_index);
-        self.vec.pop();
-
-        // Update index that was pointing to last element
-        // We do not know where it is, therefore let us find it
-        for index in &mut self.indices {
-            if *index == last_vec_index {
-                *index = vec_index;
-            }
+        self.vec.swap_remove(vec_index);
+
+        // Update index that was pointing to last element, which is now at `vec_index`
+        self.rev.swap_remove(vec_index);
+        if let Some(&moved_id) = self.rev.get(vec_index) {
+            self.indices[moved_id] = vec_index;
         }
 
-        // Index of the removed element can be added to free list
+        // Index of the removed element can be added to free list,
+        // and handles to the removed element become stale
+        self.generations[handle.id] = self.generations[handle.id].wrapping_add(1);
         self.free.push(handle.id);
     }
 }
@@ -181,6 +448,56 @@ impl<T> Deref for Pack<T> {
     }
 }
 
+/// Associates values to elements of a `Pack`, for example the buffers of a frame for each node.
+/// Handles are issued by the pack of their elements, hence values can not be pushed here and are
+/// inserted at the handle given instead.
+pub struct HandleMap<K, V> {
+    map: HashMap<Handle<K>, V>,
+}
+
+impl<K, V> HandleMap<K, V> {
+    pub fn new() -> Self {
+        Self {
+            map: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn contains(&self, handle: Handle<K>) -> bool {
+        self.map.contains_key(&handle)
+    }
+
+    pub fn get(&self, handle: Handle<K>) -> Option<&V> {
+        self.map.get(&handle)
+    }
+
+    pub fn get_mut(&mut self, handle: Handle<K>) -> Option<&mut V> {
+        self.map.get_mut(&handle)
+    }
+
+    /// Inserts a value at `handle`, returning the value previously there
+    pub fn insert(&mut self, handle: Handle<K>, value: V) -> Option<V> {
+        self.map.insert(handle, value)
+    }
+
+    pub fn remove(&mut self, handle: Handle<K>) -> Option<V> {
+        self.map.remove(&handle)
+    }
+
+    /// Returns the value at `handle`, inserting the one created by `f` when there is none.
+    /// The closure is only called on the first access, following calls return the same value.
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, handle: Handle<K>, f: F) -> &mut V {
+        self.map.entry(handle).or_insert_with(f)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::collections::HashMap;
@@ -198,9 +515,61 @@ mod test {
         }
     }
 
+    #[test]
+    fn timer_average_delta() {
+        let mut timer = Timer::with_window(3);
+        assert_eq!(timer.get_average_delta(), Duration::default());
+        assert_eq!(timer.get_fps(), 0.0);
+
+        for millis in [10, 20, 30, 40].iter() {
+            timer.push_delta(Duration::from_millis(*millis));
+        }
+        // Oldest delta is out of the window
+        assert_eq!(timer.get_average_delta(), Duration::from_millis(30));
+        assert!((timer.get_fps() - 1000.0 / 30.0).abs() < 0.01);
+
+        let mut timer = Timer::with_window(1);
+        timer.push_delta(Duration::from_millis(10));
+        timer.push_delta(Duration::from_millis(20));
+        assert_eq!(timer.get_average_delta(), Duration::from_millis(20));
+    }
+
+    #[test]
+    fn frame_times_rolling() {
+        let mut times = FrameTimes::new(4);
+        assert_eq!(times.get_avg(), 0.0);
+
+        for &millis in [10.0, 20.0, 30.0].iter() {
+            times.push(millis);
+        }
+        assert_eq!(times.get_avg(), 20.0);
+
+        // Oldest samples fall out of the window
+        times.push(40.0);
+        times.push(50.0);
+        times.push(60.0);
+        assert_eq!(times.get_samples(), vec![30.0, 40.0, 50.0, 60.0]);
+        assert_eq!(times.get_avg(), 45.0);
+        assert_eq!(times.get_min(), 30.0);
+        assert_eq!(times.get_max(), 60.0);
+    }
+
+    #[test]
+    fn pod_bytes() {
+        let values: [u16; 2] = [1, 0x0203];
+        let bytes = as_bytes(&values);
+        assert_eq!(bytes.len(), 4);
+        assert_eq!(bytes[0..2], 1u16.to_ne_bytes());
+        assert_eq!(bytes[2..4], 0x0203u16.to_ne_bytes());
+
+        let position = na::Vector3::new(1.0f32, 2.0, 3.0);
+        assert_eq!(as_bytes(&position).len(), 12);
+        assert_eq!(as_bytes(&position)[8..12], 3.0f32.to_ne_bytes());
+    }
+
     #[test]
     fn compare() {
-        let a = Handle::<Thing>::new(0);
+        let a = Handle::<Thing>::new(0, 0);
         let b = a;
         assert_eq!(a, b);
     }
@@ -208,11 +577,40 @@ mod test {
     #[test]
     fn contain() {
         let mut map = HashMap::<Handle<Thing>, Thing>::new();
-        let h = Handle::new(0);
+        let h = Handle::new(0, 0);
         map.insert(h, Thing::new(1));
         assert!(map.contains_key(&h));
     }
 
+    #[test]
+    fn handle_map_get_or_insert() {
+        let mut pack = Pack::new();
+        let a = pack.push(Thing::new(0));
+        let b = pack.push(Thing::new(1));
+
+        let mut map = HandleMap::<Thing, Vec<u32>>::new();
+        let mut created = 0;
+        map.get_or_insert_with(a, || {
+            created += 1;
+            vec![]
+        })
+        .push(1);
+        // Subsequent gets return the same element without calling the closure
+        map.get_or_insert_with(a, || {
+            created += 1;
+            vec![]
+        })
+        .push(2);
+        assert_eq!(created, 1);
+        assert_eq!(map.get(a), Some(&vec![1, 2]));
+
+        assert!(!map.contains(b));
+        map.get_or_insert_with(b, || vec![3]);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.remove(b), Some(vec![3]));
+        assert!(map.get(b).is_none());
+    }
+
     #[test]
     fn simple() {
         let mut pack = Pack::new();
@@ -246,8 +644,116 @@ mod test {
         pack.remove(handle);
         assert_eq!(pack.len(), 0);
 
-        let handle = pack.push(Thing { val: 1 });
-        assert_eq!(handle.id, 0);
-        assert_eq!(pack.get(handle).unwrap().val, 1);
+        let new_handle = pack.push(Thing { val: 1 });
+        assert_eq!(new_handle.id, 0);
+        assert_eq!(pack.get(new_handle).unwrap().val, 1);
+
+        // Old handle does not resolve to the element reusing its id
+        assert!(pack.get(handle).is_none());
+        assert!(pack.get_mut(handle).is_none());
+        assert!(pack.try_get(handle).is_none());
+        assert!(handle != new_handle);
+    }
+
+    #[test]
+    fn iter_handles() {
+        let mut pack = Pack::new();
+        let a = pack.push(Thing { val: 0 });
+        let b = pack.push(Thing { val: 1 });
+        let c = pack.push(Thing { val: 2 });
+
+        // Last element is moved where the removed one was
+        pack.remove(a);
+
+        let pairs: Vec<(Handle<Thing>, u32)> = pack
+            .iter()
+            .map(|(handle, thing)| (handle, thing.val))
+            .collect();
+        assert_eq!(pairs, vec![(b, 1), (c, 2)]);
+
+        for (handle, thing) in pack.iter_mut() {
+            thing.val = handle.id as u32 * 10;
+        }
+        assert_eq!(pack.get(b).unwrap().val, 10);
+        assert_eq!(pack.get(c).unwrap().val, 20);
+        assert_eq!(pack.get_handles(), vec![b, c]);
+
+        // A pushed element reuses the free id and is iterated in id order
+        let d = pack.push(Thing { val: 3 });
+        assert_eq!(d.id, a.id);
+        assert_eq!(pack.get_handles(), vec![d, b, c]);
+    }
+
+    #[test]
+    fn get_mut2_disjoint() {
+        let mut pack = Pack::new();
+        let a = pack.push(Thing { val: 1 });
+        let b = pack.push(Thing { val: 2 });
+        let c = pack.push(Thing { val: 3 });
+
+        let (c_thing, a_thing) = pack.get_mut2(c, a).unwrap();
+        c_thing.val += a_thing.val;
+        a_thing.val = 0;
+        assert_eq!(pack.get(a).unwrap().val, 0);
+        assert_eq!(pack.get(b).unwrap().val, 2);
+        assert_eq!(pack.get(c).unwrap().val, 4);
+
+        assert!(pack.get_mut2(b, b).is_none());
+        assert!(pack.get_mut2(a, Handle::none()).is_none());
+    }
+
+    #[test]
+    fn clear_keeps_capacity() {
+        let mut pack = Pack::new();
+        let a = pack.push(Thing { val: 1 });
+        let b = pack.push(Thing { val: 2 });
+        let capacity = pack.capacity();
+
+        pack.clear();
+        assert!(pack.is_empty());
+        assert_eq!(pack.capacity(), capacity);
+        assert!(pack.try_get(b).is_none());
+
+        // Ids start from the beginning again, with a new generation
+        let c = pack.push(Thing { val: 3 });
+        assert_eq!(c.id, a.id);
+        assert_eq!(pack.len(), 1);
+        assert_eq!(pack.get(c).unwrap().val, 3);
+        assert!(pack.get(a).is_none());
+        assert!(pack.try_get(b).is_none());
+    }
+
+    #[test]
+    fn interleaved_push_remove() {
+        let mut pack = Pack::new();
+        let mut alive: Vec<(Handle<Thing>, u32)> = vec![];
+
+        for i in 0..256u32 {
+            alive.push((pack.push(Thing { val: i }), i));
+            // Remove every third element, picking positions all over the pack
+            if i % 3 == 2 {
+                let (handle, _) = alive.remove((i as usize * 7) % alive.len());
+                pack.remove(handle);
+            }
+        }
+
+        assert_eq!(pack.len(), alive.len());
+        for (handle, val) in &alive {
+            assert_eq!(pack.get(*handle).unwrap().val, *val);
+        }
+    }
+
+    #[test]
+    fn try_get_dangling() {
+        let mut pack = Pack::new();
+        let a = pack.push(Thing { val: 0 });
+        let b = pack.push(Thing { val: 1 });
+
+        pack.remove(a);
+        assert!(pack.try_get(a).is_none());
+        assert!(pack.try_get_mut(a).is_none());
+        assert!(pack.try_get(Handle::new(4, 0)).is_none());
+        assert!(pack.try_get(Handle::none()).is_none());
+        assert_eq!(pack.try_get(b).unwrap().val, 1);
     }
 }